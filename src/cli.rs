@@ -0,0 +1,258 @@
+use std::{net::SocketAddr, path::PathBuf};
+
+use clap::{ArgAction, Args, Parser, Subcommand};
+use regex::Regex;
+
+use crate::{
+    output::{ColorChoice, OutputMode},
+    reporter::{GraphFormat, LogsPerVariant, SummaryFormat},
+};
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "ciroach",
+    version,
+    about = "Run container pipelines locally",
+    after_help = "Exit codes:
+  0    Success
+  1    A step failed
+  2    The configuration is invalid
+  3    The container engine or the machine failed, e.g. Docker is unreachable
+  4    A stage deadlocked, or made no progress within engine.stall_timeout
+  130  Interrupted by Ctrl+C"
+)]
+pub struct Cli {
+    /// Path to the pipeline configuration file.
+    #[arg(short, long, global = true, default_value = "ciroach.toml")]
+    pub config: PathBuf,
+
+    /// Colored output and emoji icons. `auto` turns both off when stdout is not a terminal
+    /// or NO_COLOR is set.
+    #[arg(long, global = true, value_enum, default_value_t = ColorChoice::Auto)]
+    pub color: ColorChoice,
+
+    /// Show more output: `-v` streams step logs live, `-vv` adds engine-level events.
+    /// `RUST_LOG` overrides which events are shown.
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Set a pipeline var used as `${{ vars.NAME }}`, over `CIROACH_VAR_NAME` and `[vars]`.
+    /// Repeatable.
+    #[arg(long = "var", global = true, value_name = "NAME=VALUE", value_parser = parse_var)]
+    pub vars: Vec<(String, String)>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Execute the pipeline (default when no command is given).
+    Run(RunArgs),
+    /// Show the stages and steps of the pipeline.
+    List,
+    /// Check the configuration, including the `[schedule]` expression, without running it.
+    Validate,
+    /// Print the pipeline as a dependency graph.
+    Graph(GraphArgs),
+    /// List steps that frequently need retries to pass.
+    Flaky(FlakyArgs),
+    /// Run pipelines on demand, triggered with `POST /run`, and on the `[schedule]`.
+    Serve(ServeArgs),
+    /// Run the pipeline from git hooks before pushing.
+    InstallHooks(HooksArgs),
+    /// Remove the hook blocks written by `install-hooks`.
+    UninstallHooks,
+    /// Remove step containers left behind by earlier runs, or images pulled for old ones.
+    Clean(CleanArgs),
+    /// Convert another CI system's configuration into a ciroach pipeline.
+    #[command(subcommand)]
+    Import(ImportSource),
+}
+
+#[derive(Debug, Default, Args)]
+pub struct RunArgs {
+    /// Pipeline to run, when the configuration defines several under `[pipelines]`.
+    pub pipeline: Option<String>,
+
+    /// Console output style. `auto` switches to `github` when GITHUB_ACTIONS=true.
+    #[arg(long, value_enum, default_value_t = OutputMode::Auto)]
+    pub output: OutputMode,
+
+    /// Only print the stage headers and the final summary table.
+    #[arg(short, long)]
+    pub quiet: bool,
+
+    /// Run only the steps selected by this profile from the `[profiles]` section.
+    #[arg(short, long, conflicts_with = "quick")]
+    pub profile: Option<String>,
+
+    /// Shorthand for `--profile quick`.
+    #[arg(long)]
+    pub quick: bool,
+
+    /// When a step fails, open a shell in a copy of its container before stopping.
+    /// Other running steps are paused meanwhile (their timeouts keep counting).
+    #[arg(long)]
+    pub debug_on_failure: bool,
+
+    /// Leave the containers of failed steps in place. Remove them with `ciroach clean --kept`.
+    #[arg(long)]
+    pub keep_failed: bool,
+
+    /// Print a chart of when each step ran after the summary table. An SVG version is
+    /// always saved in the run's log directory.
+    #[arg(long)]
+    pub timeline: bool,
+
+    /// Print plain lines instead of animated progress bars and step spinners.
+    #[arg(long)]
+    pub no_progress: bool,
+
+    /// Skip steps carrying this tag. Can be repeated.
+    #[arg(long = "skip-tag", value_name = "TAG")]
+    pub skip_tags: Vec<String>,
+
+    /// Serve a live dashboard on this address while the pipeline runs, e.g. `127.0.0.1:8999`.
+    #[arg(long, value_name = "ADDR")]
+    pub serve: Option<SocketAddr>,
+
+    /// Allow `--serve` to bind a non-loopback address. The dashboard has no authentication.
+    #[arg(long, requires = "serve")]
+    pub serve_insecure: bool,
+
+    /// Fail right away when another run holds the workspace, instead of waiting for it.
+    #[arg(long)]
+    pub no_wait: bool,
+
+    /// Take over the workspace lock left behind by a run that is no longer running.
+    #[arg(long)]
+    pub force: bool,
+
+    /// Pipe the standard input of ciroach into this step, e.g.
+    /// `cat dump.sql | ciroach run --stdin restore-db`. The step must not be retried.
+    #[arg(long, value_name = "STEP")]
+    pub stdin: Option<String>,
+
+    /// Pull images by their tags and write the digests they resolve to into
+    /// `ciroach.lock`. Later runs pull those digests, so everyone gets identical images.
+    #[arg(long)]
+    pub lock_images: bool,
+
+    /// Only show the log lines matching this regex after the run, e.g. `error|warning`.
+    /// The saved logs stay complete. Overrides `filter` under `[logs]`.
+    #[arg(long, value_name = "REGEX", value_parser = Regex::new)]
+    pub log_filter: Option<Regex>,
+
+    /// Lines shown before and after each line matching the log filter.
+    #[arg(short = 'C', long, value_name = "LINES")]
+    pub log_context: Option<usize>,
+
+    /// How the logs of a matrix step's variants are shown after the run.
+    #[arg(long, value_enum, default_value_t = LogsPerVariant::All)]
+    pub logs_per_variant: LogsPerVariant,
+
+    /// Print one last line about the run from this template, e.g.
+    /// `'{status} {passed}/{total} in {duration}'`. Placeholders: status, passed, failed,
+    /// skipped, cancelled, total, duration and failed_names.
+    #[arg(long, value_name = "TEMPLATE", value_parser = SummaryFormat::parse)]
+    pub summary_format: Option<SummaryFormat>,
+}
+
+#[derive(Debug, Args)]
+pub struct ServeArgs {
+    /// Address to listen on.
+    #[arg(long, default_value = "127.0.0.1:8999")]
+    pub listen: SocketAddr,
+
+    /// Allow listening on a non-loopback address. Triggers are not authenticated.
+    #[arg(long)]
+    pub insecure: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct HooksArgs {
+    /// Pipeline the hooks run, when the configuration defines several under `[pipelines]`.
+    pub pipeline: Option<String>,
+
+    /// Also run the pipeline after `git pull`/`git merge` (does not block anything).
+    #[arg(long)]
+    pub post_merge: bool,
+
+    /// Make the hooks pass `--quick` so only quick steps run.
+    #[arg(long)]
+    pub quick: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct FlakyArgs {
+    /// Number of recorded runs to analyze.
+    #[arg(short = 'n', long, default_value_t = 20)]
+    pub runs: usize,
+
+    /// First-attempt failure rate (in percent) above which a step is reported.
+    #[arg(short, long)]
+    pub threshold: Option<f64>,
+}
+
+#[derive(Debug, Args)]
+pub struct GraphArgs {
+    /// Pipeline to draw, when the configuration defines several under `[pipelines]`.
+    pub pipeline: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = GraphFormat::Dot)]
+    pub format: GraphFormat,
+
+    /// Show status and duration from the most recent recorded run.
+    #[arg(long)]
+    pub annotate: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct CleanArgs {
+    /// Also remove containers kept by `--keep-failed`.
+    #[arg(long, conflicts_with = "images")]
+    pub kept: bool,
+
+    /// Remove images pulled by ciroach that the pipeline no longer uses, instead of
+    /// containers. Only lists them unless `--yes` is given.
+    #[arg(long)]
+    pub images: bool,
+
+    /// Only images not pulled for this long, e.g. `30d`.
+    #[arg(long, requires = "images", value_name = "DURATION")]
+    pub older_than: Option<String>,
+
+    /// Actually remove the images listed by `--images`.
+    #[arg(long, requires = "images")]
+    pub yes: bool,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ImportSource {
+    /// Convert a GitHub Actions workflow file.
+    Github(ImportArgs),
+    /// Convert a `.gitlab-ci.yml` file.
+    Gitlab(ImportArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct ImportArgs {
+    /// Path to the YAML file to convert.
+    pub path: PathBuf,
+
+    /// Image used for jobs that do not name a container image.
+    #[arg(long, default_value = "ubuntu:latest")]
+    pub default_image: String,
+
+    /// Write the generated TOML to a file instead of stdout.
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+}
+
+fn parse_var(raw: &str) -> Result<(String, String), String> {
+    match raw.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_string(), value.to_string())),
+        _ => Err(format!("expected NAME=VALUE, got '{raw}'")),
+    }
+}
@@ -0,0 +1,116 @@
+use std::{collections::HashSet, path::Path};
+
+use anyhow::Ok;
+
+use crate::{
+    cli::CleanArgs,
+    engine::DockerEngine,
+    images::{IMAGES_MANIFEST, ImageManifest},
+    models::{Pipeline, now_millis, parse_duration},
+    output::Icon,
+};
+
+pub struct CleanCommand;
+
+impl CleanCommand {
+    pub async fn execute(
+        config: &Path,
+        vars: &[(String, String)],
+        args: CleanArgs,
+    ) -> anyhow::Result<()> {
+        if args.images {
+            return Self::clean_images(config, vars, &args).await;
+        }
+
+        let mut engine = DockerEngine::new()?;
+        engine.ping().await?;
+        let containers = engine.leftover_containers(args.kept).await?;
+
+        if containers.is_empty() {
+            println!("Nothing to clean up.");
+            return Ok(());
+        }
+
+        for name in containers.iter() {
+            match engine.remove_container(name, true).await {
+                std::result::Result::Ok(_) => println!("{} Removed {name}", Icon::Clean),
+                std::result::Result::Err(err) => {
+                    eprintln!("{} Failed to remove {name}: {err}", Icon::Warning)
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Images pulled by ciroach that the pipeline no longer references. They are listed
+    /// with their sizes first and only removed with `--yes`.
+    async fn clean_images(
+        config: &Path,
+        vars: &[(String, String)],
+        args: &CleanArgs,
+    ) -> anyhow::Result<()> {
+        let pipelines = Pipeline::all(config, vars).await?;
+        let referenced: HashSet<String> = pipelines
+            .iter()
+            .flat_map(|(_, pipeline)| &pipeline.stages)
+            .flat_map(|stage| &stage.steps)
+            .map(|step| ImageManifest::reference(&step.image))
+            .collect();
+
+        let cutoff = match &args.older_than {
+            Some(raw) => now_millis().saturating_sub(parse_duration(raw)?.as_millis() as u64),
+            None => u64::MAX,
+        };
+
+        let mut manifest = ImageManifest::load(IMAGES_MANIFEST).await?;
+        let unused: HashSet<String> = manifest
+            .pulled_before(cutoff)
+            .filter(|image| !referenced.contains(*image))
+            .map(str::to_string)
+            .collect();
+
+        let mut engine = DockerEngine::new()?;
+        engine.ping().await?;
+        let candidates = engine
+            .prune_images(|image| unused.contains(image), true)
+            .await?;
+
+        if candidates.is_empty() {
+            println!("No unused images pulled by ciroach.");
+        } else {
+            let total: i64 = candidates.iter().map(|(_, size)| size).sum();
+            for (image, size) in candidates.iter() {
+                println!("  {:<50} {:>8} MiB", image, size / (1024 * 1024));
+            }
+            println!(
+                "{} image(s), {} MiB in total.",
+                candidates.len(),
+                total / (1024 * 1024)
+            );
+        }
+
+        if !args.yes {
+            if !candidates.is_empty() {
+                println!("Run again with --yes to remove them.");
+            }
+            return Ok(());
+        }
+
+        let removed = engine
+            .prune_images(|image| unused.contains(image), false)
+            .await?;
+        for (image, _) in removed.iter() {
+            println!("{} Removed {image}", Icon::Clean);
+        }
+
+        // Images that are gone, whether removed now or by hand, need no tracking.
+        let present: HashSet<&String> = candidates.iter().map(|(image, _)| image).collect();
+        for image in unused.iter() {
+            if !present.contains(image) || removed.iter().any(|(r, _)| r == image) {
+                manifest.forget(image);
+            }
+        }
+        manifest.save().await
+    }
+}
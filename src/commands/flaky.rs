@@ -0,0 +1,37 @@
+use std::path::Path;
+
+use anyhow::Ok;
+
+use crate::{
+    cli::FlakyArgs,
+    history::{FlakyStep, HISTORY_DIR, RunHistory},
+    models::Pipeline,
+    reporter::FlakyReporter,
+};
+
+pub struct FlakyCommand;
+
+impl FlakyCommand {
+    pub async fn execute(
+        config: &Path,
+        vars: &[(String, String)],
+        args: FlakyArgs,
+    ) -> anyhow::Result<()> {
+        // History settings are shared by all pipelines of a file.
+        let (_, pipeline) = Pipeline::all(config, vars).await?.swap_remove(0);
+        let threshold = args.threshold.unwrap_or(pipeline.history.flaky_threshold);
+
+        let history = RunHistory::new(HISTORY_DIR, pipeline.history);
+        let runs = history.load(args.runs).await?;
+
+        if runs.is_empty() {
+            println!("No run history found in '{}'.", HISTORY_DIR);
+            return Ok(());
+        }
+
+        let (flaky, failing) = FlakyStep::detect(&runs, threshold);
+        FlakyReporter::report(&flaky, &failing, runs.len(), threshold);
+
+        Ok(())
+    }
+}
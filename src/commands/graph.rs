@@ -0,0 +1,44 @@
+use std::path::Path;
+
+use anyhow::Ok;
+
+use crate::{
+    cli::GraphArgs,
+    history::{HISTORY_DIR, RunHistory},
+    models::Pipeline,
+    output::Icon,
+    reporter::GraphReporter,
+};
+
+pub struct GraphCommand;
+
+impl GraphCommand {
+    pub async fn execute(
+        config: &Path,
+        vars: &[(String, String)],
+        args: GraphArgs,
+    ) -> anyhow::Result<()> {
+        let pipeline = Pipeline::new(config, args.pipeline.as_deref(), vars).await?;
+
+        let report = if args.annotate {
+            let history = RunHistory::new(HISTORY_DIR, pipeline.history.clone());
+            let last = history.load(1).await?.into_iter().next();
+            if last.is_none() {
+                eprintln!(
+                    "{} No run history found in '{}', nothing to annotate.",
+                    Icon::Warning,
+                    HISTORY_DIR
+                );
+            }
+            last
+        } else {
+            None
+        };
+
+        print!(
+            "{}",
+            GraphReporter::render(&pipeline, report.as_ref(), args.format)?
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,78 @@
+use std::{env, path::Path};
+
+use crate::{cli::HooksArgs, hooks::GitHooks, models::Pipeline, output::Icon};
+
+const PRE_PUSH: &str = "pre-push";
+const POST_MERGE: &str = "post-merge";
+
+pub struct InstallHooksCommand;
+
+impl InstallHooksCommand {
+    pub async fn execute(
+        config: &Path,
+        vars: &[(String, String)],
+        args: HooksArgs,
+    ) -> anyhow::Result<()> {
+        let hooks = GitHooks::locate()?;
+
+        // A hook that cannot pick its pipeline would fail on every push.
+        Pipeline::new(config, args.pipeline.as_deref(), vars).await?;
+
+        // Hooks run from the top of the working tree, so keep the config path relative to it.
+        let config = config.canonicalize()?;
+        let config = match config.strip_prefix(GitHooks::toplevel()?) {
+            Ok(relative) => relative.to_path_buf(),
+            Err(_) => config,
+        };
+
+        let mut command = format!(
+            "{} run --quiet --config {}",
+            shell_quote(&env::current_exe()?.to_string_lossy()),
+            shell_quote(&config.to_string_lossy()),
+        );
+        if args.quick {
+            command.push_str(" --quick");
+        }
+        if let Some(name) = &args.pipeline {
+            command.push(' ');
+            command.push_str(&shell_quote(name));
+        }
+
+        let mut names = vec![PRE_PUSH];
+        if args.post_merge {
+            names.push(POST_MERGE);
+        }
+
+        for name in names {
+            let path = hooks.install(name, &command).await?;
+            println!(
+                "{} Installed {} hook at {}",
+                Icon::Hook,
+                name,
+                path.display()
+            );
+        }
+
+        Ok(())
+    }
+}
+
+pub struct UninstallHooksCommand;
+
+impl UninstallHooksCommand {
+    pub async fn execute() -> anyhow::Result<()> {
+        let hooks = GitHooks::locate()?;
+
+        for name in [PRE_PUSH, POST_MERGE] {
+            if hooks.uninstall(name).await? {
+                println!("{} Removed ciroach from the {} hook", Icon::Clean, name);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
@@ -0,0 +1,48 @@
+use std::path::Path;
+
+use anyhow::Ok;
+use tokio::fs::{read_to_string, write};
+
+use crate::{
+    cli::ImportSource,
+    importer::{GithubImporter, GitlabImporter, Import, ImportOptions},
+    output::Icon,
+};
+
+pub struct ImportCommand;
+
+impl ImportCommand {
+    pub async fn execute(source: ImportSource) -> anyhow::Result<()> {
+        let (args, convert): (_, fn(&str, ImportOptions) -> anyhow::Result<Import>) = match source {
+            ImportSource::Github(args) => (args, GithubImporter::convert),
+            ImportSource::Gitlab(args) => (args, GitlabImporter::convert),
+        };
+
+        let source = read_to_string(&args.path).await?;
+        let options = ImportOptions {
+            default_image: args.default_image,
+        };
+        let import = convert(&source, options)?;
+
+        Self::emit(&import, args.output.as_deref()).await
+    }
+
+    async fn emit(import: &Import, output: Option<&Path>) -> anyhow::Result<()> {
+        let toml = import.to_toml()?;
+
+        let Some(path) = output else {
+            // Warnings are already embedded as comments in the emitted TOML.
+            print!("{toml}");
+            return Ok(());
+        };
+
+        write(path, toml).await?;
+        println!("{} Wrote {}", Icon::Success, path.display());
+
+        for warning in import.warnings.iter() {
+            println!("{} {}", Icon::Warning, warning);
+        }
+
+        Ok(())
+    }
+}
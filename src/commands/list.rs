@@ -0,0 +1,19 @@
+use std::path::Path;
+
+use colored::Colorize;
+
+use crate::{models::Pipeline, reporter::ListReporter};
+
+pub struct ListCommand;
+
+impl ListCommand {
+    pub async fn execute(config: &Path, vars: &[(String, String)]) -> anyhow::Result<()> {
+        for (name, pipeline) in Pipeline::all(config, vars).await? {
+            if let Some(name) = name {
+                println!("\n{} {}", "PIPELINE".bold().underline(), name.cyan().bold());
+            }
+            ListReporter::report(&pipeline);
+        }
+        Ok(())
+    }
+}
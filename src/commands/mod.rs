@@ -0,0 +1,19 @@
+mod clean;
+mod flaky;
+mod graph;
+mod hooks;
+mod import;
+mod list;
+mod run;
+mod serve;
+mod validate;
+
+pub use clean::*;
+pub use flaky::*;
+pub use graph::*;
+pub use hooks::*;
+pub use import::*;
+pub use list::*;
+pub use run::*;
+pub use serve::*;
+pub use validate::*;
@@ -0,0 +1,199 @@
+use std::{
+    env,
+    io::{self, IsTerminal},
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use anyhow::Ok;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    cli::RunArgs,
+    dashboard::Dashboard,
+    error::{CiroachError, ENGINE_FAILED},
+    github::GithubNotifier,
+    history::{HISTORY_DIR, RunHistory},
+    lock::LockPolicy,
+    models::{MetricsConfig, Pipeline, PipelineReport, QUICK_PROFILE},
+    output::{Icon, Verbosity},
+    platform,
+    reporter::{
+        ConsoleReporter, LogFilter, MetricsReporter, RunDirReporter, RunMeta, TimelineReporter,
+    },
+    runner::PipelineRunner,
+};
+
+pub struct RunCommand;
+
+impl RunCommand {
+    pub async fn execute(
+        config: &Path,
+        vars: &[(String, String)],
+        args: RunArgs,
+        verbosity: Verbosity,
+    ) -> anyhow::Result<ExitCode> {
+        let cwd = env::current_dir()?;
+
+        let mode = args.output.resolve();
+        let mut pipeline = Pipeline::new(config, args.pipeline.as_deref(), vars).await?;
+
+        let profile = match args.quick {
+            true => Some(QUICK_PROFILE),
+            false => args.profile.as_deref(),
+        };
+        pipeline.apply_profile(profile, &args.skip_tags)?;
+
+        if let Some(step) = &args.stdin {
+            if io::stdin().is_terminal() {
+                return Err(CiroachError::invalid(format!(
+                    "Nothing is piped into ciroach for --stdin {step}."
+                ))
+                .into());
+            }
+            pipeline.pipe_stdin(step)?;
+        }
+
+        Self::prune_logs(&pipeline).await;
+
+        let history = RunHistory::new(HISTORY_DIR, pipeline.history.clone());
+        let metrics = pipeline.metrics.clone();
+        let github = pipeline
+            .github
+            .clone()
+            .and_then(GithubNotifier::from_config);
+        let stage_names: Vec<String> = pipeline.stages.iter().map(|s| s.name.clone()).collect();
+        let descriptions = pipeline.step_descriptions();
+        let log_filter = args
+            .log_filter
+            .or_else(|| pipeline.logs.filter.clone())
+            .map(|pattern| LogFilter {
+                pattern,
+                context: args.log_context.unwrap_or(pipeline.logs.context),
+            });
+        let baseline = history.baseline().await;
+        let runner = PipelineRunner::new(pipeline, cwd, mode)
+            .await?
+            .debug_on_failure(args.debug_on_failure)
+            .keep_failed(args.keep_failed)
+            .progress(!args.no_progress)
+            .verbosity(verbosity)
+            .lock_policy(LockPolicy {
+                no_wait: args.no_wait,
+                force: args.force,
+            })
+            .lock_images(args.lock_images)
+            .expected_durations(
+                baseline
+                    .as_ref()
+                    .map(|baseline| baseline.durations().clone())
+                    .unwrap_or_default(),
+            );
+
+        let dashboard = match args.serve {
+            Some(addr) => Some(
+                Dashboard::start(addr, args.serve_insecure, &runner)
+                    .await
+                    .map_err(CiroachError::engine)?,
+            ),
+            None => None,
+        };
+
+        let token = CancellationToken::new();
+        let signal_token = token.clone();
+        let interrupted = token.clone();
+
+        tokio::spawn(async move {
+            let signal = platform::shutdown_signal().await;
+            println!("\n{} [{signal}] Graceful shutdown initiated...", Icon::Halt);
+            signal_token.cancel();
+        });
+
+        if let Some(github) = &github {
+            github.pending(&stage_names).await;
+        }
+
+        let report = runner.run(token).await;
+
+        if let Some(dashboard) = dashboard {
+            dashboard.stop().await;
+        }
+
+        let report = report?;
+
+        ConsoleReporter::new(baseline.as_ref(), mode)
+            .verbosity(verbosity)
+            .descriptions(descriptions)
+            .log_filter(log_filter)
+            .logs_per_variant(args.logs_per_variant)
+            .report(&report);
+        if args.timeline {
+            TimelineReporter::print(&report);
+        }
+
+        if let Some(github) = &github {
+            github.finish(&report).await;
+        }
+
+        Self::persist(&report, config, &history, metrics.as_ref()).await?;
+
+        let code = if interrupted.is_cancelled() {
+            eprintln!("\n{} Pipeline was interrupted.", Icon::Halt);
+            ExitCode::from(CiroachError::Cancelled.code())
+        } else if let Some(error) = &report.error {
+            eprintln!("\n{} Pipeline aborted: {}", Icon::Error, error);
+            ExitCode::from(report.error_code.unwrap_or(ENGINE_FAILED))
+        } else if !report.is_success() {
+            eprintln!("\n{} Pipeline failed. See report for details.", Icon::Error);
+            ExitCode::FAILURE
+        } else {
+            println!("\n{} Pipeline completed successfully!", Icon::Done);
+            ExitCode::SUCCESS
+        };
+
+        // Last on stdout, for whatever reads only the final line.
+        if let Some(summary) = &args.summary_format {
+            println!("{}", summary.render(&report));
+        }
+        Ok(code)
+    }
+
+    /// Applies the `[logs]` retention before a new run directory gets added.
+    pub async fn prune_logs(pipeline: &Pipeline) {
+        if let Err(err) = RunDirReporter::prune(&pipeline.logs).await {
+            eprintln!("{} Failed to prune old logs: {}", Icon::Warning, err);
+        }
+    }
+
+    /// Writes the run history entry, the run's log directory and metrics. Returns the
+    /// history entry when it could be recorded.
+    pub async fn persist(
+        report: &PipelineReport,
+        config: &Path,
+        history: &RunHistory,
+        metrics: Option<&MetricsConfig>,
+    ) -> anyhow::Result<Option<PathBuf>> {
+        let entry = match history.record(report).await {
+            std::result::Result::Ok(path) => Some(path),
+            Err(err) => {
+                eprintln!("{} Failed to record run history: {}", Icon::Warning, err);
+                None
+            }
+        };
+
+        match RunDirReporter::save(report, &RunMeta::new(report, config)).await {
+            std::result::Result::Ok(dir) => {
+                println!("\n{} Logs saved to {}", Icon::Folder, dir.display());
+            }
+            Err(err) => eprintln!("{} Failed to save logs: {}", Icon::Warning, err),
+        }
+
+        if let Some(metrics) = metrics
+            && let Err(err) = MetricsReporter::save(report, &metrics.path).await
+        {
+            eprintln!("{} Failed to write metrics file: {}", Icon::Warning, err);
+        }
+
+        Ok(entry)
+    }
+}
@@ -0,0 +1,29 @@
+use std::{path::Path, process::ExitCode};
+
+use anyhow::Context;
+
+use crate::{cli::ServeArgs, models::Pipeline, server::Server};
+
+pub struct ServeCommand;
+
+impl ServeCommand {
+    pub async fn execute(
+        config: &Path,
+        vars: &[(String, String)],
+        args: ServeArgs,
+    ) -> anyhow::Result<ExitCode> {
+        // Fail fast on a broken configuration; every run reloads it afterwards. `[server]`
+        // and `[schedule]` are shared by all pipelines of a file.
+        let (_, pipeline) = Pipeline::all(config, vars).await?.swap_remove(0);
+        if let Some(schedule) = &pipeline.schedule {
+            schedule
+                .load(config, vars)
+                .await
+                .context("The pipeline selected by [schedule] cannot be loaded")?;
+        }
+
+        Server::new(config, vars, pipeline.server, pipeline.schedule)
+            .serve(args.listen, args.insecure)
+            .await
+    }
+}
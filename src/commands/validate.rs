@@ -0,0 +1,43 @@
+use std::path::Path;
+
+use anyhow::Context;
+use chrono::Local;
+
+use crate::{models::Pipeline, output::Icon};
+
+pub struct ValidateCommand;
+
+impl ValidateCommand {
+    pub async fn execute(config: &Path, vars: &[(String, String)]) -> anyhow::Result<()> {
+        let pipelines = Pipeline::all(config, vars).await?;
+
+        // `[schedule]` is shared by all pipelines of a file.
+        if let Some(schedule) = &pipelines[0].1.schedule {
+            schedule
+                .load(config, vars)
+                .await
+                .context("The pipeline selected by [schedule] cannot be loaded")?;
+
+            let next = schedule
+                .cron
+                .find_next_occurrence(&Local::now(), false)
+                .with_context(|| format!("Schedule '{}' never fires", schedule.cron))?;
+            println!(
+                "{} Schedule '{}' fires next at {}.",
+                Icon::Info,
+                schedule.cron,
+                next.format("%Y-%m-%d %H:%M:%S")
+            );
+        }
+
+        let plural = if pipelines.len() == 1 { "" } else { "s" };
+        println!(
+            "{} {} is valid ({} pipeline{}).",
+            Icon::Success,
+            config.display(),
+            pipelines.len(),
+            plural
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,362 @@
+use std::net::SocketAddr;
+
+#[cfg(feature = "dashboard")]
+use std::{collections::HashMap, sync::Arc};
+
+#[cfg(feature = "dashboard")]
+use axum::{
+    Json, Router,
+    body::Body,
+    extract::{Path, State},
+    http::{StatusCode, header},
+    response::{Html, IntoResponse, Response},
+    routing::get,
+};
+#[cfg(feature = "dashboard")]
+use futures_util::{StreamExt, stream};
+#[cfg(feature = "dashboard")]
+use serde::Serialize;
+#[cfg(feature = "dashboard")]
+use tokio::{
+    net::TcpListener,
+    sync::{RwLock, broadcast},
+    task::JoinHandle,
+};
+#[cfg(feature = "dashboard")]
+use tokio_util::sync::CancellationToken;
+
+use crate::runner::PipelineRunner;
+#[cfg(feature = "dashboard")]
+use crate::{
+    events::{EventBus, PipelineEvent},
+    logger::LogBuffer,
+    models::{Pipeline, StepStatus},
+    output::Icon,
+};
+
+#[cfg(feature = "dashboard")]
+const INDEX_HTML: &str = include_str!("dashboard.html");
+
+#[cfg(feature = "dashboard")]
+pub type SharedState = Arc<RwLock<DashboardState>>;
+
+/// HTTP view of a running pipeline, started with `--serve`.
+pub struct Dashboard {
+    #[cfg(feature = "dashboard")]
+    shutdown: CancellationToken,
+    #[cfg(feature = "dashboard")]
+    handle: JoinHandle<()>,
+}
+
+impl Dashboard {
+    #[cfg(feature = "dashboard")]
+    pub async fn start(
+        addr: SocketAddr,
+        insecure: bool,
+        runner: &PipelineRunner,
+    ) -> anyhow::Result<Self> {
+        check_bind_addr(addr, insecure, "--serve-insecure")?;
+
+        let events = runner.events();
+        let state = DashboardState::track(runner.pipeline(), &events);
+
+        let app = Router::new()
+            .route("/", get(|| async { Html(INDEX_HTML) }))
+            .route("/status", get(status))
+            .route("/logs/{step}", get(logs))
+            .with_state((state, events));
+
+        let listener = TcpListener::bind(addr).await?;
+        println!(
+            "{} Dashboard listening on http://{}",
+            Icon::Listening,
+            listener.local_addr()?
+        );
+
+        let shutdown = CancellationToken::new();
+        let signal = shutdown.clone();
+        let handle = tokio::spawn(async move {
+            let server = axum::serve(listener, app)
+                .with_graceful_shutdown(async move { signal.cancelled().await });
+
+            if let Err(err) = server.await {
+                eprintln!("{} Dashboard server stopped: {}", Icon::Warning, err);
+            }
+        });
+
+        Ok(Self { shutdown, handle })
+    }
+
+    #[cfg(not(feature = "dashboard"))]
+    pub async fn start(
+        addr: SocketAddr,
+        insecure: bool,
+        _runner: &PipelineRunner,
+    ) -> anyhow::Result<Self> {
+        check_bind_addr(addr, insecure, "--serve-insecure")?;
+        anyhow::bail!("--serve requires ciroach to be built with the `dashboard` feature.")
+    }
+
+    /// Waits for open log streams to finish and stops the server.
+    pub async fn stop(self) {
+        #[cfg(feature = "dashboard")]
+        {
+            self.shutdown.cancel();
+            self.handle.await.ok();
+        }
+    }
+}
+
+/// Rejects non-loopback addresses unless `flag` was passed; nothing served here is authenticated.
+pub fn check_bind_addr(addr: SocketAddr, insecure: bool, flag: &str) -> anyhow::Result<()> {
+    if !addr.ip().is_loopback() && !insecure {
+        anyhow::bail!("Refusing to listen on non-loopback address {addr} without {flag}.");
+    }
+    Ok(())
+}
+
+#[cfg(feature = "dashboard")]
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum StepState {
+    Pending,
+    Running,
+    Finished,
+}
+
+#[cfg(feature = "dashboard")]
+#[derive(Debug, Serialize)]
+struct StepView {
+    name: String,
+    state: StepState,
+    status: Option<StepStatus>,
+    retries: u32,
+    elapsed: u64,
+    /// Milliseconds the step took in an earlier run.
+    expected: Option<u64>,
+}
+
+#[cfg(feature = "dashboard")]
+#[derive(Debug, Serialize)]
+struct StageView {
+    name: String,
+    state: StepState,
+    success: Option<bool>,
+    estimate: Option<u64>,
+    steps: Vec<StepView>,
+}
+
+/// Live stage and step states, rebuilt from the event stream.
+#[cfg(feature = "dashboard")]
+#[derive(Debug, Serialize)]
+pub struct DashboardState {
+    stages: Vec<StageView>,
+    finished: bool,
+    success: Option<bool>,
+    elapsed: Option<u64>,
+    #[serde(skip)]
+    logs: HashMap<String, LogBuffer>,
+}
+
+#[cfg(feature = "dashboard")]
+impl DashboardState {
+    /// Starts following `events` and returns the continuously updated state.
+    pub fn track(pipeline: &Pipeline, events: &EventBus) -> SharedState {
+        let state = Arc::new(RwLock::new(Self::new(pipeline)));
+
+        let mut rx = events.subscribe();
+        let updater = state.clone();
+        tokio::spawn(async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => updater.write().await.apply(event),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+
+        state
+    }
+
+    fn new(pipeline: &Pipeline) -> Self {
+        let stages = pipeline
+            .stages
+            .iter()
+            .map(|stage| StageView {
+                name: stage.name.clone(),
+                state: StepState::Pending,
+                success: None,
+                estimate: None,
+                steps: stage
+                    .steps
+                    .iter()
+                    .map(|step| StepView {
+                        name: step.exploded_name.clone(),
+                        state: StepState::Pending,
+                        status: None,
+                        retries: 0,
+                        elapsed: 0,
+                        expected: None,
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        Self {
+            stages,
+            finished: false,
+            success: None,
+            elapsed: None,
+            // Kept like the report keeps them, so both show the same lines.
+            logs: pipeline
+                .stages
+                .iter()
+                .flat_map(|stage| &stage.steps)
+                .map(|step| (step.exploded_name.clone(), LogBuffer::new(step.log_limit)))
+                .collect(),
+        }
+    }
+
+    fn apply(&mut self, event: PipelineEvent) {
+        match event {
+            PipelineEvent::StageStarted { stage, estimate } => {
+                if let Some(view) = self.stage(&stage) {
+                    view.state = StepState::Running;
+                    view.estimate = estimate;
+                }
+            }
+            PipelineEvent::StageFinished { stage, success } => {
+                if let Some(view) = self.stage(&stage) {
+                    view.state = StepState::Finished;
+                    view.success = Some(success);
+                }
+            }
+            PipelineEvent::StepStarted {
+                stage,
+                step,
+                expected,
+            } => {
+                if let Some(view) = self.step(&stage, &step) {
+                    view.state = StepState::Running;
+                    view.expected = expected;
+                }
+            }
+            PipelineEvent::ImagePulling { .. }
+            | PipelineEvent::StepRetrying { .. }
+            | PipelineEvent::StepSilent { .. } => {}
+            PipelineEvent::StepLog { step, line, .. } => {
+                self.logs
+                    .entry(step)
+                    .or_insert_with(|| LogBuffer::new(0))
+                    .push(line);
+            }
+            PipelineEvent::StepFinished {
+                stage,
+                step,
+                status,
+                retries,
+                elapsed,
+            } => {
+                if let Some(view) = self.step(&stage, &step) {
+                    view.state = StepState::Finished;
+                    view.status = Some(status);
+                    view.retries = retries;
+                    view.elapsed = elapsed;
+                }
+            }
+            PipelineEvent::PipelineFinished { success, elapsed } => {
+                self.finished = true;
+                self.success = Some(success);
+                self.elapsed = Some(elapsed);
+            }
+        }
+    }
+
+    fn stage(&mut self, name: &str) -> Option<&mut StageView> {
+        self.stages.iter_mut().find(|stage| stage.name == name)
+    }
+
+    fn step(&mut self, stage: &str, name: &str) -> Option<&mut StepView> {
+        self.stage(stage)?
+            .steps
+            .iter_mut()
+            .find(|step| step.name == name)
+    }
+
+    fn is_step_done(&self, name: &str) -> bool {
+        self.finished
+            || self
+                .stages
+                .iter()
+                .flat_map(|stage| &stage.steps)
+                .any(|step| step.name == name && matches!(step.state, StepState::Finished))
+    }
+}
+
+#[cfg(feature = "dashboard")]
+async fn status(State((state, _)): State<(SharedState, EventBus)>) -> Response {
+    Json(&*state.read().await).into_response()
+}
+
+/// Replays the stored lines for a step, then follows it live until the step finishes.
+#[cfg(feature = "dashboard")]
+async fn logs(
+    Path(step): Path<String>,
+    State((state, events)): State<(SharedState, EventBus)>,
+) -> Response {
+    // Subscribe before taking the snapshot so no line falls between the two.
+    let rx = events.subscribe();
+    let (stored, done) = {
+        let state = state.read().await;
+        let known = state
+            .stages
+            .iter()
+            .flat_map(|stage| &stage.steps)
+            .any(|view| view.name == step);
+
+        if !known {
+            return (StatusCode::NOT_FOUND, format!("Unknown step '{step}'\n")).into_response();
+        }
+
+        (
+            state
+                .logs
+                .get(&step)
+                .map(LogBuffer::lines)
+                .unwrap_or_default(),
+            state.is_step_done(&step),
+        )
+    };
+
+    let stored = stream::iter(stored);
+    let live = stream::unfold((rx, step, done), |(mut rx, step, done)| async move {
+        if done {
+            return None;
+        }
+
+        loop {
+            match rx.recv().await {
+                Ok(PipelineEvent::StepLog {
+                    step: name, line, ..
+                }) if name == step => {
+                    return Some((line, (rx, step, false)));
+                }
+                Ok(PipelineEvent::StepFinished { step: name, .. }) if name == step => return None,
+                Ok(PipelineEvent::PipelineFinished { .. }) => return None,
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    let body = stored
+        .chain(live)
+        .map(|line| Ok::<_, std::convert::Infallible>(format!("{line}\n")));
+
+    (
+        [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+        Body::from_stream(body),
+    )
+        .into_response()
+}
@@ -1,51 +1,399 @@
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::{Path, PathBuf},
+    pin::Pin,
+    process::Stdio,
+    sync::Once,
+    time::{Duration, Instant},
+};
+
 use anyhow::Ok;
 use bollard::{
-    Docker,
+    API_DEFAULT_VERSION, Docker,
     container::LogOutput,
+    exec::{CreateExecOptions, StartExecResults},
     query_parameters::{
-        CreateContainerOptionsBuilder, CreateImageOptionsBuilder, LogsOptionsBuilder,
-        RemoveContainerOptionsBuilder,
+        AttachContainerOptionsBuilder, CommitContainerOptionsBuilder,
+        CreateContainerOptionsBuilder, CreateImageOptionsBuilder,
+        DownloadFromContainerOptionsBuilder, InspectContainerOptionsBuilder,
+        ListContainersOptionsBuilder, ListImagesOptionsBuilder, LogsOptionsBuilder,
+        RemoveContainerOptionsBuilder, RemoveImageOptionsBuilder, RenameContainerOptionsBuilder,
+        ResizeExecOptionsBuilder, UploadToContainerOptionsBuilder,
+    },
+    secret::{
+        ContainerConfig, ContainerCreateBody, ContainerState, ContainerSummary, CreateImageInfo,
+        EndpointSettings, HostConfig, HostConfigLogConfig, NetworkCreateRequest, NetworkingConfig,
     },
-    secret::{ContainerCreateBody, ContainerState, HostConfig},
 };
-use futures_util::StreamExt;
-use tokio::sync::mpsc;
-use tokio_util::sync::CancellationToken;
+use crossterm::terminal;
+use futures_util::{Stream, StreamExt};
+use serde::Serialize;
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    process::Command,
+    sync::mpsc,
+};
+use tokio_util::{io::ReaderStream, sync::CancellationToken};
+
+use crate::{
+    images,
+    logger::LogMessage,
+    models::{EngineInfo, KeptContainer, OutputStats, Step, StepInput, WorkspaceIsolation},
+    output::Icon,
+    ui,
+    workspace::{self, IgnoreRules},
+};
+
+const DEFAULT_SOCKET: &str = "/var/run/docker.sock";
+/// Seconds a request to the engine may take.
+const REQUEST_TIMEOUT: u64 = 120;
+/// Oldest API version ciroach works with: Docker 20.10 and Podman 4.
+const MIN_API_VERSION: (u32, u32) = (1, 41);
+
+/// Address of the engine, e.g. a Podman or rootless Docker socket. Takes precedence over
+/// `DOCKER_HOST`.
+pub const HOST_ENV: &str = "CIROACH_DOCKER_HOST";
+
+static STORAGE_OPT_UNSUPPORTED: Once = Once::new();
+
+/// Label carrying the step name, set on every step container.
+pub const STEP_LABEL: &str = "ciroach.step";
+/// Name prefix of failed containers kept by `--keep-failed`; `ciroach clean` skips them
+/// unless `--kept` is given. Labels cannot be changed once a container exists, so the
+/// name given when a container is kept marks it instead.
+pub const KEPT_PREFIX: &str = "ciroach-kept";
+/// Label carrying the run id, to tie a container to its `logs/<run_id>/` directory.
+pub const RUN_LABEL: &str = "ciroach.run";
+
+type StdinReader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// A step container that has been started.
+pub struct StartedContainer {
+    pub id: String,
+    /// Time it took to copy the workspace in, with `workspace_isolation = "copy"`.
+    pub copied_in: Option<Duration>,
+}
+
+/// How far an image pull has come, over all of its layers.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct PullProgress {
+    pub layers_done: usize,
+    pub layers_total: usize,
+    /// Bytes downloaded so far, out of `total`. Layers only report their size once they
+    /// start downloading, so `total` grows during the pull.
+    pub current: u64,
+    pub total: u64,
+}
+
+#[derive(Debug, Default)]
+struct LayerProgress {
+    current: u64,
+    total: u64,
+    done: bool,
+}
+
+/// Folds the per-layer events of a pull into one [`PullProgress`].
+#[derive(Debug, Default)]
+struct PullLayers {
+    layers: HashMap<String, LayerProgress>,
+}
+
+impl PullLayers {
+    /// Returns the new progress when `info` concerns a layer.
+    fn update(&mut self, info: &CreateImageInfo) -> Option<PullProgress> {
+        let (Some(id), Some(status)) = (&info.id, info.status.as_deref()) else {
+            return None;
+        };
+        // Other events carrying an id name the tag being pulled, not a layer.
+        let layer = match status {
+            "Pulling fs layer" | "Waiting" | "Downloading" | "Verifying Checksum"
+            | "Download complete" | "Extracting" | "Pull complete" | "Already exists" => {
+                self.layers.entry(id.clone()).or_default()
+            }
+            _ => return None,
+        };
+
+        match status {
+            "Downloading" => {
+                if let Some(detail) = &info.progress_detail {
+                    layer.current = detail.current.unwrap_or_default().max(0) as u64;
+                    layer.total = detail.total.unwrap_or_default().max(0) as u64;
+                }
+            }
+            "Download complete" | "Extracting" => layer.current = layer.total,
+            "Pull complete" | "Already exists" => {
+                layer.current = layer.total;
+                layer.done = true;
+            }
+            _ => {}
+        }
+
+        Some(self.progress())
+    }
 
-use crate::{logger::LogMessage, models::Step};
+    fn progress(&self) -> PullProgress {
+        self.layers
+            .values()
+            .fold(PullProgress::default(), |progress, layer| PullProgress {
+                layers_done: progress.layers_done + usize::from(layer.done),
+                layers_total: progress.layers_total + 1,
+                current: progress.current + layer.current,
+                total: progress.total + layer.total,
+            })
+    }
+}
 
 pub struct DockerEngine {
     client: Docker,
+    /// Where `client` connects to, for messages and the `docker_socket` mount.
+    host: String,
+    relabel: bool,
+    run_id: Option<String>,
+    /// Patterns from the pipeline's `ignore`, for copying the workspace.
+    ignore: Vec<String>,
 }
 
 impl DockerEngine {
     pub fn new() -> anyhow::Result<Self> {
+        let configured = [HOST_ENV, "DOCKER_HOST"]
+            .iter()
+            .find_map(|var| std::env::var(var).ok().filter(|host| !host.is_empty()));
+
+        let client = match &configured {
+            Some(host) if host.starts_with("tcp://") || host.starts_with("http://") => {
+                Docker::connect_with_http(host, REQUEST_TIMEOUT, API_DEFAULT_VERSION)
+            }
+            Some(host) => Docker::connect_with_local(host, REQUEST_TIMEOUT, API_DEFAULT_VERSION),
+            None => Docker::connect_with_local_defaults(),
+        };
+        let host = configured.unwrap_or_else(|| format!("unix://{DEFAULT_SOCKET}"));
+        let client = client.map_err(|err| Self::unreachable(&host, err.into()))?;
+
         Ok(Self {
-            client: Docker::connect_with_local_defaults()?,
+            client,
+            host,
+            relabel: false,
+            run_id: None,
+            ignore: Vec::new(),
         })
     }
 
+    /// Stands in for the engine when a run never needs one, e.g. with only host steps on
+    /// a machine without Docker. Every request to it fails.
+    pub fn disconnected() -> anyhow::Result<Self> {
+        Ok(Self {
+            client: Docker::connect_with_http("tcp://127.0.0.1:0", 1, API_DEFAULT_VERSION)?,
+            host: "nowhere".to_string(),
+            relabel: false,
+            run_id: None,
+            ignore: Vec::new(),
+        })
+    }
+
+    /// Mounts the workspace with the SELinux `:z` option.
+    pub fn relabel_workspace(mut self, relabel: bool) -> Self {
+        self.relabel = relabel;
+        self
+    }
+
+    /// Names and labels step containers after this run.
+    pub fn run_id(mut self, run_id: impl Into<String>) -> Self {
+        self.run_id = Some(run_id.into());
+        self
+    }
+
+    /// Leaves files matching these patterns out of a copied workspace, besides those in
+    /// `.ciroachignore`.
+    pub fn workspace_ignore(mut self, patterns: Vec<String>) -> Self {
+        self.ignore = patterns;
+        self
+    }
+
+    /// Checks that the engine answers and speaks a compatible API version, settling on the
+    /// newest version both sides support.
+    pub async fn ping(&mut self) -> anyhow::Result<EngineInfo> {
+        let version = self
+            .client
+            .version()
+            .await
+            .map_err(|err| Self::unreachable(&self.host, err.into()))?;
+
+        let api_version = version.api_version.clone().unwrap_or_default();
+        let parsed = api_version
+            .split_once('.')
+            .and_then(|(major, minor)| Some((major.parse().ok()?, minor.parse().ok()?)));
+        if parsed.is_some_and(|parsed: (u32, u32)| parsed < MIN_API_VERSION) {
+            anyhow::bail!(
+                "The engine at {} speaks API {}, but ciroach needs {}.{} or newer. Update Docker (20.10+) or Podman (4+).",
+                self.host,
+                api_version,
+                MIN_API_VERSION.0,
+                MIN_API_VERSION.1
+            );
+        }
+        self.client = self.client.clone().negotiate_version().await?;
+
+        Ok(EngineInfo {
+            name: version
+                .platform
+                .map(|platform| platform.name)
+                .unwrap_or_else(|| "Docker".to_string()),
+            version: version.version.unwrap_or_default(),
+            api_version,
+            os: version.os.unwrap_or_default(),
+            arch: version.arch.unwrap_or_default(),
+            free_space: self.free_space().await,
+        })
+    }
+
+    fn unreachable(host: &str, err: anyhow::Error) -> anyhow::Error {
+        anyhow::anyhow!(
+            "Cannot reach Docker at {host} — is Docker running? Set {HOST_ENV} if it listens elsewhere. (original error: {err})"
+        )
+    }
+
+    /// Pulls an image, giving up when the whole pull takes longer than `limit`.
+    /// `on_progress` gets the combined progress of all layers after each layer event.
     pub async fn pull_image(
         &self,
         image: impl Into<String>,
-        on_progress: impl Fn(u64, u64),
+        limit: Duration,
+        on_progress: impl Fn(PullProgress),
     ) -> anyhow::Result<()> {
-        let image_options = CreateImageOptionsBuilder::new()
-            .from_image(&image.into())
-            .build();
+        let image = image.into();
+        let image_options = CreateImageOptionsBuilder::new().from_image(&image).build();
 
         let mut pull_stream = self.client.create_image(Some(image_options), None, None);
 
-        while let Some(pull_result) = pull_stream.next().await {
-            let info = pull_result?;
-            if let Some(detail) = info.progress_detail
-                && let (Some(current), Some(total)) = (detail.current, detail.total)
-            {
-                on_progress(current as u64, total as u64);
+        let pull = async {
+            let mut layers = PullLayers::default();
+            while let Some(pull_result) = pull_stream.next().await {
+                let info = pull_result?;
+                if let Some(digest) = info
+                    .status
+                    .as_deref()
+                    .and_then(|status| status.strip_prefix("Digest: "))
+                {
+                    tracing::debug!(%image, digest, "pulled image");
+                }
+                if let Some(progress) = layers.update(&info) {
+                    on_progress(progress);
+                }
+            }
+
+            Ok(())
+        };
+
+        match tokio::time::timeout(limit, pull).await {
+            std::result::Result::Ok(result) => result,
+            // Kept as the engine's own timeout underneath, so the pull counts as transient.
+            std::result::Result::Err(_) => Err(anyhow::Error::new(
+                bollard::errors::Error::RequestTimeoutError,
+            )
+            .context(format!("Pull timed out after {limit:?}"))),
+        }
+    }
+
+    /// Whether a failed pull is worth retrying: network trouble, timeouts and registry
+    /// 5xx, but not authentication or unknown manifests.
+    pub fn is_transient(err: &anyhow::Error) -> bool {
+        let Some(err) = err.downcast_ref::<bollard::errors::Error>() else {
+            return false;
+        };
+
+        match err {
+            bollard::errors::Error::DockerResponseServerError { status_code, .. } => {
+                *status_code >= 500 || *status_code == 408 || *status_code == 429
+            }
+            bollard::errors::Error::DockerStreamError { error } => {
+                let error = error.to_lowercase();
+                let permanent = ["manifest unknown", "not found", "unauthorized", "denied"];
+                !permanent.iter().any(|marker| error.contains(marker))
             }
+            bollard::errors::Error::RequestTimeoutError
+            | bollard::errors::Error::IOError { .. }
+            | bollard::errors::Error::HyperResponseError { .. }
+            | bollard::errors::Error::HyperLegacyError { .. } => true,
+            _ => false,
         }
+    }
 
-        Ok(())
+    /// Whether the daemon refused a container's size limit. Only overlay2 on xfs with
+    /// project quotas supports one.
+    fn lacks_storage_opt(err: &anyhow::Error) -> bool {
+        matches!(
+            err.downcast_ref::<bollard::errors::Error>(),
+            Some(bollard::errors::Error::DockerResponseServerError {
+                status_code: 400,
+                message,
+            }) if message.contains("storage-opt")
+        )
+    }
+
+    pub async fn image_exists(&self, image: &str) -> bool {
+        self.client.inspect_image(image).await.is_ok()
+    }
+
+    /// Bytes the image takes on disk.
+    pub async fn image_size(&self, image: &str) -> Option<u64> {
+        let inspect = self.client.inspect_image(image).await.ok()?;
+        inspect.size.and_then(|size| u64::try_from(size).ok())
+    }
+
+    /// Registry digest of a pulled image, e.g. `sha256:...`. Images that were built
+    /// locally and never pushed have none.
+    pub async fn repo_digest(&self, image: &str) -> anyhow::Result<Option<String>> {
+        let inspect = self.client.inspect_image(image).await?;
+        let repo_digests = inspect.repo_digests.unwrap_or_default();
+        let repository = images::repository(image);
+
+        // An image pulled under several names has a digest for each repository.
+        let matching = repo_digests
+            .iter()
+            .find(|entry| entry.split('@').next() == Some(repository))
+            .or(repo_digests.first());
+
+        Ok(matching
+            .and_then(|entry| entry.split_once('@'))
+            .map(|(_, digest)| digest.to_string()))
+    }
+
+    /// Local image references matching `filter`, with the size of their image. Unless
+    /// `dry_run`, they are removed too; those that cannot be, e.g. because a container
+    /// still uses them, are left out with a warning.
+    pub async fn prune_images(
+        &self,
+        filter: impl Fn(&str) -> bool,
+        dry_run: bool,
+    ) -> anyhow::Result<Vec<(String, i64)>> {
+        let list_options = ListImagesOptionsBuilder::new().build();
+        let images = self.client.list_images(Some(list_options)).await?;
+
+        let mut pruned = Vec::new();
+        for image in images {
+            for reference in image.repo_tags.iter().chain(image.repo_digests.iter()) {
+                if !filter(reference) {
+                    continue;
+                }
+
+                if !dry_run {
+                    let remove_options = RemoveImageOptionsBuilder::new().build();
+                    if let Err(err) = self
+                        .client
+                        .remove_image(reference, Some(remove_options), None)
+                        .await
+                    {
+                        eprintln!("{} Failed to remove {reference}: {err}", Icon::Warning);
+                        continue;
+                    }
+                }
+                pruned.push((reference.clone(), image.size));
+            }
+        }
+
+        Ok(pruned)
     }
 
     pub async fn run_container(
@@ -53,63 +401,760 @@ impl DockerEngine {
         step: &Step,
         cwd: impl Into<String>,
         user: impl Into<String>,
-    ) -> anyhow::Result<String> {
-        let container_name = format!("ciroach-{}", step.exploded_name.replace(" ", "-"));
+        network: Option<&str>,
+        stage_attempt: u32,
+        attempt: u32,
+    ) -> anyhow::Result<StartedContainer> {
+        // Every attempt of every run gets its own name, so a retry never waits on or races
+        // with the removal of the previous container.
+        let container_name = match &self.run_id {
+            Some(run_id) => {
+                let prefix = format!("ciroach-{run_id}");
+                Self::attempt_container_name(&prefix, step, stage_attempt, attempt)
+            }
+            None => {
+                // Without a run id, a leftover of an earlier run may still hold the name.
+                let name = Self::attempt_container_name("ciroach", step, stage_attempt, attempt);
+                self.remove_container(&name, true).await.ok();
+                name
+            }
+        };
 
-        self.force_remove_container(&container_name).await.ok();
+        let cwd = cwd.into();
+        let cmd = vec!["sh".to_string(), "-c".to_string(), step.command.clone()];
+        let mut config = self.container_config(step, step.image.clone(), cmd, &cwd, user);
+        let workspace =
+            (step.workspace_isolation == WorkspaceIsolation::Copy).then_some(cwd.as_str());
+        let mut stdin = Self::open_stdin(step, &cwd).await?;
+        if stdin.is_some() {
+            config.open_stdin = Some(true);
+            config.attach_stdin = Some(true);
+            config.stdin_once = Some(true);
+        }
+
+        let mut labels = HashMap::from([(STEP_LABEL.to_string(), step.exploded_name.clone())]);
+        if let Some(run_id) = &self.run_id {
+            labels.insert(RUN_LABEL.to_string(), run_id.clone());
+        }
+        config.labels = Some(labels);
+
+        if step.docker_socket {
+            self.mount_docker_socket(&mut config)?;
+        }
+
+        if let Some(network) = network {
+            let mut aliases = vec![step.exploded_name.clone()];
+            if step.name != step.exploded_name {
+                aliases.push(step.name.clone());
+            }
+
+            let endpoint = EndpointSettings {
+                aliases: Some(aliases),
+                ..Default::default()
+            };
+            config.networking_config = Some(NetworkingConfig {
+                endpoints_config: Some(HashMap::from([(network.to_string(), endpoint)])),
+            });
+            if let Some(host_config) = config.host_config.as_mut() {
+                host_config.network_mode = Some(network.to_string());
+            }
+        }
+
+        let without_limit = config.host_config.as_ref().and_then(|host| {
+            host.storage_opt.as_ref()?;
+            let mut fallback = config.clone();
+            if let Some(host) = fallback.host_config.as_mut() {
+                host.storage_opt = None;
+            }
+            Some(fallback)
+        });
 
-        let container_options = CreateContainerOptionsBuilder::new()
-            .name(&container_name)
+        match (
+            self.create_and_start(&container_name, config, workspace, &mut stdin)
+                .await,
+            without_limit,
+        ) {
+            (Err(err), Some(fallback)) if Self::lacks_storage_opt(&err) => {
+                STORAGE_OPT_UNSUPPORTED.call_once(|| {
+                    ui::suspend(|| {
+                        eprintln!(
+                            "{} The Docker storage driver does not support 'storage_limit'; steps run without it. ({})",
+                            Icon::Warning,
+                            err
+                        )
+                    });
+                });
+                self.create_and_start(&container_name, fallback, workspace, &mut stdin)
+                    .await
+            }
+            (result, _) => result,
+        }
+    }
+
+    pub async fn create_network(&self, name: &str) -> anyhow::Result<()> {
+        self.client
+            .create_network(NetworkCreateRequest {
+                name: name.to_string(),
+                driver: Some("bridge".to_string()),
+                ..Default::default()
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn remove_network(&self, name: &str) -> anyhow::Result<()> {
+        self.client.remove_network(name).await?;
+        Ok(())
+    }
+
+    /// Checks from inside the container whether something listens on `port`, by reading
+    /// `/proc/net/tcp` so the image needs no extra tools beyond `sh` and `grep`.
+    pub async fn port_listening(&self, id: &str, port: u16) -> anyhow::Result<bool> {
+        let probe = format!(
+            "cat /proc/net/tcp /proc/net/tcp6 2>/dev/null | grep -qi ':{port:04X} [0-9a-f]*:[0-9a-f]* 0a'"
+        );
+
+        let exec = self
+            .client
+            .create_exec(
+                id,
+                CreateExecOptions {
+                    attach_stdout: Some(true),
+                    attach_stderr: Some(true),
+                    cmd: Some(vec!["sh", "-c", &probe]),
+                    ..Default::default()
+                },
+            )
+            .await?;
+
+        if let StartExecResults::Attached { mut output, .. } =
+            self.client.start_exec(&exec.id, None).await?
+        {
+            while output.next().await.is_some() {}
+        }
+
+        let inspect = self.client.inspect_exec(&exec.id).await?;
+        Ok(inspect.exit_code == Some(0))
+    }
+
+    /// Stops a failed step container if it is still running and renames it so the next
+    /// run of the step does not replace it.
+    pub async fn keep_container(&self, id: &str, step: &Step) -> anyhow::Result<KeptContainer> {
+        let state = self.inspect_state(id).await?;
+        let exit_code = if state.running == Some(true) {
+            self.client.kill_container(id, None).await.ok();
+            None
+        } else {
+            state.exit_code
+        };
+
+        let short_id: String = id.chars().take(12).collect();
+        let name = format!("{}-{short_id}", Self::container_name(KEPT_PREFIX, step));
+
+        let rename_options = RenameContainerOptionsBuilder::new().name(&name).build();
+        self.client.rename_container(id, rename_options).await?;
+
+        Ok(KeptContainer { name, exit_code })
+    }
+
+    /// Names of containers left behind by earlier runs. Kept containers are only
+    /// included with `include_kept`.
+    pub async fn leftover_containers(&self, include_kept: bool) -> anyhow::Result<Vec<String>> {
+        let filters = HashMap::from([("label", vec![STEP_LABEL])]);
+        let list_options = ListContainersOptionsBuilder::new()
+            .all(true)
+            .filters(&filters)
             .build();
 
-        let cmd = vec!["sh".to_string(), "-c".to_string(), step.command.clone()];
+        let containers = self.client.list_containers(Some(list_options)).await?;
+
+        Ok(containers
+            .into_iter()
+            .filter(|container| include_kept || !Self::is_kept(container))
+            .filter_map(|container| {
+                let name = container.names?.into_iter().next()?;
+                Some(name.trim_start_matches('/').to_string())
+            })
+            .collect())
+    }
+
+    /// Removes by force every container of this run that is still there, running or not,
+    /// except the failed ones `--keep-failed` already kept. Returns how many were removed.
+    pub async fn remove_run_containers(&self) -> anyhow::Result<usize> {
+        let Some(run_id) = &self.run_id else {
+            return Ok(0);
+        };
+        let label = format!("{RUN_LABEL}={run_id}");
+        let filters = HashMap::from([("label", vec![label.as_str()])]);
+        let list_options = ListContainersOptionsBuilder::new()
+            .all(true)
+            .filters(&filters)
+            .build();
+
+        let containers = self.client.list_containers(Some(list_options)).await?;
+
+        let mut removed = 0;
+        for container in containers {
+            if Self::is_kept(&container) {
+                continue;
+            }
+            if let Some(id) = container.id
+                && self.remove_container(&id, true).await.is_ok()
+            {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Whether `--keep-failed` kept the container, going by the name it was given then.
+    fn is_kept(container: &ContainerSummary) -> bool {
+        let prefix = format!("/{KEPT_PREFIX}-");
+        container
+            .names
+            .iter()
+            .flatten()
+            .any(|name| name.starts_with(&prefix))
+    }
+
+    /// Snapshots a failed step container into an image and starts an idle copy of it with
+    /// the same mounts, environment and user, so it can be explored with `docker exec`.
+    /// The failed container itself is left alone; returns the name of the copy.
+    pub async fn debug_container(
+        &self,
+        failed_id: &str,
+        step: &Step,
+        cwd: impl Into<String>,
+        user: impl Into<String>,
+    ) -> anyhow::Result<String> {
+        let name = Self::container_name("ciroach-debug", step);
+
+        let commit_options = CommitContainerOptionsBuilder::new()
+            .container(failed_id)
+            .repo(&name)
+            .pause(false)
+            .build();
+        self.client
+            .commit_container(commit_options, ContainerConfig::default())
+            .await?;
+        self.remove_container(&name, true).await.ok();
+
+        let idle = vec![
+            "sh".to_string(),
+            "-c".to_string(),
+            "while :; do sleep 3600; done".to_string(),
+        ];
+        let config = self.container_config(step, name.clone(), idle, cwd, user);
+        self.create_and_start(&name, config, None, &mut None)
+            .await?;
+
+        Ok(name)
+    }
+
+    /// Removes a container created by [`Self::debug_container`] and its snapshot image.
+    pub async fn remove_debug_container(&self, name: &str) -> anyhow::Result<()> {
+        self.remove_container(name, true).await?;
+
+        let remove_options = RemoveImageOptionsBuilder::new().force(true).build();
+        self.client
+            .remove_image(name, Some(remove_options), None)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Runs an interactive `sh` in the container, wired to this terminal in raw mode.
+    pub async fn shell(&self, name: &str) -> anyhow::Result<()> {
+        let exec = self
+            .client
+            .create_exec(
+                name,
+                CreateExecOptions {
+                    attach_stdin: Some(true),
+                    attach_stdout: Some(true),
+                    attach_stderr: Some(true),
+                    tty: Some(true),
+                    cmd: Some(vec!["sh"]),
+                    ..Default::default()
+                },
+            )
+            .await?;
+
+        let StartExecResults::Attached {
+            mut output,
+            mut input,
+        } = self.client.start_exec(&exec.id, None).await?
+        else {
+            anyhow::bail!("Docker did not attach to the debug shell");
+        };
+
+        if let std::result::Result::Ok((width, height)) = terminal::size() {
+            let resize = ResizeExecOptionsBuilder::new()
+                .w(width as i32)
+                .h(height as i32)
+                .build();
+            self.client.resize_exec(&exec.id, resize).await.ok();
+        }
+
+        terminal::enable_raw_mode()?;
+
+        let stdin = tokio::spawn(async move {
+            let mut stdin = tokio::io::stdin();
+            tokio::io::copy(&mut stdin, &mut input).await.ok();
+            input.shutdown().await.ok();
+        });
+
+        let mut stdout = std::io::stdout();
+        while let Some(Result::Ok(chunk)) = output.next().await {
+            stdout.write_all(&chunk.into_bytes()).ok();
+            stdout.flush().ok();
+        }
+
+        stdin.abort();
+        terminal::disable_raw_mode()?;
+        println!();
+
+        Ok(())
+    }
+
+    pub async fn pause_container(&self, id: &str) -> anyhow::Result<()> {
+        self.client.pause_container(id).await?;
+        Ok(())
+    }
+
+    pub async fn unpause_container(&self, id: &str) -> anyhow::Result<()> {
+        self.client.unpause_container(id).await?;
+        Ok(())
+    }
+
+    /// Step ids are only unique within a stage, and a detached step of an earlier stage
+    /// is still running, so the stage is part of the name.
+    fn container_name(prefix: &str, step: &Step) -> String {
+        let sanitized: String = format!("{}-{}", step.stage, step.exploded_name)
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | '0'..='9' | '.' | '_' | '-' => c,
+                'A'..='Z' => c.to_ascii_lowercase(),
+                _ => '-',
+            })
+            .collect();
+
+        format!("{prefix}-{sanitized}")
+    }
+
+    /// Attempt `attempt` of the step in run `stage_attempt` of its stage, both from 1.
+    fn attempt_container_name(
+        prefix: &str,
+        step: &Step,
+        stage_attempt: u32,
+        attempt: u32,
+    ) -> String {
+        format!(
+            "{}-{stage_attempt}-{attempt}",
+            Self::container_name(prefix, step)
+        )
+    }
+
+    /// Host path of the socket the engine talks to, so a Podman socket works as well.
+    fn socket_path(&self) -> anyhow::Result<String> {
+        match self.host.strip_prefix("unix://") {
+            Some(path) => Ok(path.to_string()),
+            None => anyhow::bail!(
+                "'docker_socket' needs a local unix socket, but the engine is at '{}'",
+                self.host
+            ),
+        }
+    }
+
+    /// Binds the socket at its usual place and adds the socket's group so a non-root
+    /// workspace user can use it.
+    fn mount_docker_socket(&self, config: &mut ContainerCreateBody) -> anyhow::Result<()> {
+        let path = self.socket_path()?;
+        let host_config = config.host_config.get_or_insert_default();
+
+        host_config
+            .binds
+            .get_or_insert_default()
+            .push(format!("{path}:{DEFAULT_SOCKET}"));
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+
+            if let std::result::Result::Ok(metadata) = std::fs::metadata(&path) {
+                host_config.group_add = Some(vec![metadata.gid().to_string()]);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Docker Desktop on Windows reads `C:\code\app:/workspace` ambiguously; it wants the
+    /// host side as `//c/code/app`.
+    fn host_path(path: &str) -> String {
+        match cfg!(windows) {
+            true => Self::docker_desktop_path(path),
+            false => path.to_string(),
+        }
+    }
+
+    fn docker_desktop_path(path: &str) -> String {
+        let path = path.strip_prefix(r"\\?\").unwrap_or(path);
+        match path.split_once(':') {
+            Some((drive, rest)) if drive.len() == 1 => {
+                format!("//{}{}", drive.to_lowercase(), rest.replace('\\', "/"))
+            }
+            _ => path.replace('\\', "/"),
+        }
+    }
 
+    fn container_config(
+        &self,
+        step: &Step,
+        image: String,
+        cmd: Vec<String>,
+        cwd: impl Into<String>,
+        user: impl Into<String>,
+    ) -> ContainerCreateBody {
+        let mount_options = if self.relabel { ":z" } else { "" };
+        let cwd = cwd.into();
         let host_config = HostConfig {
-            binds: Some(vec![format!("{}:/workspace", cwd.into())]),
-            memory: Some(step.memory),
-            memory_swap: Some(step.memory),
+            // An isolated step gets a copy of the workspace instead.
+            binds: (step.workspace_isolation == WorkspaceIsolation::Shared).then(|| {
+                vec![format!(
+                    "{}:/workspace{}",
+                    Self::host_path(&cwd),
+                    mount_options
+                )]
+            }),
+            memory: step.memory,
+            memory_swap: step.memory_swap,
+            storage_opt: step
+                .storage_limit
+                .map(|bytes| HashMap::from([("size".to_string(), bytes.to_string())])),
+            extra_hosts: (!step.extra_hosts.is_empty()).then(|| step.extra_hosts.clone()),
+            dns: (!step.dns.is_empty()).then(|| step.dns.clone()),
+            log_config: step.log_config.as_ref().map(|config| HostConfigLogConfig {
+                typ: config.driver.clone(),
+                config: Some(config.options.clone()),
+            }),
             ..Default::default()
         };
 
-        let container_config = ContainerCreateBody {
-            user: Some(user.into()),
+        ContainerCreateBody {
+            // An empty user keeps the one baked into the image.
+            user: Some(user.into()).filter(|user| !user.is_empty()),
             env: step.env.clone(),
             cmd: Some(cmd),
-            image: Some(step.image.clone()),
+            image: Some(image),
             working_dir: Some("/workspace".to_string()),
             host_config: Some(host_config),
             ..Default::default()
+        }
+    }
+
+    /// Opens what the step reads on its standard input.
+    async fn open_stdin(step: &Step, cwd: &str) -> anyhow::Result<Option<StdinReader>> {
+        let reader: StdinReader = match &step.stdin {
+            None => return Ok(None),
+            Some(StepInput::File(path)) => {
+                let path = Path::new(cwd).join(path);
+                match File::open(&path).await {
+                    std::result::Result::Ok(file) => Box::new(file),
+                    std::result::Result::Err(err) => anyhow::bail!(
+                        "Cannot read the 'stdin_file' {} of step '{}': {}",
+                        path.display(),
+                        step.exploded_name,
+                        err
+                    ),
+                }
+            }
+            Some(StepInput::Process) => Box::new(tokio::io::stdin()),
         };
+        Ok(Some(reader))
+    }
+
+    /// Creates and starts a container, copying `workspace` into it in between. `stdin` is
+    /// only taken once the container has started, so a failed start leaves it unread.
+    async fn create_and_start(
+        &self,
+        name: &str,
+        config: ContainerCreateBody,
+        workspace: Option<&str>,
+        stdin: &mut Option<StdinReader>,
+    ) -> anyhow::Result<StartedContainer> {
+        let container_options = CreateContainerOptionsBuilder::new().name(name).build();
 
         let container = self
             .client
-            .create_container(Some(container_options), container_config)
+            .create_container(Some(container_options), config)
             .await?;
 
-        self.client.start_container(&container.id, None).await?;
+        let copied_in = match workspace {
+            Some(cwd) => match self.copy_workspace_in(&container.id, cwd).await {
+                std::result::Result::Ok(elapsed) => Some(elapsed),
+                std::result::Result::Err(err) => {
+                    self.remove_container(&container.id, true).await.ok();
+                    return Err(err);
+                }
+            },
+            None => None,
+        };
+
+        // Attached before the start so the command cannot miss the beginning of its input.
+        let input = match stdin {
+            Some(_) => match self.attach_stdin(&container.id).await {
+                std::result::Result::Ok(input) => Some(input),
+                std::result::Result::Err(err) => {
+                    self.remove_container(&container.id, true).await.ok();
+                    return Err(err);
+                }
+            },
+            None => None,
+        };
+
+        if let Err(err) = self.client.start_container(&container.id, None).await {
+            self.remove_container(&container.id, true).await.ok();
+            return Err(err.into());
+        }
+        tracing::debug!(name, id = %container.id, "started container");
+
+        if let (Some(mut input), Some(mut reader)) = (input, stdin.take()) {
+            tokio::spawn(async move {
+                tokio::io::copy(&mut reader, &mut input).await.ok();
+                // With `stdin_once`, closing our end closes the command's standard input.
+                input.shutdown().await.ok();
+            });
+        }
 
-        Ok(container.id)
+        Ok(StartedContainer {
+            id: container.id,
+            copied_in,
+        })
+    }
+
+    /// Attaches to the standard input only; the output is still read through the logs.
+    async fn attach_stdin(&self, id: &str) -> anyhow::Result<Pin<Box<dyn AsyncWrite + Send>>> {
+        let options = AttachContainerOptionsBuilder::new()
+            .stream(true)
+            .stdin(true)
+            .build();
+        let attached = self.client.attach_container(id, Some(options)).await?;
+        Ok(attached.input)
+    }
+
+    /// Streams the workspace into the container's `/workspace` as one tar archive,
+    /// leaving out what the ignore rules match.
+    async fn copy_workspace_in(&self, id: &str, cwd: &str) -> anyhow::Result<Duration> {
+        let timer = Instant::now();
+
+        let root = PathBuf::from(cwd);
+        let patterns = self.ignore.clone();
+        let files = tokio::task::spawn_blocking(move || {
+            let rules = IgnoreRules::load(&root, &patterns)?;
+            workspace::walk(&root, &rules)
+        })
+        .await??;
+
+        // tar gets the exact list, so ignored directories are never read at all.
+        let mut child = Command::new("tar")
+            .args([
+                "-c",
+                "-f",
+                "-",
+                "-C",
+                cwd,
+                "--no-recursion",
+                "--null",
+                "-T",
+                "-",
+            ])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|err| anyhow::anyhow!("Could not run 'tar' to copy the workspace: {err}"))?;
+        let mut stdin = child
+            .stdin
+            .take()
+            .ok_or_else(|| anyhow::anyhow!("'tar' has no input"))?;
+        let archive = child
+            .stdout
+            .take()
+            .ok_or_else(|| anyhow::anyhow!("'tar' has no output"))?;
+
+        let list = tokio::spawn(async move {
+            for file in files {
+                let mut entry = file.into_os_string().into_encoded_bytes();
+                entry.push(0);
+                stdin.write_all(&entry).await?;
+            }
+            // Dropping stdin ends the list.
+            std::io::Result::Ok(())
+        });
+
+        let options = UploadToContainerOptionsBuilder::new()
+            .path("/workspace")
+            .build();
+        self.client
+            .upload_to_container(
+                id,
+                Some(options),
+                bollard::body_try_stream(ReaderStream::new(archive)),
+            )
+            .await?;
+        list.await??;
+
+        // GNU tar exits with 1 when a file changed while it was read, which is harmless.
+        let status = child.wait().await?;
+        if status.code().is_none_or(|code| code > 1) {
+            anyhow::bail!("'tar' could not archive the workspace ({status})");
+        }
+
+        let elapsed = timer.elapsed();
+        tracing::debug!(id, ?elapsed, "copied workspace into container");
+        Ok(elapsed)
     }
 
+    /// Copies `paths` from the container's `/workspace` to the same place under `cwd`.
+    pub async fn copy_artifacts_out(
+        &self,
+        id: &str,
+        paths: &[String],
+        cwd: &str,
+    ) -> anyhow::Result<Duration> {
+        let timer = Instant::now();
+
+        for path in paths.iter().map(|path| path.trim_end_matches('/')) {
+            // The archive holds the artifact under its own name, so it is unpacked into
+            // the parent directory.
+            let target = Path::new(cwd).join(path);
+            let parent = target.parent().unwrap_or(Path::new(cwd));
+            tokio::fs::create_dir_all(parent).await?;
+
+            let mut tar = Command::new("tar")
+                .args(["-x", "-f", "-", "-C"])
+                .arg(parent)
+                .stdin(Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()
+                .map_err(|err| anyhow::anyhow!("Could not run 'tar' to copy artifacts: {err}"))?;
+            let mut stdin = tar
+                .stdin
+                .take()
+                .ok_or_else(|| anyhow::anyhow!("'tar' has no input"))?;
+
+            let options = DownloadFromContainerOptionsBuilder::new()
+                .path(&format!("/workspace/{path}"))
+                .build();
+            let mut archive = self.client.download_from_container(id, Some(options));
+            while let Some(chunk) = archive.next().await {
+                let chunk = chunk
+                    .map_err(|err| anyhow::anyhow!("Could not copy artifact '{path}': {err}"))?;
+                stdin.write_all(&chunk).await?;
+            }
+            drop(stdin);
+
+            let status = tar.wait().await?;
+            if !status.success() {
+                anyhow::bail!("'tar' could not unpack artifact '{path}' ({status})");
+            }
+        }
+
+        Ok(timer.elapsed())
+    }
+
+    /// Forwards the container's output line by line until it exits, counting what arrived.
     pub async fn stream_logs(
         &self,
         id: &str,
+        stage: &str,
         step_name: &str,
+        attempt: u32,
         log_tx: &mpsc::Sender<LogMessage>,
         token: &CancellationToken,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<OutputStats> {
         let logs_options = LogsOptionsBuilder::new()
             .stdout(true)
             .stderr(true)
             .follow(true)
             .build();
 
-        let mut stream = self.client.logs(id, Some(logs_options));
+        let stream = self.client.logs(id, Some(logs_options));
+        Self::forward_output(stream, stage, step_name, attempt, "", log_tx, token).await
+    }
+
+    /// Runs the command of a step, or with `check` its `check`, in the running container
+    /// `id` with the step's environment. Returns its exit code and output, forwarded like
+    /// the one of [`Self::stream_logs`]; the lines of a check are tagged `[check]`.
+    pub async fn exec_step(
+        &self,
+        id: &str,
+        step: &Step,
+        check: bool,
+        attempt: u32,
+        log_tx: &mpsc::Sender<LogMessage>,
+        token: &CancellationToken,
+    ) -> anyhow::Result<(i64, OutputStats)> {
+        let (command, tag) = match (check, &step.check) {
+            (true, Some(check)) => (check.clone(), "[check] "),
+            _ => (step.command.clone(), ""),
+        };
+
+        let exec = self
+            .client
+            .create_exec(
+                id,
+                CreateExecOptions {
+                    attach_stdout: Some(true),
+                    attach_stderr: Some(true),
+                    cmd: Some(vec!["sh".to_string(), "-c".to_string(), command]),
+                    env: step.env.clone(),
+                    working_dir: Some("/workspace".to_string()),
+                    ..Default::default()
+                },
+            )
+            .await?;
+
+        let stats = match self.client.start_exec(&exec.id, None).await? {
+            StartExecResults::Attached { output, .. } => {
+                Self::forward_output(
+                    output,
+                    &step.stage,
+                    &step.exploded_name,
+                    attempt,
+                    tag,
+                    log_tx,
+                    token,
+                )
+                .await?
+            }
+            StartExecResults::Detached => OutputStats::default(),
+        };
+
+        let inspect = self.client.inspect_exec(&exec.id).await?;
+        Ok((inspect.exit_code.unwrap_or(-1), stats))
+    }
+
+    /// Sends the lines of a container's output to the log task until it ends, each after
+    /// `tag`.
+    async fn forward_output(
+        stream: impl Stream<Item = Result<LogOutput, bollard::errors::Error>>,
+        stage: &str,
+        step_name: &str,
+        attempt: u32,
+        tag: &str,
+        log_tx: &mpsc::Sender<LogMessage>,
+        token: &CancellationToken,
+    ) -> anyhow::Result<OutputStats> {
+        let mut stream = std::pin::pin!(stream);
+        let mut stats = OutputStats::default();
 
         loop {
             tokio::select! {
                 _ = token.cancelled() => {
-                    return Ok(());
+                    return Ok(stats);
                 }
 
                 log = stream.next() => {
@@ -118,22 +1163,30 @@ impl DockerEngine {
                         None => break,
                     };
 
-                    let (line, is_error) = match log_item {
+                    let (chunk, is_error) = match log_item {
                         LogOutput::StdOut { message } => {
+                            stats.stdout_bytes += message.len() as u64;
                             (String::from_utf8_lossy(&message).to_string(), false)
                         }
                         LogOutput::StdErr { message } => {
+                            stats.stderr_bytes += message.len() as u64;
                             (String::from_utf8_lossy(&message).to_string(), true)
                         }
                         _ => continue,
                     };
 
-                    if !line.is_empty() {
+                    // `lines` also strips the `\r` of Windows line endings.
+                    for line in chunk.lines() {
+                        stats.lines += 1;
                         log_tx
                             .send(LogMessage {
+                                stage: stage.to_string(),
                                 step_name: step_name.to_string(),
-                                line,
+                                line: format!("{tag}{line}"),
                                 is_error,
+                                attempt,
+                                stage_attempt: None,
+                                ended: None,
                             })
                             .await
                             .ok();
@@ -142,23 +1195,422 @@ impl DockerEngine {
             }
         }
 
-        Ok(())
+        Ok(stats)
+    }
+
+    /// Why the engine likely dropped some of a finished container's output before it
+    /// reached `stream_logs`, judged by its log driver's limits and, where the log file is
+    /// readable, by the lines in it.
+    pub async fn log_truncation(&self, id: &str, received: &OutputStats) -> Option<String> {
+        let inspect = self.client.inspect_container(id, None).await.ok()?;
+        let log_config = inspect.host_config.and_then(|config| config.log_config);
+        let driver = log_config
+            .as_ref()
+            .and_then(|config| config.typ.clone())
+            .unwrap_or_default();
+        if driver == "none" {
+            return Some("its log driver is 'none', which keeps no output".to_string());
+        }
+
+        let options = log_config
+            .and_then(|config| config.config)
+            .unwrap_or_default();
+        if let Some(max_size) = options
+            .get("max-size")
+            .and_then(|size| Self::log_size(size))
+        {
+            let files = options
+                .get("max-file")
+                .and_then(|files| files.parse::<u64>().ok())
+                .unwrap_or(1);
+            let received_bytes = received.stdout_bytes + received.stderr_bytes;
+            if received_bytes >= max_size * files {
+                return Some(format!(
+                    "it printed {} KiB, more than the {} KiB its '{}' log driver keeps",
+                    received_bytes / 1024,
+                    max_size * files / 1024,
+                    driver
+                ));
+            }
+        }
+
+        // Every entry of a json-file log is one line of output.
+        let path = inspect.log_path.filter(|_| driver == "json-file")?;
+        let file = File::open(&path).await.ok()?;
+        let mut chunks = ReaderStream::new(file);
+        let mut logged = 0u64;
+        while let Some(chunk) = chunks.next().await {
+            logged += chunk.ok()?.iter().filter(|byte| **byte == b'\n').count() as u64;
+        }
+        (logged > received.lines).then(|| {
+            format!(
+                "the engine logged {} lines but only {} arrived",
+                logged, received.lines
+            )
+        })
+    }
+
+    /// Bytes from a log driver size such as `10m`, `1g` or `500k`.
+    fn log_size(raw: &str) -> Option<u64> {
+        let raw = raw.trim().to_lowercase();
+        let (digits, multiplier) = match raw.char_indices().last()? {
+            (at, 'k') => (&raw[..at], 1000),
+            (at, 'm') => (&raw[..at], 1000 * 1000),
+            (at, 'g') => (&raw[..at], 1000 * 1000 * 1000),
+            _ => (raw.as_str(), 1),
+        };
+        Some(digits.parse::<u64>().ok()? * multiplier)
+    }
+
+    /// Bytes written to the container's writable layer.
+    pub async fn layer_size(&self, id: &str) -> Option<i64> {
+        let options = InspectContainerOptionsBuilder::new().size(true).build();
+        let inspect = self
+            .client
+            .inspect_container(id, Some(options))
+            .await
+            .ok()?;
+        inspect.size_rw
+    }
+
+    /// Memory available to containers: the host's, or that of the VM on Docker Desktop.
+    pub async fn total_memory(&self) -> Option<i64> {
+        self.client.info().await.ok()?.mem_total
     }
 
-    pub async fn get_exit_state(&self, id: &str) -> anyhow::Result<ContainerState> {
+    /// Free bytes on the filesystem holding Docker's data. `None` when that is not on this
+    /// machine, as with Docker Desktop or a remote daemon.
+    pub async fn free_space(&self) -> Option<u64> {
+        let root = self.client.info().await.ok()?.docker_root_dir?;
+        if !Path::new(&root).exists() {
+            return None;
+        }
+
+        let output = Command::new("df")
+            .args(["-Pk", &root])
+            .output()
+            .await
+            .ok()?;
+        // POSIX format: a header, then `filesystem blocks used available capacity mount`.
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let available: u64 = stdout
+            .lines()
+            .nth(1)?
+            .split_whitespace()
+            .nth(3)?
+            .parse()
+            .ok()?;
+        Some(available * 1024)
+    }
+
+    /// Bytes `docker system prune` could free: unused images, stopped containers, volumes
+    /// and build cache.
+    pub async fn reclaimable_space(&self) -> Option<i64> {
+        let usage = self.client.df(None).await.ok()?;
+
+        Some(
+            [
+                usage.images_disk_usage.and_then(|usage| usage.reclaimable),
+                usage
+                    .containers_disk_usage
+                    .and_then(|usage| usage.reclaimable),
+                usage.volumes_disk_usage.and_then(|usage| usage.reclaimable),
+                usage
+                    .build_cache_disk_usage
+                    .and_then(|usage| usage.reclaimable),
+            ]
+            .into_iter()
+            .flatten()
+            .sum(),
+        )
+    }
+
+    /// Inspects a container without touching it; removing it is up to the caller.
+    pub async fn inspect_state(&self, id: &str) -> anyhow::Result<ContainerState> {
         let inspect = self.client.inspect_container(id, None).await?;
-        let state = inspect.state.unwrap_or_default();
-        self.force_remove_container(id).await.ok();
-        Ok(state)
+        Ok(inspect.state.unwrap_or_default())
     }
 
-    pub async fn force_remove_container(&self, name: &str) -> anyhow::Result<()> {
-        let remove_options = RemoveContainerOptionsBuilder::new().force(true).build();
+    /// Removes a container; with `force` a running one is killed first. Failures are
+    /// logged at debug level, as most callers only remove on a best-effort basis.
+    pub async fn remove_container(&self, name: &str, force: bool) -> anyhow::Result<()> {
+        let remove_options = RemoveContainerOptionsBuilder::new().force(force).build();
 
-        self.client
+        tracing::debug!(container = name, "removing container");
+        if let Err(err) = self
+            .client
             .remove_container(name, Some(remove_options))
-            .await?;
+            .await
+        {
+            tracing::debug!(container = name, error = %err, "could not remove container");
+            return Err(err.into());
+        }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use bollard::errors::Error;
+
+    use super::*;
+
+    fn is_transient(err: Error) -> bool {
+        DockerEngine::is_transient(&err.into())
+    }
+
+    fn server_error(status_code: u16, message: &str) -> Error {
+        Error::DockerResponseServerError {
+            status_code,
+            message: message.to_string(),
+        }
+    }
+
+    fn stream_error(error: &str) -> Error {
+        Error::DockerStreamError {
+            error: error.to_string(),
+        }
+    }
+
+    #[test]
+    fn containers_are_named_by_stage_step_and_attempt() {
+        let pipeline = toml::from_str::<crate::models::RawPipeline>(
+            r#"
+            stages_order = ["Services", "test"]
+
+            [stages.Services.steps.db]
+            image = "postgres:16"
+            command = "postgres"
+            detach = true
+
+            [stages.test.steps.db]
+            image = "postgres:16"
+            command = "psql -c 'select 1'"
+            "#,
+        )
+        .unwrap()
+        .select(None)
+        .and_then(|raw| raw.compile(&[]))
+        .unwrap();
+        let name = |stage: usize, stage_attempt, attempt| {
+            let step = &pipeline.stages[stage].steps[0];
+            DockerEngine::attempt_container_name("ciroach-run", step, stage_attempt, attempt)
+        };
+
+        assert_eq!(name(0, 1, 1), "ciroach-run-services-db-1-1");
+        assert_eq!(name(1, 1, 1), "ciroach-run-test-db-1-1");
+        assert_eq!(name(1, 1, 2), "ciroach-run-test-db-1-2");
+        assert_eq!(name(1, 2, 1), "ciroach-run-test-db-2-1");
+    }
+
+    #[test]
+    fn only_a_refused_size_limit_falls_back_to_none() {
+        let refused = server_error(
+            400,
+            "--storage-opt is supported only for overlay over xfs with 'pquota' mount option",
+        );
+        assert!(DockerEngine::lacks_storage_opt(&refused.into()));
+
+        let others = [
+            server_error(400, "invalid reference format"),
+            server_error(500, "--storage-opt is supported only for overlay over xfs"),
+            stream_error("--storage-opt is supported only for overlay over xfs"),
+        ];
+        for err in others {
+            assert!(!DockerEngine::lacks_storage_opt(&err.into()));
+        }
+        let untyped = anyhow::anyhow!("--storage-opt is supported only for overlay over xfs");
+        assert!(!DockerEngine::lacks_storage_opt(&untyped));
+    }
+
+    #[test]
+    fn timeouts_are_transient() {
+        assert!(is_transient(Error::RequestTimeoutError));
+        assert!(is_transient(server_error(408, "request timeout")));
+        assert!(is_transient(stream_error(
+            "Get \"https://registry-1.docker.io/v2/\": net/http: TLS handshake timeout"
+        )));
+    }
+
+    #[test]
+    fn a_pull_running_out_of_time_is_transient() {
+        let err = anyhow::Error::new(Error::RequestTimeoutError).context("Pull timed out");
+        assert!(DockerEngine::is_transient(&err));
+    }
+
+    #[test]
+    fn server_errors_are_transient() {
+        assert!(is_transient(server_error(500, "internal server error")));
+        assert!(is_transient(server_error(502, "bad gateway")));
+        assert!(is_transient(server_error(503, "service unavailable")));
+        assert!(is_transient(server_error(429, "toomanyrequests")));
+        assert!(is_transient(stream_error(
+            "received unexpected HTTP status: 503 Service Unavailable"
+        )));
+    }
+
+    #[test]
+    fn connection_resets_are_transient() {
+        let reset = std::io::Error::from(ErrorKind::ConnectionReset);
+        assert!(is_transient(Error::IOError { err: reset }));
+        assert!(is_transient(stream_error(
+            "read tcp 172.17.0.1:50432->104.18.121.25:443: read: connection reset by peer"
+        )));
+    }
+
+    #[test]
+    fn unknown_manifests_are_permanent() {
+        assert!(!is_transient(server_error(
+            404,
+            "manifest for alpine:nope not found: manifest unknown: manifest unknown"
+        )));
+        assert!(!is_transient(stream_error(
+            "manifest unknown: manifest unknown"
+        )));
+    }
+
+    #[test]
+    fn authentication_failures_are_permanent() {
+        assert!(!is_transient(server_error(
+            401,
+            "unauthorized: authentication required"
+        )));
+        assert!(!is_transient(stream_error(
+            "pull access denied for private/app, repository does not exist or may require 'docker login'"
+        )));
+        assert!(!is_transient(stream_error(
+            "unauthorized: incorrect username or password"
+        )));
+    }
+
+    #[test]
+    fn errors_not_from_the_engine_are_permanent() {
+        assert!(!DockerEngine::is_transient(&anyhow::anyhow!(
+            "Local image 'app' does not exist"
+        )));
+    }
+
+    fn event(id: Option<&str>, status: &str, progress: Option<(i64, i64)>) -> CreateImageInfo {
+        CreateImageInfo {
+            id: id.map(str::to_string),
+            status: Some(status.to_string()),
+            progress_detail: progress.map(|(current, total)| bollard::secret::ProgressDetail {
+                current: Some(current),
+                total: Some(total),
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn percent(progress: PullProgress) -> u64 {
+        progress.current * 100 / progress.total
+    }
+
+    #[test]
+    fn pull_progress_adds_up_the_layers() {
+        let mut layers = PullLayers::default();
+
+        // The first event names the tag, not a layer.
+        assert!(
+            layers
+                .update(&event(Some("3.19"), "Pulling from library/alpine", None))
+                .is_none()
+        );
+        layers.update(&event(Some("a1"), "Pulling fs layer", None));
+        layers.update(&event(Some("b2"), "Pulling fs layer", None));
+        let progress = layers.update(&event(Some("b2"), "Waiting", None)).unwrap();
+        assert_eq!((progress.layers_done, progress.layers_total), (0, 2));
+        assert_eq!((progress.current, progress.total), (0, 0));
+
+        layers.update(&event(Some("a1"), "Downloading", Some((1_000, 4_000))));
+        let progress = layers
+            .update(&event(Some("a1"), "Downloading", Some((3_000, 4_000))))
+            .unwrap();
+        assert_eq!((progress.current, progress.total), (3_000, 4_000));
+        assert_eq!(percent(progress), 75);
+
+        // The second layer reports its size once it starts, so the total grows.
+        let progress = layers
+            .update(&event(Some("b2"), "Downloading", Some((500, 1_000))))
+            .unwrap();
+        assert_eq!((progress.current, progress.total), (3_500, 5_000));
+        assert_eq!(percent(progress), 70);
+
+        // Extraction reports its own byte counts, which are not download progress.
+        layers.update(&event(Some("a1"), "Download complete", None));
+        let progress = layers
+            .update(&event(Some("a1"), "Extracting", Some((32_768, 4_000))))
+            .unwrap();
+        assert_eq!((progress.current, progress.total), (4_500, 5_000));
+        assert_eq!(percent(progress), 90);
+
+        let progress = layers
+            .update(&event(Some("a1"), "Pull complete", None))
+            .unwrap();
+        assert_eq!((progress.layers_done, progress.layers_total), (1, 2));
+
+        layers.update(&event(Some("b2"), "Verifying Checksum", None));
+        layers.update(&event(Some("b2"), "Download complete", None));
+        let progress = layers
+            .update(&event(Some("b2"), "Pull complete", None))
+            .unwrap();
+        assert_eq!((progress.layers_done, progress.layers_total), (2, 2));
+        assert_eq!((progress.current, progress.total), (5_000, 5_000));
+        assert_eq!(percent(progress), 100);
+
+        assert!(
+            layers
+                .update(&event(None, "Digest: sha256:4bcff63911fcb4448bd4fdacec207030997caf25e9bea4045fa6c8c44de311d1", None))
+                .is_none()
+        );
+        assert!(
+            layers
+                .update(&event(
+                    None,
+                    "Status: Downloaded newer image for alpine:3.19",
+                    None
+                ))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn layers_already_there_count_as_done_without_bytes() {
+        let mut layers = PullLayers::default();
+
+        layers.update(&event(Some("a1"), "Already exists", None));
+        layers.update(&event(Some("b2"), "Pulling fs layer", None));
+        let progress = layers
+            .update(&event(Some("b2"), "Downloading", Some((256, 1_024))))
+            .unwrap();
+
+        assert_eq!((progress.layers_done, progress.layers_total), (1, 2));
+        assert_eq!((progress.current, progress.total), (256, 1_024));
+        assert_eq!(percent(progress), 25);
+    }
+
+    #[test]
+    fn windows_paths_take_the_docker_desktop_form() {
+        let paths = [
+            (r"C:\code\app", "//c/code/app"),
+            (r"\\?\C:\code\app", "//c/code/app"),
+            (r"d:\", "//d/"),
+            (r"C:\Users\Jane Doe\my app", "//c/Users/Jane Doe/my app"),
+            (r"\\server\share\app", "//server/share/app"),
+            ("C:/code/app", "//c/code/app"),
+        ];
+
+        for (path, expected) in paths {
+            assert_eq!(DockerEngine::docker_desktop_path(path), expected, "{path}");
+        }
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn host_paths_are_kept_outside_windows() {
+        assert_eq!(DockerEngine::host_path("/home/jane/app"), "/home/jane/app");
+    }
+}
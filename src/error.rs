@@ -0,0 +1,291 @@
+use std::{fmt, path::PathBuf, process::ExitCode, time::Duration};
+
+use thiserror::Error;
+
+/// Exit code of a run in which a step failed, and of errors not classified below.
+pub const STEP_FAILED: u8 = 1;
+/// Exit code when the configuration cannot be loaded or is not a valid pipeline.
+pub const INVALID_CONFIG: u8 = 2;
+/// Exit code when the container engine or the machine failed.
+pub const ENGINE_FAILED: u8 = 3;
+/// Exit code when a stage deadlocked or made no progress within `engine.stall_timeout`.
+pub const PIPELINE_STUCK: u8 = 4;
+/// Exit code when a signal stopped the run, as shells report `SIGINT`.
+pub const INTERRUPTED: u8 = 130;
+
+/// Why a pipeline could not be loaded or run, or why a step did not succeed. Failed steps
+/// end up in the report; only the other variants end a command, told apart by the exit
+/// code of the process.
+#[derive(Debug, Error)]
+pub enum CiroachError {
+    /// The configuration file cannot be read or is not valid TOML.
+    #[error("Cannot load '{}'", path.display())]
+    Config {
+        path: PathBuf,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    /// The configuration parses but describes a pipeline that cannot run.
+    #[error("{}", Issue::list(.0))]
+    Validation(Vec<Issue>),
+    /// The container engine or the machine failed, e.g. Docker is unreachable.
+    #[error(transparent)]
+    Engine(#[from] anyhow::Error),
+    #[error("Step '{step}' exited with code {code}")]
+    StepFailed { step: String, code: i64 },
+    /// The process died from a signal, reported as exit code 128 + signal number.
+    #[error("Step '{step}' was killed by {signal} (exit code {code})")]
+    Killed {
+        step: String,
+        signal: &'static str,
+        code: i64,
+    },
+    /// The container was killed for exceeding its memory limit.
+    #[error("Step '{step}' ran out of memory")]
+    Oom { step: String },
+    #[error("Step '{step}' timed out after {limit:?}")]
+    Timeout { step: String, limit: Duration },
+    #[error("Step '{step}' ran out of its total_timeout of {limit:?}")]
+    TotalTimeout { step: String, limit: Duration },
+    #[error("The check of step '{step}' exited with code {code}")]
+    PreconditionFailed { step: String, code: i64 },
+    #[error("Step '{step}' printed nothing for {limit:?}")]
+    Silent { step: String, limit: Duration },
+    /// Nothing runs in a stage and its remaining steps can never start.
+    #[error(
+        "Deadlock detected in stage '{stage}': no step is running and these can never start.\n{blocked}"
+    )]
+    Deadlock { stage: String, blocked: String },
+    /// No step of a stage logged or finished within `engine.stall_timeout`.
+    #[error("Stage '{stage}' made no progress for {limit:?}.\n{blocked}")]
+    Stalled {
+        stage: String,
+        limit: Duration,
+        blocked: String,
+    },
+    /// A signal stopped the run, or the stage it belonged to.
+    #[error("Cancelled")]
+    Cancelled,
+}
+
+impl CiroachError {
+    /// A single problem found in the configuration.
+    pub fn invalid(message: impl Into<String>) -> Self {
+        Self::Validation(vec![Issue {
+            message: message.into(),
+        }])
+    }
+
+    /// Wraps `err` as an engine error unless it is classified already.
+    pub fn engine(err: anyhow::Error) -> Self {
+        match err.downcast::<Self>() {
+            Ok(classified) => classified,
+            Err(err) => Self::Engine(err),
+        }
+    }
+
+    /// The exit code of a step that failed by exiting non-zero.
+    pub fn exit_code(&self) -> Option<i64> {
+        match self {
+            Self::StepFailed { code, .. } | Self::Killed { code, .. } => Some(*code),
+            _ => None,
+        }
+    }
+
+    pub fn code(&self) -> u8 {
+        match self {
+            Self::Config { .. } | Self::Validation(_) => INVALID_CONFIG,
+            Self::Engine(_) => ENGINE_FAILED,
+            Self::Deadlock { .. } | Self::Stalled { .. } => PIPELINE_STUCK,
+            Self::Cancelled => INTERRUPTED,
+            Self::StepFailed { .. }
+            | Self::Killed { .. }
+            | Self::Oom { .. }
+            | Self::Timeout { .. }
+            | Self::TotalTimeout { .. }
+            | Self::PreconditionFailed { .. }
+            | Self::Silent { .. } => STEP_FAILED,
+        }
+    }
+
+    /// Prints `err` the way a `main` returning it would, and picks the exit code.
+    pub fn exit(err: anyhow::Error) -> ExitCode {
+        eprintln!("Error: {err:?}");
+        ExitCode::from(Self::code_of(&err))
+    }
+
+    /// The code of the first classified error in the chain of `err`.
+    fn code_of(err: &anyhow::Error) -> u8 {
+        Self::classify(err).map_or(STEP_FAILED, Self::code)
+    }
+
+    /// The exit code of a run that stopped early with `err`; what is not classified
+    /// otherwise is a failure of the engine or the machine.
+    pub fn run_code(err: &anyhow::Error) -> u8 {
+        Self::classify(err).map_or(ENGINE_FAILED, Self::code)
+    }
+
+    fn classify(err: &anyhow::Error) -> Option<&Self> {
+        err.chain().find_map(|cause| cause.downcast_ref::<Self>())
+    }
+}
+
+/// Something wrong with the configuration, in the words shown to the user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Issue {
+    pub message: String,
+}
+
+impl Issue {
+    fn list(issues: &[Issue]) -> String {
+        match issues {
+            [issue] => issue.to_string(),
+            _ => issues
+                .iter()
+                .map(|issue| format!("- {issue}"))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl From<anyhow::Error> for Issue {
+    /// Keeps the causes, e.g. of an invalid regex, in the message.
+    fn from(err: anyhow::Error) -> Self {
+        Self {
+            message: format!("{err:#}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_failure_class_has_its_exit_code() {
+        let limit = Duration::from_secs(60);
+        let codes = [
+            (
+                CiroachError::Config {
+                    path: PathBuf::from("ciroach.toml"),
+                    source: "expected '='".into(),
+                },
+                2,
+            ),
+            (CiroachError::invalid("Stage 'test' has no steps."), 2),
+            (
+                CiroachError::Engine(anyhow::anyhow!("Docker is unreachable")),
+                3,
+            ),
+            (CiroachError::Cancelled, 130),
+            (
+                CiroachError::StepFailed {
+                    step: "lint".into(),
+                    code: 2,
+                },
+                1,
+            ),
+            (
+                CiroachError::Killed {
+                    step: "lint".into(),
+                    signal: "SIGKILL",
+                    code: 137,
+                },
+                1,
+            ),
+            (
+                CiroachError::Oom {
+                    step: "lint".into(),
+                },
+                1,
+            ),
+            (
+                CiroachError::Timeout {
+                    step: "lint".into(),
+                    limit,
+                },
+                1,
+            ),
+            (
+                CiroachError::TotalTimeout {
+                    step: "lint".into(),
+                    limit,
+                },
+                1,
+            ),
+            (
+                CiroachError::PreconditionFailed {
+                    step: "lint".into(),
+                    code: 1,
+                },
+                1,
+            ),
+            (
+                CiroachError::Silent {
+                    step: "lint".into(),
+                    limit,
+                },
+                1,
+            ),
+            (
+                CiroachError::Deadlock {
+                    stage: "test".into(),
+                    blocked: String::new(),
+                },
+                4,
+            ),
+            (
+                CiroachError::Stalled {
+                    stage: "test".into(),
+                    limit,
+                    blocked: String::new(),
+                },
+                4,
+            ),
+        ];
+
+        for (err, code) in codes {
+            assert_eq!(err.code(), code, "{err:?}");
+        }
+    }
+
+    #[test]
+    fn the_exit_code_comes_from_the_first_classified_cause() {
+        let cancelled = anyhow::Error::new(CiroachError::Cancelled).context("Run failed");
+        assert_eq!(CiroachError::code_of(&cancelled), 130);
+
+        let invalid = anyhow::Error::new(CiroachError::invalid("Unknown stage 'tset'."));
+        assert_eq!(CiroachError::code_of(&invalid), 2);
+
+        let unclassified = anyhow::anyhow!("Cannot write the report");
+        assert_eq!(CiroachError::code_of(&unclassified), STEP_FAILED);
+    }
+
+    #[test]
+    fn a_run_that_stopped_early_is_an_engine_failure_unless_classified() {
+        let unclassified = anyhow::anyhow!("Not enough free space");
+        assert_eq!(CiroachError::run_code(&unclassified), ENGINE_FAILED);
+
+        let deadlock = anyhow::Error::new(CiroachError::Deadlock {
+            stage: "test".into(),
+            blocked: String::new(),
+        });
+        assert_eq!(CiroachError::run_code(&deadlock), PIPELINE_STUCK);
+    }
+
+    #[test]
+    fn classified_errors_are_not_wrapped_as_engine_errors() {
+        let cancelled = CiroachError::engine(CiroachError::Cancelled.into());
+        assert!(matches!(cancelled, CiroachError::Cancelled));
+
+        let unreachable = CiroachError::engine(anyhow::anyhow!("Docker is unreachable"));
+        assert_eq!(unreachable.code(), ENGINE_FAILED);
+    }
+}
@@ -0,0 +1,86 @@
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::{engine::PullProgress, models::StepStatus};
+
+const EVENT_BUFFER: usize = 1024;
+
+/// Lifecycle notifications published while a pipeline runs.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum PipelineEvent {
+    ImagePulling {
+        image: String,
+        progress: PullProgress,
+    },
+    StageStarted {
+        stage: String,
+        /// Milliseconds the stage is expected to take, from earlier runs.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        estimate: Option<u64>,
+    },
+    StageFinished {
+        stage: String,
+        success: bool,
+    },
+    StepStarted {
+        stage: String,
+        step: String,
+        /// Milliseconds the step took before, when it succeeded in an earlier run.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        expected: Option<u64>,
+    },
+    StepRetrying {
+        step: String,
+        attempt: u32,
+        max_retries: u32,
+    },
+    /// A running step printed nothing for `silent_for` milliseconds.
+    StepSilent {
+        stage: String,
+        step: String,
+        silent_for: u64,
+    },
+    StepLog {
+        stage: String,
+        step: String,
+        line: String,
+        is_error: bool,
+    },
+    StepFinished {
+        stage: String,
+        step: String,
+        status: StepStatus,
+        retries: u32,
+        elapsed: u64,
+    },
+    PipelineFinished {
+        success: bool,
+        elapsed: u64,
+    },
+}
+
+/// Fan-out channel for [`PipelineEvent`]s. Publishing never blocks; subscribers that fall
+/// behind lose the oldest events.
+#[derive(Clone)]
+pub struct EventBus {
+    tx: broadcast::Sender<PipelineEvent>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        let (tx, _) = broadcast::channel(EVENT_BUFFER);
+        Self { tx }
+    }
+}
+
+impl EventBus {
+    pub fn subscribe(&self) -> broadcast::Receiver<PipelineEvent> {
+        self.tx.subscribe()
+    }
+
+    pub fn emit(&self, event: PipelineEvent) {
+        // Sending only fails when nobody is listening.
+        self.tx.send(event).ok();
+    }
+}
@@ -0,0 +1,174 @@
+use std::{env, process::Command};
+
+use anyhow::Ok;
+use serde::Serialize;
+
+use crate::{
+    models::{GithubConfig, PipelineReport, StageReport},
+    output::Icon,
+};
+
+const API_URL: &str = "https://api.github.com";
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CommitState {
+    Pending,
+    Success,
+    Failure,
+    Error,
+}
+
+#[derive(Serialize)]
+struct StatusPayload<'a> {
+    state: CommitState,
+    description: &'a str,
+    context: &'a str,
+}
+
+/// Publishes commit statuses for the checked out revision. API failures are reported as
+/// warnings and never fail the pipeline.
+pub struct GithubNotifier {
+    client: reqwest::Client,
+    config: GithubConfig,
+    token: String,
+    sha: String,
+}
+
+impl GithubNotifier {
+    /// Returns `None` (with a warning) when the token or commit sha cannot be determined.
+    pub fn from_config(config: GithubConfig) -> Option<Self> {
+        let Some(token) = env::var(&config.token_env).ok().filter(|t| !t.is_empty()) else {
+            println!(
+                "{} GitHub status skipped: environment variable '{}' is not set.",
+                Icon::Warning,
+                config.token_env
+            );
+            return None;
+        };
+
+        let Some(sha) = Self::detect_sha() else {
+            println!(
+                "{} GitHub status skipped: set CIROACH_SHA or run inside a git checkout.",
+                Icon::Warning
+            );
+            return None;
+        };
+
+        Some(Self {
+            client: reqwest::Client::new(),
+            config,
+            token,
+            sha,
+        })
+    }
+
+    pub async fn pending(&self, stage_names: &[String]) {
+        self.post(
+            &self.config.context,
+            CommitState::Pending,
+            "Pipeline running",
+        )
+        .await;
+
+        if self.config.per_stage {
+            for stage in stage_names {
+                self.post(&self.stage_context(stage), CommitState::Pending, "Waiting")
+                    .await;
+            }
+        }
+    }
+
+    pub async fn finish(&self, report: &PipelineReport) {
+        let (state, description) = if report.is_success() {
+            (CommitState::Success, "Pipeline succeeded")
+        } else {
+            (CommitState::Failure, "Pipeline failed")
+        };
+        self.post(&self.config.context, state, description).await;
+
+        if self.config.per_stage {
+            for stage in report.stage_reports.iter() {
+                let (state, description) = Self::stage_state(stage);
+                self.post(&self.stage_context(&stage.name), state, description)
+                    .await;
+            }
+        }
+    }
+
+    fn stage_state(stage: &StageReport) -> (CommitState, &'static str) {
+        if !stage.is_success() {
+            (CommitState::Failure, "Stage failed")
+        } else if stage.is_skipped() {
+            (CommitState::Error, "Stage skipped")
+        } else {
+            (CommitState::Success, "Stage succeeded")
+        }
+    }
+
+    fn stage_context(&self, stage: &str) -> String {
+        format!("{}/{}", self.config.context, stage)
+    }
+
+    async fn post(&self, context: &str, state: CommitState, description: &str) {
+        if let Err(err) = self.send(context, state, description).await {
+            eprintln!(
+                "{} Failed to update GitHub status '{}': {}",
+                Icon::Warning,
+                context,
+                err
+            );
+        }
+    }
+
+    async fn send(
+        &self,
+        context: &str,
+        state: CommitState,
+        description: &str,
+    ) -> anyhow::Result<()> {
+        let url = format!(
+            "{}/repos/{}/statuses/{}",
+            API_URL, self.config.repo, self.sha
+        );
+
+        let response = self
+            .client
+            .post(url)
+            .bearer_auth(&self.token)
+            .header(reqwest::header::ACCEPT, "application/vnd.github+json")
+            .header(reqwest::header::USER_AGENT, "ciroach")
+            .json(&StatusPayload {
+                state,
+                description,
+                context,
+            })
+            .send()
+            .await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            anyhow::bail!("GitHub API responded with {status}: {}", body.trim());
+        }
+
+        Ok(())
+    }
+
+    /// `CIROACH_SHA`, or the checked out commit.
+    pub fn detect_sha() -> Option<String> {
+        if let Some(sha) = env::var("CIROACH_SHA").ok().filter(|sha| !sha.is_empty()) {
+            return Some(sha);
+        }
+
+        let output = Command::new("git")
+            .args(["rev-parse", "HEAD"])
+            .output()
+            .ok()?;
+
+        output
+            .status
+            .success()
+            .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+}
@@ -0,0 +1,419 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+};
+
+use anyhow::Ok;
+use chrono::Local;
+use tokio::fs::{create_dir_all, read_dir, read_to_string, remove_file, write};
+
+use crate::{
+    models::{BaselineMode, HistoryConfig, PipelineReport, StepReport, StepStatus},
+    output::Icon,
+};
+
+pub const HISTORY_DIR: &str = ".ciroach/history";
+
+/// Changes smaller than this are treated as noise and never flagged.
+const MIN_REGRESSION_DELTA_MS: i64 = 1000;
+
+pub struct RunHistory {
+    dir: PathBuf,
+    config: HistoryConfig,
+}
+
+impl RunHistory {
+    pub fn new(dir: impl Into<PathBuf>, config: HistoryConfig) -> Self {
+        Self {
+            dir: dir.into(),
+            config,
+        }
+    }
+
+    /// Loads up to `limit` recorded runs, newest first.
+    pub async fn load(&self, limit: usize) -> anyhow::Result<Vec<PipelineReport>> {
+        let mut runs = Vec::new();
+
+        for path in self.entries().await?.into_iter().rev() {
+            if runs.len() >= limit {
+                break;
+            }
+
+            let raw = read_to_string(&path).await?;
+            match serde_json::from_str(&raw) {
+                std::result::Result::Ok(report) => runs.push(report),
+                std::result::Result::Err(err) => {
+                    eprintln!(
+                        "{} Ignoring unreadable history entry {}: {}",
+                        Icon::Warning,
+                        path.display(),
+                        err
+                    );
+                }
+            }
+        }
+
+        Ok(runs)
+    }
+
+    pub async fn baseline(&self) -> Option<Baseline> {
+        let runs = self.load(self.config.window).await.ok()?;
+        Baseline::from_runs(
+            &runs,
+            self.config.baseline,
+            self.config.regression_threshold,
+        )
+    }
+
+    /// Stores the report and returns the path of the new history entry.
+    pub async fn record(&self, report: &PipelineReport) -> anyhow::Result<PathBuf> {
+        create_dir_all(&self.dir).await?;
+
+        let path = self.dir.join(format!(
+            "run_{}.json",
+            Local::now().format("%Y-%m-%d_%H-%M-%S%.3f")
+        ));
+        write(&path, serde_json::to_string_pretty(report)?).await?;
+
+        self.prune().await?;
+        Ok(path)
+    }
+
+    async fn prune(&self) -> anyhow::Result<()> {
+        let entries = self.entries().await?;
+        let excess = entries.len().saturating_sub(self.config.keep);
+
+        for path in entries.into_iter().take(excess) {
+            remove_file(path).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns history files sorted oldest first. A missing directory simply means no history.
+    async fn entries(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let mut dir = match read_dir(&self.dir).await {
+            std::result::Result::Ok(dir) => dir,
+            std::result::Result::Err(_) => return Ok(Vec::new()),
+        };
+
+        let mut paths = Vec::new();
+        while let Some(entry) = dir.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                paths.push(path);
+            }
+        }
+
+        paths.sort();
+        Ok(paths)
+    }
+}
+
+/// Reference durations that the current run is compared against.
+pub struct Baseline {
+    durations: HashMap<String, u64>,
+    known: HashSet<String>,
+    threshold: f64,
+}
+
+impl Baseline {
+    /// Duration of each step that succeeded, in milliseconds.
+    pub fn durations(&self) -> &HashMap<String, u64> {
+        &self.durations
+    }
+
+    pub fn from_runs(runs: &[PipelineReport], mode: BaselineMode, threshold: f64) -> Option<Self> {
+        let runs = match mode {
+            BaselineMode::Previous => runs.get(..1)?,
+            BaselineMode::Best => runs,
+        };
+
+        if runs.is_empty() {
+            return None;
+        }
+
+        let mut durations: HashMap<String, u64> = HashMap::new();
+        let mut known = HashSet::new();
+
+        for step in runs
+            .iter()
+            .flat_map(|run| &run.stage_reports)
+            .flat_map(|stage| &stage.step_reports)
+        {
+            known.insert(step.name.clone());
+
+            if step.status == StepStatus::Success {
+                durations
+                    .entry(step.name.clone())
+                    .and_modify(|best| *best = (*best).min(step.elapsed))
+                    .or_insert(step.elapsed);
+            }
+        }
+
+        Some(Self {
+            durations,
+            known,
+            threshold,
+        })
+    }
+
+    pub fn compare(&self, step: &StepReport) -> StepDelta {
+        if !self.known.contains(&step.name) {
+            return StepDelta::New;
+        }
+
+        let Some(&previous) = self.durations.get(&step.name) else {
+            return StepDelta::Unavailable;
+        };
+
+        if step.status != StepStatus::Success {
+            return StepDelta::Unavailable;
+        }
+
+        let delta_ms = step.elapsed as i64 - previous as i64;
+        let percent = if previous == 0 {
+            0.0
+        } else {
+            delta_ms as f64 / previous as f64 * 100.0
+        };
+
+        let severity = if delta_ms < MIN_REGRESSION_DELTA_MS || percent <= self.threshold {
+            Severity::Normal
+        } else if percent <= self.threshold * 2.0 {
+            Severity::Regression
+        } else {
+            Severity::Severe
+        };
+
+        StepDelta::Changed {
+            delta_ms,
+            percent,
+            severity,
+        }
+    }
+}
+
+pub enum StepDelta {
+    /// The step has never been seen in the compared runs.
+    New,
+    /// Either side of the comparison did not succeed.
+    Unavailable,
+    Changed {
+        delta_ms: i64,
+        percent: f64,
+        severity: Severity,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Normal,
+    Regression,
+    Severe,
+}
+
+/// Retry statistics of a single step aggregated across recorded runs.
+pub struct FlakyStep {
+    pub name: String,
+    /// Empty for steps recorded before reports named their stage.
+    pub stage: String,
+    pub runs: usize,
+    pub first_attempt_failures: usize,
+    pub avg_retries: f64,
+    /// Extra wall time of retried runs compared to runs that passed first time.
+    pub added_ms: Option<u64>,
+}
+
+impl FlakyStep {
+    /// Returns steps whose first-attempt failure rate exceeds `threshold` percent, worst
+    /// offenders first, and apart from them the steps that failed in every run, which
+    /// are broken rather than flaky.
+    pub fn detect(runs: &[PipelineReport], threshold: f64) -> (Vec<Self>, Vec<Self>) {
+        // Steps of the same name in different stages are different steps.
+        let mut samples: HashMap<(&str, &str), Vec<&StepReport>> = HashMap::new();
+
+        for step in runs
+            .iter()
+            .flat_map(|run| &run.stage_reports)
+            .flat_map(|stage| &stage.step_reports)
+            .filter(|step| matches!(step.status, StepStatus::Success | StepStatus::Failed))
+        {
+            samples
+                .entry((&step.stage, &step.name))
+                .or_default()
+                .push(step);
+        }
+
+        let (failing, flaky): (Vec<_>, Vec<_>) = samples
+            .into_iter()
+            .partition(|(_, steps)| steps.iter().all(|step| step.status == StepStatus::Failed));
+        let aggregate = |((stage, name), steps): ((&str, &str), Vec<&StepReport>)| {
+            Self::aggregate(stage, name, &steps)
+        };
+
+        let mut flaky: Vec<Self> = flaky
+            .into_iter()
+            .map(aggregate)
+            .filter(|step| step.failure_rate() > threshold)
+            .collect();
+        let mut failing: Vec<Self> = failing.into_iter().map(aggregate).collect();
+
+        flaky.sort_by(|a, b| {
+            b.failure_rate()
+                .total_cmp(&a.failure_rate())
+                .then_with(|| a.name.cmp(&b.name))
+                .then_with(|| a.stage.cmp(&b.stage))
+        });
+        failing.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.stage.cmp(&b.stage)));
+
+        (flaky, failing)
+    }
+
+    pub fn failure_rate(&self) -> f64 {
+        if self.runs == 0 {
+            return 0.0;
+        }
+        self.first_attempt_failures as f64 / self.runs as f64 * 100.0
+    }
+
+    fn aggregate(stage: &str, name: &str, steps: &[&StepReport]) -> Self {
+        let first_attempt_failures = steps
+            .iter()
+            .filter(|step| step.retries > 0 || step.status == StepStatus::Failed)
+            .count();
+
+        let total_retries: u32 = steps.iter().map(|step| step.retries).sum();
+
+        let mean = |filter: fn(&StepReport) -> bool| {
+            let elapsed: Vec<u64> = steps
+                .iter()
+                .filter(|step| step.status == StepStatus::Success && filter(step))
+                .map(|step| step.elapsed)
+                .collect();
+            (!elapsed.is_empty()).then(|| elapsed.iter().sum::<u64>() / elapsed.len() as u64)
+        };
+
+        let added_ms = match (
+            mean(|step| step.retries > 0),
+            mean(|step| step.retries == 0),
+        ) {
+            (Some(retried), Some(clean)) => Some(retried.saturating_sub(clean)),
+            _ => None,
+        };
+
+        Self {
+            name: name.to_string(),
+            stage: stage.to_string(),
+            runs: steps.len(),
+            first_attempt_failures,
+            avg_retries: total_retries as f64 / steps.len() as f64,
+            added_ms,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A run of one stage, with `(step, status, retries, elapsed)` for each step.
+    fn run(steps: &[(&str, &str, u32, u64)]) -> PipelineReport {
+        let steps: Vec<_> = steps
+            .iter()
+            .map(|(name, status, retries, elapsed)| {
+                serde_json::json!({
+                    "name": name,
+                    "stage": "test",
+                    "status": status,
+                    "retries": retries,
+                    "elapsed": elapsed,
+                })
+            })
+            .collect();
+        serde_json::from_value(serde_json::json!({
+            "stage_reports": [{ "name": "test", "step_reports": steps }],
+        }))
+        .unwrap()
+    }
+
+    fn names(steps: &[FlakyStep]) -> Vec<&str> {
+        steps.iter().map(|step| step.name.as_str()).collect()
+    }
+
+    #[test]
+    fn a_step_that_passes_after_a_retry_is_flaky() {
+        let runs = [
+            run(&[("unit", "Success", 1, 3000)]),
+            run(&[("unit", "Success", 0, 1000)]),
+            run(&[("unit", "Success", 2, 5000)]),
+            run(&[("unit", "Success", 0, 1000)]),
+        ];
+        let (flaky, failing) = FlakyStep::detect(&runs, 10.0);
+
+        assert!(failing.is_empty());
+        let [unit] = flaky.as_slice() else {
+            panic!("expected one flaky step, got {:?}", names(&flaky));
+        };
+        assert_eq!((unit.runs, unit.first_attempt_failures), (4, 2));
+        assert_eq!(unit.failure_rate(), 50.0);
+        assert_eq!(unit.avg_retries, 0.75);
+        assert_eq!(unit.added_ms, Some(3000));
+    }
+
+    #[test]
+    fn a_step_that_fails_in_every_run_is_failing_not_flaky() {
+        let runs = [
+            run(&[("lint", "Failed", 0, 100), ("unit", "Success", 0, 1000)]),
+            run(&[("lint", "Failed", 2, 300), ("unit", "Success", 0, 1000)]),
+            run(&[("lint", "Failed", 0, 100), ("unit", "Success", 0, 1000)]),
+        ];
+        let (flaky, failing) = FlakyStep::detect(&runs, 10.0);
+
+        assert!(flaky.is_empty());
+        assert_eq!(names(&failing), ["lint"]);
+        assert_eq!(failing[0].runs, 3);
+    }
+
+    #[test]
+    fn a_step_that_only_sometimes_fails_is_flaky_without_retries() {
+        let runs = [
+            run(&[("e2e", "Failed", 0, 100)]),
+            run(&[("e2e", "Success", 0, 1000)]),
+            run(&[("e2e", "Success", 0, 1000)]),
+            run(&[("e2e", "Success", 0, 1000)]),
+        ];
+        let (flaky, failing) = FlakyStep::detect(&runs, 10.0);
+
+        assert!(failing.is_empty());
+        assert_eq!(names(&flaky), ["e2e"]);
+        assert_eq!(flaky[0].failure_rate(), 25.0);
+        assert_eq!(flaky[0].added_ms, None);
+    }
+
+    #[test]
+    fn steps_at_or_under_the_threshold_and_steps_that_did_not_run_are_left_out() {
+        let runs = [
+            run(&[("unit", "Success", 1, 2000), ("docs", "Skipped", 0, 0)]),
+            run(&[("unit", "Success", 0, 1000), ("docs", "Cancelled", 0, 0)]),
+        ];
+
+        let (flaky, failing) = FlakyStep::detect(&runs, 50.0);
+        assert!(flaky.is_empty());
+        assert!(failing.is_empty());
+
+        let (flaky, _) = FlakyStep::detect(&runs, 49.0);
+        assert_eq!(names(&flaky), ["unit"]);
+    }
+
+    #[test]
+    fn worst_offenders_come_first() {
+        let runs = [
+            run(&[("a", "Success", 1, 0), ("b", "Success", 1, 0)]),
+            run(&[("a", "Success", 0, 0), ("b", "Success", 1, 0)]),
+        ];
+        let (flaky, _) = FlakyStep::detect(&runs, 0.0);
+
+        assert_eq!(names(&flaky), ["b", "a"]);
+    }
+}
@@ -0,0 +1,233 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use tokio::fs::{read_to_string, remove_file, write};
+
+const BEGIN_MARKER: &str = "# >>> ciroach >>>";
+const END_MARKER: &str = "# <<< ciroach <<<";
+
+/// Git hook scripts of the current repository. ciroach only ever touches the lines
+/// between its own markers, so hooks written by people or other tools are preserved.
+pub struct GitHooks {
+    dir: PathBuf,
+}
+
+impl GitHooks {
+    /// Locates the hooks directory, honouring `core.hooksPath` and worktrees.
+    pub fn locate() -> anyhow::Result<Self> {
+        let dir = Self::git(&["rev-parse", "--git-path", "hooks"])?;
+        Ok(Self {
+            dir: PathBuf::from(dir),
+        })
+    }
+
+    /// Root of the working tree the hooks run in.
+    pub fn toplevel() -> anyhow::Result<PathBuf> {
+        Ok(PathBuf::from(Self::git(&["rev-parse", "--show-toplevel"])?))
+    }
+
+    /// Writes `command` into the marker block of hook `name`, replacing a previous block.
+    /// The block goes right after the shebang, so it runs before whatever the hook ends
+    /// with, e.g. `exit 0` or `exec`.
+    pub async fn install(&self, name: &str, command: &str) -> anyhow::Result<PathBuf> {
+        let path = self.dir.join(name);
+        let existing = read_to_string(&path).await.unwrap_or_default();
+
+        let script = Self::strip_block(&existing).map_err(|err| Self::unbalanced(&path, err))?;
+        let (shebang, rest) = match script.split_once('\n') {
+            Some((first, rest)) if first.starts_with("#!") => (first, rest),
+            _ if script.starts_with("#!") => (script.as_str(), ""),
+            _ => ("#!/bin/sh", script.as_str()),
+        };
+        let script =
+            format!("{shebang}\n{BEGIN_MARKER}\n{command} || exit $?\n{END_MARKER}\n{rest}");
+
+        tokio::fs::create_dir_all(&self.dir).await?;
+        write(&path, script).await?;
+        Self::make_executable(&path)?;
+
+        Ok(path)
+    }
+
+    /// Removes the marker block from hook `name`. Hooks that contained nothing else are
+    /// deleted. Returns whether a block was found.
+    pub async fn uninstall(&self, name: &str) -> anyhow::Result<bool> {
+        let path = self.dir.join(name);
+        let Ok(existing) = read_to_string(&path).await else {
+            return Ok(false);
+        };
+
+        if !existing.contains(BEGIN_MARKER) && !existing.contains(END_MARKER) {
+            return Ok(false);
+        }
+
+        let script = Self::strip_block(&existing).map_err(|err| Self::unbalanced(&path, err))?;
+        let leftover = script
+            .lines()
+            .filter(|line| !line.trim().is_empty() && !line.starts_with("#!"))
+            .count();
+
+        if leftover == 0 {
+            remove_file(&path).await?;
+        } else {
+            write(&path, script).await?;
+        }
+
+        Ok(true)
+    }
+
+    /// `script` without the marker block. Fails when the markers do not pair up, as
+    /// there is no telling then which lines are ciroach's.
+    fn strip_block(script: &str) -> Result<String, &'static str> {
+        let mut inside = false;
+        let mut kept = String::new();
+
+        for line in script.lines() {
+            match (line.trim(), inside) {
+                (BEGIN_MARKER, false) => inside = true,
+                (END_MARKER, true) => inside = false,
+                (BEGIN_MARKER, true) => return Err("a second begin marker inside the block"),
+                (END_MARKER, false) => return Err("an end marker without a begin marker"),
+                (_, false) => {
+                    kept.push_str(line);
+                    kept.push('\n');
+                }
+                (_, true) => {}
+            }
+        }
+
+        match inside {
+            true => Err("a begin marker without an end marker"),
+            false => Ok(kept),
+        }
+    }
+
+    fn unbalanced(path: &Path, problem: &str) -> anyhow::Error {
+        anyhow::anyhow!(
+            "{} has {problem}, so it was left untouched. Remove the ciroach lines from it by hand.",
+            path.display()
+        )
+    }
+
+    fn git(args: &[&str]) -> anyhow::Result<String> {
+        let output = Command::new("git").args(args).output()?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "Not inside a git repository: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    #[cfg(unix)]
+    fn make_executable(path: &Path) -> anyhow::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut permissions = std::fs::metadata(path)?.permissions();
+        permissions.set_mode(permissions.mode() | 0o755);
+        std::fs::set_permissions(path, permissions)?;
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn make_executable(_path: &Path) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK: &str = "# >>> ciroach >>>\nciroach run --quiet || exit $?\n# <<< ciroach <<<\n";
+
+    fn hooks(name: &str) -> GitHooks {
+        let dir = std::env::temp_dir().join(format!("ciroach-hooks-{}-{name}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        GitHooks { dir }
+    }
+
+    #[tokio::test]
+    async fn installing_twice_and_uninstalling_restores_an_existing_hook() {
+        let hooks = hooks("round-trip");
+        let path = hooks.dir.join("pre-push");
+        let original = "#!/bin/bash\nnpm run lint\nexit 0\n";
+        std::fs::write(&path, original).unwrap();
+
+        hooks.install("pre-push", "ciroach run").await.unwrap();
+        hooks
+            .install("pre-push", "ciroach run --quiet")
+            .await
+            .unwrap();
+        // Before the hook's own `exit 0`, so a failed run still blocks the push.
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("#!/bin/bash\n{BLOCK}npm run lint\nexit 0\n")
+        );
+
+        assert!(hooks.uninstall("pre-push").await.unwrap());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), original);
+        assert!(!hooks.uninstall("pre-push").await.unwrap());
+        std::fs::remove_dir_all(&hooks.dir).ok();
+    }
+
+    #[tokio::test]
+    async fn a_hook_of_ciroach_alone_is_created_and_removed() {
+        let hooks = hooks("own");
+        let path = hooks.dir.join("pre-push");
+
+        hooks
+            .install("pre-push", "ciroach run --quiet")
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("#!/bin/sh\n{BLOCK}")
+        );
+
+        assert!(hooks.uninstall("pre-push").await.unwrap());
+        assert!(!path.exists());
+        std::fs::remove_dir_all(&hooks.dir).ok();
+    }
+
+    #[tokio::test]
+    async fn a_hook_without_a_shebang_gets_the_block_first() {
+        let hooks = hooks("no-shebang");
+        let path = hooks.dir.join("pre-push");
+        std::fs::write(&path, "exec make check\n").unwrap();
+
+        hooks
+            .install("pre-push", "ciroach run --quiet")
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("#!/bin/sh\n{BLOCK}exec make check\n")
+        );
+        std::fs::remove_dir_all(&hooks.dir).ok();
+    }
+
+    #[tokio::test]
+    async fn unbalanced_markers_leave_the_hook_untouched() {
+        let hooks = hooks("unbalanced");
+        let path = hooks.dir.join("pre-push");
+        for script in [
+            "#!/bin/sh\n# >>> ciroach >>>\nciroach run\nnpm test\n",
+            "#!/bin/sh\nnpm test\n# <<< ciroach <<<\n",
+        ] {
+            std::fs::write(&path, script).unwrap();
+
+            let err = hooks.install("pre-push", "ciroach run").await.unwrap_err();
+            assert!(err.to_string().contains("left untouched"), "{err}");
+            let err = hooks.uninstall("pre-push").await.unwrap_err();
+            assert!(err.to_string().contains("left untouched"), "{err}");
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), script);
+        }
+        std::fs::remove_dir_all(&hooks.dir).ok();
+    }
+}
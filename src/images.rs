@@ -0,0 +1,186 @@
+use std::{collections::BTreeMap, path::PathBuf};
+
+use anyhow::Ok;
+use serde::{Deserialize, Serialize};
+use tokio::fs::{create_dir_all, read_to_string, write};
+
+use crate::models::{Pipeline, Runner, now_millis};
+
+pub const IMAGES_MANIFEST: &str = ".ciroach/images.json";
+/// Written by `ciroach run --lock-images`, meant to be committed.
+pub const IMAGE_LOCK: &str = "ciroach.lock";
+
+const IMAGE_LOCK_HEADER: &str = "# Written by `ciroach run --lock-images`. Commit it so every run pulls the\n# same images; run it again to move to newer ones.\n\n";
+
+/// Images pulled by ciroach and when each was last pulled. Docker does not label pulled
+/// images, so this is what tells them apart from the user's own.
+#[derive(Debug)]
+pub struct ImageManifest {
+    path: PathBuf,
+    /// By image reference as Docker lists it.
+    images: BTreeMap<String, PulledImage>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(from = "RawPulledImage")]
+pub struct PulledImage {
+    /// Unix timestamp in milliseconds.
+    pub pulled_at: u64,
+    /// Bytes on disk, as Docker reports them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+}
+
+/// Manifests written before sizes were recorded hold just the timestamp.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawPulledImage {
+    PulledAt(u64),
+    Full {
+        pulled_at: u64,
+        #[serde(default)]
+        size: Option<u64>,
+    },
+}
+
+impl From<RawPulledImage> for PulledImage {
+    fn from(raw: RawPulledImage) -> Self {
+        match raw {
+            RawPulledImage::PulledAt(pulled_at) => Self {
+                pulled_at,
+                size: None,
+            },
+            RawPulledImage::Full { pulled_at, size } => Self { pulled_at, size },
+        }
+    }
+}
+
+impl ImageManifest {
+    /// A missing manifest simply means nothing was pulled yet.
+    pub async fn load(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let path = path.into();
+        let images = match read_to_string(&path).await {
+            std::result::Result::Ok(raw) => serde_json::from_str(&raw)?,
+            std::result::Result::Err(_) => BTreeMap::new(),
+        };
+
+        Ok(Self { path, images })
+    }
+
+    pub async fn save(&self) -> anyhow::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            create_dir_all(parent).await?;
+        }
+        write(&self.path, serde_json::to_string_pretty(&self.images)?).await?;
+        Ok(())
+    }
+
+    /// Marks the images as pulled just now, with their size when known.
+    pub fn record<'a>(&mut self, images: impl IntoIterator<Item = &'a (String, Option<u64>)>) {
+        let pulled_at = now_millis();
+        for (image, size) in images {
+            let size = size.or_else(|| self.size(image));
+            self.images
+                .insert(Self::reference(image), PulledImage { pulled_at, size });
+        }
+    }
+
+    /// Size of `image` when it was last pulled.
+    pub fn size(&self, image: &str) -> Option<u64> {
+        self.images.get(&Self::reference(image))?.size
+    }
+
+    pub fn forget(&mut self, image: &str) {
+        self.images.remove(image);
+    }
+
+    /// Images last pulled before `cutoff` (Unix milliseconds).
+    pub fn pulled_before(&self, cutoff: u64) -> impl Iterator<Item = &str> {
+        self.images
+            .iter()
+            .filter(move |(_, pulled)| pulled.pulled_at < cutoff)
+            .map(|(image, _)| image.as_str())
+    }
+
+    /// The reference as Docker lists it: `alpine` is tagged `alpine:latest`.
+    pub fn reference(image: &str) -> String {
+        let name = image.rsplit('/').next().unwrap_or(image);
+        if name.contains(':') || name.contains('@') {
+            image.to_string()
+        } else {
+            format!("{image}:latest")
+        }
+    }
+}
+
+/// The digest each image tag resolved to when the lock was written. Runs pull the image
+/// by that digest instead of by its tag.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ImageLock {
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(default)]
+    images: BTreeMap<String, String>,
+}
+
+impl ImageLock {
+    /// `None` when there is no lock file.
+    pub async fn load(path: impl Into<PathBuf>) -> anyhow::Result<Option<Self>> {
+        let path = path.into();
+        let raw = match read_to_string(&path).await {
+            std::result::Result::Ok(raw) => raw,
+            std::result::Result::Err(_) => return Ok(None),
+        };
+        let lock: Self = toml::from_str(&raw)
+            .map_err(|err| anyhow::anyhow!("Invalid '{}': {}", path.display(), err))?;
+
+        Ok(Some(Self { path, ..lock }))
+    }
+
+    /// An empty lock, or the one already at `path`.
+    pub async fn load_or_default(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let path = path.into();
+        match Self::load(&path).await? {
+            Some(lock) => Ok(lock),
+            None => Ok(Self {
+                path,
+                images: BTreeMap::new(),
+            }),
+        }
+    }
+
+    pub async fn save(&self) -> anyhow::Result<()> {
+        let content = format!("{IMAGE_LOCK_HEADER}{}", toml::to_string(self)?);
+        write(&self.path, content).await?;
+        Ok(())
+    }
+
+    pub fn insert(&mut self, image: &str, digest: String) {
+        self.images.insert(image.to_string(), digest);
+    }
+
+    /// Points the steps' image tags at their locked digests.
+    pub fn apply(&self, pipeline: &mut Pipeline) {
+        let steps = pipeline
+            .stages
+            .iter_mut()
+            .flat_map(|stage| stage.steps.iter_mut())
+            .filter(|step| step.runner == Runner::Container && !step.local_image);
+
+        for step in steps {
+            if let Some(digest) = self.images.get(&step.image) {
+                step.image = format!("{}@{}", repository(&step.image), digest);
+            }
+        }
+    }
+}
+
+/// The image name without tag or digest: `ghcr.io/org/app:1.2` is `ghcr.io/org/app`.
+pub fn repository(image: &str) -> &str {
+    let image = image.split('@').next().unwrap_or(image);
+    let name_start = image.rfind('/').map_or(0, |slash| slash + 1);
+    match image[name_start..].find(':') {
+        Some(colon) => &image[..name_start + colon],
+        None => image,
+    }
+}
@@ -0,0 +1,377 @@
+use std::collections::BTreeMap;
+
+use regex::{Captures, Regex};
+use serde::Deserialize;
+use serde_yaml::Value;
+
+use crate::importer::{
+    Import, ImportOptions, ImportedMatrix, ImportedPipeline, ImportedStage, ImportedStep, env_list,
+    scalar, string_list,
+};
+
+#[derive(Deserialize)]
+struct Workflow {
+    name: Option<String>,
+    #[serde(default)]
+    env: BTreeMap<String, Value>,
+    #[serde(default)]
+    jobs: BTreeMap<String, Job>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Job {
+    runs_on: Option<Value>,
+    container: Option<Value>,
+    needs: Option<Value>,
+    strategy: Option<Strategy>,
+    #[serde(default)]
+    env: BTreeMap<String, Value>,
+    #[serde(default)]
+    steps: Vec<JobStep>,
+    timeout_minutes: Option<u64>,
+    #[serde(flatten)]
+    other: BTreeMap<String, Value>,
+}
+
+#[derive(Deserialize)]
+struct Strategy {
+    matrix: Option<BTreeMap<String, Value>>,
+    #[serde(flatten)]
+    other: BTreeMap<String, Value>,
+}
+
+#[derive(Deserialize)]
+struct JobStep {
+    name: Option<String>,
+    run: Option<String>,
+    uses: Option<String>,
+    #[serde(default)]
+    env: BTreeMap<String, Value>,
+    #[serde(flatten)]
+    other: BTreeMap<String, Value>,
+}
+
+pub struct GithubImporter {
+    options: ImportOptions,
+    matrix_expr: Regex,
+    any_expr: Regex,
+    warnings: Vec<String>,
+}
+
+impl GithubImporter {
+    /// Converts a workflow file into a single-stage pipeline where every job becomes a step.
+    pub fn convert(source: &str, options: ImportOptions) -> anyhow::Result<Import> {
+        let workflow: Workflow = serde_yaml::from_str(source)?;
+
+        let mut importer = Self {
+            options,
+            matrix_expr: Regex::new(r"\$\{\{\s*matrix\.([A-Za-z0-9_-]+)\s*\}\}")?,
+            any_expr: Regex::new(r"\$\{\{\s*(.*?)\s*\}\}")?,
+            warnings: Vec::new(),
+        };
+
+        if workflow.jobs.is_empty() {
+            anyhow::bail!("Workflow does not define any jobs.");
+        }
+
+        let stage_name = workflow.name.unwrap_or_else(|| "workflow".to_string());
+        let mut stage = ImportedStage::default();
+
+        for (job_id, job) in workflow.jobs.iter() {
+            let step = importer.convert_job(job_id, job, &workflow.env);
+            stage.steps.insert(job_id.clone(), step);
+        }
+
+        let mut pipeline = ImportedPipeline::default();
+        pipeline.stages_order.push(stage_name.clone());
+        pipeline.stages.insert(stage_name, stage);
+
+        Ok(Import {
+            pipeline,
+            warnings: importer.warnings,
+        })
+    }
+
+    fn convert_job(
+        &mut self,
+        job_id: &str,
+        job: &Job,
+        workflow_env: &BTreeMap<String, Value>,
+    ) -> ImportedStep {
+        for key in job.other.keys().filter(|key| *key != "name") {
+            self.warn(format!("job '{job_id}': ignoring unsupported key '{key}'"));
+        }
+
+        let mut env = workflow_env.clone();
+        env.extend(job.env.clone());
+
+        let mut script = vec!["set -e".to_string()];
+
+        for (index, step) in job.steps.iter().enumerate() {
+            let label = step
+                .name
+                .clone()
+                .unwrap_or_else(|| format!("step {}", index + 1));
+
+            if let Some(uses) = &step.uses {
+                self.warn(format!(
+                    "job '{job_id}', {label}: action '{uses}' cannot be converted"
+                ));
+            }
+
+            for key in step
+                .other
+                .keys()
+                .filter(|key| !matches!(key.as_str(), "id" | "with"))
+            {
+                self.warn(format!(
+                    "job '{job_id}', {label}: ignoring unsupported key '{key}'"
+                ));
+            }
+
+            if !step.env.is_empty() {
+                env.extend(step.env.clone());
+            }
+
+            if let Some(run) = &step.run {
+                script.push(format!("# {label}"));
+                script.push(self.rewrite(job_id, run.trim_end()));
+            }
+        }
+
+        if script.len() == 1 {
+            self.warn(format!("job '{job_id}': no 'run' steps, generated a no-op"));
+            script.push("true".to_string());
+        }
+
+        ImportedStep {
+            image: self.image(job_id, job),
+            command: script.join("\n"),
+            needs: job.needs.as_ref().map(string_list),
+            env: env_list(&env).map(|env| {
+                env.into_iter()
+                    .map(|entry| self.rewrite(job_id, &entry))
+                    .collect()
+            }),
+            max_retries: None,
+            timeout: job.timeout_minutes.map(|minutes| format!("{minutes}m")),
+            matrix: self.matrix(job_id, job),
+        }
+    }
+
+    fn image(&mut self, job_id: &str, job: &Job) -> String {
+        match &job.container {
+            Some(Value::String(image)) => return self.rewrite(job_id, image),
+            Some(Value::Mapping(container)) => {
+                for key in container.keys().filter_map(Value::as_str) {
+                    if key != "image" {
+                        self.warn(format!("job '{job_id}': ignoring container option '{key}'"));
+                    }
+                }
+
+                if let Some(image) = container.get("image").and_then(Value::as_str) {
+                    return self.rewrite(job_id, image);
+                }
+            }
+            _ => {}
+        }
+
+        let runs_on = job.runs_on.as_ref().map(string_list).unwrap_or_default();
+        if !runs_on.iter().any(|label| label.starts_with("ubuntu")) {
+            self.warn(format!(
+                "job '{job_id}': runs-on {:?} has no container equivalent, using '{}'",
+                runs_on, self.options.default_image
+            ));
+        }
+
+        self.options.default_image.clone()
+    }
+
+    fn matrix(&mut self, job_id: &str, job: &Job) -> Option<ImportedMatrix> {
+        let strategy = job.strategy.as_ref()?;
+
+        for key in strategy.other.keys() {
+            self.warn(format!("job '{job_id}': ignoring strategy option '{key}'"));
+        }
+
+        let mut variables: Vec<(String, Vec<String>)> = Vec::new();
+
+        for (key, values) in strategy.matrix.as_ref()?.iter() {
+            match values {
+                _ if matches!(key.as_str(), "include" | "exclude") => {
+                    self.warn(format!(
+                        "job '{job_id}': matrix '{key}' entries are not supported"
+                    ));
+                }
+                Value::Sequence(items) => {
+                    variables.push((key.clone(), items.iter().filter_map(scalar).collect()));
+                }
+                _ => {}
+            }
+        }
+
+        if variables.is_empty() {
+            return None;
+        }
+
+        // Prefer the variable the job actually interpolates.
+        let chosen = variables
+            .iter()
+            .position(|(key, _)| self.references(job, key))
+            .unwrap_or(0);
+        let (variable, values) = variables.remove(chosen);
+
+        let ignored: Vec<String> = variables.into_iter().map(|(key, _)| key).collect();
+        if !ignored.is_empty() {
+            self.warn(format!(
+                "job '{job_id}': only one matrix variable is supported, ignoring {:?}",
+                ignored
+            ));
+        }
+
+        Some(ImportedMatrix { variable, values })
+    }
+
+    fn references(&self, job: &Job, variable: &str) -> bool {
+        let container = match &job.container {
+            Some(Value::String(image)) => Some(image.as_str()),
+            Some(Value::Mapping(container)) => container.get("image").and_then(Value::as_str),
+            _ => None,
+        };
+
+        container
+            .into_iter()
+            .chain(job.steps.iter().filter_map(|step| step.run.as_deref()))
+            .flat_map(|text| self.matrix_expr.captures_iter(text))
+            .any(|cap| &cap[1] == variable)
+    }
+
+    /// Rewrites `${{ matrix.x }}` to ciroach's `${{ x }}`, and any other expression to a
+    /// shell variable standing in for it, or to nothing when there is none, as ciroach
+    /// refuses an unknown `${{ ... }}`.
+    fn rewrite(&mut self, job_id: &str, text: &str) -> String {
+        let rewritten = self.matrix_expr.replace_all(text, "$${{ $1 }}").to_string();
+
+        let mut replaced = Vec::new();
+        let rewritten = self
+            .any_expr
+            .replace_all(&rewritten, |cap: &Captures| {
+                let expr = &cap[1];
+                if !expr.contains('.') && !expr.contains('(') {
+                    return cap[0].to_string();
+                }
+                let variable = shell_variable(expr);
+                replaced.push((expr.to_string(), variable.clone()));
+                variable.unwrap_or_default()
+            })
+            .to_string();
+
+        for (expr, variable) in replaced {
+            match variable {
+                Some(variable) => self.warn(format!(
+                    "job '{job_id}': expression '${{{{ {expr} }}}}' became '{variable}', \
+                     which has to be set by hand"
+                )),
+                None => self.warn(format!(
+                    "job '{job_id}': expression '${{{{ {expr} }}}}' cannot be converted and was removed"
+                )),
+            }
+        }
+
+        rewritten
+    }
+
+    fn warn(&mut self, message: String) {
+        if !self.warnings.contains(&message) {
+            self.warnings.push(message);
+        }
+    }
+}
+
+/// The variable a runner sets for a context lookup like `github.ref_name`, or the
+/// variable named after a secret, configuration variable or environment variable.
+fn shell_variable(expr: &str) -> Option<String> {
+    let (context, name) = expr.split_once('.')?;
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return None;
+    }
+
+    match context {
+        "github" | "runner" => Some(format!(
+            "${{{}_{}}}",
+            context.to_uppercase(),
+            name.to_uppercase()
+        )),
+        "env" | "secrets" | "vars" => Some(format!("${{{name}}}")),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::RawPipeline;
+
+    /// Workflow files under `fixtures/github/`, each next to the TOML it converts to.
+    const FIXTURES: &[(&str, &str, &str)] = &[
+        (
+            "rust",
+            include_str!("fixtures/github/rust.yml"),
+            include_str!("fixtures/github/rust.toml"),
+        ),
+        (
+            "node-matrix",
+            include_str!("fixtures/github/node-matrix.yml"),
+            include_str!("fixtures/github/node-matrix.toml"),
+        ),
+        (
+            "deploy",
+            include_str!("fixtures/github/deploy.yml"),
+            include_str!("fixtures/github/deploy.toml"),
+        ),
+        (
+            "minimal",
+            include_str!("fixtures/github/minimal.yml"),
+            include_str!("fixtures/github/minimal.toml"),
+        ),
+    ];
+
+    fn convert(source: &str) -> String {
+        let options = ImportOptions {
+            default_image: "ubuntu:latest".to_string(),
+        };
+        GithubImporter::convert(source, options)
+            .and_then(|import| import.to_toml())
+            .unwrap()
+    }
+
+    #[test]
+    fn fixtures_convert_to_their_golden_toml() {
+        for (name, source, expected) in FIXTURES {
+            assert_eq!(convert(source), *expected, "fixtures/github/{name}.yml");
+        }
+    }
+
+    #[test]
+    fn golden_toml_is_a_valid_pipeline() {
+        for (name, _, expected) in FIXTURES {
+            toml::from_str::<RawPipeline>(expected)
+                .map_err(anyhow::Error::from)
+                .and_then(|raw| raw.select(None))
+                .and_then(|raw| raw.compile(&[]))
+                .unwrap_or_else(|err| panic!("fixtures/github/{name}.toml: {err:#}"));
+        }
+    }
+
+    #[test]
+    fn a_workflow_without_jobs_is_rejected() {
+        let options = ImportOptions {
+            default_image: "ubuntu:latest".to_string(),
+        };
+        let err = GithubImporter::convert("name: empty\non: push\n", options)
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "Workflow does not define any jobs.");
+    }
+}
@@ -0,0 +1,499 @@
+use std::collections::{BTreeMap, HashMap};
+
+use regex::{Captures, Regex};
+use serde::Deserialize;
+use serde_yaml::Value;
+
+use crate::importer::{
+    Import, ImportOptions, ImportedMatrix, ImportedPipeline, ImportedStage, ImportedStep, scalar,
+    string_list,
+};
+
+/// Top-level keys that configure the pipeline rather than define a job.
+const RESERVED_KEYS: &[&str] = &[
+    "stages",
+    "variables",
+    "image",
+    "default",
+    "include",
+    "workflow",
+    "before_script",
+    "after_script",
+    "services",
+    "cache",
+];
+
+const DEFAULT_STAGES: &[&str] = &["build", "test", "deploy"];
+
+#[derive(Default, Deserialize)]
+struct Job {
+    stage: Option<String>,
+    image: Option<Value>,
+    #[serde(default)]
+    variables: BTreeMap<String, Value>,
+    needs: Option<Vec<Value>>,
+    retry: Option<Value>,
+    timeout: Option<String>,
+    parallel: Option<Value>,
+    before_script: Option<Value>,
+    script: Option<Value>,
+    after_script: Option<Value>,
+    #[serde(flatten)]
+    other: BTreeMap<String, Value>,
+}
+
+struct ParsedJob {
+    id: String,
+    stage: String,
+    job: Job,
+}
+
+pub struct GitlabImporter {
+    options: ImportOptions,
+    warnings: Vec<String>,
+}
+
+impl GitlabImporter {
+    /// Converts a `.gitlab-ci.yml` document. `stages` becomes `stages_order` and every job
+    /// becomes a step of its stage.
+    pub fn convert(source: &str, options: ImportOptions) -> anyhow::Result<Import> {
+        let mut document: BTreeMap<String, Value> = serde_yaml::from_str(source)?;
+        let mut importer = Self {
+            options,
+            warnings: Vec::new(),
+        };
+
+        let stages: Vec<String> = match document.remove("stages") {
+            Some(stages) => string_list(&stages),
+            None => DEFAULT_STAGES.iter().map(|s| s.to_string()).collect(),
+        };
+
+        let defaults: Job = match document.remove("default") {
+            Some(value) => serde_yaml::from_value(value)?,
+            None => Job::default(),
+        };
+        for key in defaults.other.keys() {
+            importer.warn(format!("default: ignoring unsupported key '{key}'"));
+        }
+
+        let global_image = document.remove("image").or(defaults.image.clone());
+        let global_variables: BTreeMap<String, Value> = match document.remove("variables") {
+            Some(value) => serde_yaml::from_value(value)?,
+            None => BTreeMap::new(),
+        };
+        let global_before = document
+            .remove("before_script")
+            .or(defaults.before_script.clone());
+        let global_after = document
+            .remove("after_script")
+            .or(defaults.after_script.clone());
+
+        for key in RESERVED_KEYS
+            .iter()
+            .filter(|key| document.contains_key(**key))
+        {
+            importer.warn(format!("ignoring unsupported top-level key '{key}'"));
+        }
+
+        let mut jobs = Vec::new();
+        let mut renamed: HashMap<String, String> = HashMap::new();
+
+        for (name, value) in document {
+            if RESERVED_KEYS.contains(&name.as_str()) {
+                continue;
+            }
+
+            if name.starts_with('.') {
+                importer.warn(format!(
+                    "hidden job '{name}' ignored (templates are not supported)"
+                ));
+                continue;
+            }
+
+            let job: Job = serde_yaml::from_value(value)?;
+            let id = Self::step_id(&name);
+            if id != name {
+                importer.warn(format!("job '{name}' renamed to '{id}'"));
+            }
+            renamed.insert(name, id.clone());
+
+            let stage = job.stage.clone().unwrap_or_else(|| "test".to_string());
+            jobs.push(ParsedJob { id, stage, job });
+        }
+
+        if jobs.is_empty() {
+            anyhow::bail!("No jobs found in GitLab CI configuration.");
+        }
+
+        let ordered_stages: Vec<String> = [".pre".to_string()]
+            .into_iter()
+            .chain(stages)
+            .chain([".post".to_string()])
+            .collect();
+
+        let stage_of: HashMap<&str, &str> = jobs
+            .iter()
+            .map(|parsed| (parsed.id.as_str(), parsed.stage.as_str()))
+            .collect();
+
+        let mut pipeline = ImportedPipeline::default();
+
+        for parsed in jobs.iter() {
+            if !ordered_stages.contains(&parsed.stage) {
+                importer.warn(format!(
+                    "job '{}': stage '{}' is not listed in 'stages'",
+                    parsed.id, parsed.stage
+                ));
+                continue;
+            }
+
+            let context = JobContext {
+                global_image: global_image.as_ref(),
+                global_variables: &global_variables,
+                global_before: global_before.as_ref(),
+                global_after: global_after.as_ref(),
+                defaults: &defaults,
+                ordered_stages: &ordered_stages,
+                stage_of: &stage_of,
+                renamed: &renamed,
+            };
+
+            let step = importer.convert_job(parsed, &context);
+            pipeline
+                .stages
+                .entry(parsed.stage.clone())
+                .or_insert_with(ImportedStage::default)
+                .steps
+                .insert(parsed.id.clone(), step);
+        }
+
+        pipeline.stages_order = ordered_stages
+            .into_iter()
+            .filter(|stage| pipeline.stages.contains_key(stage))
+            .collect();
+
+        Ok(Import {
+            pipeline,
+            warnings: importer.warnings,
+        })
+    }
+
+    fn convert_job(&mut self, parsed: &ParsedJob, context: &JobContext) -> ImportedStep {
+        let ParsedJob { id, stage, job } = parsed;
+
+        if !job.other.is_empty() {
+            let keys: Vec<&str> = job.other.keys().map(String::as_str).collect();
+            self.warn(format!("job '{id}': ignoring {}", keys.join(", ")));
+        }
+
+        let mut variables = context.global_variables.clone();
+        variables.extend(job.variables.clone());
+        let variables: BTreeMap<String, String> = variables
+            .iter()
+            .filter_map(|(key, value)| Some((key.clone(), Self::variable_value(value)?)))
+            .collect();
+
+        let matrix = self.matrix(id, job.parallel.as_ref());
+
+        let mut script = vec!["set -e".to_string()];
+        if let Some(matrix) = &matrix {
+            script.push(format!("export {0}=\"${{{{ {0} }}}}\"", matrix.variable));
+        }
+
+        let before = job.before_script.as_ref().or(context.global_before);
+        let after = job.after_script.as_ref().or(context.global_after);
+
+        script.extend(before.map(Self::script_lines).unwrap_or_default());
+        script.extend(
+            job.script
+                .as_ref()
+                .map(Self::script_lines)
+                .unwrap_or_default(),
+        );
+        if let Some(after) = after {
+            self.warn(format!(
+                "job '{id}': after_script is appended and only runs when the script succeeds"
+            ));
+            script.extend(Self::script_lines(after));
+        }
+
+        let image = job
+            .image
+            .as_ref()
+            .or(context.global_image)
+            .and_then(Self::image_name)
+            .map(|image| self.expand_image(id, &image, &variables, matrix.as_ref()))
+            .unwrap_or_else(|| self.options.default_image.clone());
+
+        let env: Vec<String> = variables
+            .iter()
+            .map(|(key, value)| format!("{key}={value}"))
+            .collect();
+
+        let retry = job.retry.as_ref().or(context.defaults.retry.as_ref());
+        let timeout = job.timeout.as_ref().or(context.defaults.timeout.as_ref());
+
+        ImportedStep {
+            image,
+            command: script.join("\n"),
+            needs: self.needs(id, stage, job.needs.as_ref(), context),
+            env: (!env.is_empty()).then_some(env),
+            max_retries: retry.and_then(|retry| self.retries(id, retry)),
+            timeout: timeout.and_then(|timeout| self.timeout(id, timeout)),
+            matrix,
+        }
+    }
+
+    /// Keeps needs within the same stage; earlier stages are already guaranteed by the
+    /// stage barrier.
+    fn needs(
+        &mut self,
+        id: &str,
+        stage: &str,
+        needs: Option<&Vec<Value>>,
+        context: &JobContext,
+    ) -> Option<Vec<String>> {
+        let position = |stage: &str| context.ordered_stages.iter().position(|s| s == stage);
+        let mut resolved = Vec::new();
+
+        for need in needs?.iter() {
+            let name = match need {
+                Value::Mapping(map) => map.get("job").and_then(scalar),
+                other => scalar(other),
+            };
+            let Some(name) = name else { continue };
+            let target = context.renamed.get(&name).cloned().unwrap_or(name.clone());
+
+            match context.stage_of.get(target.as_str()) {
+                Some(target_stage) if *target_stage == stage => resolved.push(target),
+                Some(target_stage) if position(target_stage) < position(stage) => {}
+                Some(_) => self.warn(format!(
+                    "job '{id}': need '{name}' is in a later stage and was dropped"
+                )),
+                None => self.warn(format!("job '{id}': need '{name}' does not exist")),
+            }
+        }
+
+        (!resolved.is_empty()).then_some(resolved)
+    }
+
+    fn matrix(&mut self, id: &str, parallel: Option<&Value>) -> Option<ImportedMatrix> {
+        let entries = match parallel? {
+            Value::Mapping(map) => map.get("matrix")?.as_sequence()?,
+            _ => {
+                self.warn(format!("job '{id}': numeric 'parallel' is not supported"));
+                return None;
+            }
+        };
+
+        let mut variables: Vec<(String, Vec<String>)> = entries
+            .iter()
+            .filter_map(Value::as_mapping)
+            .flat_map(|entry| entry.iter())
+            .filter_map(|(key, values)| Some((scalar(key)?, string_list(values))))
+            .collect();
+
+        if variables.is_empty() {
+            return None;
+        }
+
+        let (variable, values) = variables.remove(0);
+        if !variables.is_empty() {
+            let ignored: Vec<String> = variables.into_iter().map(|(key, _)| key).collect();
+            self.warn(format!(
+                "job '{id}': only one matrix variable is supported, ignoring {:?}",
+                ignored
+            ));
+        }
+
+        Some(ImportedMatrix { variable, values })
+    }
+
+    /// Resolves `$VAR` references in the image from known variables; the matrix variable is
+    /// rewritten to ciroach's placeholder syntax.
+    fn expand_image(
+        &mut self,
+        id: &str,
+        image: &str,
+        variables: &BTreeMap<String, String>,
+        matrix: Option<&ImportedMatrix>,
+    ) -> String {
+        let pattern = Regex::new(r"\$\{?([A-Za-z_][A-Za-z0-9_]*)\}?").expect("valid regex");
+        let mut unresolved = Vec::new();
+
+        let expanded = pattern
+            .replace_all(image, |cap: &Captures| {
+                let name = &cap[1];
+                if matrix.is_some_and(|m| m.variable == name) {
+                    format!("${{{{ {name} }}}}")
+                } else if let Some(value) = variables.get(name) {
+                    value.clone()
+                } else {
+                    unresolved.push(name.to_string());
+                    cap[0].to_string()
+                }
+            })
+            .to_string();
+
+        for name in unresolved {
+            self.warn(format!(
+                "job '{id}': image variable '${name}' is left unresolved"
+            ));
+        }
+
+        expanded
+    }
+
+    fn retries(&mut self, id: &str, retry: &Value) -> Option<u32> {
+        match retry {
+            Value::Number(n) => n.as_u64().map(|n| n as u32),
+            Value::Mapping(map) => {
+                if map.contains_key("when") || map.contains_key("exit_codes") {
+                    self.warn(format!("job '{id}': retry conditions are ignored"));
+                }
+                map.get("max")?.as_u64().map(|n| n as u32)
+            }
+            _ => None,
+        }
+    }
+
+    /// Converts GitLab's human readable durations (`1h 30m`, `3 hours`) into a single unit.
+    fn timeout(&mut self, id: &str, timeout: &str) -> Option<String> {
+        let pattern =
+            Regex::new(r"(?i)(\d+)\s*(h|hrs?|hours?|m|mins?|minutes?|s|secs?|seconds?)\b")
+                .expect("valid regex");
+
+        let seconds: u64 = pattern
+            .captures_iter(timeout)
+            .map(|cap| {
+                let value: u64 = cap[1].parse().unwrap_or(0);
+                match cap[2].to_lowercase().chars().next() {
+                    Some('h') => value * 3600,
+                    Some('m') => value * 60,
+                    _ => value,
+                }
+            })
+            .sum();
+
+        if seconds == 0 {
+            self.warn(format!("job '{id}': could not parse timeout '{timeout}'"));
+            return None;
+        }
+
+        Some(if seconds.is_multiple_of(3600) {
+            format!("{}h", seconds / 3600)
+        } else if seconds.is_multiple_of(60) {
+            format!("{}m", seconds / 60)
+        } else {
+            format!("{seconds}s")
+        })
+    }
+
+    fn script_lines(value: &Value) -> Vec<String> {
+        match value {
+            Value::Sequence(items) => items.iter().flat_map(Self::script_lines).collect(),
+            other => scalar(other).into_iter().collect(),
+        }
+    }
+
+    fn image_name(value: &Value) -> Option<String> {
+        match value {
+            Value::Mapping(map) => map.get("name").and_then(scalar),
+            other => scalar(other),
+        }
+    }
+
+    fn variable_value(value: &Value) -> Option<String> {
+        match value {
+            Value::Mapping(map) => map.get("value").and_then(scalar),
+            other => scalar(other),
+        }
+    }
+
+    /// Job names may contain spaces or colons, which are not valid in container names.
+    fn step_id(name: &str) -> String {
+        name.chars()
+            .map(|ch| {
+                if ch.is_ascii_alphanumeric() || matches!(ch, '_' | '-' | '.') {
+                    ch
+                } else {
+                    '-'
+                }
+            })
+            .collect()
+    }
+
+    fn warn(&mut self, message: String) {
+        if !self.warnings.contains(&message) {
+            self.warnings.push(message);
+        }
+    }
+}
+
+struct JobContext<'a> {
+    global_image: Option<&'a Value>,
+    global_variables: &'a BTreeMap<String, Value>,
+    global_before: Option<&'a Value>,
+    global_after: Option<&'a Value>,
+    defaults: &'a Job,
+    ordered_stages: &'a [String],
+    stage_of: &'a HashMap<&'a str, &'a str>,
+    renamed: &'a HashMap<String, String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::RawPipeline;
+
+    /// `.gitlab-ci.yml` files under `fixtures/gitlab/`, each next to the TOML it converts to.
+    const FIXTURES: &[(&str, &str, &str)] = &[
+        (
+            "rust",
+            include_str!("fixtures/gitlab/rust.yml"),
+            include_str!("fixtures/gitlab/rust.toml"),
+        ),
+        (
+            "node-matrix",
+            include_str!("fixtures/gitlab/node-matrix.yml"),
+            include_str!("fixtures/gitlab/node-matrix.toml"),
+        ),
+        (
+            "deploy",
+            include_str!("fixtures/gitlab/deploy.yml"),
+            include_str!("fixtures/gitlab/deploy.toml"),
+        ),
+        (
+            "minimal",
+            include_str!("fixtures/gitlab/minimal.yml"),
+            include_str!("fixtures/gitlab/minimal.toml"),
+        ),
+    ];
+
+    fn convert(source: &str) -> String {
+        let options = ImportOptions {
+            default_image: "ubuntu:latest".to_string(),
+        };
+        GitlabImporter::convert(source, options)
+            .and_then(|import| import.to_toml())
+            .unwrap()
+    }
+
+    #[test]
+    fn fixtures_convert_to_their_golden_toml() {
+        for (name, source, expected) in FIXTURES {
+            assert_eq!(convert(source), *expected, "fixtures/gitlab/{name}.yml");
+        }
+    }
+
+    #[test]
+    fn golden_toml_is_a_valid_pipeline() {
+        for (name, _, expected) in FIXTURES {
+            toml::from_str::<RawPipeline>(expected)
+                .map_err(anyhow::Error::from)
+                .and_then(|raw| raw.select(None))
+                .and_then(|raw| raw.compile(&[]))
+                .unwrap_or_else(|err| panic!("fixtures/gitlab/{name}.toml: {err:#}"));
+        }
+    }
+}
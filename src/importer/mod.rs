@@ -0,0 +1,103 @@
+mod github;
+mod gitlab;
+
+pub use github::*;
+pub use gitlab::*;
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use serde_yaml::Value;
+
+pub struct ImportOptions {
+    /// Image used for jobs that do not name a container image.
+    pub default_image: String,
+}
+
+/// Result of converting a foreign CI definition into a ciroach pipeline.
+pub struct Import {
+    pub pipeline: ImportedPipeline,
+    pub warnings: Vec<String>,
+}
+
+impl Import {
+    /// Renders the pipeline as TOML, listing every warning as a comment on top so dropped
+    /// constructs stay visible in the generated file.
+    pub fn to_toml(&self) -> anyhow::Result<String> {
+        let mut buffer = String::new();
+
+        if !self.warnings.is_empty() {
+            buffer.push_str("# Converted by `ciroach import` with the following warnings:\n");
+            for warning in self.warnings.iter() {
+                buffer.push_str(&format!("#   - {}\n", warning));
+            }
+            buffer.push('\n');
+        }
+
+        buffer.push_str(&toml::to_string_pretty(&self.pipeline)?);
+        Ok(buffer)
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ImportedPipeline {
+    pub stages_order: Vec<String>,
+    pub stages: BTreeMap<String, ImportedStage>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ImportedStage {
+    pub steps: BTreeMap<String, ImportedStep>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ImportedStep {
+    pub image: String,
+    pub command: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub needs: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub env: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_retries: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub matrix: Option<ImportedMatrix>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImportedMatrix {
+    pub variable: String,
+    pub values: Vec<String>,
+}
+
+/// Stringifies a scalar YAML value the way a shell would see it.
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+/// Accepts either a single string or a list of strings.
+fn string_list(value: &Value) -> Vec<String> {
+    match value {
+        Value::Sequence(items) => items.iter().filter_map(scalar).collect(),
+        other => scalar(other).into_iter().collect(),
+    }
+}
+
+fn env_list(env: &BTreeMap<String, Value>) -> Option<Vec<String>> {
+    if env.is_empty() {
+        return None;
+    }
+
+    Some(
+        env.iter()
+            .map(|(key, value)| format!("{}={}", key, scalar(value).unwrap_or_default()))
+            .collect(),
+    )
+}
@@ -0,0 +1,427 @@
+use std::{collections::BTreeMap, process::Command, time::Duration};
+
+use anyhow::Ok;
+use futures_util::{AsyncBufReadExt, TryStreamExt};
+use k8s_openapi::{
+    api::{
+        batch::v1::{Job, JobSpec},
+        core::v1::{
+            Container, ContainerState, EmptyDirVolumeSource, EnvVar,
+            PersistentVolumeClaimVolumeSource, Pod, PodSpec, PodTemplateSpec, ResourceRequirements,
+            Volume, VolumeMount,
+        },
+    },
+    apimachinery::pkg::{api::resource::Quantity, apis::meta::v1::ObjectMeta},
+};
+use kube::{
+    Api, Client,
+    api::{DeleteParams, ListParams, LogParams, PostParams},
+};
+use tokio::{sync::mpsc, time::sleep};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    engine::{RUN_LABEL, STEP_LABEL},
+    github::GithubNotifier,
+    logger::LogMessage,
+    models::{KubernetesConfig, OutputStats, Step},
+    output::Icon,
+};
+
+/// Name of the container running the step's command in each pod.
+const STEP_CONTAINER: &str = "step";
+const CLONE_CONTAINER: &str = "clone";
+const WORKSPACE_VOLUME: &str = "workspace";
+/// Label tying a pod to the Job of one attempt.
+const JOB_LABEL: &str = "ciroach.job";
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Kubernetes object names are DNS labels.
+const MAX_NAME: usize = 63;
+/// Waiting reasons a pod does not recover from by itself.
+const STUCK_REASONS: [&str; 5] = [
+    "ErrImagePull",
+    "ImagePullBackOff",
+    "InvalidImageName",
+    "CreateContainerConfigError",
+    "CreateContainerError",
+];
+
+/// How the step's container ended.
+pub struct PodExit {
+    pub exit_code: i64,
+    pub oom_killed: bool,
+}
+
+/// What the init container of each pod clones into its workspace.
+struct Source {
+    repository: String,
+    revision: Option<String>,
+}
+
+/// Runs steps with `runner = "kubernetes"` as Jobs of one pod each, in the namespace of
+/// `[engine.kubernetes]`. A Job is never retried by the cluster; ciroach makes a new one
+/// for every attempt.
+pub struct KubeEngine {
+    jobs: Api<Job>,
+    pods: Api<Pod>,
+    config: KubernetesConfig,
+    run_id: String,
+    /// `None` when the workspace is a claim every pod mounts.
+    source: Option<Source>,
+}
+
+impl KubeEngine {
+    /// Connects like `kubectl` does: with the in-cluster service account, or the current
+    /// context of the kubeconfig.
+    pub async fn new(config: &KubernetesConfig, run_id: &str, cwd: &str) -> anyhow::Result<Self> {
+        let client = Client::try_default()
+            .await
+            .map_err(|err| anyhow::anyhow!("Cannot reach the Kubernetes cluster: {err}"))?;
+
+        let source = match &config.workspace_claim {
+            Some(_) => None,
+            None => {
+                let repository = config.repository.clone().or_else(|| Self::origin(cwd));
+                let Some(repository) = repository else {
+                    anyhow::bail!(
+                        "Kubernetes steps need 'engine.kubernetes.repository' or 'workspace_claim': the workspace has no 'origin' remote to clone."
+                    );
+                };
+                let revision = config.revision.clone().or_else(GithubNotifier::detect_sha);
+                println!(
+                    "{} Kubernetes steps clone {} at {}; uncommitted changes are not part of it.",
+                    Icon::Info,
+                    repository,
+                    revision.as_deref().unwrap_or("its default branch")
+                );
+                Some(Source {
+                    repository,
+                    revision,
+                })
+            }
+        };
+
+        Ok(Self {
+            jobs: Api::namespaced(client.clone(), &config.namespace),
+            pods: Api::namespaced(client, &config.namespace),
+            config: config.clone(),
+            run_id: run_id.to_string(),
+            source,
+        })
+    }
+
+    /// Creates the Job of one attempt of the step and returns its name.
+    pub async fn run_job(&self, step: &Step, attempt: u32) -> anyhow::Result<String> {
+        let name = self.job_name(step, attempt);
+        let job = self.job(&name, step);
+        self.jobs
+            .create(&PostParams::default(), &job)
+            .await
+            .map_err(|err| anyhow::anyhow!("Cannot create Job '{name}': {err}"))?;
+        Ok(name)
+    }
+
+    /// Waits until the step's container of the Job's pod has started, and returns the
+    /// pod's name. Fails when the pod cannot get there, e.g. as its image does not exist
+    /// or cloning the workspace failed.
+    pub async fn started_pod(&self, job: &str) -> anyhow::Result<String> {
+        let params = ListParams::default().labels(&format!("{JOB_LABEL}={job}"));
+        loop {
+            let pods = self.pods.list(&params).await?;
+            if let Some(pod) = pods.items.into_iter().next() {
+                let name = pod.metadata.name.clone().unwrap_or_default();
+                if let Some(state) = Self::state(&pod, CLONE_CONTAINER, true)
+                    && let Some(terminated) = &state.terminated
+                    && terminated.exit_code != 0
+                {
+                    anyhow::bail!(
+                        "Cloning the workspace in pod '{name}' failed with exit code {}",
+                        terminated.exit_code
+                    );
+                }
+
+                if let Some(state) = Self::state(&pod, STEP_CONTAINER, false) {
+                    if state.running.is_some() || state.terminated.is_some() {
+                        return Ok(name);
+                    }
+                    if let Some(waiting) = &state.waiting
+                        && let Some(reason) = waiting.reason.as_deref()
+                        && STUCK_REASONS.contains(&reason)
+                    {
+                        anyhow::bail!(
+                            "Pod '{name}' cannot start: {reason} {}",
+                            waiting.message.as_deref().unwrap_or_default()
+                        );
+                    }
+                }
+            }
+            sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Forwards the output of the pod's step container line by line until it exits. The
+    /// cluster merges standard output and error, so no line counts as an error.
+    pub async fn stream_logs(
+        &self,
+        pod: &str,
+        stage: &str,
+        step_name: &str,
+        attempt: u32,
+        log_tx: &mpsc::Sender<LogMessage>,
+        token: &CancellationToken,
+    ) -> anyhow::Result<OutputStats> {
+        let params = LogParams {
+            container: Some(STEP_CONTAINER.to_string()),
+            follow: true,
+            ..Default::default()
+        };
+        let mut lines = self.pods.log_stream(pod, &params).await?.lines();
+        let mut stats = OutputStats::default();
+
+        loop {
+            tokio::select! {
+                _ = token.cancelled() => {
+                    return Ok(stats);
+                }
+
+                line = lines.try_next() => {
+                    let Some(line) = line? else {
+                        break;
+                    };
+                    stats.stdout_bytes += line.len() as u64 + 1;
+                    stats.lines += 1;
+                    log_tx
+                        .send(LogMessage {
+                            stage: stage.to_string(),
+                            step_name: step_name.to_string(),
+                            line: line.trim_end_matches('\r').to_string(),
+                            is_error: false,
+                            attempt,
+                            stage_attempt: None,
+                            ended: None,
+                        })
+                        .await
+                        .ok();
+                }
+            }
+        }
+
+        Ok(stats)
+    }
+
+    /// How the pod's step container ended. Its log can end before the pod's status says
+    /// so, so this waits for the status.
+    pub async fn exit_state(&self, pod: &str) -> anyhow::Result<PodExit> {
+        loop {
+            let current = self.pods.get(pod).await?;
+            if let Some(terminated) = Self::state(&current, STEP_CONTAINER, false)
+                .and_then(|state| state.terminated.as_ref())
+            {
+                return Ok(PodExit {
+                    exit_code: terminated.exit_code as i64,
+                    oom_killed: terminated.reason.as_deref() == Some("OOMKilled"),
+                });
+            }
+            sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Deletes the Job along with its pod.
+    pub async fn delete_job(&self, name: &str) -> anyhow::Result<()> {
+        self.jobs.delete(name, &DeleteParams::background()).await?;
+        Ok(())
+    }
+
+    /// Deletes every Job of this run that is still there, running or not. Returns how
+    /// many were deleted.
+    pub async fn delete_run_jobs(&self) -> anyhow::Result<usize> {
+        let params = ListParams::default().labels(&format!("{RUN_LABEL}={}", self.run_id));
+        let jobs = self.jobs.list(&params).await?;
+
+        let mut deleted = 0;
+        for job in jobs.items {
+            if let Some(name) = job.metadata.name
+                && self.delete_job(&name).await.is_ok()
+            {
+                deleted += 1;
+            }
+        }
+        Ok(deleted)
+    }
+
+    fn job(&self, name: &str, step: &Step) -> Job {
+        let labels = BTreeMap::from([
+            (RUN_LABEL.to_string(), self.run_id.clone()),
+            (JOB_LABEL.to_string(), name.to_string()),
+        ]);
+        // Step names may hold characters label values cannot.
+        let annotations = BTreeMap::from([(STEP_LABEL.to_string(), step.exploded_name.clone())]);
+        let metadata = ObjectMeta {
+            name: Some(name.to_string()),
+            labels: Some(labels.clone()),
+            annotations: Some(annotations.clone()),
+            ..Default::default()
+        };
+
+        let workspace_mount = VolumeMount {
+            name: WORKSPACE_VOLUME.to_string(),
+            mount_path: "/workspace".to_string(),
+            ..Default::default()
+        };
+        let workspace = match &self.config.workspace_claim {
+            Some(claim) => Volume {
+                name: WORKSPACE_VOLUME.to_string(),
+                persistent_volume_claim: Some(PersistentVolumeClaimVolumeSource {
+                    claim_name: claim.clone(),
+                    read_only: None,
+                }),
+                ..Default::default()
+            },
+            None => Volume {
+                name: WORKSPACE_VOLUME.to_string(),
+                empty_dir: Some(EmptyDirVolumeSource::default()),
+                ..Default::default()
+            },
+        };
+
+        let mut limits = BTreeMap::new();
+        if let Some(memory) = step.memory {
+            limits.insert("memory".to_string(), Quantity(memory.to_string()));
+        }
+        if let Some(storage) = step.storage_limit {
+            limits.insert(
+                "ephemeral-storage".to_string(),
+                Quantity(storage.to_string()),
+            );
+        }
+
+        let container = Container {
+            name: STEP_CONTAINER.to_string(),
+            image: Some(step.image.clone()),
+            command: Some(vec![
+                "sh".to_string(),
+                "-c".to_string(),
+                step.command.clone(),
+            ]),
+            working_dir: Some("/workspace".to_string()),
+            env: Some(
+                step.env
+                    .iter()
+                    .flatten()
+                    .filter_map(|var| var.split_once('='))
+                    .map(|(name, value)| EnvVar {
+                        name: name.to_string(),
+                        value: Some(value.to_string()),
+                        ..Default::default()
+                    })
+                    .collect(),
+            ),
+            resources: (!limits.is_empty()).then(|| ResourceRequirements {
+                limits: Some(limits),
+                ..Default::default()
+            }),
+            volume_mounts: Some(vec![workspace_mount.clone()]),
+            ..Default::default()
+        };
+
+        Job {
+            metadata: metadata.clone(),
+            spec: Some(JobSpec {
+                backoff_limit: Some(0),
+                template: PodTemplateSpec {
+                    metadata: Some(ObjectMeta {
+                        labels: Some(labels),
+                        annotations: Some(annotations),
+                        ..Default::default()
+                    }),
+                    spec: Some(PodSpec {
+                        restart_policy: Some("Never".to_string()),
+                        service_account_name: self.config.service_account.clone(),
+                        init_containers: self
+                            .source
+                            .as_ref()
+                            .map(|source| vec![self.clone_container(source, workspace_mount)]),
+                        containers: vec![container],
+                        volumes: Some(vec![workspace]),
+                        ..Default::default()
+                    }),
+                },
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// Clones the repository into the pod's empty workspace. The source goes in through
+    /// the environment, so it is never read as shell syntax.
+    fn clone_container(&self, source: &Source, workspace: VolumeMount) -> Container {
+        let mut script = "git clone --quiet -- \"$CIROACH_REPOSITORY\" .".to_string();
+        let mut env = vec![EnvVar {
+            name: "CIROACH_REPOSITORY".to_string(),
+            value: Some(source.repository.clone()),
+            ..Default::default()
+        }];
+        if let Some(revision) = &source.revision {
+            script.push_str(" && git checkout --quiet --detach \"$CIROACH_REVISION\"");
+            env.push(EnvVar {
+                name: "CIROACH_REVISION".to_string(),
+                value: Some(revision.clone()),
+                ..Default::default()
+            });
+        }
+
+        Container {
+            name: CLONE_CONTAINER.to_string(),
+            image: Some(self.config.clone_image.clone()),
+            command: Some(vec!["sh".to_string(), "-c".to_string(), script]),
+            working_dir: Some("/workspace".to_string()),
+            env: Some(env),
+            volume_mounts: Some(vec![workspace]),
+            ..Default::default()
+        }
+    }
+
+    /// `ciroach-<run>-<step>-<attempt>`, cut down to a valid DNS label.
+    fn job_name(&self, step: &Step, attempt: u32) -> String {
+        let prefix = format!("ciroach-{}-", self.run_id.to_lowercase());
+        let suffix = format!("-{attempt}");
+        let sanitized: String = step
+            .exploded_name
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | '0'..='9' => c,
+                'A'..='Z' => c.to_ascii_lowercase(),
+                _ => '-',
+            })
+            .collect();
+        let room = MAX_NAME.saturating_sub(prefix.len() + suffix.len());
+        let step_part: String = sanitized.chars().take(room).collect();
+        format!("{prefix}{}{suffix}", step_part.trim_matches('-'))
+    }
+
+    fn state<'p>(pod: &'p Pod, container: &str, init: bool) -> Option<&'p ContainerState> {
+        let status = pod.status.as_ref()?;
+        let statuses = match init {
+            true => status.init_container_statuses.as_ref()?,
+            false => status.container_statuses.as_ref()?,
+        };
+        statuses
+            .iter()
+            .find(|status| status.name == container)?
+            .state
+            .as_ref()
+    }
+
+    /// URL of the workspace's `origin` remote.
+    fn origin(cwd: &str) -> Option<String> {
+        let output = Command::new("git")
+            .args(["-C", cwd, "remote", "get-url", "origin"])
+            .output()
+            .ok()?;
+
+        output
+            .status
+            .success()
+            .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+}
@@ -0,0 +1,261 @@
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::Ok;
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+
+use crate::{models::now_millis, output::Icon};
+
+pub const LOCK_FILE: &str = ".ciroach/lock";
+
+/// How often a waiting run checks whether the lock was released.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What a run does when another one holds the lock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LockPolicy {
+    /// Fail right away instead of waiting.
+    pub no_wait: bool,
+    /// Take over a lock left behind by a run that is no longer running.
+    pub force: bool,
+}
+
+/// Who holds the lock, as written to the lock file.
+#[derive(Debug, Serialize, Deserialize)]
+struct Holder {
+    pid: u32,
+    run_id: String,
+    /// Unix timestamp in milliseconds.
+    started_at: u64,
+}
+
+impl Holder {
+    fn age(&self) -> String {
+        let secs = now_millis().saturating_sub(self.started_at) / 1000;
+        match secs {
+            0..60 => format!("{secs}s"),
+            60..3600 => format!("{}m", secs / 60),
+            _ => format!("{}h", secs / 3600),
+        }
+    }
+
+    /// The holder written to `file`, unless it is empty or being written.
+    fn read(mut file: &File) -> Option<Self> {
+        let mut raw = String::new();
+        file.seek(SeekFrom::Start(0)).ok()?;
+        file.read_to_string(&mut raw).ok()?;
+        serde_json::from_str(&raw).ok()
+    }
+}
+
+/// Keeps two runs from sharing a workspace: they would trample each other's files and
+/// run history. The file is locked by the OS for as long as this lives, so the lock goes
+/// with the process however it ends. The holder is cleared from the file when this is
+/// dropped; one still written there after a crash marks the lock as left behind.
+#[derive(Debug)]
+pub struct WorkspaceLock {
+    file: File,
+}
+
+impl WorkspaceLock {
+    pub async fn acquire(
+        cwd: &Path,
+        run_id: &str,
+        policy: LockPolicy,
+        token: &CancellationToken,
+    ) -> anyhow::Result<Self> {
+        let path = cwd.join(LOCK_FILE);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        let mut announced = false;
+
+        loop {
+            match file.try_lock() {
+                std::result::Result::Ok(()) => {
+                    if let Some(holder) = Holder::read(&file) {
+                        if !policy.force {
+                            anyhow::bail!(
+                                "Run '{}' (pid {}) left its lock behind in '{}' but is no longer running. Rerun with --force to take it over.",
+                                holder.run_id,
+                                holder.pid,
+                                path.display()
+                            );
+                        }
+                        eprintln!(
+                            "{} Taking over the lock of run '{}' (pid {}), which is no longer running.",
+                            Icon::Warning,
+                            holder.run_id,
+                            holder.pid
+                        );
+                    }
+                    return Self::hold(file, run_id);
+                }
+                Err(TryLockError::WouldBlock) => {}
+                Err(TryLockError::Error(err)) => return Err(err.into()),
+            }
+
+            // Windows does not let the file be read while it is locked.
+            let message = match Holder::read(&file) {
+                Some(holder) => format!(
+                    "Another run is in progress (pid {}, started {} ago).",
+                    holder.pid,
+                    holder.age()
+                ),
+                None => "Another run is in progress.".to_string(),
+            };
+            if policy.no_wait {
+                anyhow::bail!("{message}");
+            }
+            if !announced {
+                println!("{} {message} Waiting for it to finish...", Icon::Waiting);
+                announced = true;
+            }
+
+            tokio::select! {
+                _ = token.cancelled() => anyhow::bail!("Interrupted while waiting for another run to finish."),
+                _ = sleep(POLL_INTERVAL) => {}
+            }
+        }
+    }
+
+    /// Writes this run as the holder of the locked `file`.
+    fn hold(mut file: File, run_id: &str) -> anyhow::Result<Self> {
+        let holder = Holder {
+            pid: std::process::id(),
+            run_id: run_id.to_string(),
+            started_at: now_millis(),
+        };
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(serde_json::to_string(&holder)?.as_bytes())?;
+        file.flush()?;
+
+        Ok(Self { file })
+    }
+}
+
+impl Drop for WorkspaceLock {
+    fn drop(&mut self) {
+        // The OS releases the lock once the file is closed.
+        self.file.set_len(0).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::Instant};
+
+    use super::*;
+
+    fn workspace(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ciroach-lock-{}-{name}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        dir
+    }
+
+    async fn acquire(
+        cwd: &Path,
+        run_id: &str,
+        policy: LockPolicy,
+    ) -> anyhow::Result<WorkspaceLock> {
+        WorkspaceLock::acquire(cwd, run_id, policy, &CancellationToken::new()).await
+    }
+
+    #[tokio::test]
+    async fn a_second_run_fails_right_away_with_no_wait() {
+        let cwd = workspace("no-wait");
+        let _first = acquire(&cwd, "first", LockPolicy::default()).await.unwrap();
+
+        let policy = LockPolicy {
+            no_wait: true,
+            ..Default::default()
+        };
+        let err = acquire(&cwd, "second", policy).await.unwrap_err();
+        let pid = std::process::id();
+        assert!(
+            err.to_string()
+                .starts_with(&format!("Another run is in progress (pid {pid}, started")),
+            "{err}"
+        );
+        std::fs::remove_dir_all(&cwd).ok();
+    }
+
+    #[tokio::test]
+    async fn a_second_run_waits_for_the_first_to_finish() {
+        let cwd = workspace("wait");
+        let first = acquire(&cwd, "first", LockPolicy::default()).await.unwrap();
+
+        let timer = Instant::now();
+        let second = tokio::spawn({
+            let cwd = cwd.clone();
+            async move { acquire(&cwd, "second", LockPolicy::default()).await }
+        });
+        sleep(Duration::from_millis(300)).await;
+        assert!(!second.is_finished());
+        drop(first);
+
+        let second = second.await.unwrap().unwrap();
+        assert!(timer.elapsed() < Duration::from_secs(3));
+        assert_eq!(Holder::read(&second.file).unwrap().run_id, "second");
+        drop(second);
+        std::fs::remove_dir_all(&cwd).ok();
+    }
+
+    #[tokio::test]
+    async fn waiting_stops_when_the_run_is_cancelled() {
+        let cwd = workspace("cancel");
+        let _first = acquire(&cwd, "first", LockPolicy::default()).await.unwrap();
+
+        let token = CancellationToken::new();
+        token.cancel();
+        let err = WorkspaceLock::acquire(&cwd, "second", LockPolicy::default(), &token)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().starts_with("Interrupted"), "{err}");
+        std::fs::remove_dir_all(&cwd).ok();
+    }
+
+    #[tokio::test]
+    async fn a_lock_left_by_a_killed_run_is_taken_over_with_force() {
+        let cwd = workspace("stale");
+        std::fs::create_dir_all(cwd.join(".ciroach")).unwrap();
+        // What a killed run leaves: its holder, without the OS lock.
+        let stale = Holder {
+            pid: 999_999,
+            run_id: "killed".into(),
+            started_at: now_millis(),
+        };
+        std::fs::write(cwd.join(LOCK_FILE), serde_json::to_string(&stale).unwrap()).unwrap();
+
+        let err = acquire(&cwd, "next", LockPolicy::default())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Rerun with --force"), "{err}");
+
+        let policy = LockPolicy {
+            force: true,
+            ..Default::default()
+        };
+        let lock = acquire(&cwd, "next", policy).await.unwrap();
+        assert_eq!(Holder::read(&lock.file).unwrap().run_id, "next");
+        drop(lock);
+
+        // Released cleanly, so the next run needs no --force.
+        let lock = acquire(&cwd, "after", LockPolicy::default()).await.unwrap();
+        drop(lock);
+        std::fs::remove_dir_all(&cwd).ok();
+    }
+}
@@ -1,33 +1,158 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, VecDeque},
+    path::PathBuf,
+};
 
-use colored::Colorize;
-use tokio::{sync::mpsc, task::JoinHandle};
+use colored::{Color, Colorize};
+use tokio::{
+    fs::{File, create_dir_all},
+    io::{AsyncWriteExt, BufWriter},
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    events::{EventBus, PipelineEvent},
+    models::{Annotation, Matcher, Stage, log_key},
+    output::{Icon, Verbosity},
+    reporter::{FileReporter, RunDirReporter},
+    ui,
+};
+
+/// Longer lines are not matched, so pathological output can't stall the log task.
+const MAX_MATCHED_LINE: usize = 4096;
+/// Problems kept per step; a broken build can report thousands.
+const MAX_ANNOTATIONS: usize = 200;
 
 pub struct Logger {
     tx: mpsc::Sender<LogMessage>,
-    handle: JoinHandle<HashMap<String, Vec<String>>>,
+    handle: JoinHandle<CollectedLogs>,
+    /// Stops the log task taking lines, for steps that will never hang up.
+    close: CancellationToken,
+}
+
+/// Everything the steps logged, keyed by [`log_key`].
+#[derive(Default)]
+pub struct CollectedLogs {
+    pub lines: HashMap<String, Vec<String>>,
+    pub annotations: HashMap<String, Vec<Annotation>>,
 }
 
 impl Logger {
-    pub fn new(buffer: usize) -> Self {
+    /// From [`Verbosity::Verbose`] on, lines are also printed as they arrive. Each step's
+    /// lines are written in full to its file in the directory of `run_id`, but only kept
+    /// up to its `log_limit`, and scanned by its `matchers`.
+    pub fn new(
+        buffer: usize,
+        events: EventBus,
+        verbosity: Verbosity,
+        stages: &[Stage],
+        run_id: &str,
+    ) -> Self {
+        let stream = verbosity >= Verbosity::Verbose;
+        // Live lines of parallel steps line up behind prefixes of the same width.
+        let prefix_width = stages
+            .iter()
+            .flat_map(|stage| &stage.steps)
+            .map(|step| step.exploded_name.chars().count() + 2)
+            .max()
+            .unwrap_or(0);
+        let mut steps: HashMap<String, StepLog> = stages
+            .iter()
+            .flat_map(|stage| &stage.steps)
+            .map(|step| {
+                let key = log_key(&step.stage, &step.exploded_name);
+                let file = StepFile::new(run_id, &step.stage, &step.exploded_name);
+                (
+                    key,
+                    StepLog::new(step.log_limit, step.matchers.clone(), file),
+                )
+            })
+            .collect();
+        let run_id = run_id.to_string();
+
         let (tx, mut rx) = mpsc::channel::<LogMessage>(buffer);
+        let close = CancellationToken::new();
+        let closed = close.clone();
         let handle = tokio::spawn(async move {
-            let mut store: HashMap<String, Vec<String>> = HashMap::new();
-            while let Some(log) = rx.recv().await {
-                let line = log.terminal_format();
-                store.entry(log.step_name).or_default().push(line);
+            loop {
+                let log = tokio::select! {
+                    log = rx.recv() => log,
+                    // Lines already sent are still taken; later ones are refused.
+                    _ = closed.cancelled(), if !rx.is_closed() => {
+                        rx.close();
+                        continue;
+                    }
+                };
+                let Some(mut log) = log else {
+                    break;
+                };
+                if let Some(ended) = log.ended.take() {
+                    ended.send(()).ok();
+                    continue;
+                }
+                let line = log.terminal_format(0);
+                if stream {
+                    let padded = log.terminal_format(prefix_width);
+                    ui::suspend(|| println!("{padded}"));
+                }
+                events.emit(PipelineEvent::StepLog {
+                    stage: log.stage.clone(),
+                    step: log.step_name.clone(),
+                    line: log.line.trim_end().to_string(),
+                    is_error: log.is_error,
+                });
+
+                let step = steps
+                    .entry(log_key(&log.stage, &log.step_name))
+                    .or_insert_with(|| {
+                        let file = StepFile::new(&run_id, &log.stage, &log.step_name);
+                        StepLog::new(0, Vec::new(), file)
+                    });
+                if let Some(stage_attempt) = log.stage_attempt {
+                    step.attempt = 1;
+                    let separator = format!("── Stage attempt {stage_attempt} ──");
+                    step.push(separator.dimmed().to_string()).await;
+                } else if log.attempt > step.attempt {
+                    step.attempt = log.attempt;
+                    let separator = format!("── Attempt {} ──", log.attempt);
+                    step.push(separator.dimmed().to_string()).await;
+                }
+                step.problems.scan(&step.matchers, log.line.trim_end());
+                step.push(line).await;
             }
-            store
+
+            let mut collected = CollectedLogs::default();
+            for (key, mut step) in steps {
+                step.file.close().await;
+                if !step.problems.found.is_empty() {
+                    collected
+                        .annotations
+                        .insert(key.clone(), step.problems.found);
+                }
+                if !step.lines.is_empty() {
+                    collected.lines.insert(key, step.lines.into_lines());
+                }
+            }
+            collected
         });
 
-        Self { tx, handle }
+        Self { tx, handle, close }
     }
 
     pub fn tx(&self) -> mpsc::Sender<LogMessage> {
         self.tx.clone()
     }
 
-    pub async fn finish(self) -> anyhow::Result<HashMap<String, Vec<String>>> {
+    /// Like [`Self::finish`], but does not wait for steps still holding a sender, e.g.
+    /// those left running when a run gave up waiting for them to stop.
+    pub async fn finish_now(self) -> anyhow::Result<CollectedLogs> {
+        self.close.cancel();
+        self.finish().await
+    }
+
+    pub async fn finish(self) -> anyhow::Result<CollectedLogs> {
         drop(self.tx); // Dropping the last TX allows RX to close
         self.handle
             .await
@@ -36,14 +161,50 @@ impl Logger {
 }
 
 pub struct LogMessage {
+    pub stage: String,
     pub step_name: String,
     pub line: String,
     pub is_error: bool,
+    /// Number of the step's attempt that logged the line, starting at 1.
+    pub attempt: u32,
+    /// Set on the line marking that the stage runs again, to this run of it. The lines
+    /// after it start over at the step's first attempt.
+    pub stage_attempt: Option<u32>,
+    /// Set on the marker a step sends once it is done logging, see [`Self::end_of_step`].
+    pub ended: Option<oneshot::Sender<()>>,
 }
 
 impl LogMessage {
-    pub fn terminal_format(&self) -> String {
-        let name = format!("[{}]", self.step_name).bold().cyan();
+    /// A marker without a line. The log task answers it on the receiver once it has
+    /// taken every line the step sent before, so the step can report only then.
+    pub fn end_of_step(
+        stage: impl Into<String>,
+        step_name: impl Into<String>,
+    ) -> (Self, oneshot::Receiver<()>) {
+        let (ended, taken) = oneshot::channel();
+        let marker = Self {
+            stage: stage.into(),
+            step_name: step_name.into(),
+            line: String::new(),
+            is_error: false,
+            attempt: 1,
+            stage_attempt: None,
+            ended: Some(ended),
+        };
+        (marker, taken)
+    }
+
+    /// `[step] line`, or `[step#2] line` from the second attempt on, with the prefix
+    /// padded to `width` and in the step's own color.
+    pub fn terminal_format(&self, width: usize) -> String {
+        let name = if self.attempt > 1 {
+            format!("[{}#{}]", self.step_name, self.attempt)
+        } else {
+            format!("[{}]", self.step_name)
+        };
+        let name = format!("{name:<width$}")
+            .bold()
+            .color(Self::prefix_color(&self.step_name));
         let body = if self.is_error {
             self.line.trim_end().red()
         } else {
@@ -51,4 +212,290 @@ impl LogMessage {
         };
         format!("{name} {body}")
     }
+
+    /// Picked by a hash of the name, so a step keeps its color from run to run. Red and
+    /// yellow are left out, as they mean errors and warnings.
+    fn prefix_color(step_name: &str) -> Color {
+        const PALETTE: [Color; 8] = [
+            Color::Cyan,
+            Color::Green,
+            Color::Blue,
+            Color::Magenta,
+            Color::BrightCyan,
+            Color::BrightGreen,
+            Color::BrightBlue,
+            Color::BrightMagenta,
+        ];
+
+        // FNV-1a, whose result does not change between Rust releases.
+        let hash = step_name
+            .bytes()
+            .fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+                (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+            });
+        PALETTE[(hash % PALETTE.len() as u64) as usize]
+    }
+}
+
+/// A step's lines, cut down to the first and the last ones once there are more than
+/// `limit`, with a marker counting the lines left out in between.
+#[derive(Debug)]
+pub struct LogBuffer {
+    limit: usize,
+    head: Vec<String>,
+    tail: VecDeque<String>,
+    omitted: u64,
+}
+
+impl LogBuffer {
+    /// A `limit` of 0 keeps every line.
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            head: Vec::new(),
+            tail: VecDeque::new(),
+            omitted: 0,
+        }
+    }
+
+    pub fn push(&mut self, line: String) {
+        let head_size = self.limit.div_ceil(2);
+        if self.limit == 0 || self.head.len() < head_size {
+            self.head.push(line);
+            return;
+        }
+
+        self.tail.push_back(line);
+        if self.tail.len() > self.limit - head_size {
+            self.tail.pop_front();
+            self.omitted += 1;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.is_empty()
+    }
+
+    /// The kept lines, with the marker where lines were left out.
+    #[cfg(feature = "dashboard")]
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = self.head.clone();
+        lines.extend(self.marker());
+        lines.extend(self.tail.iter().cloned());
+        lines
+    }
+
+    pub fn into_lines(self) -> Vec<String> {
+        let marker = self.marker();
+        let mut lines = self.head;
+        lines.extend(marker);
+        lines.extend(self.tail);
+        lines
+    }
+
+    /// `… 2,289,400 lines omitted …`
+    fn marker(&self) -> Option<String> {
+        if self.omitted == 0 {
+            return None;
+        }
+
+        let digits = self.omitted.to_string();
+        let mut grouped = String::new();
+        for (index, digit) in digits.chars().enumerate() {
+            if index > 0 && (digits.len() - index).is_multiple_of(3) {
+                grouped.push(',');
+            }
+            grouped.push(digit);
+        }
+        let plural = if self.omitted == 1 { "" } else { "s" };
+        Some(format!("… {grouped} line{plural} omitted …"))
+    }
+}
+
+/// What the log task keeps of one step.
+struct StepLog {
+    lines: LogBuffer,
+    /// Every line, including those `lines` leaves out.
+    file: StepFile,
+    /// The attempt the last line came from.
+    attempt: u32,
+    matchers: Vec<Matcher>,
+    problems: ProblemScan,
+}
+
+impl StepLog {
+    fn new(limit: usize, matchers: Vec<Matcher>, file: StepFile) -> Self {
+        Self {
+            lines: LogBuffer::new(limit),
+            file,
+            attempt: 1,
+            matchers,
+            problems: ProblemScan::default(),
+        }
+    }
+
+    async fn push(&mut self, line: String) {
+        self.file.write(&line).await;
+        self.lines.push(line);
+    }
+}
+
+/// A step's log file in the run directory, created with the first line written to it.
+struct StepFile {
+    path: PathBuf,
+    writer: Option<BufWriter<File>>,
+    /// Set once the file could not be written, so the error is reported only once.
+    failed: bool,
+}
+
+impl StepFile {
+    fn new(run_id: &str, stage: &str, step_name: &str) -> Self {
+        Self {
+            path: RunDirReporter::step_log(run_id, stage, step_name),
+            writer: None,
+            failed: false,
+        }
+    }
+
+    async fn write(&mut self, line: &str) {
+        if self.failed {
+            return;
+        }
+        let mut text = FileReporter::plain_line(line);
+        text.push('\n');
+        if let Err(err) = self.append(&text).await {
+            self.failed = true;
+            eprintln!(
+                "{} Failed to write {}: {}",
+                Icon::Warning,
+                self.path.display(),
+                err
+            );
+        }
+    }
+
+    async fn append(&mut self, text: &str) -> std::io::Result<()> {
+        let writer = match &mut self.writer {
+            Some(writer) => writer,
+            None => {
+                if let Some(dir) = self.path.parent() {
+                    create_dir_all(dir).await?;
+                }
+                self.writer
+                    .insert(BufWriter::new(File::create(&self.path).await?))
+            }
+        };
+        writer.write_all(text.as_bytes()).await
+    }
+
+    async fn close(&mut self) {
+        if let Some(writer) = &mut self.writer {
+            writer.flush().await.ok();
+        }
+    }
+}
+
+/// The problems found in one step's lines so far.
+#[derive(Default)]
+struct ProblemScan {
+    found: Vec<Annotation>,
+    /// The matcher whose last match waits for its location on the next line.
+    awaiting: Option<usize>,
+}
+
+impl ProblemScan {
+    fn scan(&mut self, matchers: &[Matcher], line: &str) {
+        let awaiting = self.awaiting.take();
+        if matchers.is_empty() || line.len() > MAX_MATCHED_LINE {
+            return;
+        }
+
+        if let Some(index) = awaiting
+            && let Some(last) = self.found.last_mut()
+            && matchers[index].locate(last, line)
+        {
+            return;
+        }
+
+        if self.found.len() >= MAX_ANNOTATIONS {
+            return;
+        }
+        for (index, matcher) in matchers.iter().enumerate() {
+            if let Some(annotation) = matcher.annotate(line) {
+                if annotation.file.is_none() && matcher.location.is_some() {
+                    self.awaiting = Some(index);
+                }
+                self.found.push(annotation);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(limit: usize, count: usize) -> Vec<String> {
+        let mut buffer = LogBuffer::new(limit);
+        for line in 1..=count {
+            buffer.push(line.to_string());
+        }
+        buffer.into_lines()
+    }
+
+    #[test]
+    fn keeps_the_first_and_the_last_lines() {
+        assert_eq!(buffer(4, 10), ["1", "2", "… 6 lines omitted …", "9", "10"]);
+    }
+
+    #[test]
+    fn keeps_everything_within_the_limit() {
+        assert_eq!(buffer(4, 4), ["1", "2", "3", "4"]);
+        assert_eq!(buffer(4, 3), ["1", "2", "3"]);
+        assert!(buffer(4, 0).is_empty());
+    }
+
+    #[test]
+    fn a_limit_of_zero_keeps_every_line() {
+        assert_eq!(buffer(0, 5), ["1", "2", "3", "4", "5"]);
+    }
+
+    #[test]
+    fn a_limit_of_one_keeps_only_the_first_line() {
+        assert_eq!(buffer(1, 1), ["1"]);
+        assert_eq!(buffer(1, 2), ["1", "… 1 line omitted …"]);
+        assert_eq!(buffer(1, 5), ["1", "… 4 lines omitted …"]);
+    }
+
+    #[test]
+    fn an_odd_limit_keeps_the_extra_line_at_the_head() {
+        assert_eq!(buffer(3, 3), ["1", "2", "3"]);
+        assert_eq!(buffer(3, 6), ["1", "2", "… 3 lines omitted …", "6"]);
+        assert_eq!(
+            buffer(5, 9),
+            ["1", "2", "3", "… 4 lines omitted …", "8", "9"]
+        );
+    }
+
+    #[test]
+    fn the_marker_groups_the_omitted_count_by_thousands() {
+        let marker = |omitted| {
+            let mut buffer = LogBuffer::new(2);
+            buffer.omitted = omitted;
+            buffer.marker()
+        };
+        assert_eq!(marker(0), None);
+        assert_eq!(marker(999).as_deref(), Some("… 999 lines omitted …"));
+        assert_eq!(marker(1_000).as_deref(), Some("… 1,000 lines omitted …"));
+        assert_eq!(
+            marker(123_456).as_deref(),
+            Some("… 123,456 lines omitted …")
+        );
+        assert_eq!(
+            marker(2_289_400).as_deref(),
+            Some("… 2,289,400 lines omitted …")
+        );
+        assert_eq!(buffer(2, 1_002)[1], "… 1,000 lines omitted …");
+    }
 }
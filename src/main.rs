@@ -1,69 +1,85 @@
-use std::env;
-#[cfg(unix)]
-use std::os::unix::fs::MetadataExt;
+use std::process::ExitCode;
 
-use anyhow::Ok;
-use chrono::Local;
-use tokio::fs::create_dir_all;
-use tokio_util::sync::CancellationToken;
+use clap::Parser;
 
 use crate::{
-    models::Pipeline,
-    reporter::{ConsoleReporter, FileReporter},
-    runner::PipelineRunner,
+    cli::{Cli, Command},
+    commands::{
+        CleanCommand, FlakyCommand, GraphCommand, ImportCommand, InstallHooksCommand, ListCommand,
+        RunCommand, ServeCommand, UninstallHooksCommand, ValidateCommand,
+    },
+    error::CiroachError,
+    output::Verbosity,
+    telemetry::Telemetry,
 };
 
+mod cli;
+mod commands;
+mod dashboard;
 mod engine;
+mod error;
+mod events;
+mod github;
+mod history;
+mod hooks;
+mod images;
+mod importer;
+#[cfg(feature = "kubernetes")]
+mod kubernetes;
+mod lock;
 mod logger;
 mod models;
+mod output;
+mod platform;
 mod reporter;
 mod runner;
+mod server;
+mod telemetry;
 mod ui;
+mod workspace;
 
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let cwd = env::current_dir()?;
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    output::init_style(cli.color);
 
-    #[cfg(unix)]
-    let user = {
-        let meta = std::fs::metadata(&cwd)?;
-        format!("{}:{}", meta.uid(), meta.gid())
+    let command = cli.command.unwrap_or(Command::Run(Default::default()));
+    let quiet = matches!(&command, Command::Run(args) if args.quiet);
+    let verbosity = Verbosity::from_flags(quiet, cli.verbose);
+    let telemetry = match Telemetry::init(verbosity) {
+        Ok(telemetry) => telemetry,
+        Err(err) => return CiroachError::exit(err),
     };
-    #[cfg(not(unix))]
-    let user = "0:0".to_string();
 
-    let pipeline = Pipeline::new("ciroach.toml").await?;
-    let runner = PipelineRunner::new(pipeline, user, cwd).await?;
-
-    let token = CancellationToken::new();
-    let signal_token = token.clone();
-
-    tokio::spawn(async move {
-        if tokio::signal::ctrl_c().await.is_ok() {
-            println!("\n🛑 [SIGINT] Graceful shutdown initiated...");
-            signal_token.cancel();
-        }
-    });
-
-    let report = runner.run(token).await?;
-
-    ConsoleReporter::report(&report);
-
-    create_dir_all("logs").await?;
-    let log_path = format!(
-        "logs/build_{}.log",
-        Local::now().format("%Y-%m-%d_%H-%M-%S")
-    );
-
-    if let Err(err) = FileReporter::save(&report, &log_path).await {
-        eprintln!("⚠️ Failed to save log file: {}", err);
-    }
-
-    if !report.is_success() {
-        eprintln!("\n❌ Pipeline failed. See report for details.");
-        std::process::exit(1);
-    }
+    let result = match command {
+        Command::Run(args) => RunCommand::execute(&cli.config, &cli.vars, args, verbosity).await,
+        Command::List => ListCommand::execute(&cli.config, &cli.vars)
+            .await
+            .map(|_| ExitCode::SUCCESS),
+        Command::Validate => ValidateCommand::execute(&cli.config, &cli.vars)
+            .await
+            .map(|_| ExitCode::SUCCESS),
+        Command::Graph(args) => GraphCommand::execute(&cli.config, &cli.vars, args)
+            .await
+            .map(|_| ExitCode::SUCCESS),
+        Command::Flaky(args) => FlakyCommand::execute(&cli.config, &cli.vars, args)
+            .await
+            .map(|_| ExitCode::SUCCESS),
+        Command::Serve(args) => ServeCommand::execute(&cli.config, &cli.vars, args).await,
+        Command::InstallHooks(args) => InstallHooksCommand::execute(&cli.config, &cli.vars, args)
+            .await
+            .map(|_| ExitCode::SUCCESS),
+        Command::UninstallHooks => UninstallHooksCommand::execute()
+            .await
+            .map(|_| ExitCode::SUCCESS),
+        Command::Clean(args) => CleanCommand::execute(&cli.config, &cli.vars, args)
+            .await
+            .map(|_| ExitCode::SUCCESS),
+        Command::Import(source) => ImportCommand::execute(source)
+            .await
+            .map(|_| ExitCode::SUCCESS),
+    };
 
-    println!("\n✨ Pipeline completed successfully!");
-    Ok(())
+    telemetry.shutdown();
+    result.unwrap_or_else(CiroachError::exit)
 }
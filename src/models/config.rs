@@ -1,41 +1,934 @@
-use std::{path::Path, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
+use croner::Cron;
+use regex::Regex;
 use serde::Deserialize;
 use tokio::fs::read_to_string;
 
-use crate::models::RawPipeline;
+use crate::{
+    error::CiroachError,
+    models::{Annotation, RawPipeline, SkipReason},
+    output::Icon,
+};
+
+/// Profile selected by `--quick`; unless declared it runs the steps tagged `quick`.
+pub const QUICK_PROFILE: &str = "quick";
 
 #[derive(Debug, Deserialize)]
 pub struct Pipeline {
     pub stages: Vec<Stage>,
+    pub history: HistoryConfig,
+    pub metrics: Option<MetricsConfig>,
+    pub github: Option<GithubConfig>,
+    pub server: ServerConfig,
+    pub schedule: Option<ScheduleConfig>,
+    pub profiles: BTreeMap<String, ProfileConfig>,
+    pub platform: PlatformConfig,
+    pub engine: EngineConfig,
+    pub logs: LogsConfig,
+    pub needs_policy: NeedsPolicy,
+    /// Patterns for files left out of a copied workspace, besides `.ciroachignore`.
+    pub ignore: Vec<String>,
+    /// Steps of a concurrency group that may run at once; groups not listed allow one.
+    pub concurrency_groups: BTreeMap<String, usize>,
 }
 
 impl Pipeline {
-    pub async fn new(path: impl AsRef<Path>) -> anyhow::Result<Self> {
-        let config = read_to_string(path).await?;
-        let raw: RawPipeline = toml::from_str(&config)?;
-        let compiled = raw.compile()?;
+    /// Loads the pipeline called `pipeline_name`, which may only be left out when the
+    /// file defines a single pipeline. `vars` are the `--var` values.
+    pub async fn new(
+        path: impl AsRef<Path>,
+        pipeline_name: Option<&str>,
+        vars: &[(String, String)],
+    ) -> Result<Self, CiroachError> {
+        let compiled = Self::read(path)
+            .await?
+            .select(pipeline_name)
+            .and_then(|raw| raw.compile(vars))
+            .map_err(|err| CiroachError::Validation(vec![err.into()]))?;
         Ok(Self {
             stages: compiled.stages,
+            history: compiled.history,
+            metrics: compiled.metrics,
+            github: compiled.github,
+            server: compiled.server,
+            schedule: compiled.schedule,
+            profiles: compiled.profiles,
+            platform: compiled.platform,
+            engine: compiled.engine,
+            logs: compiled.logs,
+            needs_policy: compiled.needs_policy,
+            ignore: compiled.ignore,
+            concurrency_groups: compiled.concurrency_groups,
         })
     }
+
+    /// Every pipeline in the file, with its name when the file defines several.
+    pub async fn all(
+        path: impl AsRef<Path>,
+        vars: &[(String, String)],
+    ) -> Result<Vec<(Option<String>, Self)>, CiroachError> {
+        let names = Self::read(&path).await?.pipeline_names();
+        if names.is_empty() {
+            return Ok(vec![(None, Self::new(path, None, vars).await?)]);
+        }
+
+        let mut pipelines = Vec::new();
+        for name in names {
+            let pipeline = Self::new(&path, Some(&name), vars).await?;
+            pipelines.push((Some(name), pipeline));
+        }
+        Ok(pipelines)
+    }
+
+    async fn read(path: impl AsRef<Path>) -> Result<RawPipeline, CiroachError> {
+        let path = path.as_ref();
+        let config_error = |source| CiroachError::Config {
+            path: path.to_path_buf(),
+            source,
+        };
+
+        let config = read_to_string(path)
+            .await
+            .map_err(|err| config_error(err.into()))?;
+        toml::from_str(&config).map_err(|err| config_error(err.into()))
+    }
+
+    /// Descriptions keyed by the (matrix-expanded) step name.
+    pub fn step_descriptions(&self) -> HashMap<String, String> {
+        self.stages
+            .iter()
+            .flat_map(|stage| &stage.steps)
+            .filter_map(|step| {
+                let description = step.description.clone()?;
+                Some((step.exploded_name.clone(), description))
+            })
+            .collect()
+    }
+
+    /// Connects the standard input of ciroach to the step called `name`. Only one step can
+    /// consume it, so a matrix step has to be named by one of its variants. It is read
+    /// once, so the step cannot run again, neither on its own nor with its stage.
+    pub fn pipe_stdin(&mut self, name: &str) -> Result<(), CiroachError> {
+        let mut matches: Vec<(u32, &mut Step)> = self
+            .stages
+            .iter_mut()
+            .flat_map(|stage| {
+                let stage_retries = stage.retries;
+                stage
+                    .steps
+                    .iter_mut()
+                    .map(move |step| (stage_retries, step))
+            })
+            .filter(|(_, step)| step.exploded_name == name || step.name == name)
+            .collect();
+
+        let (stage_retries, step) = match matches.as_mut_slice() {
+            [] => {
+                return Err(CiroachError::invalid(format!(
+                    "Unknown step '{name}' for --stdin."
+                )));
+            }
+            [(stage_retries, step)] => (*stage_retries, step),
+            variants => {
+                return Err(CiroachError::invalid(format!(
+                    "Only one step can read --stdin, but '{}' runs as {}. Name one of them.",
+                    name,
+                    variants
+                        .iter()
+                        .map(|(_, step)| step.exploded_name.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )));
+            }
+        };
+
+        if step.skip.is_some() {
+            return Err(CiroachError::invalid(format!(
+                "Step '{}' reads --stdin but is excluded from this run.",
+                step.exploded_name
+            )));
+        }
+        if step.detach {
+            return Err(CiroachError::invalid(format!(
+                "Step '{}' is detached and cannot read --stdin.",
+                step.exploded_name
+            )));
+        }
+        if step.runner == Runner::Kubernetes {
+            return Err(CiroachError::invalid(format!(
+                "Step '{}' runs on Kubernetes and cannot read --stdin.",
+                step.exploded_name
+            )));
+        }
+        if let Some(session) = &step.session {
+            return Err(CiroachError::invalid(format!(
+                "Step '{}' runs in session '{}' and cannot read --stdin.",
+                step.exploded_name, session
+            )));
+        }
+        if matches!(step.stdin, Some(StepInput::File(_))) {
+            return Err(CiroachError::invalid(format!(
+                "Step '{}' reads its 'stdin_file' already and cannot read --stdin as well.",
+                step.exploded_name
+            )));
+        }
+        if step.max_retries > 0 {
+            return Err(CiroachError::invalid(format!(
+                "Step '{}' has 'max_retries' of {}, but --stdin can only be read once. Set it to 0 to read --stdin.",
+                step.exploded_name, step.max_retries
+            )));
+        }
+        if stage_retries > 0 {
+            return Err(CiroachError::invalid(format!(
+                "Step '{}' is in a stage with 'stage_retries' of {}, but --stdin can only be read once.",
+                step.exploded_name, stage_retries
+            )));
+        }
+
+        step.stdin = Some(StepInput::Process);
+        Ok(())
+    }
+
+    /// Marks the steps left out by `profile` and `skip_tags` as skipped. Steps stay in the
+    /// pipeline so they still show up in reports.
+    pub fn apply_profile(
+        &mut self,
+        profile: Option<&str>,
+        skip_tags: &[String],
+    ) -> Result<(), CiroachError> {
+        let mut filter = match profile {
+            Some(name) => match self.profiles.get(name) {
+                Some(profile) => profile.clone(),
+                None if name == QUICK_PROFILE => ProfileConfig {
+                    include: vec![QUICK_PROFILE.to_string()],
+                    exclude: Vec::new(),
+                },
+                None => {
+                    return Err(CiroachError::invalid(format!(
+                        "Unknown profile '{}'. Available profiles: {:?}",
+                        name,
+                        self.profiles.keys().collect::<Vec<_>>()
+                    )));
+                }
+            },
+            None => ProfileConfig::default(),
+        };
+        filter.exclude.extend(skip_tags.iter().cloned());
+
+        for step in self.stages.iter_mut().flat_map(|stage| &mut stage.steps) {
+            if !filter.matches(&step.tags) {
+                step.skip = Some(SkipReason::Profile);
+            }
+        }
+
+        // Skipping a dependent can exclude the steps needing it in turn.
+        loop {
+            let mut changed = false;
+
+            for stage in self.stages.iter_mut() {
+                for index in 0..stage.steps.len() {
+                    let step = &stage.steps[index];
+                    if step.skip.is_some() {
+                        continue;
+                    }
+
+                    let excluded = step.needs.iter().find(|need| {
+                        stage
+                            .steps
+                            .iter()
+                            .any(|other| other.matches_need(need) && other.skip.is_some())
+                    });
+                    let Some(need) = excluded else {
+                        continue;
+                    };
+
+                    if self.needs_policy == NeedsPolicy::Error {
+                        return Err(CiroachError::invalid(format!(
+                            "Step '{}' needs '{}', which the selected profile excludes. Exclude '{}' as well, adjust the profile or set 'needs_policy = \"skip\"'.",
+                            step.exploded_name, need, step.exploded_name
+                        )));
+                    }
+
+                    println!(
+                        "{} Skipping '{}': it needs '{}', which is excluded.",
+                        Icon::Warning,
+                        step.exploded_name,
+                        need
+                    );
+                    stage.steps[index].skip = Some(SkipReason::Needs);
+                    changed = true;
+                }
+            }
+
+            if !changed {
+                break;
+            }
+        }
+
+        let selected = self
+            .stages
+            .iter()
+            .flat_map(|stage| &stage.steps)
+            .any(|step| step.skip.is_none());
+
+        if !selected {
+            return Err(CiroachError::invalid(
+                "The selected profile excludes every step.",
+            ));
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Stage {
     pub name: String,
+    pub description: Option<String>,
+    /// Times the stage is run again after failing.
+    pub retries: u32,
     pub steps: Vec<Step>,
 }
 
+impl Stage {
+    /// Steps that will run, grouped by the length of their longest `needs` chain. Steps
+    /// of the same group may run at the same time.
+    pub fn parallel_levels(&self) -> Vec<Vec<&Step>> {
+        let steps: Vec<&Step> = self.steps.iter().filter(|s| s.skip.is_none()).collect();
+        let mut depth = vec![0; steps.len()];
+
+        // Relaxed once per step at most; a cycle is caught by the scheduler instead.
+        for _ in 0..steps.len() {
+            let mut changed = false;
+            for (i, step) in steps.iter().enumerate() {
+                let deepest = steps
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, other)| step.depends_on(other))
+                    .map(|(j, _)| depth[j] + 1)
+                    .max()
+                    .unwrap_or(0);
+                if deepest > depth[i] {
+                    depth[i] = deepest;
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        let mut levels: Vec<Vec<&Step>> = Vec::new();
+        for (step, depth) in steps.into_iter().zip(depth) {
+            if levels.len() <= depth {
+                levels.resize_with(depth + 1, Vec::new);
+            }
+            levels[depth].push(step);
+        }
+        levels
+    }
+
+    /// Wall-clock milliseconds of the stage when each step takes as long as in
+    /// `durations`: its longest chain of `needs`. `None` when a step that will run has no
+    /// duration.
+    pub fn estimate(&self, durations: &HashMap<String, u64>) -> Option<u64> {
+        let mut finished: HashMap<&str, u64> = HashMap::new();
+        for step in self.parallel_levels().into_iter().flatten() {
+            let start = self
+                .steps
+                .iter()
+                .filter(|other| step.depends_on(other))
+                .filter_map(|other| finished.get(other.exploded_name.as_str()))
+                .max()
+                .copied()
+                .unwrap_or(0);
+            let duration = durations.get(&step.exploded_name)?;
+            finished.insert(&step.exploded_name, start + duration);
+        }
+        finished.into_values().max()
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct Step {
     pub name: String,
     pub exploded_name: String,
+    /// The matrix variable of this variant and the value it was given.
+    pub matrix_values: Option<BTreeMap<String, String>>,
+    /// Name of the stage the step belongs to.
+    pub stage: String,
     pub image: String,
-    pub memory: i64,
+    /// The image is built earlier in the pipeline and must not be pulled.
+    pub local_image: bool,
+    /// Digest the pulled image must have.
+    pub digest: Option<String>,
+    pub from_step: Option<String>,
+    /// Mount the Docker socket of the host into the container.
+    pub docker_socket: bool,
+    pub extra_hosts: Vec<String>,
+    pub dns: Vec<String>,
+    /// Docker Desktop's VM kills out-of-memory containers without flagging them as such;
+    /// with this, exit code 137 is reported as running out of memory.
+    pub sigkill_is_oom: bool,
+    /// Bytes; `None` means unlimited.
+    pub memory: Option<i64>,
+    /// Docker's `memory_swap`: memory plus swap, or `-1` for unlimited swap.
+    pub memory_swap: Option<i64>,
+    /// Bytes the container's writable layer may grow to.
+    pub storage_limit: Option<i64>,
+    pub runner: Runner,
+    pub workspace_isolation: WorkspaceIsolation,
+    /// Workspace paths copied back to the host after an isolated step succeeds.
+    pub artifacts: Vec<String>,
     pub needs: Vec<String>,
     pub env: Option<Vec<String>>,
     pub command: String,
     pub max_retries: u32,
     pub timeout: Duration,
+    /// Bounds all attempts together, backoff included.
+    pub total_timeout: Option<Duration>,
+    pub description: Option<String>,
+    pub tags: Vec<String>,
+    /// Set when the step is part of the pipeline but must not run this time.
+    pub skip: Option<SkipReason>,
+    /// Keep the container running in the background until the pipeline ends.
+    pub detach: bool,
+    /// When a detached step counts as started.
+    pub wait_for: Option<WaitFor>,
+    /// Data written to the command's standard input, which is closed afterwards.
+    pub stdin: Option<StepInput>,
+    /// Runs as a command in the container shared by the stage's steps of this session,
+    /// after the one declared before it.
+    pub session: Option<String>,
+    /// Run before `command` in the same container. Steps with a check run both through
+    /// the exec API in a container of their own, like a session of one step.
+    pub check: Option<String>,
+    /// A failed check counts as a failed attempt rather than failing the step outright.
+    pub retry_check: bool,
+    /// Recognize problems in the step's output, tried in order on every line.
+    #[serde(skip)]
+    pub matchers: Vec<Matcher>,
+    /// Lines of output kept for the reports: the first and last halves of it, with the
+    /// count of the lines in between. `0` keeps every line.
+    pub log_limit: usize,
+    /// Warn each time the step has been this long without printing anything.
+    pub silence_warning: Option<Duration>,
+    /// Fail the step once it has been this long without printing anything.
+    pub silence_timeout: Option<Duration>,
+    /// Log driver of the step's container, instead of the engine's default.
+    pub log_config: Option<LogConfig>,
+    /// Steps sharing a group take turns, in any stage, e.g. to restore the same database.
+    pub concurrency_group: Option<String>,
+    /// Of the steps ready at once, higher ones start first. Without it, the ones that took
+    /// longest last time do.
+    pub priority: i32,
+}
+
+impl Step {
+    /// The matrix value of this variant.
+    pub fn variant(&self) -> Option<&str> {
+        self.matrix_values
+            .as_ref()
+            .and_then(|values| values.values().next())
+            .map(String::as_str)
+    }
+
+    /// Whether an entry of `needs` names this step: all its variants go by the step
+    /// name, a single one by its exploded name.
+    pub fn matches_need(&self, need: &str) -> bool {
+        need == self.name || need == self.exploded_name
+    }
+
+    pub fn depends_on(&self, other: &Step) -> bool {
+        self.needs.iter().any(|need| other.matches_need(need))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub enum StepInput {
+    /// A file, relative to the workspace. Every attempt reads it from the start.
+    File(PathBuf),
+    /// Whatever is piped into ciroach, given with `--stdin`. The step runs only once, as
+    /// it can be read only once.
+    Process,
+}
+
+/// Recognizes problems, e.g. compiler errors, in the log lines of a step.
+#[derive(Debug, Clone)]
+pub struct Matcher {
+    pub name: String,
+    /// Captures `message`, and optionally `file`, `line` and `column`.
+    pub regex: Regex,
+    /// Completes a match without `file` from the line after it, for tools like rustc
+    /// that print the location on a line of its own.
+    pub location: Option<Regex>,
+}
+
+impl Matcher {
+    /// Applied to the steps that do not list their own `matchers`.
+    pub const DEFAULTS: [&str; 2] = ["rustc", "eslint"];
+
+    /// The matcher called `name` that ships with ciroach, if any.
+    pub fn builtin(name: &str) -> Option<Self> {
+        let (regex, location) = match name {
+            // `error[E0308]: mismatched types`, then `  --> src/main.rs:4:5`.
+            "rustc" => (
+                r"^error(?:\[\w+\])?: (?P<message>.+)$",
+                Some(r"^\s*--> (?P<file>.+?):(?P<line>\d+):(?P<column>\d+)$"),
+            ),
+            // `eslint --format compact`: `app.js: line 3, col 7, Error - 'x' is not defined.`
+            "eslint" => (
+                r"^(?P<file>.+?): line (?P<line>\d+), col (?P<column>\d+), Error - (?P<message>.+)$",
+                None,
+            ),
+            _ => return None,
+        };
+        Some(Self::new(name, regex, location).expect("built-in matchers are valid"))
+    }
+
+    pub fn new(name: &str, regex: &str, location: Option<&str>) -> anyhow::Result<Self> {
+        let regex = Regex::new(regex)
+            .map_err(|err| anyhow::anyhow!("Invalid regex in matcher '{name}': {err}"))?;
+        if !regex.capture_names().any(|group| group == Some("message")) {
+            anyhow::bail!("The regex of matcher '{name}' must capture a 'message' group.");
+        }
+
+        let location = location
+            .map(|location| {
+                let location = Regex::new(location).map_err(|err| {
+                    anyhow::anyhow!("Invalid location regex in matcher '{name}': {err}")
+                })?;
+                if !location.capture_names().any(|group| group == Some("file")) {
+                    anyhow::bail!(
+                        "The location regex of matcher '{name}' must capture a 'file' group."
+                    );
+                }
+                Ok(location)
+            })
+            .transpose()?;
+
+        Ok(Self {
+            name: name.to_string(),
+            regex,
+            location,
+        })
+    }
+
+    pub fn annotate(&self, line: &str) -> Option<Annotation> {
+        let captures = self.regex.captures(line)?;
+        let group = |name| captures.name(name).map(|found| found.as_str());
+
+        Some(Annotation {
+            matcher: self.name.clone(),
+            message: group("message")?.trim().to_string(),
+            file: group("file").map(str::to_string),
+            line: group("line").and_then(|line| line.parse().ok()),
+            column: group("column").and_then(|column| column.parse().ok()),
+        })
+    }
+
+    /// Fills in where `annotation` points to when `line` is its location line.
+    pub fn locate(&self, annotation: &mut Annotation, line: &str) -> bool {
+        let Some(captures) = self
+            .location
+            .as_ref()
+            .and_then(|location| location.captures(line))
+        else {
+            return false;
+        };
+        let group = |name| captures.name(name).map(|found| found.as_str());
+
+        annotation.file = group("file").map(str::to_string);
+        annotation.line = group("line").and_then(|line| line.parse().ok());
+        annotation.column = group("column").and_then(|column| column.parse().ok());
+        true
+    }
+}
+
+/// `log_config = { driver = "json-file", options = { max-size = "100m", max-file = "3" } }`
+#[derive(Debug, Clone, Deserialize)]
+pub struct LogConfig {
+    /// The engine's default driver when unset.
+    pub driver: Option<String>,
+    #[serde(default)]
+    pub options: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WaitFor {
+    pub condition: ReadyCondition,
+    /// How long to wait for the condition. Without it only the step timeout applies.
+    pub timeout: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub enum ReadyCondition {
+    /// A log line of the step matches this regex.
+    Log(String),
+    /// Something listens on this TCP port inside the container.
+    Port(u16),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HistoryConfig {
+    pub baseline: BaselineMode,
+    pub window: usize,
+    pub keep: usize,
+    pub regression_threshold: f64,
+    pub flaky_threshold: f64,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            baseline: BaselineMode::Previous,
+            window: 5,
+            keep: 50,
+            regression_threshold: 20.0,
+            flaky_threshold: 20.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BaselineMode {
+    /// Compare against the most recent recorded run.
+    Previous,
+    /// Compare against the fastest successful duration within the window.
+    Best,
+}
+
+/// What happens to a step whose `needs` were filtered out of the run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NeedsPolicy {
+    /// Refuse to start the pipeline.
+    #[default]
+    Error,
+    /// Skip the step as well.
+    Skip,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MetricsConfig {
+    /// Destination of the Prometheus textfile, e.g. for node_exporter's textfile collector.
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GithubConfig {
+    /// Repository slug in `owner/name` form.
+    pub repo: String,
+    /// Name of the environment variable holding the API token.
+    #[serde(default = "GithubConfig::default_token_env")]
+    pub token_env: String,
+    #[serde(default = "GithubConfig::default_context")]
+    pub context: String,
+    /// Additionally post one status per stage as `<context>/<stage>`.
+    #[serde(default)]
+    pub per_stage: bool,
+}
+
+impl GithubConfig {
+    fn default_token_env() -> String {
+        "GITHUB_TOKEN".to_string()
+    }
+
+    fn default_context() -> String {
+        "ciroach".to_string()
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// What `ciroach serve` does with a trigger that arrives while a run is active.
+    pub on_busy: BusyPolicy,
+}
+
+/// Runs started by `ciroach serve` on its own, e.g. a nightly full pipeline.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleConfig {
+    /// Cron expression in local time, e.g. `0 2 * * *`. A leading seconds field is allowed.
+    pub cron: Cron,
+    /// Pipeline to run, when the file defines several under `[pipelines]`.
+    pub pipeline: Option<String>,
+    /// Profile from `[profiles]` selecting the steps to run.
+    pub profile: Option<String>,
+}
+
+impl ScheduleConfig {
+    /// Loads the pipeline a scheduled run executes, with its profile applied.
+    pub async fn load(
+        &self,
+        path: impl AsRef<Path>,
+        vars: &[(String, String)],
+    ) -> Result<Pipeline, CiroachError> {
+        let mut pipeline = Pipeline::new(path, self.pipeline.as_deref(), vars).await?;
+        pipeline.apply_profile(self.profile.as_deref(), &[])?;
+        Ok(pipeline)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BusyPolicy {
+    /// Run it after the runs ahead of it.
+    #[default]
+    Queue,
+    /// Answer with `409 Conflict`.
+    Reject,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EngineConfig {
+    pub pull: PullConfig,
+    /// Fail a stage when none of its steps logs a line or finishes for this long. Off by
+    /// default.
+    pub stall_timeout: Option<Duration>,
+    /// Bytes that must be free where Docker keeps its data. Off by default.
+    pub min_free_space: Option<i64>,
+    pub on_low_space: LowSpacePolicy,
+    /// Hold steps back while the memory limits of the running ones would exceed the
+    /// host's memory, instead of only warning about it.
+    pub strict_resources: bool,
+    /// How long an interrupted run may take to stop its steps before their containers
+    /// are removed by force.
+    pub drain_timeout: Duration,
+    #[cfg_attr(not(feature = "kubernetes"), allow(dead_code))]
+    pub kubernetes: KubernetesConfig,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        Self {
+            pull: PullConfig::default(),
+            stall_timeout: None,
+            min_free_space: None,
+            on_low_space: LowSpacePolicy::default(),
+            strict_resources: false,
+            drain_timeout: Duration::from_secs(30),
+            kubernetes: KubernetesConfig::default(),
+        }
+    }
+}
+
+/// Where steps with `runner = "kubernetes"` run, and how their pods get the workspace.
+#[derive(Debug, Clone, Deserialize)]
+#[cfg_attr(not(feature = "kubernetes"), allow(dead_code))]
+pub struct KubernetesConfig {
+    pub namespace: String,
+    /// Persistent volume claim holding the workspace, shared by every step. Without it,
+    /// each pod clones `repository` into a volume of its own.
+    pub workspace_claim: Option<String>,
+    /// Cloned by an init container; the workspace's `origin` remote when unset.
+    pub repository: Option<String>,
+    /// Checked out after cloning; the workspace's `HEAD` when unset.
+    pub revision: Option<String>,
+    /// Image of the init container, which needs `git`.
+    pub clone_image: String,
+    pub service_account: Option<String>,
+}
+
+impl Default for KubernetesConfig {
+    fn default() -> Self {
+        Self {
+            namespace: "default".to_string(),
+            workspace_claim: None,
+            repository: None,
+            revision: None,
+            clone_image: "alpine/git:latest".to_string(),
+            service_account: None,
+        }
+    }
+}
+
+/// Where a step's command runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Runner {
+    #[default]
+    Container,
+    /// Directly on this machine with `sh -c`, in the workspace and as the current user.
+    Host,
+    /// As a Job in the cluster of `[engine.kubernetes]`. Needs ciroach built with the
+    /// `kubernetes` feature.
+    Kubernetes,
+}
+
+/// How a step sees the workspace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WorkspaceIsolation {
+    /// Bind-mounted; every step works on the same directory.
+    #[default]
+    Shared,
+    /// Copied into the container, so parallel steps cannot clobber each other's files.
+    Copy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LowSpacePolicy {
+    /// Refuse to start the run.
+    #[default]
+    Fail,
+    /// Start anyway after a warning.
+    Warn,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PullConfig {
+    /// Total tries per image, including the first one.
+    pub attempts: u32,
+    /// Wait before the first retry; doubled for every further one.
+    pub backoff: Duration,
+    /// Limit for a single pull attempt of one image.
+    pub timeout: Duration,
+    /// Limit for pulling all images of the pipeline, retries included.
+    pub deadline: Duration,
+    /// Images pulled at the same time.
+    pub concurrency: usize,
+}
+
+impl Default for PullConfig {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            backoff: Duration::from_secs(2),
+            timeout: Duration::from_secs(10 * 60),
+            deadline: Duration::from_secs(30 * 60),
+            concurrency: 4,
+        }
+    }
+}
+
+/// Retention of the per-run directories under `logs/`, applied before each run, and the
+/// lines shown of them once a run finished.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LogsConfig {
+    /// Run directories kept, newest first.
+    pub keep: usize,
+    /// Run directories older than this are removed regardless of `keep`.
+    pub max_age: Option<Duration>,
+    /// Only the lines matching this are printed after a run; the saved logs stay complete.
+    #[serde(skip)]
+    pub filter: Option<Regex>,
+    /// Lines printed before and after each line matching `filter`.
+    pub context: usize,
+}
+
+impl Default for LogsConfig {
+    fn default() -> Self {
+        Self {
+            keep: 20,
+            max_age: None,
+            filter: None,
+            context: 2,
+        }
+    }
+}
+
+/// Overrides for what ciroach detects about the host. Unset fields are detected.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct PlatformConfig {
+    /// Mount the workspace with `:z`. Detected from `/sys/fs/selinux/enforce`.
+    pub selinux_relabel: Option<bool>,
+    /// Run containers as the owner of the workspace. Off on Docker Desktop (macOS, Windows).
+    pub map_user: Option<bool>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ProfileConfig {
+    /// Run only steps carrying at least one of these tags. Empty means every step.
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Never run steps carrying any of these tags.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+impl ProfileConfig {
+    fn matches(&self, tags: &[String]) -> bool {
+        let included = self.include.is_empty() || tags.iter().any(|t| self.include.contains(t));
+        let excluded = tags.iter().any(|t| self.exclude.contains(t));
+        included && !excluded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::RawPipeline;
+
+    fn restore(settings: &str) -> Pipeline {
+        let config = format!(
+            r#"
+            stages_order = ["db"]
+
+            [stages.db]
+            {settings}
+
+            [stages.db.steps.restore]
+            runner = "host"
+            command = "psql"
+            "#
+        );
+        toml::from_str::<RawPipeline>(&config)
+            .unwrap()
+            .select(None)
+            .and_then(|raw| raw.compile(&[]))
+            .unwrap()
+    }
+
+    #[test]
+    fn stdin_goes_to_a_step_that_runs_once() {
+        let mut pipeline = restore("");
+        pipeline.pipe_stdin("restore").unwrap();
+        assert!(matches!(
+            pipeline.stages[0].steps[0].stdin,
+            Some(StepInput::Process)
+        ));
+    }
+
+    #[test]
+    fn stdin_is_refused_to_a_step_that_may_run_again() {
+        for (settings, retried) in [
+            ("max_retries = 2", "'max_retries' of 2"),
+            ("stage_retries = 1", "'stage_retries' of 1"),
+        ] {
+            let err = restore(settings).pipe_stdin("restore").unwrap_err();
+            assert!(err.to_string().contains(retried), "{err}");
+        }
+    }
+
+    #[test]
+    fn stdin_is_refused_to_a_session_step() {
+        let mut pipeline = toml::from_str::<RawPipeline>(
+            r#"
+            stages_order = ["db"]
+
+            [stages.db.steps.restore]
+            image = "postgres:16"
+            command = "psql"
+            session = "db"
+            "#,
+        )
+        .unwrap()
+        .select(None)
+        .and_then(|raw| raw.compile(&[]))
+        .unwrap();
+
+        let err = pipeline.pipe_stdin("restore").unwrap_err();
+        assert!(err.to_string().contains("runs in session 'db'"), "{err}");
+    }
 }
@@ -1,158 +1,2360 @@
-use std::{collections::BTreeMap, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap},
+    env,
+    path::{Component, Path, PathBuf},
+    sync::LazyLock,
+    time::Duration,
+};
 
 use anyhow::Ok;
+use colored::Colorize;
+use indexmap::IndexMap;
 use regex::{Regex, escape};
 use serde::Deserialize;
 
-use crate::models::{Pipeline, Stage, Step};
+use crate::{
+    models::{
+        EngineConfig, GithubConfig, HistoryConfig, KubernetesConfig, LogConfig, LogsConfig,
+        LowSpacePolicy, Matcher, MetricsConfig, NeedsPolicy, Pipeline, PlatformConfig,
+        ProfileConfig, PullConfig, QUICK_PROFILE, ReadyCondition, Runner, ScheduleConfig,
+        ServerConfig, Stage, Step, StepInput, WaitFor, WorkspaceIsolation,
+    },
+    output::Icon,
+};
 
 const DEFAULT_MEMORY_LIMIT: i64 = 512 * 1024 * 1024;
+const DEFAULT_LOG_LIMIT: usize = 10_000;
+const DEFAULT_SILENCE_WARNING: Duration = Duration::from_secs(2 * 60);
+const LOCAL_IMAGE_SCHEME: &str = "local:";
+const HOST_GATEWAY: &str = "host.docker.internal:host-gateway";
+/// The single stage the deprecated `[pipeline]` layout compiles to.
+const FLAT_STAGE: &str = "pipeline";
+/// Environment variables overriding `[vars]`, e.g. `CIROACH_VAR_REGISTRY`.
+const VAR_ENV_PREFIX: &str = "CIROACH_VAR_";
+
+static VAR_REFERENCE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\$\{\{\s*vars\.([A-Za-z0-9_-]+)\s*\}\}").unwrap());
+/// Any `${{ NAME }}`, matrix variable or not.
+static PLACEHOLDER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\$\{\{\s*([^}]*?)\s*\}\}").unwrap());
 
 #[derive(Debug, Deserialize)]
 pub struct RawPipeline {
+    /// Empty when the file defines `[pipelines.<name>]` instead.
+    #[serde(default)]
+    pub stages_order: Vec<String>,
+    #[serde(default)]
+    pub stages: BTreeMap<String, RawStage>,
+    /// Several pipelines sharing the rest of the file, selected by name.
+    #[serde(default)]
+    pub pipelines: IndexMap<String, RawNamedPipeline>,
+    /// Deprecated layout of a single list of steps, see [`RawFlatPipeline`].
+    pub pipeline: Option<RawFlatPipeline>,
+    #[serde(default)]
+    pub history: HistoryConfig,
+    pub metrics: Option<MetricsConfig>,
+    pub github: Option<GithubConfig>,
+    #[serde(default)]
+    pub server: ServerConfig,
+    pub schedule: Option<ScheduleConfig>,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, ProfileConfig>,
+    #[serde(default)]
+    pub platform: PlatformConfig,
+    #[serde(default)]
+    pub engine: RawEngineConfig,
+    #[serde(default)]
+    pub logs: RawLogsConfig,
+    #[serde(default)]
+    pub defaults: RawDefaults,
+    /// Acknowledges that steps with `docker_socket = true` get root-equivalent access to
+    /// the host.
+    #[serde(default)]
+    pub allow_docker_socket: bool,
+    /// Image prefixes trusted with the Docker socket besides the official `docker` images.
+    #[serde(default)]
+    pub trusted_images: Vec<String>,
+    /// Skip stages listed in `stages_order` without a definition instead of failing, for
+    /// order lists generated from a template.
+    #[serde(default)]
+    pub allow_missing_stages: bool,
+    #[serde(default)]
+    pub needs_policy: NeedsPolicy,
+    /// Extra gitignore-style patterns for files left out of a copied workspace, applied
+    /// before `.ciroachignore`.
+    #[serde(default)]
+    pub ignore: Vec<String>,
+    /// Problem matchers steps can list besides the built-in ones, which these override.
+    #[serde(default)]
+    pub matchers: BTreeMap<String, RawMatcher>,
+    /// How many steps of each `concurrency_group` may run at once; one unless listed.
+    #[serde(default)]
+    pub concurrency_groups: BTreeMap<String, usize>,
+    /// Values steps use as `${{ vars.NAME }}`, overridden by `CIROACH_VAR_NAME` and
+    /// `--var NAME=VALUE`.
+    #[serde(default)]
+    pub vars: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RawMatcher {
+    pub regex: String,
+    pub location: Option<String>,
+}
+
+/// The layout of early releases: `[[pipeline.steps]]` run one after another, except that
+/// consecutive steps with `parallel = true` run together.
+#[derive(Debug, Deserialize)]
+pub struct RawFlatPipeline {
+    pub steps: Vec<RawFlatStep>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RawFlatStep {
+    pub name: String,
+    /// Run alongside the neighbouring steps that set it too.
+    #[serde(default)]
+    pub parallel: bool,
+    #[serde(flatten)]
+    pub step: RawStep,
+}
+
+impl RawFlatPipeline {
+    /// Compiles the steps to one stage. Each group of steps, a single step or a run of
+    /// parallel ones, needs every step of the group before it.
+    fn into_stage(self) -> anyhow::Result<RawStage> {
+        let mut steps = IndexMap::new();
+        let mut previous: Vec<String> = Vec::new();
+        let mut group: Vec<String> = Vec::new();
+        let mut after_parallel = false;
+
+        for flat in self.steps {
+            if flat.step.needs.is_some() {
+                anyhow::bail!(
+                    "Step '{}' under [pipeline] cannot use 'needs'; the order of the steps defines them.",
+                    flat.name
+                );
+            }
+            if steps.contains_key(&flat.name) {
+                anyhow::bail!("Step '{}' is defined twice under [pipeline].", flat.name);
+            }
+
+            let joins_group = flat.parallel && after_parallel;
+            if !joins_group && !group.is_empty() {
+                previous = std::mem::take(&mut group);
+            }
+            after_parallel = flat.parallel;
+
+            let mut step = flat.step;
+            step.needs = (!previous.is_empty()).then(|| previous.clone());
+            group.push(flat.name.clone());
+            steps.insert(flat.name, step);
+        }
+
+        Ok(RawStage {
+            description: None,
+            workspace_isolation: None,
+            env: None,
+            memory: None,
+            timeout: None,
+            max_retries: None,
+            stage_retries: None,
+            steps,
+        })
+    }
+}
+
+/// One of several pipelines in a file.
+#[derive(Debug, Deserialize)]
+pub struct RawNamedPipeline {
     pub stages_order: Vec<String>,
     pub stages: BTreeMap<String, RawStage>,
 }
 
 impl RawPipeline {
-    pub fn compile(self) -> anyhow::Result<Pipeline> {
+    /// Names of the pipelines under `[pipelines]`, in declaration order.
+    pub fn pipeline_names(&self) -> Vec<String> {
+        self.pipelines.keys().cloned().collect()
+    }
+
+    /// The `[vars]` with the `CIROACH_VAR_*` environment and then `cli_vars` applied over
+    /// them.
+    fn resolved_vars(&self, cli_vars: &[(String, String)]) -> BTreeMap<String, String> {
+        let mut vars = self.vars.clone();
+        let mut set = |name: &str, value: String| {
+            // Environment names are upper case; they override a var of any case.
+            let key = vars
+                .keys()
+                .find(|key| key.eq_ignore_ascii_case(name))
+                .cloned()
+                .unwrap_or_else(|| name.to_string());
+            vars.insert(key, value);
+        };
+
+        for (name, value) in env::vars() {
+            if let Some(name) = name.strip_prefix(VAR_ENV_PREFIX)
+                && !name.is_empty()
+            {
+                set(name, value);
+            }
+        }
+        for (name, value) in cli_vars {
+            set(name, value.clone());
+        }
+        vars
+    }
+
+    /// Replaces `${{ vars.NAME }}` in the steps, before matrix values are interpolated.
+    /// Values are inserted as they are, so they cannot refer to other vars.
+    fn interpolate_vars(&mut self, cli_vars: &[(String, String)]) -> anyhow::Result<()> {
+        let vars = self.resolved_vars(cli_vars);
+        for (name, value) in &vars {
+            if let Some(reference) = VAR_REFERENCE.find(value) {
+                anyhow::bail!(
+                    "Var '{}' refers to '{}'; var values cannot use other vars.",
+                    name,
+                    reference.as_str()
+                );
+            }
+        }
+
+        let interpolate = |text: &mut String, owner: &str| -> anyhow::Result<()> {
+            if !VAR_REFERENCE.is_match(text) {
+                return Ok(());
+            }
+            if let Some(undefined) = VAR_REFERENCE
+                .captures_iter(text)
+                .map(|captures| captures[1].to_string())
+                .find(|name| !vars.contains_key(name))
+            {
+                let defined = match vars.is_empty() {
+                    true => "none; define them under [vars] or pass --var NAME=VALUE".to_string(),
+                    false => vars.keys().cloned().collect::<Vec<_>>().join(", "),
+                };
+                anyhow::bail!("{owner} uses undefined var '{undefined}'. Defined vars: {defined}");
+            }
+            *text = VAR_REFERENCE
+                .replace_all(text, |captures: &regex::Captures| {
+                    vars[&captures[1]].clone()
+                })
+                .into_owned();
+            Ok(())
+        };
+
+        for (stage_name, stage) in self.stages.iter_mut() {
+            let owner = format!("Stage '{stage_name}'");
+            for entry in stage.env.iter_mut().flatten() {
+                interpolate(entry, &owner)?;
+            }
+
+            for (step_id, step) in stage.steps.iter_mut() {
+                let owner = format!("Step '{step_id}'");
+                interpolate(&mut step.image, &owner)?;
+                match &mut step.command {
+                    RawCommand::Script(script) => interpolate(script, &owner)?,
+                    RawCommand::Lines(lines) => {
+                        for line in lines.iter_mut() {
+                            interpolate(line, &owner)?;
+                        }
+                    }
+                }
+                for entry in step.env.iter_mut().flatten() {
+                    interpolate(entry, &owner)?;
+                }
+                for tag in step.tags.iter_mut() {
+                    interpolate(tag, &owner)?;
+                }
+                if let Some(check) = &mut step.check {
+                    interpolate(check, &owner)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes the pipeline called `name` the one to compile. Without a name a file with
+    /// one pipeline uses that one.
+    pub fn select(mut self, name: Option<&str>) -> anyhow::Result<Self> {
+        if let Some(flat) = self.pipeline.take() {
+            if !self.stages_order.is_empty()
+                || !self.stages.is_empty()
+                || !self.pipelines.is_empty()
+            {
+                anyhow::bail!("Define steps either under [pipeline] or in stages, not both.");
+            }
+            println!(
+                "{} The [pipeline] steps layout is deprecated. Move the steps to [stages.<name>.steps] and list the stage in 'stages_order'; 'needs' replaces 'parallel'.",
+                Icon::Warning
+            );
+            self.stages_order = vec![FLAT_STAGE.to_string()];
+            self.stages
+                .insert(FLAT_STAGE.to_string(), flat.into_stage()?);
+        }
+
+        if self.pipelines.is_empty() {
+            if let Some(name) = name {
+                anyhow::bail!(
+                    "Pipeline '{name}' is not defined. This file has a single pipeline; define several under [pipelines.<name>]."
+                );
+            }
+            if self.stages_order.is_empty() && self.stages.is_empty() {
+                anyhow::bail!(
+                    "No pipeline defined. Add 'stages_order' and [stages], or [pipelines.<name>]."
+                );
+            }
+            return Ok(self);
+        }
+
+        if !self.stages_order.is_empty() || !self.stages.is_empty() {
+            anyhow::bail!(
+                "Define stages either at the top level or under [pipelines.<name>], not both."
+            );
+        }
+
+        let names = self.pipeline_names();
+        let name = match name {
+            Some(name) => name.to_string(),
+            None if names.len() == 1 => names[0].clone(),
+            None => anyhow::bail!(
+                "This file defines several pipelines; name the one to use: {}",
+                names.join(", ")
+            ),
+        };
+        let Some(selected) = self.pipelines.shift_remove(&name) else {
+            let candidates: Vec<&str> = names.iter().map(String::as_str).collect();
+            anyhow::bail!(
+                "Pipeline '{}' is not defined.{} Defined pipelines: {}",
+                name,
+                Self::did_you_mean(&name, &candidates),
+                names.join(", ")
+            );
+        };
+
+        self.stages_order = selected.stages_order;
+        self.stages = selected.stages;
+        Ok(self)
+    }
+
+    /// `vars` are the `--var` values, which win over `CIROACH_VAR_*` and `[vars]`.
+    pub fn compile(mut self, vars: &[(String, String)]) -> anyhow::Result<Pipeline> {
+        self.interpolate_vars(vars)?;
         let mut final_stages = Vec::new();
 
+        let unlisted: Vec<&str> = self
+            .stages
+            .keys()
+            .filter(|name| !self.stages_order.contains(name))
+            .map(String::as_str)
+            .collect();
+        if !unlisted.is_empty() {
+            println!(
+                "{} Stages defined but not listed in 'stages_order' never run: {}",
+                Icon::Warning,
+                unlisted.join(", ")
+            );
+        }
+
+        let mut implicit_memory = false;
+        let matchers = self.matchers()?;
+
         for stage_name in self.stages_order.iter() {
             let Some(raw_stage) = self.stages.get(stage_name) else {
+                if !self.allow_missing_stages {
+                    anyhow::bail!(self.missing_stage(stage_name));
+                }
                 println!(
-                    "⚠️ Stage '{}' declared in order but missing definition. Skipping.",
+                    "{} Stage '{}' declared in order but missing definition. Skipping.",
+                    Icon::Warning,
                     stage_name
                 );
                 continue;
             };
 
             if raw_stage.steps.is_empty() {
-                println!("⚠️ Stage '{}' is empty. Skipping.", stage_name);
+                println!(
+                    "{} Stage '{}' is empty. Skipping.",
+                    Icon::Warning,
+                    stage_name
+                );
                 continue;
             }
 
             let mut resolved_steps = Vec::new();
 
             for (step_id, step_cfg) in raw_stage.steps.iter() {
+                step_cfg.check_runner(step_id)?;
+                let isolation = step_cfg.workspace_isolation(step_id, raw_stage)?;
+                // A host process is not limited; it must not count against the host either.
+                let memory = match step_cfg.runner {
+                    Runner::Container | Runner::Kubernetes => {
+                        step_cfg.memory_limit(raw_stage, &self.defaults)?
+                    }
+                    Runner::Host => None,
+                };
+                implicit_memory |= step_cfg.runner == Runner::Container
+                    && step_cfg.memory.is_none()
+                    && raw_stage.memory.is_none()
+                    && self.defaults.memory.is_none();
+
                 if let Some(matrix) = step_cfg.matrix.as_ref() {
-                    let pattern = format!(r"\$\{{\{{\s*{}\s*\}}\}}", escape(&matrix.variable));
-                    let regex = Regex::new(&pattern)?;
+                    let regex = matrix.placeholder()?;
+                    let name_suffix = matrix.name_suffix(step_id, &regex)?;
+                    if !step_cfg.uses_matrix_variable(&regex, raw_stage, &self.defaults) {
+                        println!(
+                            "{} Step '{}' never uses its matrix variable '${{{{ {} }}}}', so its variants are identical.",
+                            Icon::Warning,
+                            step_id,
+                            matrix.variable
+                        );
+                    }
 
                     for val in matrix.values.iter() {
+                        let interpolate = |text: &str| regex.replace_all(text, val).into_owned();
+                        let suffix = match name_suffix {
+                            Some(suffix) => interpolate(suffix),
+                            None => format!("-{val}"),
+                        };
                         resolved_steps.push(Step {
                             name: step_id.to_string(),
-                            exploded_name: format!("{}-{}", step_id, val),
-                            image: regex.replace_all(&step_cfg.image, val).to_string(),
-                            memory: step_cfg.memory_limit()?,
-                            needs: step_cfg.needs.clone().unwrap_or_default(),
-                            env: step_cfg.env.clone(),
-                            command: regex.replace_all(&step_cfg.command, val).to_string(),
-                            max_retries: step_cfg.max_retries.unwrap_or(0),
-                            timeout: step_cfg.timeout()?,
+                            exploded_name: format!("{step_id}{suffix}"),
+                            matrix_values: Some(BTreeMap::from([(
+                                matrix.variable.clone(),
+                                val.clone(),
+                            )])),
+                            stage: stage_name.clone(),
+                            image: RawStep::image_ref(&interpolate(&step_cfg.image)),
+                            local_image: step_cfg.local_image(),
+                            digest: step_cfg.digest(step_id)?,
+                            from_step: step_cfg.from_step.clone(),
+                            docker_socket: step_cfg.docker_socket,
+                            extra_hosts: step_cfg.extra_hosts(&self.defaults),
+                            dns: step_cfg.dns(&self.defaults),
+                            sigkill_is_oom: step_cfg
+                                .sigkill_is_oom
+                                .unwrap_or(self.defaults.sigkill_is_oom),
+                            memory,
+                            memory_swap: step_cfg.memory_swap(memory)?,
+                            storage_limit: step_cfg.storage_limit()?,
+                            runner: step_cfg.runner,
+                            workspace_isolation: isolation,
+                            artifacts: step_cfg
+                                .artifacts
+                                .iter()
+                                .map(|path| interpolate(path))
+                                .collect(),
+                            needs: step_cfg.needs(step_id, raw_stage, Some(val), interpolate)?,
+                            env: step_cfg
+                                .env(raw_stage, &self.defaults)
+                                .map(|env| env.iter().map(|entry| interpolate(entry)).collect()),
+                            command: step_cfg.script(|text| interpolate(text)),
+                            max_retries: step_cfg.max_retries(raw_stage, &self.defaults),
+                            timeout: step_cfg.timeout(raw_stage, &self.defaults)?,
+                            total_timeout: step_cfg.total_timeout()?,
+                            tags: step_cfg.tags().map(|tag| interpolate(&tag)).collect(),
+                            skip: None,
+                            detach: step_cfg.detach,
+                            wait_for: step_cfg.wait_for(step_id)?,
+                            stdin: step_cfg.stdin(step_id)?,
+                            session: step_cfg.session(step_id)?,
+                            check: step_cfg.check(step_id)?.map(|check| interpolate(&check)),
+                            retry_check: step_cfg.retry_check,
+                            matchers: step_cfg.matchers(step_id, &matchers)?,
+                            log_limit: step_cfg.log_limit(&self.defaults),
+                            silence_warning: step_cfg.silence_warning(&self.defaults)?,
+                            silence_timeout: step_cfg.silence_timeout()?,
+                            log_config: step_cfg.log_config.clone(),
+                            concurrency_group: step_cfg
+                                .concurrency_group
+                                .as_ref()
+                                .map(|group| interpolate(group)),
+                            priority: step_cfg.priority,
+                            description: step_cfg
+                                .description
+                                .as_ref()
+                                .map(|text| interpolate(text)),
                         });
                     }
                 } else {
                     resolved_steps.push(Step {
                         name: step_id.clone(),
                         exploded_name: step_id.clone(),
-                        image: step_cfg.image.clone(),
-                        memory: step_cfg.memory_limit()?,
-                        needs: step_cfg.needs.clone().unwrap_or_default(),
-                        env: step_cfg.env.clone(),
-                        command: step_cfg.command.clone(),
-                        max_retries: step_cfg.max_retries.unwrap_or(0),
-                        timeout: step_cfg.timeout()?,
+                        matrix_values: None,
+                        stage: stage_name.clone(),
+                        image: RawStep::image_ref(&step_cfg.image),
+                        local_image: step_cfg.local_image(),
+                        digest: step_cfg.digest(step_id)?,
+                        from_step: step_cfg.from_step.clone(),
+                        docker_socket: step_cfg.docker_socket,
+                        extra_hosts: step_cfg.extra_hosts(&self.defaults),
+                        dns: step_cfg.dns(&self.defaults),
+                        sigkill_is_oom: step_cfg
+                            .sigkill_is_oom
+                            .unwrap_or(self.defaults.sigkill_is_oom),
+                        memory,
+                        memory_swap: step_cfg.memory_swap(memory)?,
+                        storage_limit: step_cfg.storage_limit()?,
+                        runner: step_cfg.runner,
+                        workspace_isolation: isolation,
+                        artifacts: step_cfg.artifacts.clone(),
+                        needs: step_cfg.needs(step_id, raw_stage, None, str::to_string)?,
+                        env: step_cfg.env(raw_stage, &self.defaults),
+                        command: step_cfg.script(str::to_string),
+                        max_retries: step_cfg.max_retries(raw_stage, &self.defaults),
+                        timeout: step_cfg.timeout(raw_stage, &self.defaults)?,
+                        total_timeout: step_cfg.total_timeout()?,
+                        tags: step_cfg.tags().collect(),
+                        skip: None,
+                        detach: step_cfg.detach,
+                        wait_for: step_cfg.wait_for(step_id)?,
+                        stdin: step_cfg.stdin(step_id)?,
+                        session: step_cfg.session(step_id)?,
+                        check: step_cfg.check(step_id)?,
+                        retry_check: step_cfg.retry_check,
+                        matchers: step_cfg.matchers(step_id, &matchers)?,
+                        log_limit: step_cfg.log_limit(&self.defaults),
+                        silence_warning: step_cfg.silence_warning(&self.defaults)?,
+                        silence_timeout: step_cfg.silence_timeout()?,
+                        log_config: step_cfg.log_config.clone(),
+                        concurrency_group: step_cfg.concurrency_group.clone(),
+                        priority: step_cfg.priority,
+                        description: step_cfg.description.clone(),
                     });
                 }
             }
 
+            for step in &resolved_steps {
+                let matrix = raw_stage.steps[&step.name].matrix.as_ref();
+                Self::unresolved_placeholder(step, matrix)?;
+            }
+            Self::resolve_variant_needs(&mut resolved_steps)?;
+            Self::chain_sessions(&mut resolved_steps)?;
             final_stages.push(Stage {
                 name: stage_name.clone(),
+                description: raw_stage.description.clone(),
+                retries: raw_stage.stage_retries.unwrap_or(0),
                 steps: resolved_steps,
             });
         }
 
+        if implicit_memory {
+            println!(
+                "{} Steps without 'memory' are limited to {} MiB. Set 'memory' under [defaults], or \"unlimited\", to change that.",
+                Icon::Info,
+                DEFAULT_MEMORY_LIMIT / (1024 * 1024)
+            );
+        }
+
         if final_stages.is_empty() {
             anyhow::bail!("No valid stages or steps found to execute.");
         }
 
+        if let Some((group, _)) = self
+            .concurrency_groups
+            .iter()
+            .find(|(_, width)| **width == 0)
+        {
+            anyhow::bail!("Concurrency group '{group}' must allow at least one step at a time.");
+        }
+
+        Self::check_needs(&final_stages)?;
+        Self::check_image_sources(&final_stages)?;
+        self.check_docker_socket(&final_stages)?;
+
         Ok(Pipeline {
             stages: final_stages,
+            history: self.history,
+            metrics: self.metrics,
+            github: self.github,
+            server: self.server,
+            schedule: self.schedule,
+            profiles: self.profiles,
+            platform: self.platform,
+            engine: self.engine.compile()?,
+            logs: self.logs.compile()?,
+            needs_policy: self.needs_policy,
+            ignore: self.ignore,
+            concurrency_groups: self.concurrency_groups,
         })
     }
+
+    /// The built-in matchers and those of `[matchers]`, by name.
+    fn matchers(&self) -> anyhow::Result<HashMap<String, Matcher>> {
+        let mut matchers: HashMap<String, Matcher> = Matcher::DEFAULTS
+            .into_iter()
+            .filter_map(|name| Some((name.to_string(), Matcher::builtin(name)?)))
+            .collect();
+        for (name, raw) in self.matchers.iter() {
+            let matcher = Matcher::new(name, &raw.regex, raw.location.as_deref())?;
+            matchers.insert(name.clone(), matcher);
+        }
+        Ok(matchers)
+    }
+
+    fn missing_stage(&self, stage_name: &str) -> String {
+        let defined: Vec<&str> = self.stages.keys().map(String::as_str).collect();
+
+        format!(
+            "Stage '{}' is listed in 'stages_order' but not defined.{} Defined stages: {}. Set 'allow_missing_stages = true' to skip it instead.",
+            stage_name,
+            Self::did_you_mean(stage_name, &defined),
+            defined.join(", ")
+        )
+    }
+
+    /// ` Did you mean 'deploy'?` when one of `candidates` is close to `name`.
+    fn did_you_mean(name: &str, candidates: &[&str]) -> String {
+        candidates
+            .iter()
+            .map(|candidate| (Self::edit_distance(name, candidate), *candidate))
+            .filter(|(distance, candidate)| *distance <= (candidate.chars().count() / 3).max(2))
+            .min()
+            .map(|(_, candidate)| format!(" Did you mean '{candidate}'?"))
+            .unwrap_or_default()
+    }
+
+    /// Levenshtein distance, ignoring case.
+    fn edit_distance(a: &str, b: &str) -> usize {
+        let a: Vec<char> = a.to_lowercase().chars().collect();
+        let b: Vec<char> = b.to_lowercase().chars().collect();
+        let mut row: Vec<usize> = (0..=b.len()).collect();
+
+        for (i, ca) in a.iter().enumerate() {
+            let mut diagonal = row[0];
+            row[0] = i + 1;
+            for (j, cb) in b.iter().enumerate() {
+                let above = row[j + 1];
+                row[j + 1] = if ca == cb {
+                    diagonal
+                } else {
+                    1 + diagonal.min(above).min(row[j])
+                };
+                diagonal = above;
+            }
+        }
+
+        row[b.len()]
+    }
+
+    fn check_docker_socket(&self, stages: &[Stage]) -> anyhow::Result<()> {
+        let steps = stages
+            .iter()
+            .flat_map(|stage| &stage.steps)
+            .filter(|step| step.docker_socket);
+
+        for step in steps {
+            if !self.allow_docker_socket {
+                anyhow::bail!(
+                    "Step '{}' mounts the Docker socket, which gives it root access to the host. Set 'allow_docker_socket = true' at the top of the pipeline to allow it.",
+                    step.exploded_name
+                );
+            }
+
+            let repository = step.image.split([':', '@']).next().unwrap_or_default();
+            let trusted = step.local_image
+                || repository == "docker"
+                || repository == "library/docker"
+                || self
+                    .trusted_images
+                    .iter()
+                    .any(|prefix| step.image.starts_with(prefix));
+
+            if !trusted {
+                println!(
+                    "{}",
+                    format!(
+                        "{} Step '{}' gives the Docker socket to the untrusted image '{}'. It can take over the host. Add it to 'trusted_images' if this is intended.",
+                        Icon::Warning,
+                        step.exploded_name, step.image
+                    )
+                    .yellow()
+                    .bold()
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replaces `name:value` in `needs` with the exploded name of that variant.
+    fn resolve_variant_needs(steps: &mut [Step]) -> anyhow::Result<()> {
+        for index in 0..steps.len() {
+            for need_index in 0..steps[index].needs.len() {
+                let need = &steps[index].needs[need_index];
+                let Some((name, value)) = need.split_once(':') else {
+                    continue;
+                };
+
+                let variants: Vec<&Step> = steps.iter().filter(|s| s.name == name).collect();
+                // An unknown step is reported with the other unknown needs.
+                if variants.is_empty() {
+                    continue;
+                }
+                let found = variants
+                    .iter()
+                    .find(|s| s.variant() == Some(value))
+                    .map(|s| s.exploded_name.clone());
+                let Some(exploded_name) = found else {
+                    let values: Vec<&str> = variants.iter().filter_map(|s| s.variant()).collect();
+                    let has = match values.is_empty() {
+                        true => "is not a matrix step".to_string(),
+                        false => format!("only has {}", values.join(", ")),
+                    };
+                    anyhow::bail!(
+                        "Step '{}' needs variant '{}' of '{}', which {}.",
+                        steps[index].exploded_name,
+                        value,
+                        name,
+                        has
+                    );
+                };
+                steps[index].needs[need_index] = exploded_name;
+            }
+        }
+        Ok(())
+    }
+
+    /// `needs` can only name other steps of the same stage; anything else would never
+    /// complete and would not hold the step back either.
+    fn check_needs(stages: &[Stage]) -> anyhow::Result<()> {
+        for stage in stages.iter() {
+            let mut names: Vec<&str> = stage.steps.iter().map(|step| step.name.as_str()).collect();
+            names.dedup();
+
+            for step in stage.steps.iter() {
+                for need in step.needs.iter() {
+                    if step.matches_need(need) {
+                        anyhow::bail!("Step '{}' cannot need itself.", step.exploded_name);
+                    }
+                    if !stage.steps.iter().any(|other| other.matches_need(need)) {
+                        anyhow::bail!(
+                            "Step '{}' needs '{}', which is not a step of stage '{}'.{}",
+                            step.exploded_name,
+                            need,
+                            stage.name,
+                            Self::did_you_mean(need, &names)
+                        );
+                    }
+                }
+            }
+
+            if let Some(cycle) = Self::needs_cycle(stage) {
+                anyhow::bail!(
+                    "Steps of stage '{}' need each other in a cycle, so none can start: {}.",
+                    stage.name,
+                    cycle.join(" -> ")
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// A chain of steps of `stage` that each need the next, ending where it started.
+    fn needs_cycle(stage: &Stage) -> Option<Vec<&str>> {
+        // Steps that can start once the others have finished are taken out until only
+        // the cycles and the steps waiting on them are left.
+        let mut left: Vec<&Step> = stage.steps.iter().collect();
+        loop {
+            let before = left.clone();
+            left.retain(|step| before.iter().any(|other| step.depends_on(other)));
+            if left.len() == before.len() {
+                break;
+            }
+        }
+
+        // Each of those needs another one, so following the needs comes back around.
+        let mut chain = vec![*left.first()?];
+        loop {
+            let last = chain[chain.len() - 1];
+            let next = *left.iter().find(|other| last.depends_on(other))?;
+            if let Some(start) = chain.iter().position(|step| std::ptr::eq(*step, next)) {
+                let mut cycle: Vec<&str> = chain[start..]
+                    .iter()
+                    .map(|step| step.exploded_name.as_str())
+                    .collect();
+                cycle.push(&next.exploded_name);
+                return Some(cycle);
+            }
+            chain.push(next);
+        }
+    }
+
+    /// Fails on a `${{ NAME }}` left after interpolation, which would otherwise reach the
+    /// step as literal text.
+    fn unresolved_placeholder(step: &Step, matrix: Option<&MatrixConfig>) -> anyhow::Result<()> {
+        let fields = [
+            ("name", &step.exploded_name),
+            ("image", &step.image),
+            ("command", &step.command),
+        ]
+        .into_iter()
+        .chain(step.needs.iter().map(|need| ("needs", need)))
+        .chain(step.env.iter().flatten().map(|entry| ("env", entry)))
+        .chain(step.tags.iter().map(|tag| ("tags", tag)))
+        .chain(step.artifacts.iter().map(|path| ("artifacts", path)))
+        .chain(step.check.iter().map(|check| ("check", check)))
+        .chain(step.description.iter().map(|text| ("description", text)))
+        .chain(
+            step.concurrency_group
+                .iter()
+                .map(|group| ("concurrency_group", group)),
+        );
+
+        for (field, text) in fields {
+            let Some(captures) = PLACEHOLDER.captures(text) else {
+                continue;
+            };
+            let defined = match matrix {
+                Some(matrix) => format!("its matrix variable is '{}'", matrix.variable),
+                None => "it has no matrix".to_string(),
+            };
+            anyhow::bail!(
+                "Step '{}' uses unknown variable '{}' in '{}'; {}.",
+                step.name,
+                &captures[1],
+                field,
+                defined
+            );
+        }
+        Ok(())
+    }
+
+    /// The steps of a session take turns in their one container, in declaration order, so
+    /// each needs the one before it. The container is made from their common image.
+    fn chain_sessions(steps: &mut [Step]) -> anyhow::Result<()> {
+        let mut previous: HashMap<String, (String, String)> = HashMap::new();
+
+        for step in steps.iter_mut() {
+            let Some(session) = &step.session else {
+                continue;
+            };
+
+            if let Some((name, image)) = previous.get(session) {
+                if image != &step.image {
+                    anyhow::bail!(
+                        "Steps '{}' and '{}' share session '{}' but use different images ('{}' and '{}').",
+                        name,
+                        step.name,
+                        session,
+                        image,
+                        step.image
+                    );
+                }
+                if !step.needs.contains(name) {
+                    step.needs.push(name.clone());
+                }
+            }
+            previous.insert(session.clone(), (step.name.clone(), step.image.clone()));
+        }
+
+        Ok(())
+    }
+
+    /// A step using the image of another step must run after it: in an earlier stage, or
+    /// in the same stage with the producer listed in `needs`.
+    fn check_image_sources(stages: &[Stage]) -> anyhow::Result<()> {
+        for (index, stage) in stages.iter().enumerate() {
+            for step in stage.steps.iter() {
+                let Some(source) = &step.from_step else {
+                    continue;
+                };
+
+                let producer = stages
+                    .iter()
+                    .position(|other| other.steps.iter().any(|s| &s.name == source));
+
+                match producer {
+                    None => anyhow::bail!(
+                        "Step '{}' uses the image of step '{}', which does not exist.",
+                        step.exploded_name,
+                        source
+                    ),
+                    Some(_) if source == &step.name => anyhow::bail!(
+                        "Step '{}' cannot use an image built by itself.",
+                        step.exploded_name
+                    ),
+                    Some(producer) if producer > index => anyhow::bail!(
+                        "Step '{}' uses the image of step '{}', which runs in a later stage.",
+                        step.exploded_name,
+                        source
+                    ),
+                    Some(producer) if producer == index && !step.needs.contains(source) => {
+                        anyhow::bail!(
+                            "Step '{}' uses the image of step '{}' from the same stage. Add '{}' to its 'needs'.",
+                            step.exploded_name,
+                            source,
+                            source
+                        )
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct RawStage {
-    pub steps: BTreeMap<String, RawStep>,
+    pub description: Option<String>,
+    /// Default for the stage's steps.
+    pub workspace_isolation: Option<WorkspaceIsolation>,
+    /// Defaults for the stage's steps, above the pipeline defaults. Step values win, and
+    /// `env` entries are merged by name. Matrix values are interpolated into `env`.
+    pub env: Option<Vec<String>>,
+    pub memory: Option<String>,
+    pub timeout: Option<String>,
+    pub max_retries: Option<u32>,
+    /// Times the whole stage is run again when it fails, e.g. after a shared service
+    /// broke several of its steps at once. Unlike `max_retries`, steps that passed run
+    /// again too.
+    pub stage_retries: Option<u32>,
+    /// Kept in declaration order, which is the order steps are dispatched and reported in.
+    pub steps: IndexMap<String, RawStep>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct RawStep {
+    /// Required unless the step runs on the host.
+    #[serde(default)]
     pub image: String,
-    pub command: String,
+    /// Digest the pulled image must have, e.g. `sha256:...`, when `image` is a tag.
+    pub digest: Option<String>,
+    #[serde(default)]
+    pub runner: Runner,
+    /// Overrides the stage's `workspace_isolation`.
+    pub workspace_isolation: Option<WorkspaceIsolation>,
+    /// Paths, relative to the workspace, to copy back once an isolated step succeeds.
+    #[serde(default)]
+    pub artifacts: Vec<String>,
+    pub command: RawCommand,
+    /// Arguments to `set` at the top of scripts generated from a command list. Defaults to
+    /// `-eu` plus `-o pipefail` where the shell supports it.
+    pub shell_options: Option<String>,
+    /// `512mb`, `2gb` or `unlimited`. Falls back to the pipeline default.
     pub memory: Option<String>,
+    /// Swap on top of `memory`, or `unlimited`. Without it the step gets no swap.
+    pub swap: Option<String>,
+    /// Size limit of the container's writable layer, e.g. `10gb`. Needs overlay2 on xfs.
+    pub storage_limit: Option<String>,
+    /// Step names, or `name:value` for one variant of a matrix step.
     pub needs: Option<Vec<String>>,
+    /// With `same-variant`, each variant of a matrix step only needs the variant with the
+    /// same value of the matrix steps in `needs`.
+    #[serde(default)]
+    pub needs_mode: NeedsMode,
     pub env: Option<Vec<String>>,
     pub matrix: Option<MatrixConfig>,
     pub max_retries: Option<u32>,
     pub timeout: Option<String>,
+    /// Time all attempts together may take, e.g. `20m`. Retries that would start after it
+    /// are not made.
+    pub total_timeout: Option<String>,
+    /// Free-form explanation shown next to the step id. Matrix values are interpolated.
+    pub description: Option<String>,
+    /// Labels used by profiles and `--skip-tag`. Matrix values are interpolated.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Shorthand for adding the `quick` tag.
+    #[serde(default)]
+    pub quick: bool,
+    /// Start the container and leave it running for the rest of the pipeline.
+    #[serde(default)]
+    pub detach: bool,
+    /// Mount the engine's socket so the step can run `docker` itself.
+    #[serde(default)]
+    pub docker_socket: bool,
+    /// Additional `/etc/hosts` entries as `host:ip`, added to the pipeline defaults.
+    #[serde(default)]
+    pub extra_hosts: Vec<String>,
+    /// DNS servers, replacing the pipeline defaults.
+    pub dns: Option<Vec<String>>,
+    /// Make the host reachable as `host.docker.internal`. Overrides the pipeline default.
+    pub allow_host_access: Option<bool>,
+    /// Count a SIGKILL (exit code 137) as running out of memory. Overrides the pipeline
+    /// default.
+    pub sigkill_is_oom: Option<bool>,
+    /// Step that builds this step's image. The image is then never pulled.
+    pub from_step: Option<String>,
+    /// Condition a detached step must meet before its dependents may start.
+    pub wait_for: Option<RawWaitFor>,
+    /// Shorthand for `wait_for = { log = "..." }`.
+    pub ready_log: Option<String>,
+    /// Shorthand for `wait_for = { port = ... }`.
+    pub ready_port: Option<u16>,
+    /// File, relative to the workspace, written to the command's standard input.
+    pub stdin_file: Option<PathBuf>,
+    /// Problem matchers run on the step's output; the built-in ones when unset.
+    pub matchers: Option<Vec<String>>,
+    pub log_limit: Option<usize>,
+    /// Overrides the pipeline's `silence_warning`; `0s` turns the warnings off.
+    pub silence_warning: Option<String>,
+    /// Fail the step once it has been silent this long, e.g. `10m`.
+    pub silence_timeout: Option<String>,
+    /// Log driver and its options, e.g. to let a chatty step keep more output.
+    pub log_config: Option<LogConfig>,
+    /// Run in one container with the other steps of the stage in the same session.
+    pub session: Option<String>,
+    /// Command run in the container before `command`; the step fails as a precondition
+    /// when it exits non-zero.
+    pub check: Option<String>,
+    /// Let a failed `check` use up a retry instead of failing the step right away.
+    #[serde(default)]
+    pub retry_check: bool,
+    pub concurrency_group: Option<String>,
+    #[serde(default)]
+    pub priority: i32,
 }
 
 impl RawStep {
-    pub fn memory_limit(&self) -> anyhow::Result<i64> {
-        let mem = match &self.memory {
-            Some(raw) => raw.to_lowercase(),
-            None => return Ok(DEFAULT_MEMORY_LIMIT),
+    /// Whether `regex`, the placeholder of the matrix variable, appears in a field the
+    /// values are interpolated into. Without it the variants of the step are identical.
+    pub fn uses_matrix_variable(
+        &self,
+        regex: &Regex,
+        stage: &RawStage,
+        defaults: &RawDefaults,
+    ) -> bool {
+        let name_suffix = self
+            .matrix
+            .as_ref()
+            .and_then(|matrix| matrix.name_suffix.clone());
+        let mut fields = vec![self.image.clone(), self.script(str::to_string)];
+        fields.extend(name_suffix);
+        fields.extend(self.needs.iter().flatten().cloned());
+        fields.extend(self.env(stage, defaults).into_iter().flatten());
+        fields.extend(self.tags());
+        fields.extend(self.artifacts.iter().cloned());
+        fields.extend(self.check.iter().cloned());
+        fields.extend(self.description.iter().cloned());
+        fields.extend(self.concurrency_group.iter().cloned());
+
+        fields.iter().any(|field| regex.is_match(field))
+    }
+
+    /// Builds the `sh -c` script. A command list runs line by line, each echoed first so
+    /// the log shows which one failed.
+    pub fn script(&self, interpolate: impl Fn(&str) -> String) -> String {
+        let lines = match &self.command {
+            RawCommand::Script(script) => return interpolate(script),
+            RawCommand::Lines(lines) => lines,
+        };
+
+        let mut script = Vec::with_capacity(lines.len() * 2 + 2);
+        match self.shell_options.as_deref() {
+            Some(options) if options.trim().is_empty() => {}
+            Some(options) => script.push(format!("set {options}")),
+            // `pipefail` is missing from older dash releases, so only enable it where it exists.
+            None => {
+                script.push("set -eu".to_string());
+                script
+                    .push("if (set -o pipefail) 2>/dev/null; then set -o pipefail; fi".to_string());
+            }
+        }
+
+        for line in lines.iter().map(|line| interpolate(line)) {
+            script.push(format!(
+                "printf '+ %s\\n' '{}'",
+                line.replace('\'', r"'\''")
+            ));
+            script.push(line);
+        }
+
+        script.join("\n")
+    }
+
+    pub fn tags(&self) -> impl Iterator<Item = String> + '_ {
+        let quick = self
+            .quick
+            .then(|| QUICK_PROFILE.to_string())
+            .filter(|quick| !self.tags.contains(quick));
+
+        self.tags.iter().cloned().chain(quick)
+    }
+
+    pub fn extra_hosts(&self, defaults: &RawDefaults) -> Vec<String> {
+        let mut hosts: Vec<String> = defaults
+            .extra_hosts
+            .iter()
+            .chain(self.extra_hosts.iter())
+            .cloned()
+            .collect();
+
+        // Docker Desktop already resolves the name; on Linux it has to be mapped.
+        let host_access = self.allow_host_access.unwrap_or(defaults.allow_host_access);
+        if host_access && cfg!(target_os = "linux") {
+            hosts.push(HOST_GATEWAY.to_string());
+        }
+
+        hosts
+    }
+
+    pub fn dns(&self, defaults: &RawDefaults) -> Vec<String> {
+        self.dns.clone().unwrap_or_else(|| defaults.dns.clone())
+    }
+
+    /// Images built inside the pipeline are written as `local:<tag>`.
+    pub fn image_ref(image: &str) -> String {
+        image
+            .strip_prefix(LOCAL_IMAGE_SCHEME)
+            .unwrap_or(image)
+            .to_string()
+    }
+
+    /// A host step has no container to limit or connect, so container options are
+    /// rejected, or ignored with a warning where running without them is harmless.
+    pub fn check_runner(&self, step_id: &str) -> anyhow::Result<()> {
+        match self.runner {
+            Runner::Container => {
+                if self.image.is_empty() {
+                    anyhow::bail!("Step '{step_id}' needs an 'image', or 'runner = \"host\"'.");
+                }
+                return Ok(());
+            }
+            Runner::Kubernetes => return self.check_kubernetes(step_id),
+            Runner::Host => {}
+        }
+
+        let unsupported = [
+            ("image", !self.image.is_empty()),
+            ("digest", self.digest.is_some()),
+            ("detach", self.detach),
+            ("session", self.session.is_some()),
+            ("check", self.check.is_some()),
+            ("docker_socket", self.docker_socket),
+            ("from_step", self.from_step.is_some()),
+            ("artifacts", !self.artifacts.is_empty()),
+            (
+                "workspace_isolation",
+                self.workspace_isolation == Some(WorkspaceIsolation::Copy),
+            ),
+        ];
+        if let Some((option, _)) = unsupported.iter().find(|(_, set)| *set) {
+            anyhow::bail!("Step '{step_id}' runs on the host and cannot use '{option}'.");
+        }
+
+        let ignored: Vec<&str> = [
+            ("memory", self.memory.is_some()),
+            ("swap", self.swap.is_some()),
+            ("storage_limit", self.storage_limit.is_some()),
+            ("extra_hosts", !self.extra_hosts.is_empty()),
+            ("dns", self.dns.is_some()),
+            ("allow_host_access", self.allow_host_access.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(option, set)| set.then_some(option))
+        .collect();
+        if !ignored.is_empty() {
+            println!(
+                "{} Step '{}' runs on the host, which ignores {}.",
+                Icon::Warning,
+                step_id,
+                ignored.join(", ")
+            );
+        }
+
+        Ok(())
+    }
+
+    /// A pod has no engine socket, host directory or attached terminal, so the options
+    /// that rely on them are rejected rather than silently dropped.
+    fn check_kubernetes(&self, step_id: &str) -> anyhow::Result<()> {
+        if !cfg!(feature = "kubernetes") {
+            anyhow::bail!(
+                "Step '{step_id}' runs on Kubernetes, which needs ciroach built with the 'kubernetes' feature."
+            );
+        }
+        if self.image.is_empty() {
+            anyhow::bail!("Step '{step_id}' runs on Kubernetes and needs an 'image'.");
+        }
+
+        let unsupported = [
+            ("digest", self.digest.is_some()),
+            ("from_step", self.from_step.is_some()),
+            ("local:", self.local_image()),
+            ("detach", self.detach),
+            ("session", self.session.is_some()),
+            ("check", self.check.is_some()),
+            ("docker_socket", self.docker_socket),
+            ("artifacts", !self.artifacts.is_empty()),
+            (
+                "workspace_isolation",
+                self.workspace_isolation == Some(WorkspaceIsolation::Copy),
+            ),
+            ("stdin_file", self.stdin_file.is_some()),
+            ("swap", self.swap.is_some()),
+            ("extra_hosts", !self.extra_hosts.is_empty()),
+            ("dns", self.dns.is_some()),
+            ("allow_host_access", self.allow_host_access.is_some()),
+            ("log_config", self.log_config.is_some()),
+        ];
+        if let Some((option, _)) = unsupported.iter().find(|(_, set)| *set) {
+            anyhow::bail!("Step '{step_id}' runs on Kubernetes and cannot use '{option}'.");
+        }
+
+        Ok(())
+    }
+
+    /// `artifacts` are copied back from the step's own copy of the workspace, so they
+    /// need `copy`; a shared workspace has them in place already.
+    pub fn workspace_isolation(
+        &self,
+        step_id: &str,
+        stage: &RawStage,
+    ) -> anyhow::Result<WorkspaceIsolation> {
+        let isolation = match self.runner {
+            Runner::Host | Runner::Kubernetes => WorkspaceIsolation::Shared,
+            Runner::Container => self
+                .workspace_isolation
+                .or(stage.workspace_isolation)
+                .unwrap_or_default(),
+        };
+
+        if !self.artifacts.is_empty() && isolation != WorkspaceIsolation::Copy {
+            anyhow::bail!(
+                "Step '{step_id}' declares 'artifacts', which need 'workspace_isolation = \"copy\"'."
+            );
+        }
+        for artifact in self.artifacts.iter() {
+            let path = Path::new(artifact);
+            if path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+                anyhow::bail!(
+                    "Artifact '{artifact}' of step '{step_id}' must be a path inside the workspace."
+                );
+            }
+        }
+
+        Ok(isolation)
+    }
+
+    /// A detached step keeps running after its dependents start, so nothing waits for it
+    /// to read its input.
+    pub fn stdin(&self, step_id: &str) -> anyhow::Result<Option<StepInput>> {
+        let Some(path) = &self.stdin_file else {
+            return Ok(None);
+        };
+
+        if self.detach {
+            anyhow::bail!("Step '{step_id}' is detached and cannot use 'stdin_file'.");
+        }
+
+        Ok(Some(StepInput::File(path.clone())))
+    }
+
+    /// Each step of a session runs as a command in a container that is already running,
+    /// which leaves no place for a detached step, a matrix of them or their own input.
+    pub fn session(&self, step_id: &str) -> anyhow::Result<Option<String>> {
+        let Some(session) = &self.session else {
+            return Ok(None);
         };
 
-        let (digits, multiplier) = if mem.ends_with("gb") {
-            (mem.replace("gb", ""), 1024 * 1024 * 1024)
-        } else if mem.ends_with("mb") {
-            (mem.replace("mb", ""), 1024 * 1024)
-        } else if mem.ends_with("kb") {
-            (mem.replace("kb", ""), 1024)
-        } else {
-            (mem, 1)
+        let unsupported = [
+            ("detach", self.detach),
+            ("matrix", self.matrix.is_some()),
+            ("stdin_file", self.stdin_file.is_some()),
+        ];
+        if let Some((option, _)) = unsupported.iter().find(|(_, set)| *set) {
+            anyhow::bail!(
+                "Step '{step_id}' runs in session '{session}' and cannot use '{option}'."
+            );
+        }
+
+        Ok(Some(session.clone()))
+    }
+
+    /// The step's `needs` for the variant with matrix value `variant`. Entries naming a
+    /// variant stay `name:value` until the stage's steps exist.
+    pub fn needs(
+        &self,
+        step_id: &str,
+        stage: &RawStage,
+        variant: Option<&str>,
+        interpolate: impl Fn(&str) -> String,
+    ) -> anyhow::Result<Vec<String>> {
+        let pair = match (self.needs_mode, variant) {
+            (NeedsMode::All, _) => None,
+            (NeedsMode::SameVariant, Some(variant)) => Some(variant),
+            (NeedsMode::SameVariant, None) => anyhow::bail!(
+                "Step '{step_id}' sets 'needs_mode = \"same-variant\"' but has no matrix."
+            ),
         };
 
-        let value = digits.trim().parse::<i64>().map_err(|_| {
-            anyhow::anyhow!("Invalid memory format: '{}'. Use '512mb' or '1gb'", digits)
-        })?;
+        Ok(self
+            .needs
+            .iter()
+            .flatten()
+            .map(|need| {
+                let need = interpolate(need);
+                let is_matrix = stage
+                    .steps
+                    .get(&need)
+                    .is_some_and(|needed| needed.matrix.is_some());
+                match pair {
+                    Some(variant) if is_matrix => format!("{need}:{variant}"),
+                    _ => need,
+                }
+            })
+            .collect())
+    }
 
-        Ok(value * multiplier)
+    /// The check runs through the engine's exec API in the running container, which a
+    /// detached step hands over to the pipeline instead.
+    pub fn check(&self, step_id: &str) -> anyhow::Result<Option<String>> {
+        let Some(check) = &self.check else {
+            return Ok(None);
+        };
+
+        if self.detach {
+            anyhow::bail!("Step '{step_id}' is detached and cannot use 'check'.");
+        }
+
+        Ok(Some(check.clone()))
     }
 
-    pub fn timeout(&self) -> anyhow::Result<std::time::Duration> {
-        let time = match &self.timeout {
-            Some(raw) => raw.to_lowercase(),
-            None => return Ok(Duration::from_secs(60 * 60)),
+    /// `sha256:` and 64 hex digits, checked against what gets pulled for a tag.
+    pub fn digest(&self, step_id: &str) -> anyhow::Result<Option<String>> {
+        let Some(digest) = &self.digest else {
+            return Ok(None);
         };
 
-        let (digits, multiplier) = if time.ends_with("h") {
-            (time.replace("h", ""), 60 * 60)
-        } else if time.ends_with("m") {
-            (time.replace("m", ""), 60)
-        } else if time.ends_with("s") {
-            (time.replace("s", ""), 1)
-        } else {
-            (time, 1)
+        let valid = digest
+            .strip_prefix("sha256:")
+            .is_some_and(|hex| hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()));
+        if !valid {
+            anyhow::bail!(
+                "Step '{step_id}' has an invalid 'digest' '{digest}'. Expected 'sha256:' and 64 hex digits."
+            );
+        }
+        if self.image.contains('@') {
+            anyhow::bail!(
+                "Step '{step_id}' pins its image by digest already. Remove 'digest' or the '@sha256:...' part of 'image'."
+            );
+        }
+        if self.local_image() {
+            anyhow::bail!(
+                "Step '{step_id}' uses an image built in the pipeline, which has no registry digest to check."
+            );
+        }
+
+        Ok(Some(digest.clone()))
+    }
+
+    pub fn local_image(&self) -> bool {
+        self.image.starts_with(LOCAL_IMAGE_SCHEME) || self.from_step.is_some()
+    }
+
+    /// `None` means no limit.
+    pub fn memory_limit(
+        &self,
+        stage: &RawStage,
+        defaults: &RawDefaults,
+    ) -> anyhow::Result<Option<i64>> {
+        let memory = self
+            .memory
+            .as_ref()
+            .or(stage.memory.as_ref())
+            .or(defaults.memory.as_ref());
+        match memory {
+            Some(raw) => parse_memory(raw),
+            None => Ok(Some(DEFAULT_MEMORY_LIMIT)),
+        }
+    }
+
+    /// Docker's memory plus swap: equal to `memory` disables swap, `-1` lifts its limit.
+    pub fn memory_swap(&self, memory: Option<i64>) -> anyhow::Result<Option<i64>> {
+        let Some(memory) = memory else {
+            return Ok(None);
         };
 
-        let value = digits.trim().parse::<u64>().map_err(|_| {
-            anyhow::anyhow!(
-                "Invalid timeout format: '{}'. Use '1h', '30m', or '5s'",
-                digits
-            )
-        })?;
+        match &self.swap {
+            Some(raw) => Ok(Some(parse_memory(raw)?.map_or(-1, |swap| memory + swap))),
+            None => Ok(Some(memory)),
+        }
+    }
 
-        Ok(Duration::from_secs(value * multiplier))
+    pub fn storage_limit(&self) -> anyhow::Result<Option<i64>> {
+        Ok(self
+            .storage_limit
+            .as_deref()
+            .map(parse_memory)
+            .transpose()?
+            .flatten())
+    }
+
+    pub fn timeout(
+        &self,
+        stage: &RawStage,
+        defaults: &RawDefaults,
+    ) -> anyhow::Result<std::time::Duration> {
+        let timeout = self
+            .timeout
+            .as_ref()
+            .or(stage.timeout.as_ref())
+            .or(defaults.timeout.as_ref());
+        match timeout {
+            Some(raw) => parse_duration(raw),
+            None => Ok(Duration::from_secs(60 * 60)),
+        }
+    }
+
+    pub fn log_limit(&self, defaults: &RawDefaults) -> usize {
+        self.log_limit
+            .or(defaults.log_limit)
+            .unwrap_or(DEFAULT_LOG_LIMIT)
+    }
+
+    pub fn silence_warning(&self, defaults: &RawDefaults) -> anyhow::Result<Option<Duration>> {
+        let every = match self
+            .silence_warning
+            .as_ref()
+            .or(defaults.silence_warning.as_ref())
+        {
+            Some(raw) => parse_duration(raw)?,
+            None => DEFAULT_SILENCE_WARNING,
+        };
+        Ok((!every.is_zero()).then_some(every))
+    }
+
+    pub fn total_timeout(&self) -> anyhow::Result<Option<Duration>> {
+        self.total_timeout
+            .as_deref()
+            .map(parse_duration)
+            .transpose()
+    }
+
+    pub fn silence_timeout(&self) -> anyhow::Result<Option<Duration>> {
+        self.silence_timeout
+            .as_deref()
+            .map(parse_duration)
+            .transpose()
+    }
+
+    pub fn max_retries(&self, stage: &RawStage, defaults: &RawDefaults) -> u32 {
+        self.max_retries
+            .or(stage.max_retries)
+            .or(defaults.max_retries)
+            .unwrap_or(0)
+    }
+
+    /// The pipeline's default `env`, the stage's on top of it, and the step's on top of
+    /// that; an entry replaces the one of the same name below it.
+    pub fn env(&self, stage: &RawStage, defaults: &RawDefaults) -> Option<Vec<String>> {
+        let layers = [&defaults.env, &stage.env, &self.env];
+        if layers.iter().all(|layer| layer.is_none()) {
+            return None;
+        }
+
+        let name = |entry: &str| entry.split('=').next().unwrap_or_default().to_string();
+        let mut merged: Vec<String> = Vec::new();
+        for entry in layers.into_iter().flatten().flatten() {
+            merged.retain(|kept| name(kept) != name(entry));
+            merged.push(entry.clone());
+        }
+        Some(merged)
+    }
+
+    pub fn matchers(
+        &self,
+        step_id: &str,
+        defined: &HashMap<String, Matcher>,
+    ) -> anyhow::Result<Vec<Matcher>> {
+        let names: Vec<&str> = match &self.matchers {
+            Some(names) => names.iter().map(String::as_str).collect(),
+            None => Matcher::DEFAULTS.to_vec(),
+        };
+
+        names
+            .into_iter()
+            .map(|name| {
+                defined.get(name).cloned().ok_or_else(|| {
+                    let mut known: Vec<&str> = defined.keys().map(String::as_str).collect();
+                    known.sort();
+                    anyhow::anyhow!(
+                        "Step '{}' uses the matcher '{}', which is not defined.{} Defined matchers: {}",
+                        step_id,
+                        name,
+                        RawPipeline::did_you_mean(name, &known),
+                        known.join(", ")
+                    )
+                })
+            })
+            .collect()
+    }
+
+    pub fn wait_for(&self, step_id: &str) -> anyhow::Result<Option<WaitFor>> {
+        let (log, port, timeout) = match (&self.wait_for, &self.ready_log, self.ready_port) {
+            (Some(_), Some(_), _) | (Some(_), _, Some(_)) => {
+                anyhow::bail!("Step '{step_id}' sets both 'wait_for' and 'ready_log'/'ready_port'.")
+            }
+            (Some(wait_for), None, None) => {
+                let timeout = wait_for
+                    .timeout
+                    .as_deref()
+                    .map(parse_duration)
+                    .transpose()?;
+                (wait_for.log.clone(), wait_for.port, timeout)
+            }
+            (None, log, port) => (log.clone(), port, None),
+        };
+
+        let condition = match (log, port) {
+            (Some(_), Some(_)) => {
+                anyhow::bail!("Step '{step_id}' waits for both a log pattern and a port. Pick one.")
+            }
+            (Some(pattern), None) => {
+                Regex::new(&pattern).map_err(|err| {
+                    anyhow::anyhow!("Invalid readiness log pattern in step '{step_id}': {err}")
+                })?;
+                ReadyCondition::Log(pattern)
+            }
+            (None, Some(port)) => ReadyCondition::Port(port),
+            (None, None) if self.wait_for.is_some() => {
+                anyhow::bail!("'wait_for' in step '{step_id}' needs a 'log' or a 'port'.")
+            }
+            (None, None) => return Ok(None),
+        };
+
+        if !self.detach {
+            anyhow::bail!(
+                "Step '{step_id}' has a readiness condition but is not 'detach = true'. Dependents of a regular step already wait for it to finish."
+            );
+        }
+
+        Ok(Some(WaitFor { condition, timeout }))
+    }
+}
+
+/// Bytes from `512mb`, `2gb` or `64kb`; `None` for `unlimited`.
+fn parse_memory(raw: &str) -> anyhow::Result<Option<i64>> {
+    let mem = raw.to_lowercase();
+    if mem == "unlimited" {
+        return Ok(None);
+    }
+
+    let (digits, multiplier) = if mem.ends_with("gb") {
+        (mem.replace("gb", ""), 1024 * 1024 * 1024)
+    } else if mem.ends_with("mb") {
+        (mem.replace("mb", ""), 1024 * 1024)
+    } else if mem.ends_with("kb") {
+        (mem.replace("kb", ""), 1024)
+    } else {
+        (mem, 1)
+    };
+
+    let value = digits.trim().parse::<i64>().map_err(|_| {
+        anyhow::anyhow!(
+            "Invalid memory format: '{}'. Use '512mb', '1gb' or 'unlimited'",
+            digits
+        )
+    })?;
+
+    Ok(Some(value * multiplier))
+}
+
+pub fn parse_duration(raw: &str) -> anyhow::Result<Duration> {
+    let time = raw.to_lowercase();
+
+    let (digits, multiplier) = if time.ends_with("d") {
+        (time.replace("d", ""), 24 * 60 * 60)
+    } else if time.ends_with("h") {
+        (time.replace("h", ""), 60 * 60)
+    } else if time.ends_with("m") {
+        (time.replace("m", ""), 60)
+    } else if time.ends_with("s") {
+        (time.replace("s", ""), 1)
+    } else {
+        (time, 1)
+    };
+
+    let value = digits.trim().parse::<u64>().map_err(|_| {
+        anyhow::anyhow!(
+            "Invalid timeout format: '{}'. Use '1h', '30m', or '5s'",
+            digits
+        )
+    })?;
+
+    Ok(Duration::from_secs(value * multiplier))
+}
+
+/// `wait_for = { log = "...", timeout = "30s" }` or `wait_for = { port = 5432 }`.
+#[derive(Debug, Deserialize)]
+pub struct RawWaitFor {
+    pub log: Option<String>,
+    pub port: Option<u16>,
+    pub timeout: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct RawEngineConfig {
+    pub pull: RawPullConfig,
+    pub stall_timeout: Option<String>,
+    /// Free space Docker needs before a run starts, e.g. `5gb`.
+    pub min_free_space: Option<String>,
+    pub on_low_space: LowSpacePolicy,
+    pub strict_resources: bool,
+    /// Time given to steps to stop after `SIGINT` or `SIGTERM`, e.g. `30s`.
+    pub drain_timeout: Option<String>,
+    pub kubernetes: RawKubernetesConfig,
+}
+
+/// `[engine.pull]`: retries and time limits of image pulls.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct RawPullConfig {
+    pub attempts: Option<u32>,
+    pub backoff: Option<String>,
+    pub timeout: Option<String>,
+    pub deadline: Option<String>,
+    pub concurrency: Option<usize>,
+}
+
+/// `[engine.kubernetes]`: the namespace steps with `runner = "kubernetes"` run in, and
+/// where their workspace comes from.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct RawKubernetesConfig {
+    pub namespace: Option<String>,
+    /// Persistent volume claim mounted as the workspace, instead of a fresh clone per pod.
+    pub workspace_claim: Option<String>,
+    pub repository: Option<String>,
+    pub revision: Option<String>,
+    pub clone_image: Option<String>,
+    pub service_account: Option<String>,
+}
+
+impl RawKubernetesConfig {
+    fn compile(&self) -> KubernetesConfig {
+        let defaults = KubernetesConfig::default();
+        KubernetesConfig {
+            namespace: self.namespace.clone().unwrap_or(defaults.namespace),
+            workspace_claim: self.workspace_claim.clone(),
+            repository: self.repository.clone(),
+            revision: self.revision.clone(),
+            clone_image: self.clone_image.clone().unwrap_or(defaults.clone_image),
+            service_account: self.service_account.clone(),
+        }
+    }
+}
+
+impl RawEngineConfig {
+    fn duration(raw: &Option<String>, default: Duration) -> anyhow::Result<Duration> {
+        match raw {
+            Some(raw) => parse_duration(raw),
+            None => Ok(default),
+        }
+    }
+
+    fn compile(&self) -> anyhow::Result<EngineConfig> {
+        let defaults = PullConfig::default();
+        let engine_defaults = EngineConfig::default();
+
+        let attempts = self.pull.attempts.unwrap_or(defaults.attempts);
+        if attempts == 0 {
+            anyhow::bail!("'engine.pull.attempts' must be at least 1.");
+        }
+
+        let concurrency = self.pull.concurrency.unwrap_or(defaults.concurrency);
+        if concurrency == 0 {
+            anyhow::bail!("'engine.pull.concurrency' must be at least 1.");
+        }
+
+        Ok(EngineConfig {
+            pull: PullConfig {
+                attempts,
+                backoff: Self::duration(&self.pull.backoff, defaults.backoff)?,
+                timeout: Self::duration(&self.pull.timeout, defaults.timeout)?,
+                deadline: Self::duration(&self.pull.deadline, defaults.deadline)?,
+                concurrency,
+            },
+            stall_timeout: self
+                .stall_timeout
+                .as_deref()
+                .map(parse_duration)
+                .transpose()?,
+            min_free_space: self
+                .min_free_space
+                .as_deref()
+                .map(parse_memory)
+                .transpose()?
+                .flatten(),
+            on_low_space: self.on_low_space,
+            strict_resources: self.strict_resources,
+            drain_timeout: Self::duration(&self.drain_timeout, engine_defaults.drain_timeout)?,
+            kubernetes: self.kubernetes.compile(),
+        })
     }
 }
 
+/// `[logs]`: how many run directories to keep, and for how long, and which lines to show
+/// after a run.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct RawLogsConfig {
+    pub keep: Option<usize>,
+    pub max_age: Option<String>,
+    pub filter: Option<String>,
+    pub context: Option<usize>,
+}
+
+impl RawLogsConfig {
+    fn compile(&self) -> anyhow::Result<LogsConfig> {
+        let defaults = LogsConfig::default();
+
+        Ok(LogsConfig {
+            keep: self.keep.unwrap_or(defaults.keep),
+            max_age: self.max_age.as_deref().map(parse_duration).transpose()?,
+            filter: self
+                .filter
+                .as_deref()
+                .map(Regex::new)
+                .transpose()
+                .map_err(|err| anyhow::anyhow!("Invalid 'filter' under [logs]: {err}"))?,
+            context: self.context.unwrap_or(defaults.context),
+        })
+    }
+}
+
+/// Settings applied to every step unless the step sets its own.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct RawDefaults {
+    pub extra_hosts: Vec<String>,
+    pub dns: Vec<String>,
+    pub allow_host_access: bool,
+    pub sigkill_is_oom: bool,
+    /// Memory limit of steps that set none; 512mb unless given.
+    pub memory: Option<String>,
+    /// Environment of every step, beneath the stage's and the step's own.
+    pub env: Option<Vec<String>>,
+    /// Time limit of steps whose stage sets none either; 1h unless given.
+    pub timeout: Option<String>,
+    pub max_retries: Option<u32>,
+    /// Lines of output kept per step, see [`Step::log_limit`].
+    pub log_limit: Option<usize>,
+    /// Warn about steps silent for this long, and again each time as long; 2m unless given.
+    pub silence_warning: Option<String>,
+}
+
+/// `command` is either a ready-made shell script or a list of commands.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum RawCommand {
+    Script(String),
+    Lines(Vec<String>),
+}
+
 #[derive(Debug, Deserialize)]
 pub struct MatrixConfig {
     pub variable: String,
     pub values: Vec<String>,
+    /// Appended to the step name to name each variant, e.g. `-rust${{ version }}`.
+    /// Defaults to `-` and the value.
+    pub name_suffix: Option<String>,
+}
+
+/// Which variants of a needed matrix step a matrix step waits for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NeedsMode {
+    #[default]
+    All,
+    SameVariant,
+}
+
+impl MatrixConfig {
+    /// Matches `${{ VARIABLE }}`, with or without spaces inside the braces.
+    fn placeholder(&self) -> anyhow::Result<Regex> {
+        let pattern = format!(r"\$\{{\{{\s*{}\s*\}}\}}", escape(&self.variable));
+        Ok(Regex::new(&pattern)?)
+    }
+
+    /// Every variant needs its own name, so a custom suffix has to use the variable.
+    fn name_suffix<'a>(&'a self, step_id: &str, regex: &Regex) -> anyhow::Result<Option<&'a str>> {
+        match self.name_suffix.as_deref() {
+            Some(suffix) if !regex.is_match(suffix) => anyhow::bail!(
+                "Step '{}' has a matrix 'name_suffix' without '${{{{ {} }}}}'; its variants would share one name.",
+                step_id,
+                self.variable
+            ),
+            suffix => Ok(suffix),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: i64 = 1024 * 1024;
+
+    fn compile(config: &str) -> anyhow::Result<Pipeline> {
+        toml::from_str::<RawPipeline>(config)?
+            .select(None)
+            .and_then(|raw| raw.compile(&[]))
+    }
+
+    fn step<'p>(pipeline: &'p Pipeline, name: &str) -> &'p Step {
+        pipeline
+            .stages
+            .iter()
+            .flat_map(|stage| &stage.steps)
+            .find(|step| step.exploded_name == name)
+            .unwrap()
+    }
+
+    /// Three steps in a stage with defaults, one setting everything itself, and one in a
+    /// stage without any.
+    const LAYERED: &str = r#"
+        stages_order = ["integration", "unit"]
+
+        [defaults]
+        memory = "1gb"
+        env = ["LEVEL=pipeline", "PIPELINE=1"]
+        timeout = "30m"
+        max_retries = 1
+
+        [stages.integration]
+        memory = "2gb"
+        env = ["LEVEL=stage", "STAGE=1"]
+        timeout = "20m"
+        max_retries = 2
+
+        [stages.integration.steps.api]
+        image = "alpine"
+        command = "true"
+
+        [stages.integration.steps.db]
+        image = "alpine"
+        command = "true"
+        memory = "3gb"
+        env = ["LEVEL=step", "STEP=1"]
+        timeout = "10m"
+        max_retries = 3
+
+        [stages.unit.steps.lib]
+        image = "alpine"
+        command = "true"
+    "#;
+
+    #[test]
+    fn step_settings_win_over_the_stage() {
+        let pipeline = compile(LAYERED).unwrap();
+        let db = step(&pipeline, "db");
+
+        assert_eq!(db.memory, Some(3 * 1024 * MIB));
+        assert_eq!(db.timeout, Duration::from_secs(10 * 60));
+        assert_eq!(db.max_retries, 3);
+        assert_eq!(
+            db.env.as_deref().unwrap(),
+            ["PIPELINE=1", "STAGE=1", "LEVEL=step", "STEP=1"]
+        );
+    }
+
+    #[test]
+    fn stage_settings_win_over_the_pipeline_defaults() {
+        let pipeline = compile(LAYERED).unwrap();
+        let api = step(&pipeline, "api");
+
+        assert_eq!(api.memory, Some(2 * 1024 * MIB));
+        assert_eq!(api.timeout, Duration::from_secs(20 * 60));
+        assert_eq!(api.max_retries, 2);
+        assert_eq!(
+            api.env.as_deref().unwrap(),
+            ["PIPELINE=1", "LEVEL=stage", "STAGE=1"]
+        );
+    }
+
+    #[test]
+    fn pipeline_defaults_apply_to_stages_without_settings() {
+        let pipeline = compile(LAYERED).unwrap();
+        let lib = step(&pipeline, "lib");
+
+        assert_eq!(lib.memory, Some(1024 * MIB));
+        assert_eq!(lib.timeout, Duration::from_secs(30 * 60));
+        assert_eq!(lib.max_retries, 1);
+        assert_eq!(
+            lib.env.as_deref().unwrap(),
+            ["LEVEL=pipeline", "PIPELINE=1"]
+        );
+    }
+
+    #[test]
+    fn built_in_defaults_apply_without_any_settings() {
+        let pipeline = compile(
+            r#"
+            stages_order = ["unit"]
+
+            [stages.unit.steps.lib]
+            image = "alpine"
+            command = "true"
+            "#,
+        )
+        .unwrap();
+        let lib = step(&pipeline, "lib");
+
+        assert_eq!(lib.memory, Some(DEFAULT_MEMORY_LIMIT));
+        assert_eq!(lib.timeout, Duration::from_secs(60 * 60));
+        assert_eq!(lib.max_retries, 0);
+        assert_eq!(lib.env, None);
+    }
+
+    #[test]
+    fn stage_env_is_interpolated_for_each_variant() {
+        let pipeline = compile(
+            r#"
+            stages_order = ["integration"]
+
+            [stages.integration]
+            env = ["NODE_VERSION=${{ version }}", "CACHE=/cache/${{ version }}"]
+
+            [stages.integration.steps.test]
+            image = "node:${{ version }}"
+            command = "npm test"
+            env = ["CACHE=/tmp/${{ version }}"]
+            matrix = { variable = "version", values = ["20", "22"] }
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            step(&pipeline, "test-20").env.as_deref().unwrap(),
+            ["NODE_VERSION=20", "CACHE=/tmp/20"]
+        );
+        assert_eq!(
+            step(&pipeline, "test-22").env.as_deref().unwrap(),
+            ["NODE_VERSION=22", "CACHE=/tmp/22"]
+        );
+    }
+
+    #[test]
+    fn var_values_passed_to_compile_win_over_the_file() {
+        let raw = toml::from_str::<RawPipeline>(
+            r#"
+            stages_order = ["build"]
+
+            [vars]
+            registry = "ghcr.io"
+            tag = "latest"
+
+            [stages.build.steps.image]
+            image = "${{ vars.registry }}/app:${{ vars.tag }}"
+            command = "true"
+            "#,
+        )
+        .unwrap();
+
+        let pipeline = raw
+            .select(None)
+            .and_then(|raw| raw.compile(&[("tag".to_string(), "v2".to_string())]))
+            .unwrap();
+        assert_eq!(step(&pipeline, "image").image, "ghcr.io/app:v2");
+    }
+
+    /// Each step of the flat pipeline with the `needs` it compiled to.
+    fn flat_needs(steps: &str) -> Vec<(String, Vec<String>)> {
+        let flat: RawFlatPipeline = toml::from_str(steps).unwrap();
+        flat.into_stage()
+            .unwrap()
+            .steps
+            .into_iter()
+            .map(|(name, step)| (name, step.needs.unwrap_or_default()))
+            .collect()
+    }
+
+    fn needs(expected: &[(&str, &[&str])]) -> Vec<(String, Vec<String>)> {
+        expected
+            .iter()
+            .map(|(name, needs)| {
+                let needs = needs.iter().map(|need| need.to_string()).collect();
+                (name.to_string(), needs)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn a_parallel_group_needs_the_step_before_and_is_needed_by_the_one_after() {
+        let steps = r#"
+            [[steps]]
+            name = "fetch"
+            command = "true"
+
+            [[steps]]
+            name = "lint"
+            parallel = true
+            command = "true"
+
+            [[steps]]
+            name = "test"
+            parallel = true
+            command = "true"
+
+            [[steps]]
+            name = "package"
+            command = "true"
+        "#;
+
+        assert_eq!(
+            flat_needs(steps),
+            needs(&[
+                ("fetch", &[]),
+                ("lint", &["fetch"]),
+                ("test", &["fetch"]),
+                ("package", &["lint", "test"]),
+            ])
+        );
+    }
+
+    #[test]
+    fn a_leading_parallel_group_needs_nothing() {
+        let steps = r#"
+            [[steps]]
+            name = "lint"
+            parallel = true
+            command = "true"
+
+            [[steps]]
+            name = "test"
+            parallel = true
+            command = "true"
+
+            [[steps]]
+            name = "package"
+            command = "true"
+        "#;
+
+        assert_eq!(
+            flat_needs(steps),
+            needs(&[("lint", &[]), ("test", &[]), ("package", &["lint", "test"]),])
+        );
+    }
+
+    #[test]
+    fn sequential_steps_each_need_the_one_before() {
+        let steps = r#"
+            [[steps]]
+            name = "fetch"
+            command = "true"
+
+            [[steps]]
+            name = "build"
+            command = "true"
+
+            [[steps]]
+            name = "package"
+            command = "true"
+        "#;
+
+        assert_eq!(
+            flat_needs(steps),
+            needs(&[
+                ("fetch", &[]),
+                ("build", &["fetch"]),
+                ("package", &["build"]),
+            ])
+        );
+    }
+
+    #[test]
+    fn a_parallel_step_after_a_sequential_one_starts_a_new_group() {
+        let steps = r#"
+            [[steps]]
+            name = "lint"
+            parallel = true
+            command = "true"
+
+            [[steps]]
+            name = "build"
+            command = "true"
+
+            [[steps]]
+            name = "test"
+            parallel = true
+            command = "true"
+
+            [[steps]]
+            name = "docs"
+            parallel = true
+            command = "true"
+        "#;
+
+        assert_eq!(
+            flat_needs(steps),
+            needs(&[
+                ("lint", &[]),
+                ("build", &["lint"]),
+                ("test", &["build"]),
+                ("docs", &["build"]),
+            ])
+        );
+    }
+
+    #[test]
+    fn flat_steps_cannot_declare_needs() {
+        let flat: RawFlatPipeline = toml::from_str(
+            r#"
+            [[steps]]
+            name = "fetch"
+            command = "true"
+
+            [[steps]]
+            name = "build"
+            command = "true"
+            needs = ["fetch"]
+            "#,
+        )
+        .unwrap();
+
+        let err = flat.into_stage().unwrap_err();
+        assert!(err.to_string().contains("cannot use 'needs'"), "{err}");
+    }
+
+    #[test]
+    fn a_needs_cycle_is_rejected() {
+        let err = compile(
+            r#"
+            stages_order = ["build"]
+
+            [stages.build.steps.fetch]
+            runner = "host"
+            command = "true"
+
+            [stages.build.steps.compile]
+            runner = "host"
+            command = "true"
+            needs = ["fetch", "link"]
+
+            [stages.build.steps.link]
+            runner = "host"
+            command = "true"
+            needs = ["compile"]
+
+            [stages.build.steps.package]
+            runner = "host"
+            command = "true"
+            needs = ["link"]
+            "#,
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "Steps of stage 'build' need each other in a cycle, so none can start: compile -> link -> compile."
+        );
+    }
+
+    /// A matrix step in its own stage, with `fields` set on top of an image and command
+    /// that use the variable.
+    fn matrix_step(fields: &[(&str, &str)]) -> String {
+        let mut step = vec![
+            ("image", r#""rust:${{ version }}""#),
+            ("command", r#""cargo test""#),
+            (
+                "matrix",
+                r#"{ variable = "version", values = ["1.80", "1.81"] }"#,
+            ),
+        ];
+        for (key, value) in fields {
+            step.retain(|(existing, _)| existing != key);
+            step.push((key, value));
+        }
+        let step: Vec<String> = step
+            .iter()
+            .map(|(key, value)| format!("{key} = {value}"))
+            .collect();
+
+        format!(
+            "stages_order = [\"test\"]\n\n[stages.test.steps.rust]\n{}\n",
+            step.join("\n")
+        )
+    }
+
+    #[test]
+    fn matrix_values_are_substituted_into_every_field() {
+        let pipeline = compile(&matrix_step(&[
+            (
+                "matrix",
+                r#"{ variable = "version", values = ["1.80", "1.81"], name_suffix = "-rust${{ version }}" }"#,
+            ),
+            ("command", r#""cargo +${{ version }} test""#),
+            ("env", r#"["TOOLCHAIN=${{ version }}"]"#),
+            ("tags", r#"["rust-${{ version }}"]"#),
+            ("workspace_isolation", r#""copy""#),
+            ("artifacts", r#"["target/${{ version }}"]"#),
+            ("check", r#""rustc +${{ version }} --version""#),
+            ("description", r#""Tests on ${{ version }}""#),
+            ("concurrency_group", r#""cache-${{ version }}""#),
+        ]))
+        .unwrap();
+
+        for version in ["1.80", "1.81"] {
+            let rust = step(&pipeline, &format!("rust-rust{version}"));
+            assert_eq!(rust.name, "rust");
+            assert_eq!(rust.image, format!("rust:{version}"));
+            assert_eq!(rust.command, format!("cargo +{version} test"));
+            assert_eq!(
+                rust.env.as_deref().unwrap(),
+                [format!("TOOLCHAIN={version}")]
+            );
+            assert_eq!(rust.tags, [format!("rust-{version}")]);
+            assert_eq!(rust.artifacts, [format!("target/{version}")]);
+            assert_eq!(
+                rust.check.as_deref(),
+                Some(format!("rustc +{version} --version").as_str())
+            );
+            assert_eq!(
+                rust.description.as_deref(),
+                Some(format!("Tests on {version}").as_str())
+            );
+            assert_eq!(
+                rust.concurrency_group.as_deref(),
+                Some(format!("cache-{version}").as_str())
+            );
+            assert_eq!(
+                rust.matrix_values,
+                Some(BTreeMap::from([(
+                    "version".to_string(),
+                    version.to_string()
+                )]))
+            );
+        }
+    }
+
+    #[test]
+    fn needs_of_a_matrix_step_are_interpolated() {
+        let pipeline = compile(
+            r#"
+            stages_order = ["test"]
+
+            [stages.test.steps.build]
+            image = "rust:${{ version }}"
+            command = "cargo build"
+            matrix = { variable = "version", values = ["1.80", "1.81"] }
+
+            [stages.test.steps.test]
+            image = "rust:${{ target }}"
+            command = "cargo test"
+            needs = ["build-${{ target }}"]
+            matrix = { variable = "target", values = ["1.80", "1.81"] }
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(step(&pipeline, "test-1.80").needs, ["build-1.80"]);
+        assert_eq!(step(&pipeline, "test-1.81").needs, ["build-1.81"]);
+    }
+
+    #[test]
+    fn an_unknown_placeholder_fails_in_any_field() {
+        let unknown = [
+            (
+                "name",
+                "matrix",
+                r#"{ variable = "version", values = ["1.80"], name_suffix = "-${{ version }}-${{ os }}" }"#,
+            ),
+            ("image", "image", r#""rust:${{ version }}-${{ os }}""#),
+            ("command", "command", r#""cargo test --target ${{ os }}""#),
+            ("env", "env", r#"["TARGET=${{ os }}"]"#),
+            ("tags", "tags", r#"["${{ os }}"]"#),
+            ("check", "check", r#""test -d ${{ os }}""#),
+            ("description", "description", r#""Tests on ${{ os }}""#),
+            ("concurrency_group", "concurrency_group", r#""${{ os }}""#),
+        ];
+
+        for (field, key, value) in unknown {
+            let err = compile(&matrix_step(&[(key, value)])).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!(
+                    "Step 'rust' uses unknown variable 'os' in '{field}'; its matrix variable is 'version'."
+                )
+            );
+        }
+
+        let err = compile(&matrix_step(&[
+            ("workspace_isolation", r#""copy""#),
+            ("artifacts", r#"["target/${{ os }}"]"#),
+        ]))
+        .unwrap_err();
+        assert!(err.to_string().contains("in 'artifacts'"), "{err}");
+    }
+
+    #[test]
+    fn a_typo_in_the_matrix_variable_is_an_unknown_placeholder() {
+        let err = compile(&matrix_step(&[
+            ("image", r#""rust:${{ version }}""#),
+            (
+                "matrix",
+                r#"{ variable = "ver", values = ["1.80", "1.81"] }"#,
+            ),
+        ]))
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Step 'rust' uses unknown variable 'version' in 'image'; its matrix variable is 'ver'."
+        );
+
+        let err = compile(
+            r#"
+            stages_order = ["test"]
+
+            [stages.test.steps.rust]
+            image = "rust:${{ version }}"
+            command = "cargo test"
+            "#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().ends_with("it has no matrix."), "{err}");
+    }
+
+    /// Whether the `rust` step of [`matrix_step`] with `fields` uses its matrix variable.
+    fn uses_matrix_variable(stage_env: Option<&str>, fields: &[(&str, &str)]) -> bool {
+        let mut config = matrix_step(fields);
+        if let Some(env) = stage_env {
+            config.push_str(&format!("\n[stages.test]\nenv = {env}\n"));
+        }
+        let pipeline: RawPipeline = toml::from_str(&config).unwrap();
+        let stage = &pipeline.stages["test"];
+        let rust = &stage.steps["rust"];
+        let regex = rust.matrix.as_ref().unwrap().placeholder().unwrap();
+
+        rust.uses_matrix_variable(&regex, stage, &pipeline.defaults)
+    }
+
+    #[test]
+    fn a_matrix_variable_used_nowhere_is_reported() {
+        assert!(uses_matrix_variable(None, &[]));
+        assert!(!uses_matrix_variable(None, &[("image", r#""rust:1.80""#)]));
+        assert!(uses_matrix_variable(
+            None,
+            &[
+                ("image", r#""rust:1.80""#),
+                (
+                    "command",
+                    r#"["rustup default ${{version}}", "cargo test"]"#
+                ),
+            ]
+        ));
+        assert!(uses_matrix_variable(
+            Some(r#"["TOOLCHAIN=${{ version }}"]"#),
+            &[("image", r#""rust:1.80""#)]
+        ));
+        assert!(uses_matrix_variable(
+            None,
+            &[
+                ("image", r#""rust:1.80""#),
+                (
+                    "matrix",
+                    r#"{ variable = "version", values = ["1.80", "1.81"], name_suffix = "-${{ version }}" }"#,
+                ),
+            ]
+        ));
+
+        // Only a warning: the identical variants still compile.
+        let pipeline = compile(&matrix_step(&[("image", r#""rust:1.80""#)])).unwrap();
+        assert_eq!(step(&pipeline, "rust-1.80").image, "rust:1.80");
+        assert_eq!(step(&pipeline, "rust-1.81").image, "rust:1.80");
+    }
 }
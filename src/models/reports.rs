@@ -1,23 +1,100 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+};
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::models::Step;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct PipelineReport {
+    /// Names the run's `logs/<run_id>/` directory and its containers.
+    #[serde(default)]
+    pub run_id: String,
     pub stage_reports: Vec<StageReport>,
+    /// Wall-clock time of the whole run, in milliseconds.
+    #[serde(default)]
+    pub elapsed: u64,
+    /// Unix timestamps in milliseconds.
+    #[serde(default)]
+    pub started_at: u64,
+    #[serde(default)]
+    pub finished_at: u64,
+    /// Absent in reports recorded before it was captured.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub engine: Option<EngineInfo>,
+    /// Recorded before triggers existed, every run was started by hand.
+    #[serde(default)]
+    pub trigger: Trigger,
+    /// Keyed by [`log_key`], as step ids are only unique within a stage.
+    #[serde(skip)]
     pub logs: HashMap<String, Vec<String>>,
+    /// Why the run stopped early, e.g. the engine failed or a stage got stuck. The steps
+    /// it never got to are skipped.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Exit code for [`Self::error`], see [`crate::error::CiroachError::run_code`].
+    #[serde(skip)]
+    pub error_code: Option<u8>,
+    /// The image pulls of the run, in the order they finished.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pull_stats: Vec<PullStat>,
 }
 
 impl PipelineReport {
     pub fn is_success(&self) -> bool {
+        self.error.is_none()
+            && self
+                .stage_reports
+                .iter()
+                .flat_map(|step| &step.step_reports)
+                .all(|step| step.status != StepStatus::Failed)
+    }
+
+    /// `(stage, step, lines)` for every step that logged, in declaration order.
+    pub fn ordered_logs(&self) -> Vec<(&str, &str, &[String])> {
         self.stage_reports
             .iter()
-            .flat_map(|step| &step.step_reports)
-            .all(|step| step.status != StepStatus::Failed)
+            .flat_map(|stage| stage.step_reports.iter().map(move |step| (stage, step)))
+            .filter_map(|(stage, step)| {
+                self.logs
+                    .get(&log_key(&stage.name, &step.name))
+                    .map(|lines| (stage.name.as_str(), step.name.as_str(), lines.as_slice()))
+            })
+            .collect()
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// What started a run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Trigger {
+    /// `ciroach run`, a git hook or `POST /run`.
+    #[default]
+    Manual,
+    /// The `[schedule]` of `ciroach serve`.
+    Schedule,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct StageReport {
+    #[serde(default)]
+    pub name: String,
     pub step_reports: Vec<StepReport>,
+    /// Wall-clock time from the first step starting to the last one finishing, in
+    /// milliseconds. Zero for stages that did not run.
+    #[serde(default)]
+    pub elapsed: u64,
+    /// Unix timestamps in milliseconds.
+    #[serde(default)]
+    pub started_at: u64,
+    #[serde(default)]
+    pub finished_at: u64,
+    /// Reports of the failed attempts before the last, when the stage was run again.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub earlier_attempts: Vec<StageReport>,
 }
 
 impl StageReport {
@@ -26,50 +103,343 @@ impl StageReport {
             .iter()
             .all(|step| step.status != StepStatus::Failed)
     }
+
+    pub fn is_skipped(&self) -> bool {
+        self.step_reports
+            .iter()
+            .all(|step| step.status == StepStatus::Skipped)
+    }
+
+    /// `Stage build: 3 steps, 2m 14s`, with `(attempt 2)` when the stage was run again.
+    pub fn timing_summary(&self) -> String {
+        let steps = self.step_reports.len();
+        let plural = if steps == 1 { "" } else { "s" };
+
+        if self.is_skipped() {
+            format!("Stage {}: {} step{}, skipped", self.name, steps, plural)
+        } else if !self.earlier_attempts.is_empty() {
+            format!(
+                "Stage {}: {} step{}, {} (attempt {})",
+                self.name,
+                steps,
+                plural,
+                format_wall_clock(self.elapsed),
+                self.earlier_attempts.len() + 1
+            )
+        } else {
+            format!(
+                "Stage {}: {} step{}, {}",
+                self.name,
+                steps,
+                plural,
+                format_wall_clock(self.elapsed)
+            )
+        }
+    }
+}
+
+/// Key of a step's lines in [`PipelineReport::logs`].
+pub fn log_key(stage: &str, step: &str) -> String {
+    format!("{stage}/{step}")
+}
+
+/// Current time as a Unix timestamp in milliseconds, as stored in the reports.
+pub fn now_millis() -> u64 {
+    Utc::now().timestamp_millis() as u64
+}
+
+/// `42s`, `7m 02s` or `1h 03m 20s`.
+pub fn format_wall_clock(millis: u64) -> String {
+    let secs = millis / 1000;
+    let (hours, minutes, seconds) = (secs / 3600, secs / 60 % 60, secs % 60);
+
+    if hours > 0 {
+        format!("{hours}h {minutes:02}m {seconds:02}s")
+    } else if minutes > 0 {
+        format!("{minutes}m {seconds:02}s")
+    } else {
+        format!("{seconds}s")
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct StepReport {
     pub name: String,
+    /// The `name` the step was configured with, shared by all variants of a matrix step.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub base_name: String,
+    /// Name of the stage the step belongs to.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub stage: String,
+    /// The matrix variable of a variant and the value it ran with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub matrix_values: Option<BTreeMap<String, String>>,
     pub status: StepStatus,
     pub retries: u32,
     pub elapsed: u64,
+    /// Why the step was skipped or cancelled, or what cut a failed step short.
+    #[serde(
+        default,
+        alias = "skip_reason",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub reason: Option<SkipReason>,
+    /// Container left in place by `--keep-failed`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kept: Option<KeptContainer>,
+    /// Exit code of the last attempt when it failed by exiting non-zero.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i64>,
+    /// Bytes written to the container's writable layer by the last attempt.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub layer_size: Option<i64>,
+    /// What the container printed, as it arrived from the engine.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<OutputStats>,
+    /// Image the step ran in, as configured, and the registry digest it resolved to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_digest: Option<String>,
+    /// The step's log file in the run directory, relative to the workspace. Absent when
+    /// the step logged nothing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_file: Option<PathBuf>,
+    /// Unix timestamps in milliseconds; zero for steps that did not run.
+    #[serde(default)]
+    pub started_at: u64,
+    #[serde(default)]
+    pub finished_at: u64,
+    /// Milliseconds the step was ready to start but waited for a slot of its concurrency
+    /// group.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group_wait: Option<u64>,
+    /// One entry per try; the gaps between them are the backoff before a retry.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attempts: Vec<Attempt>,
+    /// Problems the step's matchers found in its output, in the order they were logged.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub annotations: Vec<Annotation>,
+}
+
+/// Output of a container step received from the engine.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutputStats {
+    pub stdout_bytes: u64,
+    pub stderr_bytes: u64,
+    pub lines: u64,
+    /// Why some of the output was likely dropped by the engine before it arrived.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub truncated: Option<String>,
+}
+
+/// The container engine a run used, as reported by its `/version` endpoint.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EngineInfo {
+    /// E.g. `Docker Engine - Community` or `Podman Engine`.
+    pub name: String,
+    pub version: String,
+    pub api_version: String,
+    pub os: String,
+    pub arch: String,
+    /// Bytes free where the engine keeps its data, when that is on this machine.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub free_space: Option<u64>,
+}
+
+impl std::fmt::Display for EngineInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} (API {}) on {}/{}",
+            self.name, self.version, self.api_version, self.os, self.arch
+        )?;
+        if let Some(free) = self.free_space {
+            write!(f, ", {} MiB free", free / (1024 * 1024))?;
+        }
+        Ok(())
+    }
+}
+
+/// A problem, e.g. a compiler error, recognized in a log line by a matcher.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Annotation {
+    /// Name of the matcher that found it.
+    pub matcher: String,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub line: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub column: Option<u32>,
+}
+
+impl std::fmt::Display for Annotation {
+    /// `src/main.rs:4:5: mismatched types`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{file}")?;
+            if let Some(line) = self.line {
+                write!(f, ":{line}")?;
+            }
+            if let Some(column) = self.column {
+                write!(f, ":{column}")?;
+            }
+            write!(f, ": ")?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// One try at running a step. Reports from before the outcome was recorded only have the
+/// times.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attempt {
+    pub started_at: u64,
+    pub finished_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<StepStatus>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i64>,
+    /// Why the attempt failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Milliseconds waited before the next attempt.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backoff: Option<u64>,
+}
+
+impl std::fmt::Display for Attempt {
+    /// `failed after 12s: Step 'test' exited with code 1, then waited 2s`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let outcome = match self.status {
+            Some(StepStatus::Success) => "passed",
+            Some(StepStatus::Failed) => "failed",
+            Some(StepStatus::Cancelled) => "stopped",
+            Some(StepStatus::Skipped) | None => "ran",
+        };
+        write!(
+            f,
+            "{outcome} after {}",
+            format_wall_clock(self.finished_at.saturating_sub(self.started_at))
+        )?;
+        if let Some(error) = &self.error {
+            write!(f, ": {error}")?;
+        }
+        if let Some(backoff) = self.backoff {
+            write!(f, ", then waited {}", format_wall_clock(backoff))?;
+        }
+        Ok(())
+    }
 }
 
 impl StepReport {
-    pub fn success(name: impl Into<String>, retries: u32, elapsed: u64) -> Self {
+    pub fn success(step: &Step, retries: u32, elapsed: u64) -> Self {
         Self {
-            name: name.into(),
+            name: step.exploded_name.clone(),
+            base_name: step.name.clone(),
+            stage: step.stage.clone(),
+            matrix_values: step.matrix_values.clone(),
             status: StepStatus::Success,
             retries,
             elapsed,
+            reason: None,
+            kept: None,
+            exit_code: None,
+            layer_size: None,
+            output: None,
+            image: None,
+            image_digest: None,
+            log_file: None,
+            started_at: 0,
+            finished_at: 0,
+            group_wait: None,
+            attempts: Vec::new(),
+            annotations: Vec::new(),
         }
     }
 
-    pub fn failed(name: impl Into<String>, retries: u32, elapsed: u64) -> Self {
+    pub fn failed(step: &Step, retries: u32, elapsed: u64) -> Self {
         Self {
-            name: name.into(),
+            name: step.exploded_name.clone(),
+            base_name: step.name.clone(),
+            stage: step.stage.clone(),
+            matrix_values: step.matrix_values.clone(),
             status: StepStatus::Failed,
             retries,
             elapsed,
+            reason: None,
+            kept: None,
+            exit_code: None,
+            layer_size: None,
+            output: None,
+            image: None,
+            image_digest: None,
+            log_file: None,
+            started_at: 0,
+            finished_at: 0,
+            group_wait: None,
+            attempts: Vec::new(),
+            annotations: Vec::new(),
         }
     }
 
-    pub fn cancelled(name: impl Into<String>, retries: u32, elapsed: u64) -> Self {
+    pub fn cancelled(step: &Step, retries: u32, elapsed: u64) -> Self {
         Self {
-            name: name.into(),
+            name: step.exploded_name.clone(),
+            base_name: step.name.clone(),
+            stage: step.stage.clone(),
+            matrix_values: step.matrix_values.clone(),
             status: StepStatus::Cancelled,
             retries,
             elapsed,
+            reason: Some(SkipReason::Interrupted),
+            kept: None,
+            exit_code: None,
+            layer_size: None,
+            output: None,
+            image: None,
+            image_digest: None,
+            log_file: None,
+            started_at: 0,
+            finished_at: 0,
+            group_wait: None,
+            attempts: Vec::new(),
+            annotations: Vec::new(),
         }
     }
 
-    pub fn skipped(name: impl Into<String>) -> Self {
+    fn skipped(step: &Step) -> Self {
         Self {
-            name: name.into(),
+            name: step.exploded_name.clone(),
+            base_name: step.name.clone(),
+            stage: step.stage.clone(),
+            matrix_values: step.matrix_values.clone(),
             status: StepStatus::Skipped,
             retries: 0,
             elapsed: 0,
+            reason: None,
+            kept: None,
+            exit_code: None,
+            layer_size: None,
+            output: None,
+            image: None,
+            image_digest: None,
+            log_file: None,
+            started_at: 0,
+            finished_at: 0,
+            group_wait: None,
+            attempts: Vec::new(),
+            annotations: Vec::new(),
+        }
+    }
+
+    /// A step that did not run, and why.
+    pub fn excluded(step: &Step, reason: SkipReason) -> Self {
+        Self {
+            reason: Some(reason),
+            ..Self::skipped(step)
         }
     }
 
@@ -86,10 +456,67 @@ impl StepReport {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum StepStatus {
     Success,
     Failed,
     Cancelled,
     Skipped,
 }
+
+/// How long pulling an image took and how much of it had to be downloaded.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PullStat {
+    pub image: String,
+    /// Bytes downloaded; none when the local copy was up to date.
+    pub bytes: u64,
+    /// Wall-clock time of the pull, retries included, in milliseconds.
+    pub elapsed: u64,
+    /// The local copy was used, either up to date or because the pull failed.
+    pub cached: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeptContainer {
+    pub name: String,
+    /// `None` when the container was stopped by a timeout.
+    pub exit_code: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SkipReason {
+    /// Filtered out by `--profile` or `--skip-tag`.
+    Profile,
+    /// A step it needs was filtered out, with `needs_policy = "skip"`.
+    Needs,
+    /// A step it needs, directly or transitively, did not succeed.
+    Dependency,
+    /// An earlier stage failed, so the stage never started.
+    Halted,
+    /// The run was cancelled, e.g. by Ctrl+C, before or while the step ran.
+    Interrupted,
+    /// The engine or the machine failed before the step's turn.
+    Aborted,
+    /// The step failed once its `total_timeout` was spent, with retries left unmade.
+    #[serde(rename = "total_timeout")]
+    TotalTimeout,
+    /// The step's `check` failed, so its command never ran.
+    #[serde(rename = "precondition_failed")]
+    PreconditionFailed,
+}
+
+impl std::fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Profile => write!(f, "profile"),
+            Self::Needs => write!(f, "needs"),
+            Self::Dependency => write!(f, "dependency failed"),
+            Self::Halted => write!(f, "earlier stage failed"),
+            Self::Interrupted => write!(f, "interrupted"),
+            Self::Aborted => write!(f, "run aborted"),
+            Self::TotalTimeout => write!(f, "total timeout"),
+            Self::PreconditionFailed => write!(f, "precondition failed"),
+        }
+    }
+}
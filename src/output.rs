@@ -0,0 +1,188 @@
+use std::{
+    env, fmt,
+    io::{self, IsTerminal},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use clap::ValueEnum;
+use tracing::Level;
+use tracing_subscriber::filter::Targets;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum OutputMode {
+    /// Pick `github` when running inside GitHub Actions, `terminal` otherwise.
+    #[default]
+    Auto,
+    /// Interactive output with live progress bars.
+    Terminal,
+    /// Plain lines with `::group::` sections and `::error::` annotations.
+    Github,
+}
+
+impl OutputMode {
+    pub fn resolve(self) -> Self {
+        match self {
+            Self::Auto if env::var("GITHUB_ACTIONS").is_ok_and(|v| v == "true") => Self::Github,
+            Self::Auto => Self::Terminal,
+            mode => mode,
+        }
+    }
+
+    pub fn is_github(self) -> bool {
+        self == Self::Github
+    }
+
+    /// Whether animated progress bars may be drawn; never when stdout is not a terminal.
+    pub fn live_progress(self) -> bool {
+        !self.is_github() && io::stdout().is_terminal()
+    }
+}
+
+/// How much the console shows. Report files always get everything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Verbosity {
+    /// Stage headers and the final summary table.
+    Quiet,
+    #[default]
+    Normal,
+    /// Step logs are streamed as they arrive.
+    Verbose,
+    /// Engine-level events too: container ids, pull digests, removals, retry sleeps.
+    Debug,
+}
+
+impl Verbosity {
+    pub fn from_flags(quiet: bool, verbose: u8) -> Self {
+        match (quiet, verbose) {
+            (true, _) => Self::Quiet,
+            (false, 0) => Self::Normal,
+            (false, 1) => Self::Verbose,
+            (false, _) => Self::Debug,
+        }
+    }
+
+    pub fn is_quiet(self) -> bool {
+        self == Self::Quiet
+    }
+
+    /// Tracing events printed on the console; `RUST_LOG` takes precedence when set.
+    pub fn console_filter(self) -> Targets {
+        if let Some(filter) = env::var("RUST_LOG").ok().and_then(|var| var.parse().ok()) {
+            return filter;
+        }
+
+        match self {
+            Self::Quiet | Self::Normal | Self::Verbose => Targets::new(),
+            Self::Debug => Targets::new().with_target(env!("CARGO_PKG_NAME"), Level::DEBUG),
+        }
+    }
+}
+
+/// Whether styled output may use emoji; set once by [`init_style`].
+static UNICODE_ICONS: AtomicBool = AtomicBool::new(true);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum ColorChoice {
+    /// Color when stdout is a terminal and `NO_COLOR` is not set.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// Resolves colors and icons for the whole process. Plain output also swaps emoji for
+/// ASCII tags, since log viewers that mangle escape codes tend to mangle emoji too.
+pub fn init_style(choice: ColorChoice) {
+    let styled = match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => {
+            let no_color = env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+            !no_color && io::stdout().is_terminal()
+        }
+    };
+
+    colored::control::set_override(styled);
+    UNICODE_ICONS.store(styled, Ordering::Relaxed);
+}
+
+/// Status icons in console output, with their ASCII fallbacks.
+#[derive(Debug, Clone, Copy)]
+pub enum Icon {
+    Warning,
+    Error,
+    Halt,
+    Clean,
+    #[cfg(feature = "dashboard")]
+    Listening,
+    Waiting,
+    Roach,
+    #[cfg(feature = "server")]
+    Launch,
+    Platform,
+    Debug,
+    Pause,
+    Retry,
+    Ready,
+    Logs,
+    Kept,
+    Folder,
+    Hook,
+    Done,
+    Success,
+    Info,
+}
+
+impl Icon {
+    fn glyphs(self) -> (&'static str, &'static str) {
+        match self {
+            Self::Warning => ("⚠️", "[warn]"),
+            Self::Error => ("❌", "[error]"),
+            Self::Halt => ("🛑", "[stop]"),
+            Self::Clean => ("🧹", "[clean]"),
+            #[cfg(feature = "dashboard")]
+            Self::Listening => ("📡", "[listen]"),
+            Self::Waiting => ("⏳", "[wait]"),
+            Self::Roach => ("🪳", "ciroach:"),
+            #[cfg(feature = "server")]
+            Self::Launch => ("🚀", "[start]"),
+            // These render narrower than their width in most terminals.
+            Self::Platform => ("🖥️ ", "[platform]"),
+            Self::Debug => ("🐞", "[debug]"),
+            Self::Pause => ("⏸️ ", "[pause]"),
+            Self::Retry => ("🔄", "[retry]"),
+            Self::Ready => ("🟢", "[ready]"),
+            Self::Logs => ("📖", "ciroach:"),
+            Self::Kept => ("📦", "[kept]"),
+            Self::Folder => ("📁", "[logs]"),
+            Self::Hook => ("🪝", "[hook]"),
+            Self::Done => ("✨", "[done]"),
+            Self::Success => ("✅", "[ok]"),
+            Self::Info => ("ℹ️ ", "[info]"),
+        }
+    }
+}
+
+impl fmt::Display for Icon {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (unicode, ascii) = self.glyphs();
+        if UNICODE_ICONS.load(Ordering::Relaxed) {
+            f.write_str(unicode)
+        } else {
+            f.write_str(ascii)
+        }
+    }
+}
+
+/// Escapes data for a GitHub workflow command message.
+pub fn github_escape(value: &str) -> String {
+    value
+        .replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+/// Escapes data for a GitHub workflow command property such as `title`.
+pub fn github_escape_property(value: &str) -> String {
+    github_escape(value).replace(':', "%3A").replace(',', "%2C")
+}
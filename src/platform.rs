@@ -0,0 +1,107 @@
+#[cfg(unix)]
+use std::os::unix::fs::MetadataExt;
+use std::{path::Path, sync::Once};
+
+use crate::{models::PlatformConfig, output::Icon};
+
+const SELINUX_ENFORCE: &str = "/sys/fs/selinux/enforce";
+
+static LOGGED: Once = Once::new();
+
+/// How the workspace is shared with step containers on this host.
+pub struct Platform {
+    /// `uid:gid` the containers run as; `None` keeps the image's user.
+    pub user: Option<String>,
+    /// Mount the workspace with `:z` so SELinux lets containers read it.
+    pub relabel: bool,
+    selinux: bool,
+    docker_desktop: bool,
+}
+
+impl Platform {
+    /// Applies the `[platform]` overrides on top of what the host looks like.
+    pub fn detect(cwd: &Path, config: &PlatformConfig) -> anyhow::Result<Self> {
+        let selinux = cfg!(target_os = "linux") && Path::new(SELINUX_ENFORCE).exists();
+        // Docker Desktop's file sharing already maps ownership to the host user, and
+        // Windows has no uid to map in the first place.
+        let docker_desktop = cfg!(any(target_os = "macos", windows));
+
+        let relabel = config.selinux_relabel.unwrap_or(selinux);
+        let map_user = config.map_user.unwrap_or(!docker_desktop);
+
+        Ok(Self {
+            user: map_user.then(|| Self::workspace_user(cwd)).transpose()?,
+            relabel,
+            selinux,
+            docker_desktop,
+        })
+    }
+
+    /// Prints the outcome of [`Self::detect`], only the first time it is called.
+    pub fn announce(&self) {
+        LOGGED.call_once(|| self.log());
+    }
+
+    fn log(&self) {
+        let mount = match (self.relabel, self.selinux) {
+            (true, true) => "SELinux detected, relabeling the workspace mount (:z)",
+            (true, false) => "relabeling the workspace mount (:z)",
+            (false, true) => "SELinux detected, but relabeling is turned off",
+            (false, false) => "plain workspace mount",
+        };
+        let user = match (&self.user, self.docker_desktop) {
+            (Some(user), _) => format!("containers run as {user}"),
+            (None, true) => "Docker Desktop detected, containers keep the image's user".to_string(),
+            (None, false) => "containers keep the image's user".to_string(),
+        };
+
+        println!("{} Platform: {mount}; {user}.", Icon::Platform);
+    }
+
+    fn workspace_user(cwd: &Path) -> anyhow::Result<String> {
+        #[cfg(unix)]
+        let user = {
+            let meta = std::fs::metadata(cwd)?;
+            format!("{}:{}", meta.uid(), meta.gid())
+        };
+        #[cfg(not(unix))]
+        let user = {
+            let _ = cwd;
+            "0:0".to_string()
+        };
+
+        Ok(user)
+    }
+}
+
+/// Waits until ciroach is asked to stop and returns the signal's name: `SIGINT` or
+/// `SIGTERM`, as sent by systemd and most CI systems, or on Windows `CTRL_C`,
+/// `CTRL_BREAK` or `CTRL_CLOSE`.
+pub async fn shutdown_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        if let Ok(mut term) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => return "SIGINT",
+                _ = term.recv() => return "SIGTERM",
+            }
+        }
+    }
+    #[cfg(windows)]
+    {
+        use tokio::signal::windows::{ctrl_break, ctrl_close};
+
+        if let (Ok(mut brk), Ok(mut close)) = (ctrl_break(), ctrl_close()) {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => return "CTRL_C",
+                _ = brk.recv() => return "CTRL_BREAK",
+                _ = close.recv() => return "CTRL_CLOSE",
+            }
+        }
+    }
+
+    tokio::signal::ctrl_c().await.ok();
+    "SIGINT"
+}
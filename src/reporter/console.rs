@@ -1,60 +1,572 @@
-use colored::Colorize;
+use std::collections::HashMap;
 
-use crate::models::{PipelineReport, StepStatus};
+use clap::ValueEnum;
+use colored::{ColoredString, Colorize};
+use indicatif::HumanBytes;
+use regex::Regex;
 
-pub struct ConsoleReporter;
+use crate::{
+    history::{Baseline, Severity, StepDelta},
+    models::{PipelineReport, SkipReason, StepReport, StepStatus, format_wall_clock, log_key},
+    output::{Icon, OutputMode, Verbosity, github_escape, github_escape_property},
+    reporter::FileReporter,
+};
 
-impl ConsoleReporter {
-    pub fn report(report: &PipelineReport) {
-        println!("\n--- 📖 Pipeline Execution Logs ---");
+/// Problems listed per failed step; the rest are in the JSON report.
+const PROBLEMS_SHOWN: usize = 5;
 
-        for (step_name, lines) in report.logs.iter() {
-            println!("\n=== {} ===", step_name.to_uppercase());
-            for line in lines {
-                println!("{line}");
+pub struct ConsoleReporter<'a> {
+    baseline: Option<&'a Baseline>,
+    mode: OutputMode,
+    verbosity: Verbosity,
+    descriptions: HashMap<String, String>,
+    log_filter: Option<LogFilter>,
+    logs_per_variant: LogsPerVariant,
+}
+
+/// Shows only the log lines matching `pattern`, and `context` lines around each.
+#[derive(Debug, Clone)]
+pub struct LogFilter {
+    pub pattern: Regex,
+    pub context: usize,
+}
+
+impl LogFilter {
+    /// The lines to print, with the runs of lines left out replaced by their count.
+    fn apply(&self, lines: &[String]) -> Vec<String> {
+        let mut shown = vec![false; lines.len()];
+        for (index, plain) in FileReporter::plain(lines).iter().enumerate() {
+            // Lines start with `[step] `, which must not count as a match.
+            if self.pattern.is_match(log_body(plain)) {
+                let end = (index + self.context).min(lines.len() - 1);
+                shown[index.saturating_sub(self.context)..=end].fill(true);
+            }
+        }
+
+        let mut filtered = Vec::new();
+        let mut hidden = 0;
+        for (line, shown) in lines.iter().zip(shown) {
+            if !shown {
+                hidden += 1;
+                continue;
+            }
+            if hidden > 0 {
+                filtered.push(Self::marker(hidden));
+                hidden = 0;
+            }
+            filtered.push(line.clone());
+        }
+        if hidden > 0 {
+            filtered.push(Self::marker(hidden));
+        }
+        filtered
+    }
+
+    fn marker(hidden: usize) -> String {
+        let plural = if hidden == 1 { "" } else { "s" };
+        format!("… {hidden} line{plural} not matching the filter …")
+            .dimmed()
+            .to_string()
+    }
+}
+
+/// A log line without its `[step] ` prefix and the padding after it.
+fn log_body(plain: &str) -> &str {
+    plain
+        .split_once("] ")
+        .map_or(plain, |(_, text)| text.trim_start())
+}
+
+/// How the log dump shows the variants of a matrix step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum LogsPerVariant {
+    /// The full log of every variant.
+    #[default]
+    All,
+    /// The full log of the first failed variant and a line for each other one.
+    FirstFailed,
+    /// The lines all variants start with once, then the rest of each variant's log.
+    Merged,
+}
+
+impl<'a> ConsoleReporter<'a> {
+    pub fn new(baseline: Option<&'a Baseline>, mode: OutputMode) -> Self {
+        Self {
+            baseline,
+            mode,
+            verbosity: Verbosity::Normal,
+            descriptions: HashMap::new(),
+            log_filter: None,
+            logs_per_variant: LogsPerVariant::All,
+        }
+    }
+
+    /// Narrows the log dump down to the lines of interest.
+    pub fn log_filter(mut self, log_filter: Option<LogFilter>) -> Self {
+        self.log_filter = log_filter;
+        self
+    }
+
+    /// Shortens the log dump of matrix steps, whose variants often log nearly the same.
+    pub fn logs_per_variant(mut self, logs_per_variant: LogsPerVariant) -> Self {
+        self.logs_per_variant = logs_per_variant;
+        self
+    }
+
+    /// Step descriptions printed as a dimmed line under each row.
+    pub fn descriptions(mut self, descriptions: HashMap<String, String>) -> Self {
+        self.descriptions = descriptions;
+        self
+    }
+
+    /// Quiet runs skip the log dump; verbose runs already streamed the logs, so only
+    /// those of failed steps are repeated.
+    pub fn verbosity(mut self, verbosity: Verbosity) -> Self {
+        self.verbosity = verbosity;
+        self
+    }
+
+    pub fn report(&self, report: &PipelineReport) {
+        self.print_logs(report);
+        self.print_table(report);
+        self.print_problems(report);
+        self.print_kept(report);
+
+        if self.mode.is_github() {
+            self.print_annotations(report);
+        }
+    }
+
+    fn print_logs(&self, report: &PipelineReport) {
+        let failed_only = self.verbosity >= Verbosity::Verbose;
+        let logged: Vec<(&StepReport, &[String])> = report
+            .stage_reports
+            .iter()
+            .flat_map(|stage| stage.step_reports.iter().map(move |step| (stage, step)))
+            .filter(|(_, step)| !failed_only || step.status == StepStatus::Failed)
+            .filter_map(|(stage, step)| {
+                report
+                    .logs
+                    .get(&log_key(&stage.name, &step.name))
+                    .map(|lines| (step, lines.as_slice()))
+            })
+            .collect();
+
+        if self.verbosity.is_quiet() || (failed_only && logged.is_empty()) {
+            return;
+        }
+
+        println!("\n--- {} Pipeline Execution Logs ---", Icon::Logs);
+
+        for variants in self.group_variants(logged) {
+            match variants.as_slice() {
+                [(step, lines)] => self.print_step_log(&step.name, lines),
+                variants => match self.logs_per_variant {
+                    LogsPerVariant::All => {
+                        for (step, lines) in variants {
+                            self.print_step_log(&step.name, lines);
+                        }
+                    }
+                    LogsPerVariant::FirstFailed => self.print_first_failed(variants),
+                    LogsPerVariant::Merged => self.print_merged(variants),
+                },
+            }
+        }
+    }
+
+    /// Puts the variants of each matrix step together, in the order the first of them
+    /// logged. Without a per-variant mode every step stays on its own.
+    fn group_variants<'r>(
+        &self,
+        logged: Vec<(&'r StepReport, &'r [String])>,
+    ) -> Vec<Vec<(&'r StepReport, &'r [String])>> {
+        let mut groups: Vec<Vec<(&StepReport, &[String])>> = Vec::new();
+        for (step, lines) in logged {
+            let family = groups.iter_mut().find(|variants| {
+                let (first, _) = variants[0];
+                first.matrix_values.is_some()
+                    && step.matrix_values.is_some()
+                    && (&first.stage, &first.base_name) == (&step.stage, &step.base_name)
+            });
+            match family {
+                Some(variants) if self.logs_per_variant != LogsPerVariant::All => {
+                    variants.push((step, lines))
+                }
+                _ => groups.push(vec![(step, lines)]),
+            }
+        }
+        groups
+    }
+
+    fn print_step_log(&self, title: &str, lines: &[String]) {
+        if self.mode.is_github() {
+            println!("::group::{}", github_escape(title));
+        } else {
+            println!("\n=== {} ===", title.to_uppercase());
+        }
+
+        let filtered;
+        let lines = match &self.log_filter {
+            Some(filter) => {
+                filtered = filter.apply(lines);
+                filtered.as_slice()
             }
+            None => lines,
+        };
+        for line in lines {
+            println!("{line}");
         }
 
+        if self.mode.is_github() {
+            println!("::endgroup::");
+        }
+    }
+
+    /// The log of the first failed variant, or of the first variant when none failed,
+    /// then a line for each of the others.
+    fn print_first_failed(&self, variants: &[(&StepReport, &[String])]) {
+        let shown = variants
+            .iter()
+            .position(|(step, _)| step.status == StepStatus::Failed)
+            .unwrap_or(0);
+        let (step, lines) = variants[shown];
+        self.print_step_log(&step.name, lines);
+
+        for (step, lines) in variants.iter().filter(|(other, _)| other.name != step.name) {
+            let plural = if lines.len() == 1 { "" } else { "s" };
+            let mut summary = format!("{} line{plural}", lines.len());
+            if let Some(log_file) = &step.log_file {
+                summary.push_str(&format!(" in {}", log_file.display()));
+            }
+            println!(
+                "{} {} {}",
+                Self::status_label(step.status),
+                step.name.cyan(),
+                summary.dimmed()
+            );
+        }
+    }
+
+    /// The lines every variant starts with once, then what each variant logged after
+    /// them. Variants with nothing in common are printed in full.
+    fn print_merged(&self, variants: &[(&StepReport, &[String])]) {
+        let bodies: Vec<Vec<String>> = variants
+            .iter()
+            .map(|(_, lines)| {
+                FileReporter::plain(lines)
+                    .iter()
+                    .map(|plain| log_body(plain).to_string())
+                    .collect()
+            })
+            .collect();
+        let common = (0..bodies[0].len())
+            .take_while(|&index| {
+                bodies
+                    .iter()
+                    .all(|body| body.get(index) == bodies[0].get(index))
+            })
+            .count();
+
+        if common > 0 {
+            let base = &variants[0].0.base_name;
+            self.print_step_log(&format!("{base} (all variants)"), &bodies[0][..common]);
+        }
+        for (step, lines) in variants {
+            if lines.len() > common {
+                self.print_step_log(&step.name, &lines[common..]);
+            }
+        }
+    }
+
+    /// Emits one error annotation per failed step so failures show up on the run summary,
+    /// and one per problem its matchers found, placed on the file and line when known.
+    fn print_annotations(&self, report: &PipelineReport) {
+        for step in report
+            .stage_reports
+            .iter()
+            .flat_map(|stage| &stage.step_reports)
+            .filter(|step| step.status == StepStatus::Failed)
+        {
+            for annotation in step.annotations.iter() {
+                let mut properties = Vec::new();
+                if let Some(file) = &annotation.file {
+                    properties.push(format!("file={}", github_escape_property(file)));
+                }
+                if let Some(line) = annotation.line {
+                    properties.push(format!("line={line}"));
+                }
+                if let Some(column) = annotation.column {
+                    properties.push(format!("col={column}"));
+                }
+                properties.push(format!("title={}", github_escape_property(&step.name)));
+
+                println!(
+                    "::error {}::{}",
+                    properties.join(","),
+                    github_escape(&annotation.message)
+                );
+            }
+
+            println!(
+                "::error title={}::{}",
+                github_escape_property(&step.name),
+                github_escape(&format!(
+                    "Step '{}' failed after {} retries ({}s)",
+                    step.name,
+                    step.retries,
+                    step.get_elasped_report()
+                )),
+            );
+        }
+    }
+
+    fn print_table(&self, report: &PipelineReport) {
+        let baseline = self.baseline;
+
         println!(
             "\n{}",
-            "--- 🪳 Final Pipeline Report ---\n".bold().underline()
+            format!("--- {} Final Pipeline Report ---\n", Icon::Roach)
+                .bold()
+                .underline()
         );
 
-        println!(
-            "{:<4} {:<30} {:<12} {:<10} {:<12}",
+        let width = if baseline.is_some() { 91 } else { 73 };
+
+        print!(
+            "{:<4} {:<30} {:<15} {:<10} {:<12}",
             "No".bold(),
             "Step Name".bold(),
             "Status".bold(),
             "Retries".bold(),
             "Duration".bold(),
         );
+        if baseline.is_some() {
+            print!(" {:<18}", "Delta".bold());
+        }
+        println!();
 
-        println!("{}", "-".repeat(70).dimmed());
+        println!("{}", "-".repeat(width).dimmed());
 
         let mut report_index = 1;
 
         for stage in report.stage_reports.iter() {
             for step in stage.step_reports.iter() {
-                let status = match step.status {
-                    StepStatus::Success => "PASS".green().bold(),
-                    StepStatus::Failed => "FAIL".red().bold(),
-                    StepStatus::Cancelled => "STOP".yellow().bold(),
-                    StepStatus::Skipped => "SKIP".white().dimmed(),
-                };
+                let status = Self::status_label(step.status);
 
-                println!(
-                    "{:<4} {:<30} {:<12} {:<10} {:<12}",
+                print!(
+                    "{:<4} {:<30} {:<15} {:<10} {:<12}",
                     report_index,
                     step.name.cyan(),
                     status,
                     step.retries,
                     format!("{}s", step.get_elasped_report()),
                 );
+                if let Some(baseline) = baseline {
+                    print!(" {:<18}", Self::format_delta(baseline.compare(step)));
+                }
+                if let Some(reason) = step.reason {
+                    print!(" {}", format!("({reason})").dimmed());
+                }
+                if step.status == StepStatus::Failed
+                    && let Some(log_file) = &step.log_file
+                {
+                    print!(" {}", log_file.display());
+                }
+                println!();
+
+                if let Some(description) = self.descriptions.get(&step.name) {
+                    println!("     {}", Self::truncate(description, width - 5).dimmed());
+                }
+                if let Some(reason) = step
+                    .output
+                    .as_ref()
+                    .and_then(|output| output.truncated.as_ref())
+                {
+                    println!(
+                        "     {}",
+                        format!("{} Output likely cut short: {reason}", Icon::Warning).yellow()
+                    );
+                }
+                if self.verbosity >= Verbosity::Verbose
+                    && let Some(output) = &step.output
+                {
+                    let printed = format!(
+                        "Printed {} lines: {} KiB to stdout, {} KiB to stderr",
+                        output.lines,
+                        output.stdout_bytes.div_ceil(1024),
+                        output.stderr_bytes.div_ceil(1024)
+                    );
+                    println!("     {}", printed.dimmed());
+                }
+                if step.attempts.len() > 1
+                    && (self.verbosity >= Verbosity::Verbose
+                        || step.reason == Some(SkipReason::TotalTimeout))
+                {
+                    for (number, attempt) in step.attempts.iter().enumerate() {
+                        let attempt = format!("Attempt {}: {attempt}", number + 1);
+                        println!("     {}", attempt.dimmed());
+                    }
+                }
+                if self.verbosity >= Verbosity::Verbose
+                    && let Some(waited) = step.group_wait.filter(|waited| *waited > 0)
+                {
+                    let waited = format!(
+                        "Waited {} for its concurrency group",
+                        format_wall_clock(waited)
+                    );
+                    println!("     {}", waited.dimmed());
+                }
+                if self.verbosity >= Verbosity::Verbose
+                    && let Some(image) = &step.image
+                {
+                    println!(
+                        "     {}",
+                        Self::image_line(image, step.image_digest.as_deref()).dimmed()
+                    );
+                }
 
                 report_index += 1;
             }
         }
 
-        println!("{}", "-".repeat(70).dimmed());
+        println!("{}", "-".repeat(width).dimmed());
+        self.print_timing(report);
+    }
+
+    /// `rust:1 @ sha256:0123456789ab`, with the digest cut to 12 hex digits.
+    fn status_label(status: StepStatus) -> ColoredString {
+        match status {
+            StepStatus::Success => "PASS".green().bold(),
+            StepStatus::Failed => "FAIL".red().bold(),
+            StepStatus::Cancelled => "STOP".yellow().bold(),
+            StepStatus::Skipped => "SKIP".white().dimmed(),
+        }
+    }
+
+    fn image_line(image: &str, digest: Option<&str>) -> String {
+        match digest {
+            Some(digest) => {
+                let (algorithm, hex) = digest.split_once(':').unwrap_or(("", digest));
+                let short = hex.get(..12).unwrap_or(hex);
+                format!("{image} @ {algorithm}:{short}")
+            }
+            None => image.to_string(),
+        }
+    }
+
+    /// Wall-clock times; summing step durations would overcount parallel steps.
+    fn print_timing(&self, report: &PipelineReport) {
+        for stage in report.stage_reports.iter() {
+            println!("{}", stage.timing_summary().dimmed());
+        }
+        // Pulls overlap each other and the steps, so only the longest one is given.
+        if let Some(longest) = report.pull_stats.iter().map(|stat| stat.elapsed).max() {
+            let bytes: u64 = report.pull_stats.iter().map(|stat| stat.bytes).sum();
+            let line = format!(
+                "Image pulls: {} downloaded for {} images, longest {}",
+                HumanBytes(bytes),
+                report.pull_stats.len(),
+                format_wall_clock(longest)
+            );
+            println!("{}", line.dimmed());
+        }
+        println!(
+            "{}",
+            format!("Total: {}", format_wall_clock(report.elapsed)).bold()
+        );
+    }
+
+    fn print_problems(&self, report: &PipelineReport) {
+        let failed: Vec<_> = report
+            .stage_reports
+            .iter()
+            .flat_map(|stage| &stage.step_reports)
+            .filter(|step| step.status == StepStatus::Failed && !step.annotations.is_empty())
+            .collect();
+
+        if failed.is_empty() {
+            return;
+        }
+
+        println!("\n{} Problems:", Icon::Error);
+        for step in failed {
+            println!("   {}", step.name.cyan());
+            for annotation in step.annotations.iter().take(PROBLEMS_SHOWN) {
+                println!("     {annotation}");
+            }
+            if step.annotations.len() > PROBLEMS_SHOWN {
+                let more = format!("… and {} more", step.annotations.len() - PROBLEMS_SHOWN);
+                println!("     {}", more.dimmed());
+            }
+        }
+    }
+
+    fn print_kept(&self, report: &PipelineReport) {
+        let kept: Vec<_> = report
+            .stage_reports
+            .iter()
+            .flat_map(|stage| &stage.step_reports)
+            .filter_map(|step| Some((step, step.kept.as_ref()?)))
+            .collect();
+
+        if kept.is_empty() {
+            return;
+        }
+
+        println!(
+            "\n{} Kept containers (remove with `ciroach clean --kept`):",
+            Icon::Kept
+        );
+        for (step, container) in kept {
+            let exit_code = match container.exit_code {
+                Some(code) => code.to_string(),
+                None => "timeout".to_string(),
+            };
+
+            println!(
+                "   {:<40} {:<30} exit {}",
+                container.name,
+                step.name.cyan(),
+                exit_code
+            );
+        }
+    }
+
+    fn format_delta(delta: StepDelta) -> ColoredString {
+        let (delta_ms, percent, severity) = match delta {
+            StepDelta::New => return "new".blue(),
+            StepDelta::Unavailable => return "-".dimmed(),
+            StepDelta::Changed {
+                delta_ms,
+                percent,
+                severity,
+            } => (delta_ms, percent, severity),
+        };
+
+        let secs = delta_ms as f64 / 1000.0;
+        let text = if secs.abs() < 10.0 {
+            format!("{secs:+.1}s ({percent:+.0}%)")
+        } else {
+            format!("{secs:+.0}s ({percent:+.0}%)")
+        };
+
+        match severity {
+            Severity::Normal => text.dimmed(),
+            Severity::Regression => text.yellow(),
+            Severity::Severe => text.red().bold(),
+        }
+    }
+
+    /// Cuts `text` to a single line of at most `max` characters.
+    fn truncate(text: &str, max: usize) -> String {
+        let mut lines = text.trim().lines();
+        let first = lines.next().unwrap_or_default().trim();
+
+        if lines.next().is_none() && first.chars().count() <= max {
+            return first.to_string();
+        }
+
+        let cut: String = first.chars().take(max.saturating_sub(1)).collect();
+        format!("{}…", cut.trim_end())
     }
 }
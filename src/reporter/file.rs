@@ -1,12 +1,19 @@
+use std::{path::Path, sync::LazyLock};
+
 use anyhow::Ok;
+use regex::Regex;
 use tokio::{fs::File, io::AsyncWriteExt};
 
-use crate::models::PipelineReport;
+use crate::models::{PipelineReport, format_wall_clock};
+
+static ANSI_ESCAPE: LazyLock<Regex> = LazyLock::new(|| Regex::new("\x1b\\[[0-9;]*m").unwrap());
 
+/// Writes the full report, including every step's logs, as plain text whatever the
+/// console verbosity and color settings.
 pub struct FileReporter;
 
 impl FileReporter {
-    pub async fn save(report: &PipelineReport, path: &str) -> anyhow::Result<()> {
+    pub async fn save(report: &PipelineReport, path: &Path) -> anyhow::Result<()> {
         let mut file = File::create(path).await?;
         let mut buffer = String::new();
 
@@ -14,7 +21,11 @@ impl FileReporter {
 
         for stage in report.stage_reports.iter() {
             for step in stage.step_reports.iter() {
-                let status_str = format!("{:?}", step.status);
+                let status_str = match (step.reason, step.exit_code) {
+                    (Some(reason), _) => format!("{:?} ({reason})", step.status),
+                    (None, Some(code)) => format!("{:?} (exit code {code})", step.status),
+                    (None, None) => format!("{:?}", step.status),
+                };
                 buffer.push_str(&format!(
                     "Step: {} | Status {} | Duration {}s\n",
                     step.name,
@@ -24,9 +35,33 @@ impl FileReporter {
             }
         }
 
+        buffer.push('\n');
+        for stage in report.stage_reports.iter() {
+            buffer.push_str(&stage.timing_summary());
+            buffer.push('\n');
+        }
+        buffer.push_str(&format!("Total: {}\n", format_wall_clock(report.elapsed)));
+
+        for (_, step_name, lines) in report.ordered_logs() {
+            buffer.push_str(&format!("\n--- Logs: {} ---\n", step_name));
+            for line in Self::plain(lines) {
+                buffer.push_str(&line);
+                buffer.push('\n');
+            }
+        }
+
         file.write_all(buffer.as_bytes()).await?;
         file.flush().await?;
 
         Ok(())
     }
+
+    /// Log lines without the terminal color codes.
+    pub fn plain(lines: &[String]) -> Vec<String> {
+        lines.iter().map(|line| Self::plain_line(line)).collect()
+    }
+
+    pub fn plain_line(line: &str) -> String {
+        ANSI_ESCAPE.replace_all(line, "").into_owned()
+    }
 }
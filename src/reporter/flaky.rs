@@ -0,0 +1,77 @@
+use colored::Colorize;
+
+use crate::{history::FlakyStep, output::Icon};
+
+pub struct FlakyReporter;
+
+impl FlakyReporter {
+    pub fn report(steps: &[FlakyStep], failing: &[FlakyStep], runs: usize, threshold: f64) {
+        println!(
+            "\n{}",
+            format!(
+                "--- {} Flaky Steps (last {runs} runs, > {threshold:.0}% first-attempt failures) ---\n",
+                Icon::Roach
+            )
+                .bold()
+                .underline()
+        );
+
+        if steps.is_empty() {
+            println!("{}", "No flaky steps detected.".green());
+        } else {
+            Self::table(steps);
+        }
+
+        if !failing.is_empty() {
+            println!(
+                "\n{} {}",
+                Icon::Error,
+                "Failing in every run, so left out above:".red().bold()
+            );
+            for step in failing {
+                match step.stage.is_empty() {
+                    true => println!("  {} ({} runs)", step.name.cyan(), step.runs),
+                    false => println!(
+                        "  {} in stage '{}' ({} runs)",
+                        step.name.cyan(),
+                        step.stage,
+                        step.runs
+                    ),
+                }
+            }
+        }
+    }
+
+    fn table(steps: &[FlakyStep]) {
+        println!(
+            "{:<30} {:<15} {:<8} {:<12} {:<12} {:<12}",
+            "Step Name".bold(),
+            "Stage".bold(),
+            "Runs".bold(),
+            "Fail Rate".bold(),
+            "Avg Retries".bold(),
+            "Added Time".bold(),
+        );
+
+        println!("{}", "-".repeat(94).dimmed());
+
+        for step in steps {
+            let added = match step.added_ms {
+                Some(ms) => format!("{:.1}s", ms as f64 / 1000.0),
+                None => "-".to_string(),
+            };
+
+            println!(
+                "{:<30} {:<15} {:<8} {:<12} {:<12} {:<12}",
+                step.name.cyan(),
+                step.stage,
+                step.runs,
+                format!("{:.0}%", step.failure_rate()).yellow(),
+                format!("{:.2}", step.avg_retries),
+                added,
+            );
+        }
+
+        println!("{}", "-".repeat(94).dimmed());
+    }
+}
@@ -0,0 +1,295 @@
+use std::{collections::HashMap, fmt::Write};
+
+use clap::ValueEnum;
+
+use crate::models::{
+    Pipeline, PipelineReport, Stage, Step, StepReport, StepStatus, format_wall_clock,
+};
+
+/// Fill colors given to images in order of first use.
+const IMAGE_COLORS: [&str; 8] = [
+    "#cfe2ff", "#d1e7dd", "#fff3cd", "#f8d7da", "#e2d9f3", "#ffe5d0", "#d2f4ea", "#e9ecef",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum GraphFormat {
+    /// Graphviz DOT, e.g. `ciroach graph | dot -Tsvg > pipeline.svg`.
+    #[default]
+    Dot,
+    /// Mermaid flowchart, renders in GitHub Markdown.
+    Mermaid,
+}
+
+/// Renders the compiled pipeline as a DAG: stages as clusters, steps as nodes colored by
+/// image, and `needs` as edges. Given a report, nodes also show status and duration.
+pub struct GraphReporter;
+
+impl GraphReporter {
+    pub fn render(
+        pipeline: &Pipeline,
+        report: Option<&PipelineReport>,
+        format: GraphFormat,
+    ) -> anyhow::Result<String> {
+        let steps: HashMap<&str, &StepReport> = report
+            .iter()
+            .flat_map(|report| &report.stage_reports)
+            .flat_map(|stage| &stage.step_reports)
+            .map(|step| (step.name.as_str(), step))
+            .collect();
+
+        match format {
+            GraphFormat::Dot => Self::dot(pipeline, &steps),
+            GraphFormat::Mermaid => Self::mermaid(pipeline, &steps),
+        }
+    }
+
+    fn dot(pipeline: &Pipeline, reports: &HashMap<&str, &StepReport>) -> anyhow::Result<String> {
+        let colors = Self::image_colors(pipeline);
+        let mut out = String::new();
+
+        writeln!(out, "digraph pipeline {{")?;
+        writeln!(out, "    rankdir=LR;")?;
+        writeln!(out, "    compound=true;")?;
+        writeln!(
+            out,
+            "    node [shape=box, style=\"rounded,filled\", fontname=\"Helvetica\"];"
+        )?;
+
+        for (s, stage) in pipeline.stages.iter().enumerate() {
+            writeln!(out, "    subgraph cluster_{s} {{")?;
+            writeln!(out, "        label=\"{}\";", Self::dot_escape(&stage.name))?;
+
+            for (group, (name, members)) in Self::groups(stage).into_iter().enumerate() {
+                let indent = if members.len() > 1 {
+                    writeln!(out, "        subgraph cluster_{s}_{group} {{")?;
+                    writeln!(
+                        out,
+                        "            label=\"{} (matrix)\";",
+                        Self::dot_escape(name)
+                    )?;
+                    writeln!(out, "            style=dashed;")?;
+                    "            "
+                } else {
+                    "        "
+                };
+
+                for i in members.iter().copied() {
+                    let step = &stage.steps[i];
+                    let report = reports.get(step.exploded_name.as_str());
+                    let mut attrs = format!(
+                        "label=\"{}\", fillcolor=\"{}\", tooltip=\"{}\"",
+                        Self::dot_escape(&Self::label(step, report.copied(), "\n"))
+                            .replace('\n', "\\n"),
+                        colors[step.image.as_str()],
+                        Self::dot_escape(&step.image)
+                    );
+                    if let Some(report) = report {
+                        write!(
+                            attrs,
+                            ", color=\"{}\", penwidth=2",
+                            Self::status_color(report.status)
+                        )?;
+                    }
+                    writeln!(out, "{indent}s{s}_{i} [{attrs}];")?;
+                }
+
+                if members.len() > 1 {
+                    writeln!(out, "        }}")?;
+                }
+            }
+
+            writeln!(out, "    }}")?;
+        }
+
+        for (s, stage) in pipeline.stages.iter().enumerate() {
+            for (from, to) in Self::needs(stage) {
+                writeln!(out, "    s{s}_{from} -> s{s}_{to};")?;
+            }
+        }
+
+        // Stages run one after another; clusters cannot be linked directly, so the edge
+        // goes between their first steps and is clipped to the cluster borders.
+        for (s, pair) in pipeline.stages.windows(2).enumerate() {
+            if !pair[0].steps.is_empty() && !pair[1].steps.is_empty() {
+                writeln!(
+                    out,
+                    "    s{s}_0 -> s{}_0 [ltail=cluster_{s}, lhead=cluster_{}, style=dashed];",
+                    s + 1,
+                    s + 1
+                )?;
+            }
+        }
+
+        writeln!(out, "}}")?;
+        Ok(out)
+    }
+
+    fn mermaid(
+        pipeline: &Pipeline,
+        reports: &HashMap<&str, &StepReport>,
+    ) -> anyhow::Result<String> {
+        let colors = Self::image_colors(pipeline);
+        let mut classes: HashMap<String, Vec<String>> = HashMap::new();
+        let mut out = String::new();
+
+        writeln!(out, "flowchart LR")?;
+
+        for (s, stage) in pipeline.stages.iter().enumerate() {
+            writeln!(
+                out,
+                "    subgraph stage_{s}[\"{}\"]",
+                Self::mermaid_escape(&stage.name)
+            )?;
+
+            for (group, (name, members)) in Self::groups(stage).into_iter().enumerate() {
+                let indent = if members.len() > 1 {
+                    writeln!(
+                        out,
+                        "        subgraph stage_{s}_{group}[\"{} (matrix)\"]",
+                        Self::mermaid_escape(name)
+                    )?;
+                    "            "
+                } else {
+                    "        "
+                };
+
+                for i in members.iter().copied() {
+                    let step = &stage.steps[i];
+                    let id = format!("s{s}_{i}");
+                    let report = reports.get(step.exploded_name.as_str()).copied();
+                    writeln!(
+                        out,
+                        "{indent}{id}[\"{}\"]",
+                        Self::mermaid_escape(&Self::label(step, report, "<br/>"))
+                    )?;
+
+                    let image = format!("image{}", Self::color_index(&colors, &step.image));
+                    classes.entry(image).or_default().push(id.clone());
+                    if let Some(report) = report {
+                        let status = format!("{:?}", report.status).to_lowercase();
+                        classes.entry(status).or_default().push(id);
+                    }
+                }
+
+                if members.len() > 1 {
+                    writeln!(out, "        end")?;
+                }
+            }
+
+            writeln!(out, "    end")?;
+        }
+
+        for (s, stage) in pipeline.stages.iter().enumerate() {
+            for (from, to) in Self::needs(stage) {
+                writeln!(out, "    s{s}_{from} --> s{s}_{to}")?;
+            }
+        }
+        for s in 1..pipeline.stages.len() {
+            writeln!(out, "    stage_{} -.-> stage_{s}", s - 1)?;
+        }
+
+        for (index, color) in IMAGE_COLORS.iter().enumerate() {
+            writeln!(out, "    classDef image{index} fill:{color}")?;
+        }
+        for status in [
+            StepStatus::Success,
+            StepStatus::Failed,
+            StepStatus::Cancelled,
+            StepStatus::Skipped,
+        ] {
+            writeln!(
+                out,
+                "    classDef {} stroke:{},stroke-width:3px",
+                format!("{:?}", status).to_lowercase(),
+                Self::status_color(status)
+            )?;
+        }
+
+        let mut classes: Vec<_> = classes.into_iter().collect();
+        classes.sort();
+        for (class, ids) in classes {
+            writeln!(out, "    class {} {class}", ids.join(","))?;
+        }
+
+        Ok(out)
+    }
+
+    /// Steps in declaration order, with the variants of a matrix step kept together.
+    fn groups(stage: &Stage) -> Vec<(&str, Vec<usize>)> {
+        let mut groups: Vec<(&str, Vec<usize>)> = Vec::new();
+
+        for (i, step) in stage.steps.iter().enumerate() {
+            match groups.iter_mut().find(|(name, _)| *name == step.name) {
+                Some((_, members)) => members.push(i),
+                None => groups.push((&step.name, vec![i])),
+            }
+        }
+
+        groups
+    }
+
+    /// `needs` edges as indices into `stage.steps`; a need on a matrix step means all of
+    /// its variants unless it names one.
+    fn needs(stage: &Stage) -> Vec<(usize, usize)> {
+        let mut edges = Vec::new();
+
+        for (to, step) in stage.steps.iter().enumerate() {
+            for needed in step.needs.iter() {
+                for (from, other) in stage.steps.iter().enumerate() {
+                    if other.matches_need(needed) {
+                        edges.push((from, to));
+                    }
+                }
+            }
+        }
+
+        edges
+    }
+
+    fn label(step: &Step, report: Option<&StepReport>, line_break: &str) -> String {
+        match report {
+            Some(report) => format!(
+                "{}{line_break}{:?} {}",
+                step.exploded_name,
+                report.status,
+                format_wall_clock(report.elapsed)
+            ),
+            None => step.exploded_name.clone(),
+        }
+    }
+
+    fn image_colors(pipeline: &Pipeline) -> HashMap<&str, &'static str> {
+        let mut colors = HashMap::new();
+
+        for step in pipeline.stages.iter().flat_map(|stage| &stage.steps) {
+            let next = IMAGE_COLORS[colors.len() % IMAGE_COLORS.len()];
+            colors.entry(step.image.as_str()).or_insert(next);
+        }
+
+        colors
+    }
+
+    fn color_index(colors: &HashMap<&str, &'static str>, image: &str) -> usize {
+        IMAGE_COLORS
+            .iter()
+            .position(|color| *color == colors[image])
+            .unwrap_or_default()
+    }
+
+    fn status_color(status: StepStatus) -> &'static str {
+        match status {
+            StepStatus::Success => "#2da44e",
+            StepStatus::Failed => "#cf222e",
+            StepStatus::Cancelled => "#bf8700",
+            StepStatus::Skipped => "#8c959f",
+        }
+    }
+
+    fn dot_escape(text: &str) -> String {
+        text.replace('\\', "\\\\").replace('"', "\\\"")
+    }
+
+    fn mermaid_escape(text: &str) -> String {
+        text.replace('"', "#quot;")
+    }
+}
@@ -0,0 +1,42 @@
+use colored::Colorize;
+
+use crate::models::Pipeline;
+
+pub struct ListReporter;
+
+impl ListReporter {
+    pub fn report(pipeline: &Pipeline) {
+        for (name, profile) in pipeline.profiles.iter() {
+            println!(
+                "{} {:<20} include {:?}, exclude {:?}",
+                "profile".bold(),
+                name.cyan(),
+                profile.include,
+                profile.exclude
+            );
+        }
+
+        for stage in pipeline.stages.iter() {
+            println!("\n{}", stage.name.to_uppercase().bold());
+            if let Some(description) = &stage.description {
+                println!("  {}", description.dimmed());
+            }
+
+            for step in stage.steps.iter() {
+                let description = step.description.as_deref().unwrap_or_default();
+                print!(
+                    "  • {:<28} {}",
+                    step.exploded_name.cyan(),
+                    description.dimmed()
+                );
+                if step.detach {
+                    print!(" {}", "(detached)".blue());
+                }
+                if !step.tags.is_empty() {
+                    print!(" {}", format!("[{}]", step.tags.join(", ")).yellow());
+                }
+                println!();
+            }
+        }
+    }
+}
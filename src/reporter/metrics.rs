@@ -0,0 +1,105 @@
+use std::{fmt::Write, path::Path};
+
+use anyhow::Ok;
+use tokio::fs::{create_dir_all, rename, write};
+
+use crate::models::PipelineReport;
+
+pub struct MetricsReporter;
+
+impl MetricsReporter {
+    /// Writes the report in Prometheus text format. The file is replaced atomically so a
+    /// scraping collector never observes a partially written file.
+    pub async fn save(report: &PipelineReport, path: &Path) -> anyhow::Result<()> {
+        if let Some(parent) = path.parent()
+            && !parent.as_os_str().is_empty()
+        {
+            create_dir_all(parent).await?;
+        }
+
+        let tmp_path = path.with_extension("prom.tmp");
+        write(&tmp_path, Self::render(report)?).await?;
+        rename(&tmp_path, path).await?;
+
+        Ok(())
+    }
+
+    fn render(report: &PipelineReport) -> anyhow::Result<String> {
+        let mut buffer = String::new();
+
+        writeln!(
+            buffer,
+            "# HELP ciroach_step_duration_seconds Wall-clock duration of a step including retries."
+        )?;
+        writeln!(buffer, "# TYPE ciroach_step_duration_seconds gauge")?;
+        for stage in report.stage_reports.iter() {
+            for step in stage.step_reports.iter() {
+                writeln!(
+                    buffer,
+                    "ciroach_step_duration_seconds{{stage=\"{}\",step=\"{}\",status=\"{}\"}} {}",
+                    Self::escape(&stage.name),
+                    Self::escape(&step.name),
+                    format!("{:?}", step.status).to_lowercase(),
+                    step.elapsed as f64 / 1000.0,
+                )?;
+            }
+        }
+
+        writeln!(
+            buffer,
+            "# HELP ciroach_step_retries_total Retries performed by a step."
+        )?;
+        writeln!(buffer, "# TYPE ciroach_step_retries_total counter")?;
+        for stage in report.stage_reports.iter() {
+            for step in stage.step_reports.iter() {
+                writeln!(
+                    buffer,
+                    "ciroach_step_retries_total{{stage=\"{}\",step=\"{}\"}} {}",
+                    Self::escape(&stage.name),
+                    Self::escape(&step.name),
+                    step.retries,
+                )?;
+            }
+        }
+
+        writeln!(
+            buffer,
+            "# HELP ciroach_pipeline_duration_seconds Wall-clock duration of the pipeline."
+        )?;
+        writeln!(buffer, "# TYPE ciroach_pipeline_duration_seconds gauge")?;
+        writeln!(
+            buffer,
+            "ciroach_pipeline_duration_seconds {}",
+            report.elapsed as f64 / 1000.0
+        )?;
+
+        writeln!(
+            buffer,
+            "# HELP ciroach_pipeline_success Whether the last pipeline run succeeded."
+        )?;
+        writeln!(buffer, "# TYPE ciroach_pipeline_success gauge")?;
+        writeln!(
+            buffer,
+            "ciroach_pipeline_success {}",
+            u8::from(report.is_success())
+        )?;
+
+        Ok(buffer)
+    }
+
+    /// Escapes a label value as required by the exposition format and drops control
+    /// characters that some collectors reject.
+    fn escape(value: &str) -> String {
+        let mut escaped = String::with_capacity(value.len());
+        for ch in value.chars() {
+            match ch {
+                '\\' => escaped.push_str("\\\\"),
+                '"' => escaped.push_str("\\\""),
+                '\n' => escaped.push_str("\\n"),
+                ch if ch.is_control() => {}
+                ch => escaped.push(ch),
+            }
+        }
+        escaped
+    }
+}
@@ -1,5 +1,19 @@
 mod console;
 mod file;
+mod flaky;
+mod graph;
+mod list;
+mod metrics;
+mod run_dir;
+mod summary;
+mod timeline;
 
 pub use console::*;
 pub use file::*;
+pub use flaky::*;
+pub use graph::*;
+pub use list::*;
+pub use metrics::*;
+pub use run_dir::*;
+pub use summary::*;
+pub use timeline::*;
@@ -0,0 +1,177 @@
+use std::{
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::Ok;
+use chrono::{Local, TimeZone};
+use serde::Serialize;
+use tokio::fs::{create_dir_all, read_dir, remove_dir_all, try_exists, write};
+
+use crate::{
+    github::GithubNotifier,
+    models::{EngineInfo, LogsConfig, PipelineReport, PullStat},
+    output::Icon,
+    reporter::{FileReporter, TimelineReporter},
+};
+
+pub const LOGS_DIR: &str = "logs";
+
+/// Written to `meta.json` next to the logs of a run.
+#[derive(Debug, Serialize)]
+pub struct RunMeta {
+    pub run_id: String,
+    pub config: PathBuf,
+    pub git_sha: Option<String>,
+    pub engine: Option<EngineInfo>,
+    /// What each container step ran in, for telling apart runs on a moved tag.
+    pub images: Vec<StepImage>,
+    /// Time and bytes spent pulling each image.
+    pub pull_stats: Vec<PullStat>,
+    pub started_at: String,
+    pub finished_at: String,
+    pub success: bool,
+    pub exit_code: u8,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StepImage {
+    pub stage: String,
+    pub step: String,
+    pub image: String,
+    pub digest: Option<String>,
+}
+
+impl RunMeta {
+    pub fn new(report: &PipelineReport, config: &Path) -> Self {
+        let timestamp = |millis: u64| {
+            Local
+                .timestamp_millis_opt(millis as i64)
+                .single()
+                .map(|time| time.to_rfc3339())
+                .unwrap_or_default()
+        };
+        let success = report.is_success();
+        let images = report
+            .stage_reports
+            .iter()
+            .flat_map(|stage| stage.step_reports.iter().map(move |step| (stage, step)))
+            .filter_map(|(stage, step)| {
+                Some(StepImage {
+                    stage: stage.name.clone(),
+                    step: step.name.clone(),
+                    image: step.image.clone()?,
+                    digest: step.image_digest.clone(),
+                })
+            })
+            .collect();
+
+        Self {
+            run_id: report.run_id.clone(),
+            config: config.to_path_buf(),
+            git_sha: GithubNotifier::detect_sha(),
+            engine: report.engine.clone(),
+            images,
+            pull_stats: report.pull_stats.clone(),
+            started_at: timestamp(report.started_at),
+            finished_at: timestamp(report.finished_at),
+            success,
+            exit_code: if success { 0 } else { 1 },
+        }
+    }
+}
+
+/// One directory per run under `logs/`: the pipeline report, a log file per step under
+/// `steps/<stage>/`, a timeline chart and `meta.json`.
+pub struct RunDirReporter;
+
+impl RunDirReporter {
+    /// Writes the run directory and returns its path.
+    pub async fn save(report: &PipelineReport, meta: &RunMeta) -> anyhow::Result<PathBuf> {
+        let dir = Path::new(LOGS_DIR).join(&report.run_id);
+        create_dir_all(&dir).await?;
+
+        FileReporter::save(report, &dir.join("report.log")).await?;
+
+        for (stage_name, step_name, lines) in report.ordered_logs() {
+            let path = Self::step_log(&report.run_id, stage_name, step_name);
+            // The log task already wrote every line of the run's steps there; the kept
+            // lines are only a fallback.
+            if try_exists(&path).await? {
+                continue;
+            }
+            if let Some(stage_dir) = path.parent() {
+                create_dir_all(stage_dir).await?;
+            }
+            let mut content = FileReporter::plain(lines).join("\n");
+            content.push('\n');
+            write(path, content).await?;
+        }
+
+        write(dir.join("timeline.svg"), TimelineReporter::svg(report)?).await?;
+        write(dir.join("meta.json"), serde_json::to_string_pretty(meta)?).await?;
+
+        Ok(dir)
+    }
+
+    /// Where [`Self::save`] puts the log of a step, relative to the workspace. Step ids are
+    /// only unique within a stage.
+    pub fn step_log(run_id: &str, stage_name: &str, step_name: &str) -> PathBuf {
+        Path::new(LOGS_DIR)
+            .join(run_id)
+            .join("steps")
+            .join(Self::file_name(stage_name))
+            .join(format!("{}.log", Self::file_name(step_name)))
+    }
+
+    /// Removes run directories beyond the newest `keep`, and those older than `max_age`.
+    pub async fn prune(config: &LogsConfig) -> anyhow::Result<()> {
+        let mut dir = match read_dir(LOGS_DIR).await {
+            std::result::Result::Ok(dir) => dir,
+            std::result::Result::Err(_) => return Ok(()),
+        };
+
+        let mut runs = Vec::new();
+        while let Some(entry) = dir.next_entry().await? {
+            let meta = entry.metadata().await?;
+            if meta.is_dir() {
+                runs.push((entry.path(), meta.modified().ok()));
+            }
+        }
+
+        // Run ids start with their timestamp, so the oldest come first.
+        runs.sort();
+        let excess = runs.len().saturating_sub(config.keep);
+
+        for (index, (path, modified)) in runs.into_iter().enumerate() {
+            let expired = config.max_age.is_some_and(|max_age| {
+                modified
+                    .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+                    .is_some_and(|age| age > max_age)
+            });
+
+            if (index < excess || expired)
+                && let Err(err) = remove_dir_all(&path).await
+            {
+                eprintln!(
+                    "{} Failed to remove old logs {}: {}",
+                    Icon::Warning,
+                    path.display(),
+                    err
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    fn file_name(step_name: &str) -> String {
+        step_name
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '_' | '-' => c,
+                _ => '_',
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,206 @@
+use crate::models::{PipelineReport, StepStatus, format_wall_clock};
+
+const PLACEHOLDERS: [&str; 8] = [
+    "status",
+    "passed",
+    "failed",
+    "skipped",
+    "cancelled",
+    "total",
+    "duration",
+    "failed_names",
+];
+
+/// One line about a finished run for prompts and scripts, rendered from a template like
+/// `{status} {passed}/{total} in {duration}`. `{{` and `}}` are literal braces.
+#[derive(Debug, Clone)]
+pub struct SummaryFormat {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone)]
+enum Part {
+    Text(String),
+    Placeholder(&'static str),
+}
+
+impl SummaryFormat {
+    pub fn parse(template: &str) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = template.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    let mut closed = false;
+                    for c in chars.by_ref() {
+                        if c == '}' {
+                            closed = true;
+                            break;
+                        }
+                        name.push(c);
+                    }
+                    if !closed {
+                        return Err(format!("'{{{name}' is not closed with '}}'"));
+                    }
+                    let Some(placeholder) = PLACEHOLDERS.into_iter().find(|known| *known == name)
+                    else {
+                        return Err(format!(
+                            "unknown placeholder '{{{name}}}'. Use one of: {}",
+                            PLACEHOLDERS.join(", ")
+                        ));
+                    };
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(Part::Placeholder(placeholder));
+                }
+                '}' => return Err("unmatched '}'. Write '}}' for a literal one".to_string()),
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+
+        Ok(Self { parts })
+    }
+
+    pub fn render(&self, report: &PipelineReport) -> String {
+        let steps: Vec<_> = report
+            .stage_reports
+            .iter()
+            .flat_map(|stage| &stage.step_reports)
+            .collect();
+        let count = |status: StepStatus| {
+            steps
+                .iter()
+                .filter(|step| step.status == status)
+                .count()
+                .to_string()
+        };
+
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Text(text) => text.clone(),
+                Part::Placeholder(name) => match *name {
+                    "status" => match report.is_success() {
+                        true => "passed".to_string(),
+                        false => "failed".to_string(),
+                    },
+                    "passed" => count(StepStatus::Success),
+                    "failed" => count(StepStatus::Failed),
+                    "skipped" => count(StepStatus::Skipped),
+                    "cancelled" => count(StepStatus::Cancelled),
+                    "total" => steps.len().to_string(),
+                    "duration" => format_wall_clock(report.elapsed),
+                    "failed_names" => steps
+                        .iter()
+                        .filter(|step| step.status == StepStatus::Failed)
+                        .map(|step| step.name.as_str())
+                        .collect::<Vec<_>>()
+                        .join(","),
+                    _ => unreachable!("placeholders are checked when parsing"),
+                },
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(steps: &[(&str, &str)], error: Option<&str>) -> PipelineReport {
+        let steps: Vec<_> = steps
+            .iter()
+            .map(|(name, status)| {
+                serde_json::json!({ "name": name, "status": status, "retries": 0, "elapsed": 0 })
+            })
+            .collect();
+        serde_json::from_value(serde_json::json!({
+            "stage_reports": [{ "name": "default", "step_reports": steps }],
+            "elapsed": 83_000,
+            "error": error,
+        }))
+        .unwrap()
+    }
+
+    fn render(template: &str, report: &PipelineReport) -> String {
+        SummaryFormat::parse(template).unwrap().render(report)
+    }
+
+    #[test]
+    fn renders_every_placeholder() {
+        let report = report(
+            &[
+                ("lint", "Success"),
+                ("test", "Failed"),
+                ("bench", "Failed"),
+                ("docs", "Skipped"),
+                ("deploy", "Cancelled"),
+            ],
+            None,
+        );
+        assert_eq!(
+            render(
+                "{status} {passed}/{total} failed={failed} skipped={skipped} \
+                 cancelled={cancelled} in {duration}: {failed_names}",
+                &report
+            ),
+            "failed 1/5 failed=2 skipped=1 cancelled=1 in 1m 23s: test,bench"
+        );
+    }
+
+    #[test]
+    fn a_run_that_stopped_early_is_failed_even_without_failed_steps() {
+        let passed = report(&[("lint", "Success")], None);
+        assert_eq!(render("{status}", &passed), "passed");
+        assert_eq!(render("[{failed_names}]", &passed), "[]");
+
+        let stuck = report(&[("lint", "Success")], Some("stage 'default' deadlocked"));
+        assert_eq!(render("{status}", &stuck), "failed");
+    }
+
+    #[test]
+    fn doubled_braces_are_literal() {
+        let report = report(&[("lint", "Success")], None);
+        assert_eq!(render("{{status}}", &report), "{status}");
+        assert_eq!(render("{{{status}}}", &report), "{passed}");
+        assert_eq!(render("}}{{", &report), "}{");
+        assert_eq!(render("", &report), "");
+    }
+
+    #[test]
+    fn an_unclosed_brace_is_rejected() {
+        let err = SummaryFormat::parse("{status} {passed").unwrap_err();
+        assert_eq!(err, "'{passed' is not closed with '}'");
+        assert!(SummaryFormat::parse("{").is_err());
+    }
+
+    #[test]
+    fn an_unmatched_closing_brace_is_rejected() {
+        let err = SummaryFormat::parse("{status} done}").unwrap_err();
+        assert_eq!(err, "unmatched '}'. Write '}}' for a literal one");
+        assert!(SummaryFormat::parse("{status}}").is_err());
+    }
+
+    #[test]
+    fn an_unknown_placeholder_is_rejected() {
+        let err = SummaryFormat::parse("{status} {passd}").unwrap_err();
+        assert!(err.starts_with("unknown placeholder '{passd}'. Use one of: status, passed"));
+        assert!(SummaryFormat::parse("{}").is_err());
+        assert!(SummaryFormat::parse("{ status }").is_err());
+    }
+}
@@ -0,0 +1,143 @@
+use std::fmt::Write;
+
+use colored::Colorize;
+
+use crate::models::{Attempt, PipelineReport, StepReport, StepStatus, format_wall_clock};
+
+const CHART_WIDTH: u64 = 60;
+const SVG_WIDTH: u64 = 900;
+const SVG_LABEL_WIDTH: u64 = 240;
+const SVG_ROW_HEIGHT: u64 = 22;
+
+/// Gantt chart of when each step ran relative to the whole run. Retried steps show one
+/// segment per attempt, with the backoff in between left blank.
+pub struct TimelineReporter;
+
+impl TimelineReporter {
+    pub fn print(report: &PipelineReport) {
+        println!(
+            "\n{}",
+            format!("--- Timeline ({}) ---\n", format_wall_clock(report.elapsed))
+                .bold()
+                .underline()
+        );
+
+        for stage in report.stage_reports.iter() {
+            for step in Self::ran(stage.step_reports.iter()) {
+                let mut row = vec![' '; CHART_WIDTH as usize];
+                for attempt in Self::attempts(step) {
+                    let (start, end) = Self::span(report, &attempt, CHART_WIDTH);
+                    for cell in row.iter_mut().take(end + 1).skip(start) {
+                        *cell = '#';
+                    }
+                }
+
+                let bar: String = row.into_iter().collect();
+                let bar = match step.status {
+                    StepStatus::Success => bar.green(),
+                    StepStatus::Failed => bar.red(),
+                    StepStatus::Cancelled | StepStatus::Skipped => bar.yellow(),
+                };
+
+                println!(
+                    "{:<30} |{}| {}",
+                    step.name.cyan(),
+                    bar,
+                    format_wall_clock(step.elapsed)
+                );
+            }
+        }
+    }
+
+    /// The same chart as a standalone SVG document.
+    pub fn svg(report: &PipelineReport) -> anyhow::Result<String> {
+        let steps: Vec<&StepReport> = report
+            .stage_reports
+            .iter()
+            .flat_map(|stage| Self::ran(stage.step_reports.iter()))
+            .collect();
+        let chart_width = SVG_WIDTH - SVG_LABEL_WIDTH;
+        let height = (steps.len() as u64 + 1) * SVG_ROW_HEIGHT;
+
+        let mut svg = String::new();
+        writeln!(
+            svg,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{SVG_WIDTH}" height="{height}" font-family="monospace" font-size="12">"#
+        )?;
+        writeln!(
+            svg,
+            r#"<text x="4" y="15">Total: {}</text>"#,
+            format_wall_clock(report.elapsed)
+        )?;
+
+        for (row, step) in steps.iter().enumerate() {
+            let y = (row as u64 + 1) * SVG_ROW_HEIGHT;
+            let color = match step.status {
+                StepStatus::Success => "#2da44e",
+                StepStatus::Failed => "#cf222e",
+                StepStatus::Cancelled | StepStatus::Skipped => "#bf8700",
+            };
+
+            writeln!(
+                svg,
+                r#"<text x="4" y="{}">{}</text>"#,
+                y + 15,
+                Self::escape(&step.name)
+            )?;
+            for (number, attempt) in Self::attempts(step).iter().enumerate() {
+                let (start, end) = Self::span(report, attempt, chart_width);
+                writeln!(
+                    svg,
+                    r#"<rect x="{}" y="{}" width="{}" height="{}" fill="{color}"><title>{}, attempt {}: {}</title></rect>"#,
+                    SVG_LABEL_WIDTH + start as u64,
+                    y + 3,
+                    end - start + 1,
+                    SVG_ROW_HEIGHT - 6,
+                    Self::escape(&step.name),
+                    number + 1,
+                    Self::escape(&attempt.to_string())
+                )?;
+            }
+        }
+
+        writeln!(svg, "</svg>")?;
+        Ok(svg)
+    }
+
+    fn ran<'a>(
+        steps: impl Iterator<Item = &'a StepReport>,
+    ) -> impl Iterator<Item = &'a StepReport> {
+        steps.filter(|step| step.started_at > 0)
+    }
+
+    /// Reports from before attempts were recorded only know the step's overall span.
+    fn attempts(step: &StepReport) -> Vec<Attempt> {
+        if step.attempts.is_empty() {
+            vec![Attempt {
+                started_at: step.started_at,
+                finished_at: step.finished_at,
+                ..Attempt::default()
+            }]
+        } else {
+            step.attempts.clone()
+        }
+    }
+
+    /// First and last cell covered by `attempt` on a chart `width` cells wide.
+    fn span(report: &PipelineReport, attempt: &Attempt, width: u64) -> (usize, usize) {
+        let total = report.finished_at.saturating_sub(report.started_at).max(1);
+        let cell = |time: u64| {
+            let offset = time.saturating_sub(report.started_at).min(total);
+            (offset * (width - 1) / total) as usize
+        };
+
+        let start = cell(attempt.started_at);
+        (start, cell(attempt.finished_at).max(start))
+    }
+
+    fn escape(text: &str) -> String {
+        text.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+    }
+}
@@ -0,0 +1,131 @@
+use std::{
+    collections::HashSet,
+    io::IsTerminal,
+    sync::{Arc, Mutex as StdMutex},
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    sync::Mutex,
+};
+
+use crate::{engine::DockerEngine, models::Step, output::Icon, ui};
+
+/// Coordinates `--debug-on-failure`: only one debug session runs at a time, and the
+/// containers of steps still running are paused until it ends.
+pub struct DebugGate {
+    engine: Arc<DockerEngine>,
+    cwd: String,
+    user: String,
+    session: Mutex<()>,
+    running: StdMutex<HashSet<String>>,
+}
+
+impl DebugGate {
+    pub fn new(engine: Arc<DockerEngine>, cwd: impl Into<String>, user: impl Into<String>) -> Self {
+        Self {
+            engine,
+            cwd: cwd.into(),
+            user: user.into(),
+            session: Mutex::new(()),
+            running: StdMutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn track(&self, container_id: &str) {
+        if let Ok(mut running) = self.running.lock() {
+            running.insert(container_id.to_string());
+        }
+    }
+
+    pub fn untrack(&self, container_id: &str) {
+        if let Ok(mut running) = self.running.lock() {
+            running.remove(container_id);
+        }
+    }
+
+    /// Opens a copy of the failed container for inspection and blocks until the user is
+    /// done. The failed container itself is left to the caller.
+    pub async fn inspect(&self, step: &Step, container_id: &str) {
+        let _session = self.session.lock().await;
+        let _bars = ui::hide_bars();
+        let paused = self.pause_others(container_id).await;
+
+        match self
+            .engine
+            .debug_container(container_id, step, &self.cwd, &self.user)
+            .await
+        {
+            Ok(name) => {
+                println!(
+                    "\n{} Step '{}' failed. Its container is kept for debugging:",
+                    Icon::Debug,
+                    step.exploded_name
+                );
+                println!("   docker exec -it {name} sh");
+
+                if std::io::stdin().is_terminal() && std::io::stdout().is_terminal() {
+                    println!("   Opening a shell now, exit it to continue.\n");
+                    if let Err(err) = self.engine.shell(&name).await {
+                        eprintln!("{} Debug shell failed: {}", Icon::Warning, err);
+                    }
+                } else {
+                    println!("   Press Enter to remove it and continue.");
+                    let mut line = String::new();
+                    BufReader::new(tokio::io::stdin())
+                        .read_line(&mut line)
+                        .await
+                        .ok();
+                }
+
+                if let Err(err) = self.engine.remove_debug_container(&name).await {
+                    eprintln!(
+                        "{} Failed to remove debug container '{}': {}",
+                        Icon::Warning,
+                        name,
+                        err
+                    );
+                }
+            }
+            Err(err) => {
+                eprintln!(
+                    "{} Could not prepare a debug container: {}",
+                    Icon::Warning,
+                    err
+                );
+            }
+        }
+
+        for id in paused {
+            self.engine.unpause_container(&id).await.ok();
+        }
+    }
+
+    async fn pause_others(&self, failed_id: &str) -> Vec<String> {
+        let running: Vec<String> = match self.running.lock() {
+            Ok(running) => running
+                .iter()
+                .filter(|id| *id != failed_id)
+                .cloned()
+                .collect(),
+            Err(_) => return Vec::new(),
+        };
+
+        let mut paused = Vec::new();
+        for id in running {
+            if self.engine.pause_container(&id).await.is_ok() {
+                paused.push(id);
+            }
+        }
+
+        if !paused.is_empty() {
+            println!(
+                "{} Paused {} running step(s) meanwhile.",
+                Icon::Pause,
+                paused.len()
+            );
+        }
+
+        paused
+    }
+}
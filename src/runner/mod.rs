@@ -1,7 +1,15 @@
+pub mod debug;
 pub mod pipeline;
+pub mod pulls;
+pub mod service;
+pub mod session;
 pub mod stage;
 pub mod step;
 
+pub use debug::*;
 pub use pipeline::*;
+pub use pulls::*;
+pub use service::*;
+pub use session::*;
 pub use stage::*;
 pub use step::*;
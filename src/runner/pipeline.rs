@@ -1,121 +1,1758 @@
 use anyhow::Ok;
+use chrono::Local;
 use colored::Colorize;
-use futures_util::future::try_join_all;
+use futures_util::future::join_all;
+use tokio::{sync::Semaphore, task::AbortHandle, time::sleep};
 use tokio_util::sync::CancellationToken;
 
-use std::{collections::HashSet, path::PathBuf, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex, Once,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
+#[cfg(feature = "kubernetes")]
+use crate::kubernetes::KubeEngine;
 use crate::{
     engine::DockerEngine,
-    logger::Logger,
-    models::{Pipeline, PipelineReport, Stage, StageReport, StepReport},
-    runner::StageRunner,
-    ui::PreFlightUI,
+    error::CiroachError,
+    events::{EventBus, PipelineEvent},
+    images::{IMAGE_LOCK, IMAGES_MANIFEST, ImageLock, ImageManifest},
+    lock::{LockPolicy, WorkspaceLock},
+    logger::{CollectedLogs, LogMessage, Logger},
+    models::{
+        Annotation, EngineInfo, LowSpacePolicy, Pipeline, PipelineReport, PullConfig, PullStat,
+        Runner, SkipReason, Stage, StageReport, Step, StepReport, Trigger, format_wall_clock,
+        log_key, now_millis,
+    },
+    output::{Icon, OutputMode, Verbosity},
+    platform::Platform,
+    reporter::RunDirReporter,
+    runner::{ConcurrencyGroups, DebugGate, ImagePulls, PullState, Services, StageRunner},
+    ui::{self, PreFlightUI, Progress, StageUI},
 };
 
+/// The engine is announced once per process, not once per run of `ciroach serve`.
+static ENGINE_LOGGED: Once = Once::new();
+
+enum PullOutcome {
+    /// Downloaded this many bytes.
+    Pulled(u64),
+    /// The pull failed but an earlier copy of the image is available.
+    Cached(anyhow::Error),
+    /// The image cannot be used, for this reason.
+    Failed(String),
+}
+
 pub struct PipelineRunner {
+    run_id: String,
     pipeline: Pipeline,
     engine: Arc<DockerEngine>,
+    engine_info: Option<EngineInfo>,
+    /// Runs the steps with `runner = "kubernetes"`, when there are any.
+    #[cfg(feature = "kubernetes")]
+    cluster: Option<Arc<KubeEngine>>,
     cwd: String,
     user: String,
+    platform: Platform,
+    mode: OutputMode,
+    events: EventBus,
+    debug: Option<Arc<DebugGate>>,
+    keep_failed: bool,
+    progress: bool,
+    verbosity: Verbosity,
+    lock_policy: LockPolicy,
+    lock_images: bool,
+    groups: Arc<ConcurrencyGroups>,
+    pull_stats: Mutex<Vec<PullStat>>,
+    durations: HashMap<String, u64>,
 }
 
 impl PipelineRunner {
+    /// Fails with [`CiroachError::Engine`] when the engine cannot be reached.
     pub async fn new(
         pipeline: Pipeline,
-        user: impl Into<String>,
         cwd: PathBuf,
-    ) -> anyhow::Result<Self> {
-        let engine = Arc::new(DockerEngine::new()?);
+        mode: OutputMode,
+    ) -> Result<Self, CiroachError> {
+        Self::connect(pipeline, cwd, mode)
+            .await
+            .map_err(CiroachError::engine)
+    }
+
+    async fn connect(pipeline: Pipeline, cwd: PathBuf, mode: OutputMode) -> anyhow::Result<Self> {
+        let platform = Platform::detect(&cwd, &pipeline.platform)?;
+        let run_id = Self::new_run_id();
+        // A pipeline of host steps runs without any engine.
+        let containers = pipeline
+            .stages
+            .iter()
+            .flat_map(|stage| &stage.steps)
+            .any(|step| step.runner == Runner::Container);
+
+        let mut engine = match containers {
+            true => DockerEngine::new()?,
+            false => DockerEngine::disconnected()?,
+        }
+        .relabel_workspace(platform.relabel)
+        .run_id(&run_id)
+        .workspace_ignore(pipeline.ignore.clone());
+        let engine_info = match containers {
+            true => Some(engine.ping().await?),
+            false => None,
+        };
+        #[cfg(feature = "kubernetes")]
+        let cluster = match pipeline
+            .stages
+            .iter()
+            .flat_map(|stage| &stage.steps)
+            .any(|step| step.runner == Runner::Kubernetes)
+        {
+            true => {
+                let cwd = cwd.to_string_lossy();
+                let cluster = KubeEngine::new(&pipeline.engine.kubernetes, &run_id, &cwd).await?;
+                Some(Arc::new(cluster))
+            }
+            false => None,
+        };
 
         Ok(Self {
+            run_id,
+            groups: Arc::new(ConcurrencyGroups::new(&pipeline)),
+            pull_stats: Mutex::default(),
             pipeline,
-            engine,
+            engine: Arc::new(engine),
+            engine_info,
+            #[cfg(feature = "kubernetes")]
+            cluster,
             cwd: cwd.to_string_lossy().to_string(),
-            user: user.into(),
+            user: platform.user.clone().unwrap_or_default(),
+            platform,
+            mode,
+            events: EventBus::default(),
+            debug: None,
+            keep_failed: false,
+            progress: true,
+            verbosity: Verbosity::Normal,
+            lock_policy: LockPolicy::default(),
+            lock_images: false,
+            durations: HashMap::new(),
+        })
+    }
+
+    /// Pauses the run on the first failed step and opens a shell in a copy of its container.
+    pub fn debug_on_failure(mut self, enabled: bool) -> Self {
+        self.debug =
+            enabled.then(|| Arc::new(DebugGate::new(self.engine.clone(), &self.cwd, &self.user)));
+        self
+    }
+
+    /// Leaves the containers of failed steps in place for post-mortem.
+    pub fn keep_failed(mut self, keep_failed: bool) -> Self {
+        self.keep_failed = keep_failed;
+        self
+    }
+
+    /// Allows animated progress bars; plain lines are printed otherwise.
+    pub fn progress(mut self, enabled: bool) -> Self {
+        self.progress = enabled;
+        self
+    }
+
+    pub fn verbosity(mut self, verbosity: Verbosity) -> Self {
+        self.verbosity = verbosity;
+        self
+    }
+
+    /// What to do when another run holds the workspace lock.
+    pub fn lock_policy(mut self, lock_policy: LockPolicy) -> Self {
+        self.lock_policy = lock_policy;
+        self
+    }
+
+    /// Pulls images by tag and records their digests in `ciroach.lock`, instead of pulling
+    /// the digests locked there.
+    pub fn lock_images(mut self, lock_images: bool) -> Self {
+        self.lock_images = lock_images;
+        self
+    }
+
+    /// How long each step took before, in milliseconds, to start the long ones first.
+    pub fn expected_durations(mut self, durations: HashMap<String, u64>) -> Self {
+        self.durations = durations;
+        self
+    }
+
+    #[cfg(feature = "dashboard")]
+    pub fn pipeline(&self) -> &Pipeline {
+        &self.pipeline
+    }
+
+    /// Handle for subscribing to the run's events; subscribe before calling [`Self::run`].
+    #[cfg(feature = "dashboard")]
+    pub fn events(&self) -> EventBus {
+        self.events.clone()
+    }
+
+    /// Failed steps are part of the report; an error means the run could not go on, from
+    /// [`CiroachError::Cancelled`] or [`CiroachError::Engine`] when not classified otherwise.
+    #[tracing::instrument(name = "pipeline", skip_all, fields(stages = self.pipeline.stages.len()))]
+    pub async fn run(self, token: CancellationToken) -> Result<PipelineReport, CiroachError> {
+        let cancelled = token.clone();
+        self.execute(token).await.map_err(|err| {
+            if cancelled.is_cancelled() {
+                CiroachError::Cancelled
+            } else {
+                CiroachError::engine(err)
+            }
         })
     }
 
-    pub async fn run(self, token: CancellationToken) -> anyhow::Result<PipelineReport> {
-        let logger = Logger::new(100);
+    async fn execute(mut self, token: CancellationToken) -> anyhow::Result<PipelineReport> {
+        let _lock =
+            WorkspaceLock::acquire(Path::new(&self.cwd), &self.run_id, self.lock_policy, &token)
+                .await?;
+        if !self.lock_images
+            && let Some(image_lock) = ImageLock::load(Path::new(&self.cwd).join(IMAGE_LOCK)).await?
+        {
+            image_lock.apply(&mut self.pipeline);
+        }
+        let timer = Instant::now();
+        let started_at = now_millis();
+        if !self.verbosity.is_quiet() {
+            self.platform.announce();
+            if let Some(engine_info) = &self.engine_info {
+                ENGINE_LOGGED.call_once(|| {
+                    println!("{} Engine: {}.", Icon::Platform, engine_info);
+                });
+            }
+        }
+
+        let logger = Logger::new(
+            100,
+            self.events.clone(),
+            self.verbosity,
+            &self.pipeline.stages,
+            &self.run_id,
+        );
+
         let mut stage_reports = Vec::new();
+        let mut drained = true;
+        let outcome = match Services::start(self.engine.clone(), &self.pipeline).await {
+            std::result::Result::Ok(services) => {
+                let services = Arc::new(services);
+                let outcome = tokio::select! {
+                    outcome = self.run_stages(&mut stage_reports, &logger, &services, &token) => outcome,
+                    _ = Self::drain_expired(&token, self.pipeline.engine.drain_timeout) => {
+                        drained = false;
+                        self.force_stop().await;
+                        Err(anyhow::anyhow!("steps did not stop in time"))
+                    }
+                };
+                services.teardown().await;
+                outcome
+            }
+            Err(err) => Err(err),
+        };
+        // Cancelling is reported through the skipped steps, not as a failure of the run.
+        let mut error = match outcome {
+            Err(err) if !token.is_cancelled() => Some(err),
+            _ => None,
+        };
+        for stage in self.pipeline.stages.iter().skip(stage_reports.len()) {
+            let reason = match token.is_cancelled() {
+                true => SkipReason::Interrupted,
+                false => SkipReason::Aborted,
+            };
+            stage_reports.push(self.skip_stage(stage, reason));
+        }
+
+        let collected = match drained {
+            true => logger.finish().await,
+            false => logger.finish_now().await,
+        };
+        // The steps' reports still stand without their logs.
+        let collected = collected.unwrap_or_else(|err| {
+            error.get_or_insert(err);
+            CollectedLogs::default()
+        });
+
+        let mut report = PipelineReport {
+            run_id: self.run_id.clone(),
+            stage_reports,
+            elapsed: timer.elapsed().as_millis() as u64,
+            started_at,
+            finished_at: now_millis(),
+            engine: self.engine_info.clone(),
+            trigger: Trigger::Manual,
+            logs: collected.lines,
+            error_code: error.as_ref().map(CiroachError::run_code),
+            error: error.map(|err| format!("{err:#}")),
+            pull_stats: self
+                .pull_stats
+                .lock()
+                .map(|mut stats| std::mem::take(&mut *stats))
+                .unwrap_or_default(),
+        };
+        Self::link_log_files(&mut report);
+        Self::attach_annotations(&mut report, collected.annotations);
+
+        self.events.emit(PipelineEvent::PipelineFinished {
+            success: report.is_success(),
+            elapsed: report.elapsed,
+        });
+
+        Ok(report)
+    }
+
+    /// Completes `drain_timeout` after the run was cancelled.
+    async fn drain_expired(token: &CancellationToken, drain_timeout: Duration) {
+        token.cancelled().await;
+        tokio::time::sleep(drain_timeout).await;
+    }
+
+    /// Removes what the steps that ignored the cancellation left running, so the run can
+    /// still end with a report.
+    async fn force_stop(&self) {
+        eprintln!(
+            "{} Steps did not stop within {}; removing their containers.",
+            Icon::Warning,
+            format_wall_clock(self.pipeline.engine.drain_timeout.as_millis() as u64)
+        );
+        // Every container of the run goes, whatever it was started for; only the
+        // failed ones `--keep-failed` already kept stay.
+        match self.engine.remove_run_containers().await {
+            std::result::Result::Ok(removed) => {
+                let plural = if removed == 1 { "" } else { "s" };
+                println!(
+                    "{} Removed {removed} container{plural} of the run",
+                    Icon::Clean
+                );
+            }
+            Err(err) => eprintln!("{} Failed to remove containers: {}", Icon::Warning, err),
+        }
+        #[cfg(feature = "kubernetes")]
+        if let Some(cluster) = &self.cluster {
+            match cluster.delete_run_jobs().await {
+                std::result::Result::Ok(deleted) => {
+                    let plural = if deleted == 1 { "" } else { "s" };
+                    println!("{} Deleted {deleted} Job{plural} of the run", Icon::Clean);
+                }
+                Err(err) => eprintln!("{} Failed to delete Jobs: {}", Icon::Warning, err),
+            }
+        }
+    }
+
+    /// Points every step that logged, including those cut short by a cancellation, at the
+    /// file the run directory will hold its lines in.
+    fn link_log_files(report: &mut PipelineReport) {
+        for stage in report.stage_reports.iter_mut() {
+            for step in stage.step_reports.iter_mut() {
+                if report.logs.contains_key(&log_key(&stage.name, &step.name)) {
+                    step.log_file = Some(RunDirReporter::step_log(
+                        &report.run_id,
+                        &stage.name,
+                        &step.name,
+                    ));
+                }
+            }
+        }
+    }
+
+    fn attach_annotations(
+        report: &mut PipelineReport,
+        mut annotations: HashMap<String, Vec<Annotation>>,
+    ) {
+        for stage in report.stage_reports.iter_mut() {
+            for step in stage.step_reports.iter_mut() {
+                if let Some(found) = annotations.remove(&log_key(&stage.name, &step.name)) {
+                    step.annotations = found;
+                }
+            }
+        }
+    }
+
+    /// Runs the stages, adding their reports to `stage_reports`. On an error the stages
+    /// after the failing one are left out, and so is the failing one when none of its
+    /// steps ran.
+    async fn run_stages(
+        &self,
+        stage_reports: &mut Vec<StageReport>,
+        logger: &Logger,
+        services: &Arc<Services>,
+        token: &CancellationToken,
+    ) -> anyhow::Result<()> {
+        let images = Self::images(self.pipeline.stages.iter());
+        if !images.is_empty() && !self.verbosity.is_quiet() {
+            println!("\n-- {} --", "PRE-FLIGHT".bold());
+        }
+        self.check_free_space().await?;
+
+        // Steps start as soon as their own image is there, while the others still pull.
+        let pulls = Arc::new(ImagePulls::new(&images));
+        let pre_flight = async {
+            let pulled = self.pull_images(images.clone(), &pulls, token).await;
+            pulls.fail_pending("not pulled");
+            pulled?;
+            let all_pulled = images
+                .iter()
+                .all(|img| pulls.state(img) == PullState::Ready);
+            if self.lock_images && all_pulled {
+                self.write_image_lock(&images).await?;
+            }
+            Ok(())
+        };
+        let stages = self.run_each_stage(stage_reports, logger, services, &pulls, token);
+
+        let (pre_flight, stages) = tokio::join!(pre_flight, stages);
+        stages.and(pre_flight)
+    }
+
+    async fn run_each_stage(
+        &self,
+        stage_reports: &mut Vec<StageReport>,
+        logger: &Logger,
+        services: &Arc<Services>,
+        pulls: &Arc<ImagePulls>,
+        token: &CancellationToken,
+    ) -> anyhow::Result<()> {
+        let mut halted = false;
+        let host_memory = self.engine.total_memory().await;
 
         for stage in self.pipeline.stages.iter() {
-            if token.is_cancelled() {
-                stage_reports.push(self.skip_stage(stage));
+            let excluded = stage.steps.iter().all(|step| step.skip.is_some());
+
+            if halted || token.is_cancelled() || excluded {
+                let reason = match halted {
+                    true => SkipReason::Halted,
+                    false => SkipReason::Interrupted,
+                };
+                stage_reports.push(self.skip_stage(stage, reason));
                 continue;
             }
 
-            println!("\n-- Stage: {} --", stage.name.to_uppercase().bold());
+            let estimate = stage.estimate(&self.durations);
+            // Above the bars of pulls that may still be running.
+            ui::suspend(|| {
+                println!("\n-- Stage: {} --", stage.name.to_uppercase().bold());
+                if let Some(description) = &stage.description {
+                    println!("   {}", description.dimmed());
+                }
+                if let Some(estimate) = estimate
+                    && !self.verbosity.is_quiet()
+                {
+                    let line = format!(
+                        "Estimated {} based on earlier runs",
+                        format_wall_clock(estimate)
+                    );
+                    println!("   {}", line.dimmed());
+                }
+            });
+
+            self.events.emit(PipelineEvent::StageStarted {
+                stage: stage.name.clone(),
+                estimate,
+            });
+
+            self.ensure_images(stage, pulls, token).await?;
+            let memory_budget = self.check_memory(stage, host_memory);
+
+            let mut earlier_attempts = Vec::new();
+            let report = loop {
+                let runner = StageRunner::new(
+                    stage,
+                    self.engine.clone(),
+                    &self.cwd,
+                    &self.user,
+                    self.events.clone(),
+                    self.debug.clone(),
+                    services.clone(),
+                )
+                .keep_failed(self.keep_failed)
+                .attempt(earlier_attempts.len() as u32 + 1)
+                .stall_timeout(self.pipeline.engine.stall_timeout)
+                .memory_budget(memory_budget)
+                .expected_durations(&self.durations)
+                .concurrency_groups(self.groups.clone())
+                .image_pulls(pulls.clone());
+                #[cfg(feature = "kubernetes")]
+                let runner = runner.cluster(self.cluster.clone());
+
+                let ui = (self.progress_style() == Progress::Live)
+                    .then(|| StageUI::new(stage).follow(self.events.subscribe()));
+                // The stage cancels its own token when it fails early or stalls. The
+                // pipeline's stays live, so the stages after are still skipped or run
+                // on their own terms, and a retry of this one starts uncancelled.
+                let report = runner.run(logger.tx(), token.child_token()).await;
 
-            self.pre_pull_images(stage).await?;
+                self.events.emit(PipelineEvent::StageFinished {
+                    stage: stage.name.clone(),
+                    success: report.as_ref().is_ok_and(|report| report.is_success()),
+                });
+                if let Some(ui) = ui {
+                    ui.await.ok();
+                }
+                let report = match report {
+                    std::result::Result::Ok(report) => report,
+                    Err(failure) => {
+                        stage_reports.push(StageReport {
+                            earlier_attempts,
+                            ..failure.report
+                        });
+                        return Err(failure.error);
+                    }
+                };
 
-            let runner = StageRunner::new(stage, self.engine.clone(), &self.cwd, &self.user);
-            let report = runner.run(logger.tx(), token.clone()).await?;
+                let retries_left = stage.retries > earlier_attempts.len() as u32;
+                if report.is_success() || !retries_left || token.is_cancelled() {
+                    break StageReport {
+                        earlier_attempts,
+                        ..report
+                    };
+                }
+                earlier_attempts.push(report);
+                self.rerun_stage(stage, earlier_attempts.len() as u32, logger, services)
+                    .await;
+            };
 
             stage_reports.push(report.clone());
 
             if !report.is_success() {
-                token.cancel();
-                println!("🛑 Pipeline halted due to error in stage '{}'", stage.name);
+                halted = true;
+                println!(
+                    "{} Pipeline halted due to error in stage '{}'",
+                    Icon::Halt,
+                    stage.name
+                );
             }
         }
 
-        let final_logs = logger.finish().await?;
+        Ok(())
+    }
 
-        Ok(PipelineReport {
-            stage_reports,
-            logs: final_logs,
-        })
+    /// Clears what the failed `attempt` of `stage` left running before the stage starts
+    /// over. The steps' containers are gone with the attempt; its services are not.
+    async fn rerun_stage(&self, stage: &Stage, attempt: u32, logger: &Logger, services: &Services) {
+        let steps: Vec<&str> = stage
+            .steps
+            .iter()
+            .map(|step| step.exploded_name.as_str())
+            .collect();
+        services.stop(&steps).await;
+
+        let line = format!(
+            "{} Retrying stage '{}' ({}/{})",
+            Icon::Retry,
+            stage.name,
+            attempt,
+            stage.retries
+        );
+        ui::suspend(|| println!("{line}"));
+
+        // Marks where the attempt starts in the logs of the steps that will run again.
+        let tx = logger.tx();
+        for step in stage.steps.iter().filter(|step| step.skip.is_none()) {
+            tx.send(LogMessage {
+                stage: stage.name.clone(),
+                step_name: step.exploded_name.clone(),
+                line: line.clone(),
+                is_error: false,
+                attempt: 1,
+                stage_attempt: Some(attempt + 1),
+                ended: None,
+            })
+            .await
+            .ok();
+        }
+
+        self.events.emit(PipelineEvent::StageStarted {
+            stage: stage.name.clone(),
+            estimate: stage.estimate(&self.durations),
+        });
+    }
+
+    /// Warns when the steps of `stage` that may run at once are allowed more memory than
+    /// the host has. With `engine.strict_resources`, returns the host's memory as the
+    /// budget the stage's scheduler keeps to instead.
+    fn check_memory(&self, stage: &Stage, host_memory: Option<i64>) -> Option<i64> {
+        let host_memory = host_memory?;
+        let limits = |level: &Vec<&Step>| level.iter().filter_map(|s| s.memory).sum::<i64>();
+        let levels = stage.parallel_levels();
+        let widest = levels.iter().max_by_key(|level| limits(level))?;
+        let requested = limits(widest);
+        let mib = |bytes: i64| bytes / (1024 * 1024);
+
+        if self.verbosity >= Verbosity::Verbose {
+            let steps: Vec<String> = widest
+                .iter()
+                .map(|s| match s.memory {
+                    Some(memory) => format!("{} {} MiB", s.exploded_name, mib(memory)),
+                    None => format!("{} unlimited", s.exploded_name),
+                })
+                .collect();
+            println!(
+                "   Memory: {} = {} MiB at most at once, {} MiB available",
+                steps.join(" + "),
+                mib(requested),
+                mib(host_memory)
+            );
+        }
+
+        if requested <= host_memory {
+            return None;
+        }
+
+        if self.pipeline.engine.strict_resources {
+            println!(
+                "{} Steps may ask for {} MiB at once but only {} MiB is available; some will wait for others to finish.",
+                Icon::Info,
+                mib(requested),
+                mib(host_memory)
+            );
+            Some(host_memory)
+        } else {
+            eprintln!(
+                "{} {} steps of stage '{}' may run at once with {} MiB of memory between them, but only {} MiB is available. Set 'engine.strict_resources = true' to run fewer at a time.",
+                Icon::Warning,
+                widest.len(),
+                stage.name,
+                mib(requested),
+                mib(host_memory)
+            );
+            None
+        }
     }
 
-    fn skip_stage(&self, stage: &Stage) -> StageReport {
+    /// A step that fills the disk takes the whole daemon down with it, so a run does not
+    /// start below `engine.min_free_space`. Skipped when the free space cannot be measured.
+    async fn check_free_space(&self) -> anyhow::Result<()> {
+        let Some(min_free) = self.pipeline.engine.min_free_space else {
+            return Ok(());
+        };
+        let Some(free) = self.engine.free_space().await else {
+            tracing::debug!("free disk space unknown, skipping the check");
+            return Ok(());
+        };
+
+        let mib = |bytes: i64| bytes / (1024 * 1024);
+        if free as i64 >= min_free {
+            return Ok(());
+        }
+
+        let mut message = format!(
+            "Only {} MiB free for Docker, below 'engine.min_free_space' of {} MiB.",
+            mib(free as i64),
+            mib(min_free)
+        );
+        if let Some(reclaimable) = self.engine.reclaimable_space().await.filter(|r| *r > 0) {
+            message.push_str(&format!(
+                " 'docker system prune' could free up to {} MiB.",
+                mib(reclaimable)
+            ));
+        }
+
+        match self.pipeline.engine.on_low_space {
+            LowSpacePolicy::Fail => anyhow::bail!(message),
+            LowSpacePolicy::Warn => {
+                eprintln!("{} {}", Icon::Warning, message);
+                Ok(())
+            }
+        }
+    }
+
+    /// The `digest`s steps pin `img` to.
+    fn pinned_digests(&self, img: &str) -> Vec<String> {
+        let mut digests: Vec<String> = self
+            .pipeline
+            .stages
+            .iter()
+            .flat_map(|stage| &stage.steps)
+            .filter(|step| step.skip.is_none() && step.runner == Runner::Container)
+            .filter(|step| step.image == img)
+            .filter_map(|step| step.digest.clone())
+            .collect();
+        digests.sort();
+        digests.dedup();
+        digests
+    }
+
+    /// Why the pulled `img` does not match the digests it is pinned to, if it does not.
+    async fn verify_digest(engine: &DockerEngine, img: &str, digests: &[String]) -> Option<String> {
+        if digests.is_empty() {
+            return None;
+        }
+
+        let actual = match engine.repo_digest(img).await {
+            std::result::Result::Ok(actual) => actual,
+            Err(err) => return Some(format!("{err:#}")),
+        };
+        let expected = digests
+            .iter()
+            .find(|expected| actual.as_ref() != Some(*expected))?;
+        Some(format!(
+            "pulled {}, but its 'digest' is pinned to {}",
+            actual.as_deref().unwrap_or("no registry digest"),
+            expected
+        ))
+    }
+
+    /// Records the digest each of `images` was just pulled at in `ciroach.lock`. Entries
+    /// for images this run did not pull, e.g. outside the selected profile, are kept.
+    async fn write_image_lock(&self, images: &HashSet<String>) -> anyhow::Result<()> {
+        let path = Path::new(&self.cwd).join(IMAGE_LOCK);
+        let mut image_lock = ImageLock::load_or_default(&path).await?;
+
+        let mut images: Vec<&String> = images.iter().filter(|image| !image.contains('@')).collect();
+        images.sort();
+        for image in images {
+            match self.engine.repo_digest(image).await? {
+                Some(digest) => image_lock.insert(image, digest),
+                None => eprintln!(
+                    "{} Image '{}' has no registry digest and is left out of '{}'.",
+                    Icon::Warning,
+                    image,
+                    IMAGE_LOCK
+                ),
+            }
+        }
+
+        image_lock.save().await?;
+        println!(
+            "{} Image digests written to {}",
+            Icon::Folder,
+            path.display()
+        );
+        Ok(())
+    }
+
+    /// Remembered so `ciroach clean --images` can tell these from the user's own images.
+    async fn record_pulls(images: &[(String, Option<u64>)]) -> anyhow::Result<()> {
+        if images.is_empty() {
+            return Ok(());
+        }
+
+        let mut manifest = ImageManifest::load(IMAGES_MANIFEST).await?;
+        manifest.record(images);
+        manifest.save().await
+    }
+
+    fn progress_style(&self) -> Progress {
+        if self.verbosity.is_quiet() {
+            Progress::Off
+        } else if self.progress && self.mode.live_progress() {
+            Progress::Live
+        } else {
+            Progress::Plain
+        }
+    }
+
+    /// `reason` applies to the steps not already excluded from the run.
+    fn skip_stage(&self, stage: &Stage, reason: SkipReason) -> StageReport {
         StageReport {
+            name: stage.name.clone(),
             step_reports: stage
                 .steps
                 .iter()
-                .map(|step| StepReport::skipped(&step.exploded_name))
+                .map(|step| StepReport::excluded(step, step.skip.unwrap_or(reason)))
                 .collect(),
+            elapsed: 0,
+            started_at: 0,
+            finished_at: 0,
+            earlier_attempts: Vec::new(),
+        }
+    }
+
+    /// Stops the pulls still running and returns their images.
+    fn abort_pulls(
+        pending: &[(AbortHandle, String)],
+        ui: &PreFlightUI,
+        reason: &str,
+    ) -> Vec<String> {
+        pending
+            .iter()
+            .filter(|(task, _)| !task.is_finished())
+            .map(|(task, img)| {
+                task.abort();
+                ui.failed_image(img, reason);
+                img.clone()
+            })
+            .collect()
+    }
+
+    fn record_pull(&self, stat: PullStat) {
+        if let std::result::Result::Ok(mut stats) = self.pull_stats.lock() {
+            stats.push(stat);
         }
     }
 
-    async fn pre_pull_images(&self, stage: &Stage) -> anyhow::Result<()> {
-        let unique_images: HashSet<String> =
-            stage.steps.iter().map(|step| step.image.clone()).collect();
+    /// Pulls `img`, retrying errors that look transient with exponential backoff.
+    async fn pull_with_retry(
+        engine: &DockerEngine,
+        img: &str,
+        policy: &PullConfig,
+        ui: &PreFlightUI,
+        events: &EventBus,
+    ) -> anyhow::Result<u64> {
+        let mut attempt = 1;
+        let downloaded = AtomicU64::new(0);
+
+        loop {
+            let result = engine
+                .pull_image(img, policy.timeout, |progress| {
+                    downloaded.store(progress.current, Ordering::Relaxed);
+                    ui.update_progress(img, progress);
+                    events.emit(PipelineEvent::ImagePulling {
+                        image: img.to_string(),
+                        progress,
+                    });
+                })
+                .await;
+
+            match result {
+                std::result::Result::Ok(_) => return Ok(downloaded.load(Ordering::Relaxed)),
+                std::result::Result::Err(err)
+                    if attempt < policy.attempts && DockerEngine::is_transient(&err) =>
+                {
+                    attempt += 1;
+                    ui.retry_image(img, attempt, policy.attempts);
+                    let delay = Self::pull_backoff(policy.backoff, attempt - 1);
+                    tracing::debug!(image = img, ?delay, error = %err, "retrying pull");
+                    sleep(delay).await;
+                }
+                std::result::Result::Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Start time plus a random suffix, e.g. `20250301-142233-9f3a`: sorts by start time
+    /// and stays unique when runs start within the same second.
+    fn new_run_id() -> String {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.subsec_nanos())
+            .unwrap_or_default();
+        let suffix = (nanos ^ std::process::id().rotate_left(16)) & 0xffff;
+
+        format!("{}-{:04x}", Local::now().format("%Y%m%d-%H%M%S"), suffix)
+    }
+
+    /// `base * 2^(retry - 1)` plus up to half of that again as jitter, so parallel pulls
+    /// don't hit the registry in lockstep.
+    fn pull_backoff(base: Duration, retry: u32) -> Duration {
+        let delay = base * 2u32.pow(retry.saturating_sub(1));
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.subsec_nanos())
+            .unwrap_or_default();
+        let jitter = delay.mul_f64(f64::from(nanos % 1000) / 2000.0);
+
+        delay + jitter
+    }
+
+    /// Registry images used by the steps that will run.
+    fn images<'a>(stages: impl Iterator<Item = &'a Stage>) -> HashSet<String> {
+        stages
+            .flat_map(|stage| &stage.steps)
+            .filter(|step| step.skip.is_none() && !step.local_image)
+            .filter(|step| step.runner == Runner::Container)
+            .map(|step| step.image.clone())
+            .collect()
+    }
+
+    /// Steps that will run from `img`.
+    fn steps_using(&self, img: &str) -> Vec<&str> {
+        self.pipeline
+            .stages
+            .iter()
+            .flat_map(|stage| &stage.steps)
+            .filter(|step| step.skip.is_none() && step.runner == Runner::Container)
+            .filter(|step| step.image == img)
+            .map(|step| step.exploded_name.as_str())
+            .collect()
+    }
+
+    /// Images are pulled up front; this only pulls again what went missing since, e.g.
+    /// removed by a step with the Docker socket.
+    async fn ensure_images(
+        &self,
+        stage: &Stage,
+        pulls: &Arc<ImagePulls>,
+        token: &CancellationToken,
+    ) -> anyhow::Result<()> {
+        let mut missing = HashSet::new();
+        for image in Self::images(std::iter::once(stage)) {
+            if pulls.state(&image) == PullState::Ready && !self.engine.image_exists(&image).await {
+                missing.insert(image);
+            }
+        }
 
+        self.pull_images(missing, pulls, token).await
+    }
+
+    /// Pulls `unique_images`, marking each in `pulls` once it is there, or failed when it
+    /// cannot be pulled and has no local copy, or its digest is not the pinned one.
+    async fn pull_images(
+        &self,
+        unique_images: HashSet<String>,
+        pulls: &Arc<ImagePulls>,
+        token: &CancellationToken,
+    ) -> anyhow::Result<()> {
         if unique_images.is_empty() {
             return Ok(());
         }
+        for image in &unique_images {
+            pulls.set(image, PullState::Pending);
+        }
 
-        let ui = Arc::new(PreFlightUI::new(&unique_images));
+        let mut cached = HashSet::new();
+        for image in &unique_images {
+            if self.engine.image_exists(image).await {
+                cached.insert(image.clone());
+            }
+        }
 
-        let pull_tasks = unique_images.into_iter().map(|img| {
-            let engine = self.engine.clone();
-            let progress_ui = Arc::clone(&ui);
+        let ui = Arc::new(PreFlightUI::new(
+            &unique_images,
+            &cached,
+            self.progress_style(),
+        ));
+        let slots = Arc::new(Semaphore::new(self.pipeline.engine.pull.concurrency));
+        // Smallest first by the sizes of earlier pulls, so more steps can start early.
+        // Same order as the tasks, to name the images whose pulls get aborted.
+        let manifest = ImageManifest::load(IMAGES_MANIFEST).await.ok();
+        let size = |img: &str| manifest.as_ref().and_then(|manifest| manifest.size(img));
+        let mut images: Vec<String> = unique_images.iter().cloned().collect();
+        images.sort_by_key(|img| (size(img).is_none(), size(img), img.clone()));
 
-            tokio::spawn(async move {
-                let img_clone = img.clone();
-                let finish_ui = Arc::clone(&progress_ui);
+        let pull_tasks: Vec<_> = images
+            .iter()
+            .cloned()
+            .map(|img| {
+                let engine = self.engine.clone();
+                let finish_ui = Arc::clone(&ui);
+                let policy = self.pipeline.engine.pull.clone();
+                let slots = Arc::clone(&slots);
+                let events = self.events.clone();
+                let pulls = Arc::clone(pulls);
+                let digests = self.pinned_digests(&img);
 
-                let result = engine
-                    .pull_image(&img, move |curr, tot| {
-                        progress_ui.update_progress(&img_clone, curr, tot);
-                    })
-                    .await;
+                tokio::spawn(async move {
+                    let _slot = slots.acquire_owned().await;
+                    finish_ui.start_image(&img);
+                    let started = Instant::now();
+                    let result =
+                        Self::pull_with_retry(&engine, &img, &policy, &finish_ui, &events).await;
+                    let elapsed = started.elapsed();
+
+                    let outcome = match result {
+                        std::result::Result::Ok(bytes) => {
+                            finish_ui.succeed_image(&img, bytes, elapsed);
+                            PullOutcome::Pulled(bytes)
+                        }
+                        std::result::Result::Err(err) if engine.image_exists(&img).await => {
+                            finish_ui.cached_image(&img);
+                            PullOutcome::Cached(err)
+                        }
+                        std::result::Result::Err(err) => {
+                            finish_ui.failed_image(&img, &format!("{err:#}"));
+                            PullOutcome::Failed(format!("{err:#}"))
+                        }
+                    };
+                    let outcome = match outcome {
+                        PullOutcome::Failed(_) => outcome,
+                        _ => match Self::verify_digest(&engine, &img, &digests).await {
+                            Some(mismatch) => PullOutcome::Failed(mismatch),
+                            None => outcome,
+                        },
+                    };
+
+                    pulls.set(
+                        &img,
+                        match &outcome {
+                            PullOutcome::Failed(reason) => PullState::Failed(reason.clone()),
+                            _ => PullState::Ready,
+                        },
+                    );
+                    (img, outcome, elapsed)
+                })
+            })
+            .collect();
 
-                match result {
-                    std::result::Result::Ok(_) => finish_ui.succeed_image(&img),
-                    std::result::Result::Err(_) => finish_ui.failed_image(&img),
+        let pending: Vec<_> = pull_tasks
+            .iter()
+            .map(|task| task.abort_handle())
+            .zip(images.iter().cloned())
+            .collect();
+
+        let deadline = self.pipeline.engine.pull.deadline;
+        let results = tokio::select! {
+            results = join_all(pull_tasks) => results,
+            _ = sleep(deadline) => {
+                let reason = format!("did not finish within {}", format_wall_clock(deadline.as_millis() as u64));
+                for img in Self::abort_pulls(&pending, &ui, &reason) {
+                    pulls.set(&img, PullState::Failed(reason.clone()));
+                }
+                Vec::new()
+            }
+            _ = token.cancelled() => {
+                for img in Self::abort_pulls(&pending, &ui, "cancelled") {
+                    pulls.set(&img, PullState::Failed("cancelled".to_string()));
+                }
+                return Ok(());
+            }
+        };
+
+        // Every pull runs to completion so all failing images are reported together.
+        let mut pulled = Vec::new();
+        for result in results {
+            let (img, outcome, elapsed) = result?;
+            let stat = |bytes: u64, cached: bool| PullStat {
+                image: img.clone(),
+                bytes,
+                elapsed: elapsed.as_millis() as u64,
+                cached,
+            };
+            match outcome {
+                PullOutcome::Pulled(bytes) => {
+                    self.record_pull(stat(bytes, bytes == 0));
+                    let size = self.engine.image_size(&img).await;
+                    pulled.push((img, size));
+                }
+                PullOutcome::Cached(err) => {
+                    self.record_pull(stat(0, true));
+                    eprintln!(
+                        "{} Could not pull '{}', using the local copy: {}",
+                        Icon::Warning,
+                        img,
+                        err
+                    );
                 }
+                PullOutcome::Failed(_) => {}
+            }
+        }
 
-                result
+        if let Err(err) = Self::record_pulls(&pulled).await {
+            eprintln!(
+                "{} Could not record pulled images in '{}': {}",
+                Icon::Warning,
+                IMAGES_MANIFEST,
+                err
+            );
+        }
+
+        let mut failed: Vec<(String, String)> = images
+            .iter()
+            .filter_map(|img| match pulls.state(img) {
+                PullState::Failed(reason) => Some((img.clone(), reason)),
+                _ => None,
             })
+            .collect();
+        if !failed.is_empty() {
+            failed.sort();
+            ui::suspend(|| {
+                eprintln!(
+                    "\n{} Pre-flight failed; the steps needing these images fail:",
+                    Icon::Error
+                );
+                for (img, reason) in &failed {
+                    eprintln!(
+                        "  {} (needed by {}): {}",
+                        img.bold(),
+                        self.steps_using(img).join(", "),
+                        reason
+                    );
+                }
+            });
+        }
+
+        ui::suspend(|| println!());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use tokio::fs::{create_dir_all, remove_dir, remove_dir_all};
+
+    use super::*;
+    use crate::{
+        error::PIPELINE_STUCK,
+        models::{RawPipeline, StepStatus},
+        reporter::{FileReporter, LOGS_DIR},
+    };
+
+    static WORKSPACES: AtomicUsize = AtomicUsize::new(0);
+
+    fn pipeline(config: &str) -> Pipeline {
+        toml::from_str::<RawPipeline>(config)
+            .unwrap()
+            .select(None)
+            .and_then(|raw| raw.compile(&[]))
+            .unwrap()
+    }
+
+    /// A runner for `config` in a workspace of its own.
+    async fn runner(config: &str) -> PipelineRunner {
+        let pipeline = pipeline(config);
+        let workspace = std::env::temp_dir().join(format!(
+            "ciroach-test-{}-{}",
+            std::process::id(),
+            WORKSPACES.fetch_add(1, Ordering::Relaxed)
+        ));
+        create_dir_all(&workspace).await.unwrap();
+
+        PipelineRunner::new(pipeline, workspace, OutputMode::Github)
+            .await
+            .unwrap()
+            .progress(false)
+            .verbosity(Verbosity::Quiet)
+    }
+
+    /// Runs `runner`, then removes its workspace and the run directory the step logs
+    /// were streamed to.
+    async fn finish(
+        runner: PipelineRunner,
+        token: CancellationToken,
+    ) -> Result<PipelineReport, CiroachError> {
+        let workspace = PathBuf::from(&runner.cwd);
+        let run_dir = Path::new(LOGS_DIR).join(&runner.run_id);
+        let report = runner.run(token).await;
+
+        remove_dir_all(&workspace).await.ok();
+        remove_dir_all(&run_dir).await.ok();
+        // Only goes once no other test is using it.
+        remove_dir(LOGS_DIR).await.ok();
+        report
+    }
+
+    async fn run(config: &str, token: CancellationToken) -> Result<PipelineReport, CiroachError> {
+        finish(runner(config).await, token).await
+    }
+
+    fn step_names(stage: &StageReport) -> Vec<&str> {
+        stage
+            .step_reports
+            .iter()
+            .map(|step| step.name.as_str())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn reports_and_logs_follow_declaration_order() {
+        // Declared against the alphabet, and finishing in reverse.
+        let report = run(
+            r#"
+            stages_order = ["build"]
+
+            [stages.build.steps.zeta]
+            runner = "host"
+            command = "sleep 0.4; echo zeta"
+
+            [stages.build.steps.alpha]
+            runner = "host"
+            command = "sleep 0.2; echo alpha"
+
+            [stages.build.steps.mid]
+            runner = "host"
+            command = "echo mid"
+            "#,
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            step_names(&report.stage_reports[0]),
+            ["zeta", "alpha", "mid"]
+        );
+        let logged: Vec<&str> = report
+            .ordered_logs()
+            .into_iter()
+            .map(|(_, step, _)| step)
+            .collect();
+        assert_eq!(logged, ["zeta", "alpha", "mid"]);
+    }
+
+    #[tokio::test]
+    async fn steps_of_the_same_id_in_two_stages_keep_their_own_logs() {
+        let report = run(
+            r#"
+            stages_order = ["unit", "integration"]
+
+            [stages.unit.steps.test]
+            runner = "host"
+            command = "echo unit tests"
+
+            [stages.integration.steps.test]
+            runner = "host"
+            command = "echo integration tests"
+            "#,
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+        let logs = |stage: &str| {
+            let lines = &report.logs[&log_key(stage, "test")];
+            lines
+                .iter()
+                .map(|line| FileReporter::plain_line(line))
+                .collect::<Vec<_>>()
+        };
+        assert!(logs("unit").iter().any(|line| line.contains("unit tests")));
+        assert!(
+            !logs("unit")
+                .iter()
+                .any(|line| line.contains("integration tests"))
+        );
+        assert!(
+            logs("integration")
+                .iter()
+                .any(|line| line.contains("integration tests"))
+        );
+        assert!(
+            !logs("integration")
+                .iter()
+                .any(|line| line.contains("unit tests"))
+        );
+
+        let sections: Vec<(&str, &str)> = report
+            .ordered_logs()
+            .into_iter()
+            .map(|(stage, step, _)| (stage, step))
+            .collect();
+        assert_eq!(sections, [("unit", "test"), ("integration", "test")]);
+    }
+
+    /// Three steps taking turns in one slot, declared shortest first.
+    const ONE_SLOT: &str = r#"
+        stages_order = ["test"]
+
+        [stages.test.steps.lint]
+        runner = "host"
+        command = "sleep 0.1"
+        concurrency_group = "slot"
+
+        [stages.test.steps.unit]
+        runner = "host"
+        command = "sleep 0.1"
+        concurrency_group = "slot"
+
+        [stages.test.steps.integration]
+        runner = "host"
+        command = "sleep 0.1"
+        concurrency_group = "slot"
+    "#;
+
+    fn start_order(report: &PipelineReport) -> Vec<&str> {
+        let mut steps: Vec<&StepReport> = report.stage_reports[0].step_reports.iter().collect();
+        steps.sort_by_key(|step| step.started_at);
+        steps.iter().map(|step| step.name.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn steps_that_took_longest_before_are_started_first() {
+        let report = finish(runner(ONE_SLOT).await, CancellationToken::new())
+            .await
+            .unwrap();
+        assert_eq!(start_order(&report), ["lint", "unit", "integration"]);
+
+        let durations = HashMap::from([
+            ("lint".to_string(), 20_000),
+            ("unit".to_string(), 95_000),
+            ("integration".to_string(), 540_000),
+        ]);
+        let runner = runner(ONE_SLOT).await.expected_durations(durations);
+        let report = finish(runner, CancellationToken::new()).await.unwrap();
+        assert_eq!(start_order(&report), ["integration", "unit", "lint"]);
+    }
+
+    #[tokio::test]
+    async fn every_line_before_a_failure_makes_the_report() {
+        // Fast failures right after a burst of output, on stdout and stderr at once.
+        let report = run(
+            r#"
+            stages_order = ["test"]
+
+            [stages.test.steps.stdout]
+            runner = "host"
+            command = "seq 1 5000; exit 1"
+
+            [stages.test.steps.stderr]
+            runner = "host"
+            command = "seq 1 5000 >&2; exit 2"
+            "#,
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+        for step in &report.stage_reports[0].step_reports {
+            assert_eq!(step.status, StepStatus::Failed);
+            let numbers: Vec<u32> = report.logs[&log_key("test", &step.name)]
+                .iter()
+                .filter_map(|line| {
+                    // Lines come as `[step] text`.
+                    let line = FileReporter::plain_line(line);
+                    line.split_once("] ")?.1.trim().parse().ok()
+                })
+                .collect();
+            assert_eq!(numbers, (1..=5000).collect::<Vec<_>>(), "{}", step.name);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_failed_pre_pull_still_reports_every_step() {
+        let config = r#"
+            stages_order = ["build", "test", "deploy"]
+
+            [engine.pull]
+            attempts = 1
+
+            [stages.build.steps.compile]
+            image = "rust:1.80"
+            command = "cargo build"
+
+            [stages.build.steps.lint]
+            runner = "host"
+            command = "true"
+
+            [stages.test.steps.unit]
+            runner = "host"
+            command = "true"
+
+            [stages.deploy.steps.publish]
+            runner = "host"
+            command = "true"
+        "#;
+        // Connected without Docker, where every pull fails.
+        let mut runner =
+            runner(&config.replace(r#"image = "rust:1.80""#, r#"runner = "host""#)).await;
+        runner.pipeline = pipeline(config);
+        let report = finish(runner, CancellationToken::new()).await.unwrap();
+
+        let table: Vec<(&str, Vec<&str>)> = report
+            .stage_reports
+            .iter()
+            .map(|stage| (stage.name.as_str(), step_names(stage)))
+            .collect();
+        assert_eq!(
+            table,
+            [
+                ("build", vec!["compile", "lint"]),
+                ("test", vec!["unit"]),
+                ("deploy", vec!["publish"]),
+            ]
+        );
+        let statuses: Vec<(StepStatus, Option<SkipReason>)> = report
+            .stage_reports
+            .iter()
+            .flat_map(|stage| &stage.step_reports)
+            .map(|step| (step.status, step.reason))
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                (StepStatus::Failed, None),
+                (StepStatus::Success, None),
+                (StepStatus::Skipped, Some(SkipReason::Halted)),
+                (StepStatus::Skipped, Some(SkipReason::Halted)),
+            ]
+        );
+    }
+
+    fn statuses(report: &PipelineReport) -> Vec<(&str, StepStatus, Option<SkipReason>)> {
+        report
+            .stage_reports
+            .iter()
+            .flat_map(|stage| &stage.step_reports)
+            .map(|step| (step.name.as_str(), step.status, step.reason))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn ctrl_c_stops_the_running_step_and_skips_the_rest() {
+        let token = CancellationToken::new();
+        let ctrl_c = token.clone();
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(300)).await;
+            ctrl_c.cancel();
         });
 
-        try_join_all(pull_tasks).await?;
+        let timer = Instant::now();
+        let report = run(
+            r#"
+            stages_order = ["build", "test"]
 
-        println!();
+            [stages.build.steps.compile]
+            runner = "host"
+            command = "sleep 30"
 
-        Ok(())
+            [stages.test.steps.unit]
+            runner = "host"
+            command = "true"
+            "#,
+            token,
+        )
+        .await;
+
+        assert!(timer.elapsed() < Duration::from_secs(10));
+        let report = report.unwrap();
+        assert_eq!(
+            statuses(&report),
+            [
+                (
+                    "compile",
+                    StepStatus::Cancelled,
+                    Some(SkipReason::Interrupted)
+                ),
+                ("unit", StepStatus::Skipped, Some(SkipReason::Interrupted)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn a_failed_stage_halts_the_stages_after_without_cancelling_the_run() {
+        let token = CancellationToken::new();
+        let report = run(
+            r#"
+            stages_order = ["build", "test"]
+
+            [stages.build.steps.compile]
+            runner = "host"
+            command = "exit 1"
+
+            [stages.build.steps.docs]
+            runner = "host"
+            command = "sleep 0.3"
+
+            [stages.test.steps.unit]
+            runner = "host"
+            command = "true"
+            "#,
+            token.clone(),
+        )
+        .await
+        .unwrap();
+
+        // The step next to the failed one finishes; only what comes after is skipped.
+        assert!(!token.is_cancelled());
+        assert_eq!(
+            statuses(&report),
+            [
+                ("compile", StepStatus::Failed, None),
+                ("docs", StepStatus::Success, None),
+                ("unit", StepStatus::Skipped, Some(SkipReason::Halted)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn a_stalled_stage_cancels_its_own_steps_but_not_the_run() {
+        let token = CancellationToken::new();
+        let timer = Instant::now();
+        let report = run(
+            r#"
+            stages_order = ["build", "test"]
+
+            [engine]
+            stall_timeout = "1s"
+
+            [stages.build.steps.compile]
+            runner = "host"
+            command = "echo started; sleep 30"
+
+            [stages.test.steps.unit]
+            runner = "host"
+            command = "true"
+            "#,
+            token.clone(),
+        )
+        .await
+        .unwrap();
+
+        assert!(timer.elapsed() < Duration::from_secs(10));
+        assert!(!token.is_cancelled());
+        assert!(report.error.as_ref().unwrap().contains("made no progress"));
+        assert_eq!(report.error_code, Some(PIPELINE_STUCK));
+        // The step the stage stopped ran up to then; the stage after never started.
+        assert_eq!(
+            statuses(&report),
+            [
+                ("compile", StepStatus::Cancelled, Some(SkipReason::Aborted)),
+                ("unit", StepStatus::Skipped, Some(SkipReason::Aborted)),
+            ]
+        );
+        let compile = &report.stage_reports[0].step_reports[0];
+        assert!(compile.elapsed >= 1000);
+        assert!(compile.log_file.is_some());
+        assert_eq!(
+            report.logs[&log_key("build", "compile")],
+            ["[compile] started"]
+        );
+    }
+
+    #[tokio::test]
+    async fn a_step_that_keeps_logging_does_not_stall_its_stage() {
+        let report = run(
+            r#"
+            stages_order = ["build"]
+
+            [engine]
+            stall_timeout = "1s"
+
+            [stages.build.steps.compile]
+            runner = "host"
+            command = "for i in 1 2 3 4 5 6; do echo $i; sleep 0.4; done"
+            "#,
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(report.error, None);
+        assert_eq!(statuses(&report), [("compile", StepStatus::Success, None)]);
+    }
+
+    #[tokio::test]
+    async fn a_stage_retry_runs_with_a_token_of_its_own() {
+        // The token of the first attempt is cancelled once it ends.
+        let report = run(
+            r#"
+            stages_order = ["build"]
+
+            [stages.build]
+            stage_retries = 1
+
+            [stages.build.steps.flaky]
+            runner = "host"
+            command = "test -f failed || { touch failed; exit 1; }"
+
+            [stages.build.steps.compile]
+            runner = "host"
+            command = "sleep 0.2"
+            "#,
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+        let build = &report.stage_reports[0];
+        assert_eq!(build.earlier_attempts.len(), 1);
+        assert_eq!(
+            statuses(&report),
+            [
+                ("flaky", StepStatus::Success, None),
+                ("compile", StepStatus::Success, None),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Docker daemon"]
+    async fn quick_retries_never_collide_on_container_names() {
+        // The detached `db` of the first stage is still up while the second stage's `db`
+        // runs 5 times over, twice each.
+        let report = run(
+            r#"
+            stages_order = ["services", "test"]
+
+            [stages.services.steps.db]
+            image = "alpine:3"
+            command = "sleep 60"
+            detach = true
+
+            [stages.test]
+            stage_retries = 4
+
+            [stages.test.steps.db]
+            image = "alpine:3"
+            command = "exit 1"
+            max_retries = 1
+            "#,
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(report.error, None);
+        let test = &report.stage_reports[1];
+        assert_eq!(test.earlier_attempts.len(), 4);
+        let attempts: Vec<Option<i64>> = test
+            .earlier_attempts
+            .iter()
+            .chain([test])
+            .flat_map(|stage| &stage.step_reports[0].attempts)
+            .map(|attempt| attempt.exit_code)
+            .collect();
+        assert_eq!(attempts, [Some(1); 10]);
+    }
+
+    #[tokio::test]
+    async fn a_timed_out_attempt_is_cancelled_alone() {
+        let report = run(
+            r#"
+            stages_order = ["build"]
+
+            [stages.build.steps.flaky]
+            runner = "host"
+            command = "test -f timed-out || { touch timed-out; sleep 30; }"
+            timeout = "1s"
+            max_retries = 1
+
+            [stages.build.steps.compile]
+            runner = "host"
+            command = "sleep 0.5"
+            "#,
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+        let flaky = &report.stage_reports[0].step_reports[0];
+        assert_eq!(flaky.retries, 1);
+        assert_eq!(
+            statuses(&report),
+            [
+                ("flaky", StepStatus::Success, None),
+                ("compile", StepStatus::Success, None),
+            ]
+        );
+    }
+
+    /// Needs Docker Desktop on the Windows host.
+    #[tokio::test]
+    async fn the_flat_layout_runs_like_the_stage_it_compiles_to() {
+        let flat = r#"
+            [[pipeline.steps]]
+            name = "fetch"
+            runner = "host"
+            command = "sleep 0.2; echo fetched"
+
+            [[pipeline.steps]]
+            name = "lint"
+            parallel = true
+            runner = "host"
+            command = "echo linted"
+
+            [[pipeline.steps]]
+            name = "test"
+            parallel = true
+            runner = "host"
+            command = "echo 1 failure; exit 1"
+
+            [[pipeline.steps]]
+            name = "package"
+            runner = "host"
+            command = "echo packaged"
+        "#;
+        let staged = r#"
+            stages_order = ["pipeline"]
+
+            [stages.pipeline.steps.fetch]
+            runner = "host"
+            command = "sleep 0.2; echo fetched"
+
+            [stages.pipeline.steps.lint]
+            runner = "host"
+            command = "echo linted"
+            needs = ["fetch"]
+
+            [stages.pipeline.steps.test]
+            runner = "host"
+            command = "echo 1 failure; exit 1"
+            needs = ["fetch"]
+
+            [stages.pipeline.steps.package]
+            runner = "host"
+            command = "echo packaged"
+            needs = ["lint", "test"]
+        "#;
+
+        let flat = run(flat, CancellationToken::new()).await.unwrap();
+        let staged = run(staged, CancellationToken::new()).await.unwrap();
+
+        for report in [&flat, &staged] {
+            assert_eq!(report.stage_reports.len(), 1);
+            assert_eq!(report.stage_reports[0].name, "pipeline");
+            assert!(!report.is_success());
+            assert_eq!(report.error, None);
+
+            let step = |name: &str| {
+                report.stage_reports[0]
+                    .step_reports
+                    .iter()
+                    .find(|step| step.name == name)
+                    .unwrap()
+            };
+            // The parallel group starts once the step before it is done.
+            assert!(step("lint").started_at >= step("fetch").finished_at);
+            assert!(step("test").started_at >= step("fetch").finished_at);
+        }
+        assert_eq!(statuses(&flat), statuses(&staged));
+        assert_eq!(
+            statuses(&flat),
+            [
+                ("fetch", StepStatus::Success, None),
+                ("lint", StepStatus::Success, None),
+                ("test", StepStatus::Failed, None),
+                ("package", StepStatus::Skipped, Some(SkipReason::Dependency)),
+            ]
+        );
+
+        let logs = |report: &PipelineReport| {
+            report
+                .ordered_logs()
+                .into_iter()
+                .map(|(stage, step, lines)| {
+                    let lines: Vec<String> = lines
+                        .iter()
+                        .map(|line| FileReporter::plain_line(line))
+                        .collect();
+                    (stage.to_string(), step.to_string(), lines)
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(logs(&flat), logs(&staged));
+        assert!(logs(&flat).iter().any(|(_, step, lines)| {
+            step == "test" && lines.iter().any(|line| line.ends_with("1 failure"))
+        }));
+    }
+
+    #[cfg(all(windows, feature = "windows-docker"))]
+    #[tokio::test]
+    async fn a_windows_workspace_is_mounted_into_containers() {
+        let runner = runner(
+            r#"
+            stages_order = ["smoke"]
+
+            [stages.smoke.steps.mount]
+            image = "alpine:3.20"
+            command = "cat /workspace/marker; printf 'crlf\r\n'"
+            "#,
+        )
+        .await;
+        tokio::fs::write(Path::new(&runner.cwd).join("marker"), "mounted\n")
+            .await
+            .unwrap();
+        let report = finish(runner, CancellationToken::new()).await.unwrap();
+
+        assert!(report.is_success(), "{:?}", report.error);
+        let lines: Vec<String> = report.logs[&log_key("smoke", "mount")]
+            .iter()
+            .map(|line| FileReporter::plain_line(line))
+            .collect();
+        assert!(
+            lines.iter().any(|line| line.ends_with("mounted")),
+            "{lines:?}"
+        );
+        assert!(lines.iter().any(|line| line.ends_with("crlf")), "{lines:?}");
     }
 }
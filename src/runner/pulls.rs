@@ -0,0 +1,70 @@
+use std::collections::{HashMap, HashSet};
+
+use tokio::sync::watch;
+
+/// How far the pull of an image has come.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PullState {
+    Pending,
+    Ready,
+    /// Why the image cannot be used, e.g. the error of its last pull attempt.
+    Failed(String),
+}
+
+/// The state of every image the run pulls, so each step can start once its own image is
+/// there instead of after all pulls. Images not listed are ready.
+#[derive(Debug)]
+pub struct ImagePulls {
+    states: watch::Sender<HashMap<String, PullState>>,
+}
+
+impl Default for ImagePulls {
+    fn default() -> Self {
+        Self::new(&HashSet::new())
+    }
+}
+
+impl ImagePulls {
+    pub fn new(images: &HashSet<String>) -> Self {
+        let states = images
+            .iter()
+            .map(|image| (image.clone(), PullState::Pending))
+            .collect();
+        Self {
+            states: watch::Sender::new(states),
+        }
+    }
+
+    pub fn state(&self, image: &str) -> PullState {
+        self.states
+            .borrow()
+            .get(image)
+            .cloned()
+            .unwrap_or(PullState::Ready)
+    }
+
+    pub fn set(&self, image: &str, state: PullState) {
+        self.states.send_modify(|states| {
+            states.insert(image.to_string(), state);
+        });
+    }
+
+    /// Fails the images still pending, so no step waits for them forever.
+    pub fn fail_pending(&self, reason: &str) {
+        self.states.send_if_modified(|states| {
+            let mut modified = false;
+            for state in states.values_mut() {
+                if *state == PullState::Pending {
+                    *state = PullState::Failed(reason.to_string());
+                    modified = true;
+                }
+            }
+            modified
+        });
+    }
+
+    /// Sees a change each time an image becomes ready or fails.
+    pub fn subscribe(&self) -> watch::Receiver<HashMap<String, PullState>> {
+        self.states.subscribe()
+    }
+}
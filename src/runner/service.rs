@@ -0,0 +1,117 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio::{sync::Mutex, task::JoinHandle, time::timeout};
+use tokio_util::sync::CancellationToken;
+
+use crate::{engine::DockerEngine, models::Pipeline, output::Icon};
+
+const LOG_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Service {
+    step_name: String,
+    container_id: String,
+    logs: JoinHandle<()>,
+}
+
+/// Detached steps that outlive their stage, and the network that lets later steps reach
+/// them by step name.
+pub struct Services {
+    engine: Arc<DockerEngine>,
+    network: Option<String>,
+    token: CancellationToken,
+    running: Mutex<Vec<Service>>,
+}
+
+impl Services {
+    /// Creates the pipeline network when any step that will run is detached.
+    pub async fn start(engine: Arc<DockerEngine>, pipeline: &Pipeline) -> anyhow::Result<Self> {
+        let detached = pipeline
+            .stages
+            .iter()
+            .flat_map(|stage| &stage.steps)
+            .any(|step| step.detach && step.skip.is_none());
+
+        let network = if detached {
+            let name = format!("ciroach-{}", std::process::id());
+            engine.remove_network(&name).await.ok();
+            engine.create_network(&name).await?;
+            Some(name)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            engine,
+            network,
+            token: CancellationToken::new(),
+            running: Mutex::new(Vec::new()),
+        })
+    }
+
+    pub fn network(&self) -> Option<&str> {
+        self.network.as_deref()
+    }
+
+    /// Stops the log streams of detached steps at teardown.
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+
+    pub async fn register(&self, step_name: &str, container_id: &str, logs: JoinHandle<()>) {
+        self.running.lock().await.push(Service {
+            step_name: step_name.to_string(),
+            container_id: container_id.to_string(),
+            logs,
+        });
+    }
+
+    /// Removes the detached containers of `steps`, so a stage run again starts them anew.
+    pub async fn stop(&self, steps: &[&str]) {
+        let services = {
+            let mut running = self.running.lock().await;
+            let (stopped, kept) = std::mem::take(&mut *running)
+                .into_iter()
+                .partition(|service| steps.contains(&service.step_name.as_str()));
+            *running = kept;
+            stopped
+        };
+        self.remove(services).await;
+    }
+
+    /// Removes every detached container, ignoring how it exits, then the network.
+    pub async fn teardown(&self) {
+        let services = std::mem::take(&mut *self.running.lock().await);
+        self.remove(services).await;
+
+        self.token.cancel();
+
+        if let Some(network) = &self.network
+            && let Err(err) = self.engine.remove_network(network).await
+        {
+            eprintln!(
+                "{} Failed to remove network '{}': {}",
+                Icon::Warning,
+                network,
+                err
+            );
+        }
+    }
+
+    async fn remove(&self, services: Vec<Service>) {
+        for service in services.iter() {
+            self.engine
+                .remove_container(&service.container_id, true)
+                .await
+                .ok();
+            println!("{} Stopped service '{}'", Icon::Clean, service.step_name);
+        }
+
+        // Removing the container ends its log stream; give it a moment to flush.
+        for service in services {
+            let abort = service.logs.abort_handle();
+            if timeout(LOG_DRAIN_TIMEOUT, service.logs).await.is_err() {
+                abort.abort();
+            }
+        }
+    }
+}
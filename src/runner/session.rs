@@ -0,0 +1,77 @@
+use std::{collections::HashMap, sync::Arc};
+
+use tokio::sync::Mutex;
+
+use crate::{
+    engine::{DockerEngine, StartedContainer},
+    models::Step,
+};
+
+/// Keeps a session container running between the commands of its steps.
+const KEEP_ALIVE: &str = "trap 'exit 0' TERM; while :; do sleep 3600 & wait; done";
+
+/// Containers shared by the session steps of a stage. Each is started by the first of its
+/// steps to run and removed when one of them fails or the stage ends.
+pub struct Sessions {
+    engine: Arc<DockerEngine>,
+    running: Mutex<HashMap<String, String>>,
+}
+
+impl Sessions {
+    pub fn new(engine: Arc<DockerEngine>) -> Self {
+        Self {
+            engine,
+            running: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The container of `session`, started from `step` when there is none yet. Only a
+    /// new container reports the time it took to copy the workspace in.
+    pub async fn container(
+        &self,
+        session: &str,
+        step: &Step,
+        cwd: &str,
+        user: &str,
+        network: Option<&str>,
+        stage_attempt: u32,
+    ) -> anyhow::Result<StartedContainer> {
+        let mut running = self.running.lock().await;
+        if let Some(id) = running.get(session) {
+            return Ok(StartedContainer {
+                id: id.clone(),
+                copied_in: None,
+            });
+        }
+
+        let name = format!("{session}-session");
+        let keep_alive = Step {
+            name: name.clone(),
+            exploded_name: name,
+            command: KEEP_ALIVE.to_string(),
+            stdin: None,
+            ..step.clone()
+        };
+        let started = self
+            .engine
+            .run_container(&keep_alive, cwd, user, network, stage_attempt, 1)
+            .await?;
+        running.insert(session.to_string(), started.id.clone());
+        Ok(started)
+    }
+
+    /// Removes the container of `session`, so the next of its steps starts afresh.
+    pub async fn discard(&self, session: &str) {
+        let id = self.running.lock().await.remove(session);
+        if let Some(id) = id {
+            self.engine.remove_container(&id, true).await.ok();
+        }
+    }
+
+    pub async fn teardown(&self) {
+        let running = std::mem::take(&mut *self.running.lock().await);
+        for id in running.values() {
+            self.engine.remove_container(id, true).await.ok();
+        }
+    }
+}
@@ -1,27 +1,103 @@
-use std::{collections::HashSet, sync::Arc};
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    fmt::Write,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use tokio::sync::mpsc;
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore, broadcast, mpsc},
+    task::JoinHandle,
+    time::timeout,
+};
 use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
 
+#[cfg(feature = "kubernetes")]
+use crate::kubernetes::KubeEngine;
 use crate::{
     engine::DockerEngine,
+    error::CiroachError,
+    events::{EventBus, PipelineEvent},
     logger::LogMessage,
-    models::{Stage, StageReport, Step, StepReport},
-    runner::StepRunner,
+    models::{
+        Pipeline, Runner, SkipReason, Stage, StageReport, Step, StepReport, StepStatus, now_millis,
+    },
+    output::Icon,
+    runner::{DebugGate, ImagePulls, PullState, Services, Sessions, StepRunner},
+    ui,
 };
 
 #[derive(Debug, Default)]
 struct StageState {
     pub started: HashSet<String>,
     pub completed: HashSet<String>,
+    /// Steps that did not succeed; whatever needs them is skipped.
+    pub blocked: HashSet<String>,
     pub reports: Vec<StepReport>,
+    /// Step tasks, awaited before the stage returns.
+    pub tasks: Vec<JoinHandle<()>>,
+    /// Steps ready to start but for a slot of their concurrency group, and since when.
+    pub waiting: HashMap<String, Instant>,
+}
+
+/// The slots of every concurrency group of a run, shared by its stages.
+#[derive(Debug, Default)]
+pub struct ConcurrencyGroups {
+    slots: HashMap<String, Arc<Semaphore>>,
+}
+
+impl ConcurrencyGroups {
+    pub fn new(pipeline: &Pipeline) -> Self {
+        let slots = pipeline
+            .stages
+            .iter()
+            .flat_map(|stage| &stage.steps)
+            .filter_map(|step| step.concurrency_group.as_ref())
+            .map(|group| {
+                let width = pipeline.concurrency_groups.get(group).copied().unwrap_or(1);
+                (group.clone(), Arc::new(Semaphore::new(width)))
+            })
+            .collect();
+
+        Self { slots }
+    }
+
+    /// A slot of `group`, held for as long as the permit lives.
+    fn try_acquire(&self, group: &str) -> Option<OwnedSemaphorePermit> {
+        self.slots.get(group)?.clone().try_acquire_owned().ok()
+    }
+}
+
+/// Why a stage gave up, with the reports of its steps up to then.
+pub struct StageFailure {
+    pub report: StageReport,
+    pub error: anyhow::Error,
 }
 
 pub struct StageRunner<'s> {
     stage: &'s Stage,
+    /// The stage's steps in the order they are started when ready at the same time.
+    order: Vec<&'s Step>,
+    /// Milliseconds each step took before.
+    expected: HashMap<String, u64>,
     engine: Arc<DockerEngine>,
+    #[cfg(feature = "kubernetes")]
+    cluster: Option<Arc<KubeEngine>>,
     cwd: String,
     user: String,
+    events: EventBus,
+    debug: Option<Arc<DebugGate>>,
+    services: Arc<Services>,
+    keep_failed: bool,
+    /// Run of the stage under `stage_retries`, starting at 1.
+    attempt: u32,
+    stall_timeout: Option<Duration>,
+    memory_budget: Option<i64>,
+    groups: Arc<ConcurrencyGroups>,
+    sessions: Arc<Sessions>,
+    pulls: Arc<ImagePulls>,
 }
 
 impl<'s> StageRunner<'s> {
@@ -30,30 +106,175 @@ impl<'s> StageRunner<'s> {
         engine: Arc<DockerEngine>,
         cwd: impl Into<String>,
         user: impl Into<String>,
+        events: EventBus,
+        debug: Option<Arc<DebugGate>>,
+        services: Arc<Services>,
     ) -> Self {
+        let sessions = Arc::new(Sessions::new(engine.clone()));
         Self {
             stage,
+            order: stage.steps.iter().collect(),
+            expected: HashMap::new(),
             engine,
+            #[cfg(feature = "kubernetes")]
+            cluster: None,
             cwd: cwd.into(),
             user: user.into(),
+            events,
+            debug,
+            services,
+            keep_failed: false,
+            attempt: 1,
+            stall_timeout: None,
+            memory_budget: None,
+            groups: Arc::default(),
+            sessions,
+            pulls: Arc::default(),
         }
     }
 
+    pub fn keep_failed(mut self, keep_failed: bool) -> Self {
+        self.keep_failed = keep_failed;
+        self
+    }
+
+    /// Run of the stage under `stage_retries`, starting at 1.
+    pub fn attempt(mut self, attempt: u32) -> Self {
+        self.attempt = attempt;
+        self
+    }
+
+    /// Gives up on the stage when no step logs a line or finishes for this long.
+    pub fn stall_timeout(mut self, stall_timeout: Option<Duration>) -> Self {
+        self.stall_timeout = stall_timeout;
+        self
+    }
+
+    /// Bytes the memory limits of running steps may add up to; further steps wait.
+    pub fn memory_budget(mut self, memory_budget: Option<i64>) -> Self {
+        self.memory_budget = memory_budget;
+        self
+    }
+
+    /// Starts ready steps by `priority`, then those that took longest in `durations`
+    /// first, so the long ones do not end up extending the stage.
+    pub fn expected_durations(mut self, durations: &HashMap<String, u64>) -> Self {
+        self.expected = self
+            .stage
+            .steps
+            .iter()
+            .filter_map(|step| {
+                let duration = durations.get(&step.exploded_name)?;
+                Some((step.exploded_name.clone(), *duration))
+            })
+            .collect();
+        self.order.sort_by_key(|step| {
+            let duration = self.expected.get(&step.exploded_name).copied().unwrap_or(0);
+            (Reverse(step.priority), Reverse(duration))
+        });
+        self
+    }
+
+    pub fn concurrency_groups(mut self, groups: Arc<ConcurrencyGroups>) -> Self {
+        self.groups = groups;
+        self
+    }
+
+    /// Steps wait for their image in `pulls`, and fail when it cannot be pulled.
+    pub fn image_pulls(mut self, pulls: Arc<ImagePulls>) -> Self {
+        self.pulls = pulls;
+        self
+    }
+
+    /// The cluster the stage's Kubernetes steps run in.
+    #[cfg(feature = "kubernetes")]
+    pub fn cluster(mut self, cluster: Option<Arc<KubeEngine>>) -> Self {
+        self.cluster = cluster;
+        self
+    }
+
+    /// `token` is this run's own: it is cancelled when the stage fails early, so callers
+    /// pass a child of the pipeline's token.
+    #[tracing::instrument(
+        name = "stage",
+        skip_all,
+        fields(stage = %self.stage.name, steps = self.stage.steps.len())
+    )]
     pub async fn run(
         &self,
         log_tx: mpsc::Sender<LogMessage>,
         token: CancellationToken,
-    ) -> anyhow::Result<StageReport> {
+    ) -> Result<StageReport, StageFailure> {
+        let timer = Instant::now();
+        let started_at = now_millis();
+        // Should this future be dropped, the steps still see the cancellation and remove
+        // their containers; aborting their tasks would leave the containers running.
+        let _cancel_on_drop = token.clone().drop_guard();
         let mut state = StageState::default();
+
+        let result = self.drive(&mut state, &log_tx, &token).await;
+
+        // Nothing spawned by the stage may outlive it.
+        if result.is_err() {
+            token.cancel();
+        }
+        for task in state.tasks.drain(..) {
+            task.await.ok();
+        }
+        self.sessions.teardown().await;
+
+        let aborted = result.is_err();
+        let report = StageReport {
+            elapsed: timer.elapsed().as_millis() as u64,
+            started_at,
+            finished_at: now_millis(),
+            ..self.finalize_report(state, aborted)
+        };
+        match result {
+            Ok(()) => Ok(report),
+            Err(error) => Err(StageFailure { report, error }),
+        }
+    }
+
+    async fn drive(
+        &self,
+        state: &mut StageState,
+        log_tx: &mpsc::Sender<LogMessage>,
+        token: &CancellationToken,
+    ) -> anyhow::Result<()> {
+        let mut stalled: Option<CiroachError> = None;
         let (status_tx, mut status_rx) = mpsc::channel::<StepReport>(100);
+        let mut pulled = self.pulls.subscribe();
+        // Only needed to hold off the stall timeout.
+        let mut activity = self.stall_timeout.map(|_| self.events.subscribe());
         let total_steps = self.stage.steps.len();
 
+        for step in self.stage.steps.iter() {
+            if let Some(reason) = step.skip {
+                let report = StepReport::excluded(step, reason);
+                self.events.emit(PipelineEvent::StepFinished {
+                    stage: self.stage.name.clone(),
+                    step: report.name.clone(),
+                    status: report.status,
+                    retries: 0,
+                    elapsed: 0,
+                });
+                state.started.insert(report.name.clone());
+                state.completed.insert(report.name.clone());
+                state.reports.push(report);
+            }
+        }
+
         loop {
             if !token.is_cancelled() {
-                self.dispatch_ready_steps(&mut state, &log_tx, &status_tx, &token);
+                self.dispatch_ready_steps(state, log_tx, &status_tx, token);
             }
 
             if state.started.len() == state.completed.len() {
+                if let Some(stalled) = stalled {
+                    return Err(stalled.into());
+                }
+
                 // If the token was cancelled, and we have received reports for everything we started,
                 // we can safely stop the loop even if some steps in the stage never ran.
                 if token.is_cancelled() || state.started.len() == total_steps {
@@ -61,23 +282,84 @@ impl<'s> StageRunner<'s> {
                 }
 
                 // If we aren't cancelled, but nothing is running and we aren't finished, it's a deadlock.
-                if !token.is_cancelled() {
-                    anyhow::bail!(
-                        "Deadlock detected in stage '{}'! Check your 'needs' configuration.",
-                        self.stage.name
-                    );
+                if !self.waits_for_image(state) {
+                    return Err(CiroachError::Deadlock {
+                        stage: self.stage.name.clone(),
+                        blocked: self.blocked_steps(state),
+                    }
+                    .into());
                 }
             }
 
-            if let Some(rep) = status_rx.recv().await {
+            // A finished pull lets the steps waiting for that image start, and a logged
+            // line restarts the stall timeout.
+            let next = async {
+                tokio::select! {
+                    received = status_rx.recv() => Some(received),
+                    changed = pulled.changed() => {
+                        if changed.is_err() {
+                            std::future::pending::<()>().await;
+                        }
+                        None
+                    }
+                    _ = self.step_logged(activity.as_mut()) => None,
+                }
+            };
+            let next = match self.stall_timeout.filter(|_| stalled.is_none()) {
+                Some(limit) => match timeout(limit, next).await {
+                    Ok(next) => next,
+                    Err(_) => {
+                        // Stop what is still running and report once it has wound down.
+                        stalled = Some(CiroachError::Stalled {
+                            stage: self.stage.name.clone(),
+                            limit,
+                            blocked: self.blocked_steps(state),
+                        });
+                        token.cancel();
+                        continue;
+                    }
+                },
+                None => next.await,
+            };
+            let Some(received) = next else {
+                continue;
+            };
+
+            if let Some(rep) = received {
+                self.events.emit(PipelineEvent::StepFinished {
+                    stage: self.stage.name.clone(),
+                    step: rep.name.clone(),
+                    status: rep.status,
+                    retries: rep.retries,
+                    elapsed: rep.elapsed,
+                });
                 state.completed.insert(rep.name.clone());
+                if rep.status != StepStatus::Success {
+                    state.blocked.insert(rep.name.clone());
+                }
                 state.reports.push(rep);
             } else {
                 break;
             }
         }
 
-        Ok(self.finalize_report(state))
+        Ok(())
+    }
+
+    /// Returns once a step of this stage logs a line, or never without `events`.
+    async fn step_logged(&self, events: Option<&mut broadcast::Receiver<PipelineEvent>>) {
+        let Some(events) = events else {
+            return std::future::pending().await;
+        };
+        loop {
+            match events.recv().await {
+                Ok(PipelineEvent::StepLog { stage, .. }) if stage == self.stage.name => return,
+                // Too many lines to keep up with is activity as well.
+                Err(broadcast::error::RecvError::Lagged(_)) => return,
+                Ok(_) => continue,
+                Err(broadcast::error::RecvError::Closed) => std::future::pending().await,
+            }
+        }
     }
 
     fn dispatch_ready_steps(
@@ -87,40 +369,247 @@ impl<'s> StageRunner<'s> {
         status_tx: &mpsc::Sender<StepReport>,
         token: &CancellationToken,
     ) {
-        for step in self.stage.steps.iter() {
+        self.skip_blocked_steps(state);
+
+        for &step in self.order.iter() {
             if state.started.contains(&step.exploded_name) {
                 continue;
             }
 
-            if self.can_start(step, &state.completed) {
+            match self.image_state(step) {
+                PullState::Ready => {}
+                PullState::Pending => continue,
+                PullState::Failed(reason) => {
+                    self.fail_without_image(state, step, &reason);
+                    continue;
+                }
+            }
+
+            if self.can_start(step, &state.completed) && self.fits_memory(step, state) {
+                let slot = match &step.concurrency_group {
+                    Some(group) => match self.groups.try_acquire(group) {
+                        Some(slot) => Some(slot),
+                        None => {
+                            state
+                                .waiting
+                                .entry(step.exploded_name.clone())
+                                .or_insert_with(Instant::now);
+                            continue;
+                        }
+                    },
+                    None => None,
+                };
+                let group_wait = state
+                    .waiting
+                    .remove(&step.exploded_name)
+                    .map(|since| since.elapsed().as_millis() as u64);
                 state.started.insert(step.exploded_name.clone());
 
-                let runner =
-                    StepRunner::new(step.clone(), self.engine.clone(), &self.cwd, &self.user);
+                self.events.emit(PipelineEvent::StepStarted {
+                    stage: self.stage.name.clone(),
+                    step: step.exploded_name.clone(),
+                    expected: self.expected.get(&step.exploded_name).copied(),
+                });
+
+                let runner = StepRunner::new(
+                    step.clone(),
+                    self.engine.clone(),
+                    &self.cwd,
+                    &self.user,
+                    self.services.clone(),
+                )
+                .debug(self.debug.clone())
+                .keep_failed(self.keep_failed)
+                .stage_attempt(self.attempt)
+                .events(self.events.clone())
+                .sessions(self.sessions.clone());
+                #[cfg(feature = "kubernetes")]
+                let runner = runner.cluster(self.cluster.clone());
 
                 let log_tx_inner = log_tx.clone();
                 let status_tx_inner = status_tx.clone();
                 let token_inner = token.clone();
 
-                tokio::spawn(async move {
-                    let result = runner.run(log_tx_inner, token_inner).await;
-                    status_tx_inner.send(result).await.ok();
-                });
+                state.tasks.push(tokio::spawn(
+                    async move {
+                        let mut result = runner.run(log_tx_inner, token_inner).await;
+                        // Freed before the stage hears of it, so the next step can take it.
+                        drop(slot);
+                        result.group_wait = group_wait;
+                        status_tx_inner.send(result).await.ok();
+                    }
+                    .in_current_span(),
+                ));
             }
         }
     }
 
-    fn can_start(&self, step: &Step, completed: &HashSet<String>) -> bool {
-        step.needs.iter().all(|needed_base_name| {
-            self.stage
-                .steps
-                .iter()
-                .filter(|s| &s.name == needed_base_name)
-                .all(|s| completed.contains(&s.exploded_name))
+    fn image_state(&self, step: &Step) -> PullState {
+        match step.runner == Runner::Container && !step.local_image {
+            true => self.pulls.state(&step.image),
+            false => PullState::Ready,
+        }
+    }
+
+    fn waits_for_image(&self, state: &StageState) -> bool {
+        self.stage.steps.iter().any(|step| {
+            !state.started.contains(&step.exploded_name)
+                && self.image_state(step) == PullState::Pending
         })
     }
 
-    fn finalize_report(&self, mut state: StageState) -> StageReport {
+    fn fail_without_image(&self, state: &mut StageState, step: &Step, reason: &str) {
+        ui::suspend(|| {
+            eprintln!(
+                "{} Step '{}' cannot run without image '{}': {}",
+                Icon::Error,
+                step.exploded_name,
+                step.image,
+                reason
+            )
+        });
+        let report = StepReport {
+            image: Some(step.image.clone()),
+            ..StepReport::failed(step, 0, 0)
+        };
+        self.events.emit(PipelineEvent::StepFinished {
+            stage: self.stage.name.clone(),
+            step: report.name.clone(),
+            status: report.status,
+            retries: 0,
+            elapsed: 0,
+        });
+        state.started.insert(report.name.clone());
+        state.completed.insert(report.name.clone());
+        state.blocked.insert(report.name.clone());
+        state.reports.push(report);
+    }
+
+    /// A failure only stops the steps depending on it; independent steps of the stage run
+    /// to completion.
+    fn skip_blocked_steps(&self, state: &mut StageState) {
+        loop {
+            let blocked = self.stage.steps.iter().find(|step| {
+                !state.started.contains(&step.exploded_name)
+                    && self
+                        .needed(step)
+                        .any(|s| state.blocked.contains(&s.exploded_name))
+            });
+            let Some(step) = blocked else {
+                break;
+            };
+
+            let report = StepReport::excluded(step, SkipReason::Dependency);
+            self.events.emit(PipelineEvent::StepFinished {
+                stage: self.stage.name.clone(),
+                step: report.name.clone(),
+                status: report.status,
+                retries: 0,
+                elapsed: 0,
+            });
+            state.started.insert(report.name.clone());
+            state.completed.insert(report.name.clone());
+            state.blocked.insert(report.name.clone());
+            state.reports.push(report);
+        }
+    }
+
+    /// One line per unfinished step: whether it is running, or which of its `needs` are
+    /// unmet and why.
+    fn blocked_steps(&self, state: &StageState) -> String {
+        let mut table = String::new();
+
+        for step in self.stage.steps.iter() {
+            if state.completed.contains(&step.exploded_name) {
+                continue;
+            }
+
+            let detail = if state.started.contains(&step.exploded_name) {
+                "running".to_string()
+            } else if self.image_state(step) == PullState::Pending {
+                format!("waits for image '{}'", step.image)
+            } else if let Some(group) = step
+                .concurrency_group
+                .as_ref()
+                .filter(|_| state.waiting.contains_key(&step.exploded_name))
+            {
+                format!("waits for concurrency group '{group}'")
+            } else {
+                let unmet = step
+                    .needs
+                    .iter()
+                    .filter_map(|need| {
+                        let variants: Vec<&Step> = self
+                            .stage
+                            .steps
+                            .iter()
+                            .filter(|s| s.matches_need(need))
+                            .collect();
+                        let unmet = if variants.is_empty() {
+                            "never defined"
+                        } else if variants
+                            .iter()
+                            .any(|s| state.blocked.contains(&s.exploded_name))
+                        {
+                            "failed"
+                        } else if variants
+                            .iter()
+                            .any(|s| !state.completed.contains(&s.exploded_name))
+                        {
+                            "not yet complete"
+                        } else {
+                            return None;
+                        };
+                        Some(format!("{need} ({unmet})"))
+                    })
+                    .collect::<Vec<_>>();
+                format!("needs {}", unmet.join(", "))
+            };
+
+            writeln!(table, "  {:<30} {}", step.exploded_name, detail).ok();
+        }
+
+        table
+    }
+
+    fn can_start(&self, step: &Step, completed: &HashSet<String>) -> bool {
+        self.needed(step)
+            .all(|s| completed.contains(&s.exploded_name))
+    }
+
+    /// Whether `step` fits in the memory budget next to the running steps. With nothing
+    /// running it always does, so a step larger than the budget still gets its turn.
+    fn fits_memory(&self, step: &Step, state: &StageState) -> bool {
+        let Some(budget) = self.memory_budget else {
+            return true;
+        };
+
+        let mut running = self.stage.steps.iter().filter(|s| {
+            state.started.contains(&s.exploded_name) && !state.completed.contains(&s.exploded_name)
+        });
+        let reserved: i64 = running.clone().filter_map(|s| s.memory).sum();
+
+        running.next().is_none() || reserved + step.memory.unwrap_or(0) <= budget
+    }
+
+    /// Every step named in `step.needs`, with all its variants unless one is named.
+    fn needed<'a>(&'a self, step: &'a Step) -> impl Iterator<Item = &'a Step> {
+        self.stage.steps.iter().filter(|s| step.depends_on(s))
+    }
+
+    /// A stage that gave up stopped its steps itself, so they were `aborted` rather than
+    /// interrupted.
+    fn finalize_report(&self, mut state: StageState, aborted: bool) -> StageReport {
+        let reason = match aborted {
+            true => SkipReason::Aborted,
+            false => SkipReason::Interrupted,
+        };
+        for report in state.reports.iter_mut() {
+            if report.reason == Some(SkipReason::Interrupted) {
+                report.reason = Some(reason);
+            }
+        }
+
         let finished_names: HashSet<String> = state
             .reports
             .iter()
@@ -130,17 +619,115 @@ impl<'s> StageRunner<'s> {
         for step in self.stage.steps.iter() {
             if !finished_names.contains(&step.exploded_name) {
                 if state.started.contains(&step.exploded_name) {
-                    state
-                        .reports
-                        .push(StepReport::failed(&step.exploded_name, 0, 0));
+                    state.reports.push(StepReport::failed(step, 0, 0));
                 } else {
-                    state.reports.push(StepReport::skipped(&step.exploded_name));
+                    // Nothing is dispatched once the run is cancelled.
+                    state.reports.push(StepReport::excluded(step, reason));
                 }
             }
         }
 
+        // Reports arrive in completion order; present them in declaration order.
+        state.reports.sort_by_key(|report| {
+            self.stage
+                .steps
+                .iter()
+                .position(|step| step.exploded_name == report.name)
+        });
+
         StageReport {
+            name: self.stage.name.clone(),
             step_reports: state.reports,
+            elapsed: 0,
+            started_at: 0,
+            finished_at: 0,
+            earlier_attempts: Vec::new(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    use super::*;
+    use crate::models::RawPipeline;
+
+    #[tokio::test]
+    async fn a_deadlock_leaves_no_step_running() {
+        let pipeline = toml::from_str::<RawPipeline>(
+            r#"
+            stages_order = ["test"]
+
+            [stages.test.steps.build]
+            runner = "host"
+            command = "sleep 0.2"
+
+            [stages.test.steps.migrate]
+            runner = "host"
+            command = "true"
+            concurrency_group = "db"
+            "#,
+        )
+        .unwrap()
+        .select(None)
+        .and_then(|raw| raw.compile(&[]))
+        .unwrap();
+        let engine = Arc::new(DockerEngine::disconnected().unwrap());
+        let services = Arc::new(Services::start(engine.clone(), &pipeline).await.unwrap());
+        let groups = Arc::new(ConcurrencyGroups::new(&pipeline));
+        // Held by a step of another stage for the whole run.
+        let _held = groups.try_acquire("db").unwrap();
+        let events = EventBus::default();
+        let mut received = events.subscribe();
+        let cwd = std::env::temp_dir().to_string_lossy().to_string();
+
+        let runner = StageRunner::new(&pipeline.stages[0], engine, cwd, "", events, None, services)
+            .concurrency_groups(groups);
+        let (log_tx, mut log_rx) = mpsc::channel(100);
+        tokio::spawn(async move { while log_rx.recv().await.is_some() {} });
+        let token = CancellationToken::new();
+        let Err(failure) = runner.run(log_tx, token.clone()).await else {
+            panic!("the stage did not fail");
+        };
+        let err = failure.error.to_string();
+
+        // The steps that never finished, one per line after the first; none is running.
+        let unfinished: Vec<String> = err
+            .lines()
+            .skip(1)
+            .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+            .collect();
+        assert!(err.starts_with("Deadlock detected"), "{err}");
+        assert_eq!(unfinished, ["migrate waits for concurrency group 'db'"]);
+        // Whatever the stage started was cancelled and has finished by the time it returns.
+        assert!(token.is_cancelled());
+        let (mut started, mut finished) = (Vec::new(), Vec::new());
+        loop {
+            match received.try_recv() {
+                Ok(PipelineEvent::StepStarted { step, .. }) => started.push(step),
+                Ok(PipelineEvent::StepFinished { step, .. }) => finished.push(step),
+                Ok(_) => {}
+                Err(TryRecvError::Empty) => break,
+                Err(err) => panic!("{err}"),
+            }
+        }
+        assert_eq!(started, ["build"]);
+        assert_eq!(finished, started);
+        // The step that ran keeps its report; the one that never could is aborted.
+        let steps: Vec<_> = failure
+            .report
+            .step_reports
+            .iter()
+            .map(|step| (step.name.as_str(), step.status, step.reason))
+            .collect();
+        assert_eq!(
+            steps,
+            [
+                ("build", StepStatus::Success, None),
+                ("migrate", StepStatus::Skipped, Some(SkipReason::Aborted)),
+            ]
+        );
+        assert!(failure.report.step_reports[0].elapsed >= 200);
+    }
+}
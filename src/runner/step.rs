@@ -1,26 +1,129 @@
 use std::{
-    sync::Arc,
+    path::Path,
+    process::Stdio,
+    sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+    },
     time::{Duration, Instant},
 };
 
-use anyhow::Ok;
+use regex::Regex;
 use tokio::{
-    sync::{Mutex, mpsc},
-    time::{sleep, timeout},
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
+    process::Command,
+    sync::{Mutex, mpsc, oneshot},
+    task::JoinHandle,
+    time::{interval, sleep, timeout},
 };
 use tokio_util::sync::CancellationToken;
+use tracing::{Span, field::Empty};
 
+#[cfg(feature = "kubernetes")]
+use crate::kubernetes::KubeEngine;
 use crate::{
     engine::DockerEngine,
+    error::CiroachError,
+    events::{EventBus, PipelineEvent},
     logger::LogMessage,
-    models::{Step, StepReport},
+    models::{
+        Attempt, KeptContainer, OutputStats, ReadyCondition, Runner, SkipReason, Step, StepInput,
+        StepReport, StepStatus, format_wall_clock, now_millis,
+    },
+    output::Icon,
+    runner::{DebugGate, Services, Sessions},
+    ui,
 };
 
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const SIGKILL_EXIT_CODE: i64 = 128 + 9;
+const SIGTERM_EXIT_CODE: i64 = 128 + 15;
+/// How often a running step's silence is checked.
+const SILENCE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const WATCHED_OUTPUT_BUFFER: usize = 100;
+
+/// Removes the container of an attempt if it is still tracked when the attempt ends
+/// without cleaning up, e.g. because its future was dropped mid-way.
+struct ContainerGuard {
+    engine: Arc<DockerEngine>,
+    id: Arc<Mutex<Option<String>>>,
+}
+
+impl Drop for ContainerGuard {
+    fn drop(&mut self) {
+        let Ok(mut id) = self.id.try_lock() else {
+            return;
+        };
+
+        if let Some(id) = id.take() {
+            let engine = self.engine.clone();
+            tokio::spawn(async move {
+                engine.remove_container(&id, true).await.ok();
+            });
+        }
+    }
+}
+
+/// Deletes the Job of a Kubernetes attempt however the attempt ends.
+#[cfg(feature = "kubernetes")]
+struct JobGuard {
+    cluster: Arc<KubeEngine>,
+    job: String,
+}
+
+#[cfg(feature = "kubernetes")]
+impl Drop for JobGuard {
+    fn drop(&mut self) {
+        let cluster = self.cluster.clone();
+        let job = std::mem::take(&mut self.job);
+        tokio::spawn(async move {
+            cluster.delete_job(&job).await.ok();
+        });
+    }
+}
+
+/// Kills the process group of a host step. Killing only the shell would leave the
+/// processes it started running.
+struct ProcessGroupGuard(Option<u32>);
+
+impl Drop for ProcessGroupGuard {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Some(pid) = self.0 {
+            std::process::Command::new("kill")
+                .args(["-KILL", "--", &format!("-{pid}")])
+                .stderr(Stdio::null())
+                .status()
+                .ok();
+        }
+    }
+}
+
 pub struct StepRunner {
     step: Step,
     engine: Arc<DockerEngine>,
+    /// Runs the step when its `runner` is `kubernetes`.
+    #[cfg(feature = "kubernetes")]
+    cluster: Option<Arc<KubeEngine>>,
     cwd: String,
     user: String,
+    services: Arc<Services>,
+    sessions: Arc<Sessions>,
+    debug: Option<Arc<DebugGate>>,
+    keep_failed: bool,
+    events: EventBus,
+    /// Container of the final failed attempt, kept when someone wants to look at it.
+    failed_container: Mutex<Option<String>>,
+    /// Writable layer size of the last attempt that ran to completion.
+    layer_size: Mutex<Option<i64>>,
+    /// Output of the last attempt that ran to completion.
+    output: Mutex<Option<OutputStats>>,
+    /// Number of the attempt running now, starting at 1.
+    attempt: AtomicU32,
+    /// Run of the step's stage this is, starting at 1.
+    stage_attempt: u32,
+    /// Registry digest of the image the last attempt ran in.
+    image_digest: Mutex<Option<String>>,
 }
 
 impl StepRunner {
@@ -29,98 +132,318 @@ impl StepRunner {
         engine: Arc<DockerEngine>,
         cwd: impl Into<String>,
         user: impl Into<String>,
+        services: Arc<Services>,
     ) -> Self {
+        let sessions = Arc::new(Sessions::new(engine.clone()));
         Self {
             step,
             engine,
+            #[cfg(feature = "kubernetes")]
+            cluster: None,
             cwd: cwd.into(),
             user: user.into(),
+            services,
+            sessions,
+            debug: None,
+            keep_failed: false,
+            events: EventBus::default(),
+            failed_container: Mutex::new(None),
+            layer_size: Mutex::new(None),
+            output: Mutex::new(None),
+            attempt: AtomicU32::new(1),
+            stage_attempt: 1,
+            image_digest: Mutex::new(None),
         }
     }
 
+    pub fn debug(mut self, debug: Option<Arc<DebugGate>>) -> Self {
+        self.debug = debug;
+        self
+    }
+
+    /// Leaves the container of a failed step in place instead of removing it.
+    pub fn keep_failed(mut self, keep_failed: bool) -> Self {
+        self.keep_failed = keep_failed;
+        self
+    }
+
+    /// Run of the step's stage under `stage_retries`, starting at 1.
+    pub fn stage_attempt(mut self, stage_attempt: u32) -> Self {
+        self.stage_attempt = stage_attempt;
+        self
+    }
+
+    pub fn events(mut self, events: EventBus) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// The session containers of the step's stage.
+    pub fn sessions(mut self, sessions: Arc<Sessions>) -> Self {
+        self.sessions = sessions;
+        self
+    }
+
+    #[cfg(feature = "kubernetes")]
+    pub fn cluster(mut self, cluster: Option<Arc<KubeEngine>>) -> Self {
+        self.cluster = cluster;
+        self
+    }
+
+    #[tracing::instrument(
+        name = "step",
+        skip_all,
+        fields(
+            step = %self.step.exploded_name,
+            image = %self.step.image,
+            memory_limit = self.step.memory,
+            retries = 0,
+            exit_code = Empty,
+            oom = Empty,
+        )
+    )]
     pub async fn run(
         self,
         log_tx: mpsc::Sender<LogMessage>,
         token: CancellationToken,
+    ) -> StepReport {
+        let started_at = now_millis();
+        let mut attempts = Vec::new();
+        let report = self
+            .run_attempts(log_tx.clone(), token, &mut attempts)
+            .await;
+
+        // The report must not overtake the step's last lines on their way to the log task.
+        let (marker, taken) = LogMessage::end_of_step(&self.step.stage, &self.step.exploded_name);
+        if log_tx.send(marker).await.is_ok() {
+            taken.await.ok();
+        }
+
+        StepReport {
+            started_at,
+            finished_at: now_millis(),
+            attempts,
+            layer_size: *self.layer_size.lock().await,
+            output: self.output.lock().await.take(),
+            image: (self.step.runner != Runner::Host).then(|| self.step.image.clone()),
+            image_digest: self.image_digest.lock().await.clone(),
+            ..report
+        }
+    }
+
+    async fn run_attempts(
+        &self,
+        log_tx: mpsc::Sender<LogMessage>,
+        token: CancellationToken,
+        spans: &mut Vec<Attempt>,
     ) -> StepReport {
         let timer = Instant::now();
+        let deadline = self.step.total_timeout.map(|total| timer + total);
         let mut attempts = 0;
         let max_retries = self.step.max_retries;
         let step_name = &self.step.exploded_name;
 
         loop {
-            match self.execute_attempt(&log_tx, &token).await {
-                std::result::Result::Ok(_) => {
+            let last_attempt = attempts >= max_retries;
+            let retain = last_attempt && (self.debug.is_some() || self.keep_failed);
+
+            let attempt_started = now_millis();
+            self.attempt.store(attempts + 1, Ordering::Relaxed);
+            // Ends with the attempt, so nothing it started carries over into a retry;
+            // cancelling the stage still reaches it.
+            let attempt_token = token.child_token();
+            let _end_attempt = attempt_token.clone().drop_guard();
+            let result = self
+                .execute_attempt(&log_tx, &attempt_token, attempts + 1, retain, deadline)
+                .await;
+            spans.push(Attempt {
+                started_at: attempt_started,
+                finished_at: now_millis(),
+                status: Some(match &result {
+                    Ok(_) => StepStatus::Success,
+                    Err(CiroachError::Cancelled) => StepStatus::Cancelled,
+                    Err(_) => StepStatus::Failed,
+                }),
+                exit_code: result.as_ref().err().and_then(CiroachError::exit_code),
+                error: result
+                    .as_ref()
+                    .err()
+                    .filter(|err| !matches!(err, CiroachError::Cancelled))
+                    .map(ToString::to_string),
+                backoff: None,
+            });
+
+            match result {
+                Ok(_) => {
                     return StepReport::success(
-                        step_name,
+                        &self.step,
                         attempts,
                         timer.elapsed().as_millis() as u64,
                     );
                 }
-                std::result::Result::Err(err)
-                    if token.is_cancelled() && err.to_string() == "Cancelled" =>
-                {
+                Err(CiroachError::Cancelled) => {
                     return StepReport::cancelled(
-                        step_name,
+                        &self.step,
                         attempts,
                         timer.elapsed().as_millis() as u64,
                     );
                 }
-                std::result::Result::Err(err) => {
-                    if attempts < self.step.max_retries && !token.is_cancelled() {
+                Err(err) => {
+                    let throttle_duration = Duration::from_secs(2u64.pow(attempts + 1));
+                    // A retry that could only start once the budget is spent is not made.
+                    let out_of_time = matches!(err, CiroachError::TotalTimeout { .. })
+                        || deadline.is_some_and(|deadline| {
+                            attempts < max_retries && Instant::now() + throttle_duration >= deadline
+                        });
+
+                    let precondition = matches!(err, CiroachError::PreconditionFailed { .. });
+                    let retriable = !precondition || self.step.retry_check;
+
+                    if attempts < max_retries && !token.is_cancelled() && !out_of_time && retriable
+                    {
                         attempts += 1;
 
+                        Span::current().record("retries", attempts);
+                        tracing::info!(attempt = attempts, error = %err, "retrying step");
+
+                        self.events.emit(PipelineEvent::StepRetrying {
+                            step: step_name.clone(),
+                            attempt: attempts,
+                            max_retries,
+                        });
                         self.log_retry(&log_tx, attempts, max_retries, &err).await;
 
-                        let throttle_secs = 2u64.pow(attempts);
-                        let throttle_duration = Duration::from_secs(throttle_secs);
+                        tracing::debug!(delay = ?throttle_duration, "sleeping before retry");
+                        if let Some(attempt) = spans.last_mut() {
+                            attempt.backoff = Some(throttle_duration.as_millis() as u64);
+                        }
 
                         tokio::select! {
                             _ = sleep(throttle_duration) => {
                                 continue;
                             }
                             _ = token.cancelled() => {
-                                return StepReport::cancelled(step_name, attempts, timer.elapsed().as_millis() as u64);
+                                return StepReport::cancelled(&self.step, attempts, timer.elapsed().as_millis() as u64);
                             }
                         }
                     }
 
-                    token.cancel();
-                    return StepReport::failed(
-                        step_name,
-                        attempts,
-                        timer.elapsed().as_millis() as u64,
-                    );
+                    if out_of_time && attempts < max_retries {
+                        self.log_retries_abandoned(&log_tx, max_retries - attempts)
+                            .await;
+                    }
+                    let kept = self.handle_failed_container().await;
+
+                    return StepReport {
+                        kept,
+                        reason: if precondition {
+                            Some(SkipReason::PreconditionFailed)
+                        } else {
+                            out_of_time.then_some(SkipReason::TotalTimeout)
+                        },
+                        exit_code: err.exit_code(),
+                        ..StepReport::failed(
+                            &self.step,
+                            attempts,
+                            timer.elapsed().as_millis() as u64,
+                        )
+                    };
                 }
             }
         }
     }
 
+    /// Runs attempt number `attempt`, starting at 1. With `retain`, the container of a
+    /// failed attempt is left in place for post-mortem instead of being removed; otherwise
+    /// it is gone before this returns, ahead of any retry. The attempt ends at `deadline`,
+    /// the end of the step's `total_timeout`, if that comes before its own `timeout`.
     async fn execute_attempt(
         &self,
         log_tx: &mpsc::Sender<LogMessage>,
         token: &CancellationToken,
-    ) -> anyhow::Result<()> {
+        attempt: u32,
+        retain: bool,
+        deadline: Option<Instant>,
+    ) -> Result<(), CiroachError> {
         let container_id = Arc::new(Mutex::new(None));
         let exec_id = Arc::clone(&container_id);
+        let _guard = ContainerGuard {
+            engine: self.engine.clone(),
+            id: Arc::clone(&container_id),
+        };
 
-        let exec_fut = self.execute(log_tx, exec_id, token);
-        let timeout_fut = timeout(self.step.timeout, exec_fut);
+        // The output passes through the silence watch on its way to the log task.
+        let (watched_tx, mut watched_rx) = mpsc::channel(WATCHED_OUTPUT_BUFFER);
+        let output_tx = if self.watches_silence() {
+            &watched_tx
+        } else {
+            log_tx
+        };
 
-        tokio::select! {
+        let exec_fut = self.execute(output_tx, exec_id, token, attempt);
+        let limit = match deadline {
+            Some(deadline) => self
+                .step
+                .timeout
+                .min(deadline.saturating_duration_since(Instant::now())),
+            None => self.step.timeout,
+        };
+        let timeout_fut = timeout(limit, exec_fut);
+
+        let result = tokio::select! {
             _ = token.cancelled() => {
+                Self::flush_watched(&mut watched_rx, log_tx).await;
                 self.cleanup_container(&container_id).await;
-                Err(anyhow::anyhow!("Cancelled"))
+                Err(CiroachError::Cancelled)
             }
-            res = timeout_fut => match res {
-                std::result::Result::Ok(inner) => inner,
-                std::result::Result::Err(_) => {
-                    self.log_timeout(log_tx, self.step.timeout).await;
-                    self.cleanup_container(&container_id).await;
-                    Err(anyhow::anyhow!("Timeout"))
+            res = timeout_fut => {
+                Self::flush_watched(&mut watched_rx, log_tx).await;
+                match res {
+                    Ok(inner) => inner,
+                    Err(_) if limit < self.step.timeout => {
+                        let total = self.step.total_timeout.unwrap_or(limit);
+                        self.log_total_timeout(log_tx, total).await;
+                        Err(CiroachError::TotalTimeout {
+                            step: self.step.exploded_name.clone(),
+                            limit: total,
+                        })
+                    }
+                    Err(_) => {
+                        self.log_timeout(log_tx, self.step.timeout).await;
+                        Err(CiroachError::Timeout {
+                            step: self.step.exploded_name.clone(),
+                            limit: self.step.timeout,
+                        })
+                    }
                 }
             }
+            limit = self.watch_silence(&mut watched_rx, log_tx) => {
+                self.log_silent(log_tx, limit).await;
+                Err(CiroachError::Silent {
+                    step: self.step.exploded_name.clone(),
+                    limit,
+                })
+            }
+        };
+
+        if let (Some(debug), Some(id)) = (&self.debug, container_id.lock().await.as_ref()) {
+            debug.untrack(id);
+        }
+
+        // Whatever the failed command left behind, or is still doing, must not meet the
+        // next step of the session. The container of a step's own session goes either way.
+        if let Some(session) = self.session()
+            && (result.is_err() || self.step.session.is_none())
+        {
+            self.sessions.discard(&session).await;
+        }
+
+        if result.is_err() && retain && !token.is_cancelled() {
+            *self.failed_container.lock().await = container_id.lock().await.take();
         }
+
+        self.cleanup_container(&container_id).await;
+        result
     }
 
     async fn execute(
@@ -128,44 +451,609 @@ impl StepRunner {
         log_tx: &mpsc::Sender<LogMessage>,
         id_tracker: Arc<Mutex<Option<String>>>,
         token: &CancellationToken,
-    ) -> anyhow::Result<()> {
-        let id = self
+        attempt: u32,
+    ) -> Result<(), CiroachError> {
+        if self.step.runner == Runner::Host {
+            return self.execute_on_host(log_tx).await;
+        }
+        #[cfg(feature = "kubernetes")]
+        if self.step.runner == Runner::Kubernetes {
+            return self.execute_on_cluster(log_tx, token, attempt).await;
+        }
+
+        if self.step.local_image && !self.engine.image_exists(&self.step.image).await {
+            return Err(CiroachError::Engine(anyhow::anyhow!(
+                "Local image '{}' does not exist. It has to be built by an earlier step (Step: {})",
+                self.step.image,
+                self.step.exploded_name
+            )));
+        }
+        *self.image_digest.lock().await = self
             .engine
-            .run_container(&self.step, &self.cwd, &self.user)
+            .repo_digest(&self.step.image)
+            .await
+            .ok()
+            .flatten();
+
+        if let Some(session) = self.session() {
+            return self.execute_in_session(&session, log_tx, token).await;
+        }
+
+        let started = self
+            .engine
+            .run_container(
+                &self.step,
+                &self.cwd,
+                &self.user,
+                self.services.network(),
+                self.stage_attempt,
+                attempt,
+            )
             .await?;
+        let id = started.id;
+        if let Some(elapsed) = started.copied_in {
+            self.log_copy(log_tx, "Copied the workspace in", elapsed)
+                .await;
+        }
+
+        Self::save_running_container_id(Arc::clone(&id_tracker), &id).await;
+
+        if let Some(debug) = &self.debug {
+            debug.track(&id);
+        }
 
-        Self::save_running_container_id(id_tracker, &id).await;
+        if self.step.detach {
+            self.start_detached(&id, log_tx, token).await?;
+            // The container now belongs to the pipeline's services.
+            id_tracker.lock().await.take();
+            return Ok(());
+        }
 
-        self.engine
-            .stream_logs(&id, &self.step.exploded_name, log_tx, token)
+        let mut output = self
+            .engine
+            .stream_logs(
+                &id,
+                &self.step.stage,
+                &self.step.exploded_name,
+                self.attempt(),
+                log_tx,
+                token,
+            )
             .await?;
+        output.truncated = self.engine.log_truncation(&id, &output).await;
+        if let Some(reason) = &output.truncated {
+            self.log_truncated(log_tx, reason).await;
+        }
+        *self.output.lock().await = Some(output);
+
+        let state = self.engine.inspect_state(&id).await?;
+        *self.layer_size.lock().await = self.engine.layer_size(&id).await;
 
-        let state = self.engine.get_exit_state(&id).await?;
+        let span = Span::current();
+        span.record("oom", state.oom_killed.unwrap_or(false));
+        if let Some(code) = state.exit_code {
+            span.record("exit_code", code);
+        }
 
-        if state.oom_killed == Some(true) {
+        let sigkill = state.exit_code == Some(SIGKILL_EXIT_CODE);
+        if state.oom_killed == Some(true) || (sigkill && self.step.sigkill_is_oom) {
             self.log_oom(log_tx).await;
-            anyhow::bail!(
-                "System ran out of memory (Step: {})",
-                self.step.exploded_name
-            );
+            return Err(CiroachError::Oom {
+                step: self.step.exploded_name.clone(),
+            });
+        }
+
+        self.check_exit_code(log_tx, state.exit_code.unwrap_or(-1))
+            .await?;
+
+        if !self.step.artifacts.is_empty() {
+            let elapsed = self
+                .engine
+                .copy_artifacts_out(&id, &self.step.artifacts, &self.cwd)
+                .await?;
+            self.log_copy(log_tx, "Copied artifacts out", elapsed).await;
+        }
+
+        Ok(())
+    }
+
+    /// The session the step runs in: its own, for a step with a `check` outside of one.
+    fn session(&self) -> Option<String> {
+        self.step.session.clone().or_else(|| {
+            self.step
+                .check
+                .as_ref()
+                .map(|_| self.step.exploded_name.clone())
+        })
+    }
+
+    /// Runs the check, then the command, in the running container of the step's session.
+    async fn execute_in_session(
+        &self,
+        session: &str,
+        log_tx: &mpsc::Sender<LogMessage>,
+        token: &CancellationToken,
+    ) -> Result<(), CiroachError> {
+        let started = self
+            .sessions
+            .container(
+                session,
+                &self.step,
+                &self.cwd,
+                &self.user,
+                self.services.network(),
+                self.stage_attempt,
+            )
+            .await?;
+        if let Some(elapsed) = started.copied_in {
+            self.log_copy(log_tx, "Copied the workspace in", elapsed)
+                .await;
+        }
+
+        if self.step.check.is_some() {
+            let (code, _) = self
+                .engine
+                .exec_step(&started.id, &self.step, true, self.attempt(), log_tx, token)
+                .await?;
+            if code != 0 {
+                self.log_check_failed(log_tx, code).await;
+                return Err(CiroachError::PreconditionFailed {
+                    step: self.step.exploded_name.clone(),
+                    code,
+                });
+            }
+        }
+
+        let (code, output) = self
+            .engine
+            .exec_step(
+                &started.id,
+                &self.step,
+                false,
+                self.attempt(),
+                log_tx,
+                token,
+            )
+            .await?;
+        *self.output.lock().await = Some(output);
+        Span::current().record("exit_code", code);
+
+        self.check_exit_code(log_tx, code).await?;
+
+        if !self.step.artifacts.is_empty() {
+            let elapsed = self
+                .engine
+                .copy_artifacts_out(&started.id, &self.step.artifacts, &self.cwd)
+                .await?;
+            self.log_copy(log_tx, "Copied artifacts out", elapsed).await;
+        }
+
+        Ok(())
+    }
+
+    /// Detached steps are left out: their output keeps coming after the attempt ends.
+    fn watches_silence(&self) -> bool {
+        !self.step.detach
+            && (self.step.silence_warning.is_some() || self.step.silence_timeout.is_some())
+    }
+
+    /// Forwards the attempt's output to the log task, warning each time the step has been
+    /// silent for another `silence_warning`. Returns once it has been silent for its
+    /// `silence_timeout`, if it has one.
+    async fn watch_silence(
+        &self,
+        output_rx: &mut mpsc::Receiver<LogMessage>,
+        log_tx: &mpsc::Sender<LogMessage>,
+    ) -> Duration {
+        if !self.watches_silence() {
+            return std::future::pending().await;
         }
 
-        if state.exit_code != Some(0) {
-            let code = state.exit_code.unwrap_or(-1);
-            self.log_bad_exit_code(log_tx, code).await;
-            anyhow::bail!(
-                "Non-zero exit code {code} (Step: {})",
+        let mut last_output = Instant::now();
+        let mut warnings = 0;
+        let mut checks = interval(SILENCE_CHECK_INTERVAL);
+        loop {
+            // This future is dropped when the attempt ends; a line it had taken but not yet
+            // sent would be lost, so the room for it is made before taking it.
+            let permit = log_tx.reserve().await.ok();
+            tokio::select! {
+                Some(log) = output_rx.recv() => {
+                    last_output = Instant::now();
+                    warnings = 0;
+                    if let Some(permit) = permit {
+                        permit.send(log);
+                    }
+                }
+                _ = checks.tick() => {
+                    let silent_for = last_output.elapsed();
+                    if let Some(limit) = self.step.silence_timeout
+                        && silent_for >= limit
+                    {
+                        return limit;
+                    }
+                    if let Some(every) = self.step.silence_warning
+                        && silent_for >= every * (warnings + 1)
+                    {
+                        warnings += 1;
+                        self.warn_silent(silent_for);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Hands over what the silence watch has not forwarded yet, so the lines keep their
+    /// order with the ones logged after the attempt.
+    async fn flush_watched(
+        output_rx: &mut mpsc::Receiver<LogMessage>,
+        log_tx: &mpsc::Sender<LogMessage>,
+    ) {
+        output_rx.close();
+        while let Ok(log) = output_rx.try_recv() {
+            log_tx.send(log).await.ok();
+        }
+    }
+
+    fn warn_silent(&self, silent_for: Duration) {
+        let silent_for = silent_for.as_millis() as u64;
+        ui::suspend(|| {
+            eprintln!(
+                "{} Step '{}' has produced no output for {}",
+                Icon::Waiting,
+                self.step.exploded_name,
+                format_wall_clock(silent_for)
+            )
+        });
+        self.events.emit(PipelineEvent::StepSilent {
+            stage: self.step.stage.clone(),
+            step: self.step.exploded_name.clone(),
+            silent_for,
+        });
+    }
+
+    fn attempt(&self) -> u32 {
+        self.attempt.load(Ordering::Relaxed)
+    }
+
+    async fn check_exit_code(
+        &self,
+        log_tx: &mpsc::Sender<LogMessage>,
+        code: i64,
+    ) -> Result<(), CiroachError> {
+        if code == 0 {
+            return Ok(());
+        }
+
+        self.log_bad_exit_code(log_tx, code).await;
+        Err(match code {
+            SIGKILL_EXIT_CODE => CiroachError::Killed {
+                step: self.step.exploded_name.clone(),
+                signal: "SIGKILL",
+                code,
+            },
+            SIGTERM_EXIT_CODE => CiroachError::Killed {
+                step: self.step.exploded_name.clone(),
+                signal: "SIGTERM",
+                code,
+            },
+            _ => CiroachError::StepFailed {
+                step: self.step.exploded_name.clone(),
+                code,
+            },
+        })
+    }
+
+    /// Runs the command with `sh -c` in the workspace. Whatever it started is killed once
+    /// it exits, or when this future is dropped on timeout or cancellation.
+    async fn execute_on_host(&self, log_tx: &mpsc::Sender<LogMessage>) -> Result<(), CiroachError> {
+        let mut command = Command::new("sh");
+        #[cfg(unix)]
+        command.process_group(0);
+
+        let mut child = command
+            .arg("-c")
+            .arg(&self.step.command)
+            .current_dir(&self.cwd)
+            .envs(
+                self.step
+                    .env
+                    .iter()
+                    .flatten()
+                    .filter_map(|var| var.split_once('=')),
+            )
+            .stdin(self.host_stdin()?)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|err| anyhow::anyhow!("Could not start 'sh' on the host: {err}"))?;
+        let _group = ProcessGroupGuard(child.id());
+
+        let stdout = child.stdout.take();
+        let stderr = child.stderr.take();
+        let (status, _, _) = tokio::join!(
+            child.wait(),
+            self.forward_output(stdout, false, log_tx),
+            self.forward_output(stderr, true, log_tx)
+        );
+        let status = status.map_err(anyhow::Error::from)?;
+
+        #[cfg(unix)]
+        let signal = std::os::unix::process::ExitStatusExt::signal(&status);
+        #[cfg(not(unix))]
+        let signal: Option<i32> = None;
+
+        // Same convention as a container's exit code: 128 plus the signal number.
+        let code = match (status.code(), signal) {
+            (Some(code), _) => code as i64,
+            (None, Some(signal)) => 128 + signal as i64,
+            (None, None) => -1,
+        };
+        Span::current().record("exit_code", code);
+
+        self.check_exit_code(log_tx, code).await
+    }
+
+    /// Runs the attempt as a Job and follows its pod to the end. The Job is deleted once
+    /// the attempt ends, also when this future is dropped on timeout or cancellation.
+    #[cfg(feature = "kubernetes")]
+    async fn execute_on_cluster(
+        &self,
+        log_tx: &mpsc::Sender<LogMessage>,
+        token: &CancellationToken,
+        attempt: u32,
+    ) -> Result<(), CiroachError> {
+        let Some(cluster) = &self.cluster else {
+            return Err(CiroachError::Engine(anyhow::anyhow!(
+                "Step '{}' runs on Kubernetes, but no cluster is connected",
                 self.step.exploded_name
-            );
+            )));
+        };
+
+        let job = cluster.run_job(&self.step, attempt).await?;
+        let _guard = JobGuard {
+            cluster: cluster.clone(),
+            job: job.clone(),
+        };
+
+        let pod = cluster.started_pod(&job).await?;
+        let output = cluster
+            .stream_logs(
+                &pod,
+                &self.step.stage,
+                &self.step.exploded_name,
+                self.attempt(),
+                log_tx,
+                token,
+            )
+            .await?;
+        *self.output.lock().await = Some(output);
+
+        let exit = cluster.exit_state(&pod).await?;
+        let span = Span::current();
+        span.record("oom", exit.oom_killed);
+        span.record("exit_code", exit.exit_code);
+
+        let sigkill = exit.exit_code == SIGKILL_EXIT_CODE;
+        if exit.oom_killed || (sigkill && self.step.sigkill_is_oom) {
+            self.log_oom(log_tx).await;
+            return Err(CiroachError::Oom {
+                step: self.step.exploded_name.clone(),
+            });
         }
 
+        self.check_exit_code(log_tx, exit.exit_code).await
+    }
+
+    fn host_stdin(&self) -> Result<Stdio, CiroachError> {
+        Ok(match &self.step.stdin {
+            None => Stdio::null(),
+            Some(StepInput::File(path)) => {
+                let path = Path::new(&self.cwd).join(path);
+                let file = std::fs::File::open(&path).map_err(|err| {
+                    anyhow::anyhow!(
+                        "Cannot read the 'stdin_file' {} of step '{}': {}",
+                        path.display(),
+                        self.step.exploded_name,
+                        err
+                    )
+                })?;
+                Stdio::from(file)
+            }
+            Some(StepInput::Process) => Stdio::inherit(),
+        })
+    }
+
+    async fn forward_output(
+        &self,
+        output: Option<impl AsyncRead + Unpin>,
+        is_error: bool,
+        log_tx: &mpsc::Sender<LogMessage>,
+    ) {
+        let Some(output) = output else {
+            return;
+        };
+
+        let mut lines = BufReader::new(output).split(b'\n');
+        while let Ok(Some(line)) = lines.next_segment().await {
+            let line = String::from_utf8_lossy(&line);
+            log_tx
+                .send(LogMessage {
+                    stage: self.step.stage.clone(),
+                    step_name: self.step.exploded_name.clone(),
+                    line: line.trim_end_matches('\r').to_string(),
+                    is_error,
+                    attempt: self.attempt(),
+                    stage_attempt: None,
+                    ended: None,
+                })
+                .await
+                .ok();
+        }
+    }
+
+    /// Streams the detached container's logs for the rest of the pipeline and waits for
+    /// its readiness condition before handing it over to [`Services`].
+    async fn start_detached(
+        &self,
+        id: &str,
+        log_tx: &mpsc::Sender<LogMessage>,
+        token: &CancellationToken,
+    ) -> Result<(), CiroachError> {
+        let (ready_tx, ready_rx) = oneshot::channel();
+        let logs = self.spawn_service_logs(id, log_tx.clone(), ready_tx);
+
+        if let Err(err) = self.wait_until_ready(id, ready_rx, log_tx, token).await {
+            logs.abort();
+            return Err(err);
+        }
+
+        self.services
+            .register(&self.step.exploded_name, id, logs)
+            .await;
+        self.log_service_ready(log_tx).await;
+
         Ok(())
     }
 
+    fn spawn_service_logs(
+        &self,
+        id: &str,
+        log_tx: mpsc::Sender<LogMessage>,
+        ready_tx: oneshot::Sender<()>,
+    ) -> JoinHandle<()> {
+        let engine = self.engine.clone();
+        let id = id.to_string();
+        let stage = self.step.stage.clone();
+        let step_name = self.step.exploded_name.clone();
+        let attempt = self.attempt();
+        let token = self.services.token();
+        let pattern = match self.step.wait_for.as_ref().map(|wait| &wait.condition) {
+            Some(ReadyCondition::Log(pattern)) => Regex::new(pattern).ok(),
+            _ => None,
+        };
+
+        tokio::spawn(async move {
+            let (tx, mut rx) = mpsc::channel::<LogMessage>(100);
+
+            let stream = async move {
+                engine
+                    .stream_logs(&id, &stage, &step_name, attempt, &tx, &token)
+                    .await
+                    .ok();
+            };
+
+            let forward = async {
+                let mut ready_tx = Some(ready_tx);
+                while let Some(log) = rx.recv().await {
+                    if let Some(pattern) = &pattern
+                        && pattern.is_match(&log.line)
+                        && let Some(ready_tx) = ready_tx.take()
+                    {
+                        ready_tx.send(()).ok();
+                    }
+                    log_tx.send(log).await.ok();
+                }
+            };
+
+            tokio::join!(stream, forward);
+        })
+    }
+
+    async fn wait_until_ready(
+        &self,
+        id: &str,
+        ready_rx: oneshot::Receiver<()>,
+        log_tx: &mpsc::Sender<LogMessage>,
+        token: &CancellationToken,
+    ) -> Result<(), CiroachError> {
+        let Some(wait_for) = &self.step.wait_for else {
+            return Ok(());
+        };
+
+        let poll = self.poll_ready(id, &wait_for.condition, ready_rx, token);
+        let Some(limit) = wait_for.timeout else {
+            return poll.await;
+        };
+
+        match timeout(limit, poll).await {
+            Ok(result) => result,
+            Err(_) => {
+                self.log_not_ready(log_tx, limit).await;
+                Err(CiroachError::Engine(anyhow::anyhow!(
+                    "Not ready after {:?} (Step: {})",
+                    limit,
+                    self.step.exploded_name
+                )))
+            }
+        }
+    }
+
+    async fn poll_ready(
+        &self,
+        id: &str,
+        condition: &ReadyCondition,
+        mut ready_rx: oneshot::Receiver<()>,
+        token: &CancellationToken,
+    ) -> Result<(), CiroachError> {
+        loop {
+            let state = self.engine.inspect_state(id).await?;
+            if state.running != Some(true) {
+                return Err(CiroachError::Engine(anyhow::anyhow!(
+                    "Service exited with code {} before becoming ready (Step: {})",
+                    state.exit_code.unwrap_or(-1),
+                    self.step.exploded_name
+                )));
+            }
+
+            let is_ready = match condition {
+                ReadyCondition::Log(_) => ready_rx.try_recv().is_ok(),
+                ReadyCondition::Port(port) => self.engine.port_listening(id, *port).await?,
+            };
+
+            if is_ready {
+                return Ok(());
+            }
+
+            tokio::select! {
+                _ = sleep(READY_POLL_INTERVAL) => {}
+                _ = token.cancelled() => return Err(CiroachError::Cancelled),
+            }
+        }
+    }
+
+    /// Offers the retained container for debugging, then keeps or removes it.
+    async fn handle_failed_container(&self) -> Option<KeptContainer> {
+        let id = self.failed_container.lock().await.take()?;
+
+        if let Some(debug) = &self.debug {
+            debug.inspect(&self.step, &id).await;
+        }
+
+        if self.keep_failed {
+            match self.engine.keep_container(&id, &self.step).await {
+                Ok(kept) => return Some(kept),
+                Err(err) => {
+                    ui::suspend(|| {
+                        eprintln!(
+                            "{} Could not keep the container of step '{}': {}",
+                            Icon::Warning,
+                            self.step.exploded_name,
+                            err
+                        )
+                    });
+                }
+            }
+        }
+
+        self.engine.remove_container(&id, true).await.ok();
+        None
+    }
+
     async fn cleanup_container(&self, id_mutex: &Arc<Mutex<Option<String>>>) {
         let mut guard = id_mutex.lock().await;
         if let Some(id) = guard.take() {
-            self.engine.force_remove_container(&id).await.ok();
+            self.engine.remove_container(&id, true).await.ok();
         }
     }
 
@@ -179,11 +1067,115 @@ impl StepRunner {
 }
 
 impl StepRunner {
+    async fn log_copy(&self, tx: &mpsc::Sender<LogMessage>, what: &str, elapsed: Duration) {
+        tx.send(LogMessage {
+            stage: self.step.stage.clone(),
+            step_name: self.step.exploded_name.clone(),
+            attempt: self.attempt(),
+            line: format!("{} {what} in {:.2}s", Icon::Folder, elapsed.as_secs_f64()),
+            is_error: false,
+            stage_attempt: None,
+            ended: None,
+        })
+        .await
+        .ok();
+    }
+
     async fn log_timeout(&self, tx: &mpsc::Sender<LogMessage>, timeout: Duration) {
         tx.send(LogMessage {
+            stage: self.step.stage.clone(),
+            step_name: self.step.exploded_name.clone(),
+            attempt: self.attempt(),
+            line: format!("{} Step timed out after {:?}", Icon::Waiting, timeout),
+            is_error: true,
+            stage_attempt: None,
+            ended: None,
+        })
+        .await
+        .ok();
+    }
+
+    async fn log_truncated(&self, tx: &mpsc::Sender<LogMessage>, reason: &str) {
+        tx.send(LogMessage {
+            stage: self.step.stage.clone(),
+            step_name: self.step.exploded_name.clone(),
+            attempt: self.attempt(),
+            line: format!(
+                "{} Some output was likely lost: {reason}. Raise 'max-size' in the step's 'log_config' or make it print less.",
+                Icon::Warning
+            ),
+            is_error: true,
+            stage_attempt: None,
+            ended: None,
+        })
+        .await
+        .ok();
+    }
+
+    async fn log_check_failed(&self, tx: &mpsc::Sender<LogMessage>, code: i64) {
+        tx.send(LogMessage {
+            stage: self.step.stage.clone(),
+            step_name: self.step.exploded_name.clone(),
+            attempt: self.attempt(),
+            line: format!(
+                "{} Precondition failed: the check exited with code {}",
+                Icon::Error,
+                code
+            ),
+            is_error: true,
+            stage_attempt: None,
+            ended: None,
+        })
+        .await
+        .ok();
+    }
+
+    async fn log_silent(&self, tx: &mpsc::Sender<LogMessage>, limit: Duration) {
+        tx.send(LogMessage {
+            stage: self.step.stage.clone(),
+            step_name: self.step.exploded_name.clone(),
+            attempt: self.attempt(),
+            line: format!("{} Step printed nothing for {:?}", Icon::Waiting, limit),
+            is_error: true,
+            stage_attempt: None,
+            ended: None,
+        })
+        .await
+        .ok();
+    }
+
+    async fn log_total_timeout(&self, tx: &mpsc::Sender<LogMessage>, total: Duration) {
+        tx.send(LogMessage {
+            stage: self.step.stage.clone(),
             step_name: self.step.exploded_name.clone(),
-            line: format!("⏳ Step timed out after {:?}", timeout),
+            attempt: self.attempt(),
+            line: format!(
+                "{} Step ran out of its total_timeout of {:?}",
+                Icon::Waiting,
+                total
+            ),
             is_error: true,
+            stage_attempt: None,
+            ended: None,
+        })
+        .await
+        .ok();
+    }
+
+    async fn log_retries_abandoned(&self, tx: &mpsc::Sender<LogMessage>, left: u32) {
+        tx.send(LogMessage {
+            stage: self.step.stage.clone(),
+            step_name: self.step.exploded_name.clone(),
+            attempt: self.attempt(),
+            line: format!(
+                "{} Giving up on {} more {}: no time left of the step's total_timeout",
+                Icon::Retry,
+                left,
+                if left == 1 { "retry" } else { "retries" }
+            ),
+            is_error: true,
+            stage_attempt: None,
+            ended: None,
         })
         .await
         .ok();
@@ -194,25 +1186,77 @@ impl StepRunner {
         tx: &mpsc::Sender<LogMessage>,
         attempts: u32,
         max_retries: u32,
-        err: &anyhow::Error,
+        err: &CiroachError,
     ) {
         tx.send(LogMessage {
+            stage: self.step.stage.clone(),
             step_name: self.step.exploded_name.clone(),
+            attempt: self.attempt(),
             line: format!(
-                "🔄 Retrying step ({}/{}) - Error: {}",
-                attempts, max_retries, err
+                "{} Retrying step ({}/{}) - Error: {}",
+                Icon::Retry,
+                attempts,
+                max_retries,
+                err
             ),
             is_error: true,
+            stage_attempt: None,
+            ended: None,
         })
         .await
         .ok();
     }
 
+    async fn log_not_ready(&self, tx: &mpsc::Sender<LogMessage>, limit: Duration) {
+        tx.send(LogMessage {
+            stage: self.step.stage.clone(),
+            step_name: self.step.exploded_name.clone(),
+            attempt: self.attempt(),
+            line: format!("{} Service not ready after {:?}", Icon::Waiting, limit),
+            is_error: true,
+            stage_attempt: None,
+            ended: None,
+        })
+        .await
+        .ok();
+    }
+
+    async fn log_service_ready(&self, tx: &mpsc::Sender<LogMessage>) {
+        tx.send(LogMessage {
+            stage: self.step.stage.clone(),
+            step_name: self.step.exploded_name.clone(),
+            attempt: self.attempt(),
+            line: format!(
+                "{} Service is up and keeps running until the pipeline ends",
+                Icon::Ready
+            ),
+            is_error: false,
+            stage_attempt: None,
+            ended: None,
+        })
+        .await
+        .ok();
+    }
+
+    fn memory_limit(&self) -> String {
+        match self.step.memory {
+            Some(bytes) => format!("{} MiB", bytes / (1024 * 1024)),
+            None => "unlimited".to_string(),
+        }
+    }
+
     async fn log_oom(&self, tx: &mpsc::Sender<LogMessage>) {
         tx.send(LogMessage {
+            stage: self.step.stage.clone(),
             step_name: self.step.exploded_name.clone(),
-            line: "System ran out of memory".to_string(),
+            attempt: self.attempt(),
+            line: format!(
+                "System ran out of memory (limit {}). Raise the step's 'memory' if it needs more.",
+                self.memory_limit()
+            ),
             is_error: true,
+            stage_attempt: None,
+            ended: None,
         })
         .await
         .ok();
@@ -220,9 +1264,22 @@ impl StepRunner {
 
     async fn log_bad_exit_code(&self, tx: &mpsc::Sender<LogMessage>, code: i64) {
         tx.send(LogMessage {
+            stage: self.step.stage.clone(),
             step_name: self.step.exploded_name.clone(),
-            line: format!("Process exited with code {code}"),
+            attempt: self.attempt(),
+            line: match code {
+                SIGKILL_EXIT_CODE => format!(
+                    "Process was killed by SIGKILL (exit code {code}). This is usually the out-of-memory killer, e.g. of the Docker Desktop VM; the memory limit is {}.",
+                    self.memory_limit()
+                ),
+                SIGTERM_EXIT_CODE => {
+                    format!("Process was terminated by SIGTERM (exit code {code})")
+                }
+                _ => format!("Process exited with code {code}"),
+            },
             is_error: true,
+            stage_attempt: None,
+            ended: None,
         })
         .await
         .ok();
@@ -0,0 +1,557 @@
+use std::{net::SocketAddr, path::PathBuf, process::ExitCode};
+
+#[cfg(feature = "server")]
+use std::{
+    collections::{BTreeMap, HashSet},
+    env,
+    sync::Arc,
+};
+
+#[cfg(feature = "server")]
+use anyhow::Context;
+#[cfg(feature = "server")]
+use axum::{
+    Json, Router,
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+#[cfg(feature = "server")]
+use chrono::Local;
+#[cfg(feature = "server")]
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "server")]
+use serde_json::json;
+#[cfg(feature = "server")]
+use tokio::{
+    fs::read_to_string,
+    net::TcpListener,
+    sync::{Mutex, mpsc},
+    time::sleep,
+};
+#[cfg(feature = "server")]
+use tokio_util::sync::CancellationToken;
+
+#[cfg(feature = "server")]
+use crate::{
+    commands::RunCommand,
+    dashboard::{DashboardState, SharedState},
+    history::{HISTORY_DIR, RunHistory},
+    models::{BusyPolicy, Pipeline, PipelineReport, Step, Trigger},
+    output::{Icon, OutputMode},
+    platform,
+    reporter::ConsoleReporter,
+    runner::PipelineRunner,
+};
+use crate::{
+    dashboard::check_bind_addr,
+    models::{ScheduleConfig, ServerConfig},
+};
+
+/// Long-running mode that executes the workspace pipeline whenever `POST /run` is called
+/// and whenever the optional `[schedule]` fires. Runs never overlap; see [`ServerConfig`]
+/// for what happens to triggers during a run.
+pub struct Server {
+    config: PathBuf,
+    /// The `--var` values, applied each time a run reloads the configuration.
+    #[cfg_attr(not(feature = "server"), allow(dead_code))]
+    vars: Vec<(String, String)>,
+    #[cfg_attr(not(feature = "server"), allow(dead_code))]
+    settings: ServerConfig,
+    #[cfg_attr(not(feature = "server"), allow(dead_code))]
+    schedule: Option<ScheduleConfig>,
+}
+
+impl Server {
+    pub fn new(
+        config: impl Into<PathBuf>,
+        vars: &[(String, String)],
+        settings: ServerConfig,
+        schedule: Option<ScheduleConfig>,
+    ) -> Self {
+        Self {
+            config: config.into(),
+            vars: vars.to_vec(),
+            settings,
+            schedule,
+        }
+    }
+
+    #[cfg(feature = "server")]
+    pub async fn serve(self, addr: SocketAddr, insecure: bool) -> anyhow::Result<ExitCode> {
+        check_bind_addr(addr, insecure, "--insecure")?;
+
+        let shutdown = CancellationToken::new();
+        let (queue, rx) = mpsc::unbounded_channel();
+        let state = Arc::new(ServerState {
+            config: self.config,
+            vars: self.vars,
+            on_busy: self.settings.on_busy,
+            schedule: self.schedule.clone(),
+            runs: Mutex::new(BTreeMap::new()),
+            queue,
+        });
+
+        let worker = tokio::spawn(state.clone().work(rx, shutdown.clone()));
+        if let Some(schedule) = self.schedule {
+            tokio::spawn(state.clone().schedule(schedule, shutdown.clone()));
+        }
+
+        let app = Router::new()
+            .route("/run", post(trigger))
+            .route("/runs", get(list))
+            .route("/runs/{id}", get(show))
+            .with_state(state);
+
+        let listener = TcpListener::bind(addr).await?;
+        println!(
+            "{} Listening for triggers on http://{}",
+            Icon::Listening,
+            listener.local_addr()?
+        );
+
+        let signal = shutdown.clone();
+        tokio::spawn(async move {
+            platform::shutdown_signal().await;
+            println!(
+                "\n{} Shutting down, cancelling the active run...",
+                Icon::Halt
+            );
+            signal.cancel();
+        });
+
+        let stop = shutdown.clone();
+        axum::serve(listener, app)
+            .with_graceful_shutdown(async move { stop.cancelled().await })
+            .await?;
+
+        worker.await?;
+        Ok(ExitCode::SUCCESS)
+    }
+
+    #[cfg(not(feature = "server"))]
+    pub async fn serve(self, addr: SocketAddr, insecure: bool) -> anyhow::Result<ExitCode> {
+        check_bind_addr(addr, insecure, "--insecure")?;
+        anyhow::bail!(
+            "Cannot serve '{}': ciroach was built without the `server` feature.",
+            self.config.display()
+        )
+    }
+}
+
+/// Optional body of `POST /run`.
+#[cfg(feature = "server")]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RunRequest {
+    /// Pipeline to run, when the configuration defines several under `[pipelines]`.
+    pipeline: Option<String>,
+    /// Variables added to (or replacing those of) every step.
+    env: BTreeMap<String, String>,
+    /// Steps to run, by name or matrix-expanded name. Their `needs` are included automatically.
+    steps: Vec<String>,
+}
+
+#[cfg(feature = "server")]
+impl RunRequest {
+    fn apply(&self, pipeline: &mut Pipeline) -> anyhow::Result<()> {
+        if !self.env.is_empty() {
+            for step in pipeline
+                .stages
+                .iter_mut()
+                .flat_map(|stage| &mut stage.steps)
+            {
+                let env = step.env.get_or_insert_with(Vec::new);
+                for (key, value) in self.env.iter() {
+                    env.retain(|entry| entry.split('=').next() != Some(key.as_str()));
+                    env.push(format!("{key}={value}"));
+                }
+            }
+        }
+
+        if self.steps.is_empty() {
+            return Ok(());
+        }
+
+        let matches =
+            |name: &str, exploded: &str| self.steps.iter().any(|s| s == name || s == exploded);
+
+        let unknown: Vec<&String> = self
+            .steps
+            .iter()
+            .filter(|wanted| {
+                !pipeline
+                    .stages
+                    .iter()
+                    .flat_map(|stage| &stage.steps)
+                    .any(|step| &step.name == *wanted || &step.exploded_name == *wanted)
+            })
+            .collect();
+
+        if !unknown.is_empty() {
+            anyhow::bail!("Unknown steps: {:?}", unknown);
+        }
+
+        Self::retain_steps(pipeline, |step| matches(&step.name, &step.exploded_name));
+        Ok(())
+    }
+
+    /// Keeps the steps matching `selected` together with everything they transitively
+    /// need, and drops stages that end up empty.
+    fn retain_steps(pipeline: &mut Pipeline, selected: impl Fn(&Step) -> bool) {
+        for stage in pipeline.stages.iter_mut() {
+            let mut keep: HashSet<String> = stage
+                .steps
+                .iter()
+                .filter(|step| selected(step))
+                .map(|step| step.exploded_name.clone())
+                .collect();
+
+            loop {
+                let needed: HashSet<&String> = stage
+                    .steps
+                    .iter()
+                    .filter(|step| keep.contains(&step.exploded_name))
+                    .flat_map(|step| &step.needs)
+                    .collect();
+
+                let missing: Vec<String> = stage
+                    .steps
+                    .iter()
+                    .filter(|step| {
+                        needed.iter().any(|need| step.matches_need(need))
+                            && !keep.contains(&step.exploded_name)
+                    })
+                    .map(|step| step.exploded_name.clone())
+                    .collect();
+
+                if missing.is_empty() {
+                    break;
+                }
+                keep.extend(missing);
+            }
+
+            stage
+                .steps
+                .retain(|step| keep.contains(&step.exploded_name));
+        }
+
+        pipeline.stages.retain(|stage| !stage.steps.is_empty());
+    }
+}
+
+#[cfg(feature = "server")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum RunState {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+    /// The pipeline could not be executed at all, see `error`.
+    Errored,
+}
+
+#[cfg(feature = "server")]
+impl RunState {
+    fn is_active(self) -> bool {
+        matches!(self, Self::Queued | Self::Running)
+    }
+}
+
+#[cfg(feature = "server")]
+#[derive(Debug, Serialize)]
+struct RunRecord {
+    id: u64,
+    state: RunState,
+    trigger: Trigger,
+    request: RunRequest,
+    error: Option<String>,
+    #[serde(skip)]
+    live: Option<SharedState>,
+    /// Finished reports are read back from the run history instead of being kept in memory.
+    #[serde(skip)]
+    history_entry: Option<PathBuf>,
+}
+
+#[cfg(feature = "server")]
+struct ServerState {
+    config: PathBuf,
+    vars: Vec<(String, String)>,
+    on_busy: BusyPolicy,
+    schedule: Option<ScheduleConfig>,
+    runs: Mutex<BTreeMap<u64, RunRecord>>,
+    queue: mpsc::UnboundedSender<u64>,
+}
+
+#[cfg(feature = "server")]
+impl ServerState {
+    /// Adds a run to the queue, returning its id. Fails once the worker has stopped.
+    fn enqueue(
+        &self,
+        runs: &mut BTreeMap<u64, RunRecord>,
+        trigger: Trigger,
+        request: RunRequest,
+    ) -> Option<u64> {
+        let id = runs.keys().next_back().map_or(1, |last| last + 1);
+        runs.insert(
+            id,
+            RunRecord {
+                id,
+                state: RunState::Queued,
+                trigger,
+                request,
+                error: None,
+                live: None,
+                history_entry: None,
+            },
+        );
+
+        self.queue.send(id).ok().map(|_| id)
+    }
+
+    /// Queues a run each time `schedule` fires. Fire times missed while a scheduled run
+    /// is still waiting in the queue are coalesced into that run.
+    async fn schedule(self: Arc<Self>, schedule: ScheduleConfig, shutdown: CancellationToken) {
+        println!("{} Scheduled runs on '{}'", Icon::Listening, schedule.cron);
+
+        loop {
+            let next = match schedule.cron.find_next_occurrence(&Local::now(), false) {
+                Ok(next) => next,
+                Err(err) => {
+                    eprintln!(
+                        "{} Schedule '{}' stopped: {}",
+                        Icon::Error,
+                        schedule.cron,
+                        err
+                    );
+                    return;
+                }
+            };
+            let delay = (next - Local::now()).to_std().unwrap_or_default();
+
+            tokio::select! {
+                _ = shutdown.cancelled() => return,
+                _ = sleep(delay) => {}
+            }
+
+            let mut runs = self.runs.lock().await;
+            let pending = runs
+                .values()
+                .any(|run| run.trigger == Trigger::Schedule && run.state == RunState::Queued);
+            let busy = runs.values().any(|run| run.state.is_active());
+
+            if pending || (busy && self.on_busy == BusyPolicy::Reject) {
+                println!(
+                    "{} Skipping the scheduled run at {}, a run is already in progress",
+                    Icon::Info,
+                    next.format("%H:%M:%S")
+                );
+                continue;
+            }
+
+            if self
+                .enqueue(&mut runs, Trigger::Schedule, RunRequest::default())
+                .is_none()
+            {
+                return;
+            }
+        }
+    }
+
+    async fn work(
+        self: Arc<Self>,
+        mut rx: mpsc::UnboundedReceiver<u64>,
+        shutdown: CancellationToken,
+    ) {
+        loop {
+            let id = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                id = rx.recv() => match id {
+                    Some(id) => id,
+                    None => break,
+                },
+            };
+
+            self.execute(id, shutdown.child_token()).await;
+        }
+
+        for run in self.runs.lock().await.values_mut() {
+            if run.state == RunState::Queued {
+                run.state = RunState::Cancelled;
+            }
+        }
+    }
+
+    async fn execute(&self, id: u64, token: CancellationToken) {
+        let (trigger, request) = match self.runs.lock().await.get_mut(&id) {
+            Some(run) => {
+                run.state = RunState::Running;
+                (run.trigger, run.request.clone())
+            }
+            None => return,
+        };
+
+        match trigger {
+            Trigger::Manual => println!("\n{} Starting run #{id}", Icon::Launch),
+            Trigger::Schedule => println!("\n{} Starting scheduled run #{id}", Icon::Launch),
+        }
+        let result = self
+            .run_pipeline(id, trigger, &request, token.clone())
+            .await;
+
+        let mut runs = self.runs.lock().await;
+        let Some(run) = runs.get_mut(&id) else {
+            return;
+        };
+
+        match result {
+            Ok((report, entry)) => {
+                run.state = if token.is_cancelled() {
+                    RunState::Cancelled
+                } else if report.is_success() {
+                    RunState::Succeeded
+                } else {
+                    RunState::Failed
+                };
+                run.history_entry = entry;
+            }
+            Err(err) => {
+                eprintln!("{} Run #{id} could not be executed: {}", Icon::Error, err);
+                run.state = RunState::Errored;
+                run.error = Some(err.to_string());
+            }
+        }
+    }
+
+    async fn run_pipeline(
+        &self,
+        id: u64,
+        trigger: Trigger,
+        request: &RunRequest,
+        token: CancellationToken,
+    ) -> anyhow::Result<(PipelineReport, Option<PathBuf>)> {
+        let cwd = env::current_dir()?;
+        let mode = OutputMode::Auto.resolve();
+
+        let mut pipeline = match (trigger, &self.schedule) {
+            (Trigger::Schedule, Some(schedule)) => schedule
+                .load(&self.config, &self.vars)
+                .await
+                .context("The pipeline selected by [schedule] cannot be loaded")?,
+            _ => Pipeline::new(&self.config, request.pipeline.as_deref(), &self.vars).await?,
+        };
+        request.apply(&mut pipeline)?;
+        RunCommand::prune_logs(&pipeline).await;
+
+        let history = RunHistory::new(HISTORY_DIR, pipeline.history.clone());
+        let metrics = pipeline.metrics.clone();
+        let baseline = history.baseline().await;
+        let runner = PipelineRunner::new(pipeline, cwd, mode)
+            .await?
+            .expected_durations(
+                baseline
+                    .as_ref()
+                    .map(|baseline| baseline.durations().clone())
+                    .unwrap_or_default(),
+            );
+
+        let live = DashboardState::track(runner.pipeline(), &runner.events());
+        if let Some(run) = self.runs.lock().await.get_mut(&id) {
+            run.live = Some(live);
+        }
+
+        let mut report = runner.run(token).await?;
+        report.trigger = trigger;
+        ConsoleReporter::new(baseline.as_ref(), mode).report(&report);
+
+        let entry = RunCommand::persist(&report, &self.config, &history, metrics.as_ref()).await?;
+        Ok((report, entry))
+    }
+}
+
+#[cfg(feature = "server")]
+async fn trigger(
+    State(state): State<Arc<ServerState>>,
+    body: Option<Json<RunRequest>>,
+) -> Response {
+    let request = body.map(|Json(request)| request).unwrap_or_default();
+
+    // Validate against the current configuration so bad requests fail immediately.
+    let validation =
+        match Pipeline::new(&state.config, request.pipeline.as_deref(), &state.vars).await {
+            Ok(mut pipeline) => request.apply(&mut pipeline),
+            Err(err) => Err(err.into()),
+        };
+    if let Err(err) = validation {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": err.to_string() })),
+        )
+            .into_response();
+    }
+
+    let mut runs = state.runs.lock().await;
+
+    if state.on_busy == BusyPolicy::Reject
+        && let Some(active) = runs.values().find(|run| run.state.is_active())
+    {
+        return (
+            StatusCode::CONFLICT,
+            Json(json!({ "error": "a run is already in progress", "active": active.id })),
+        )
+            .into_response();
+    }
+
+    let Some(id) = state.enqueue(&mut runs, Trigger::Manual, request) else {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({ "error": "server is shutting down" })),
+        )
+            .into_response();
+    };
+
+    (
+        StatusCode::ACCEPTED,
+        Json(json!({ "id": id, "state": RunState::Queued, "url": format!("/runs/{id}") })),
+    )
+        .into_response()
+}
+
+#[cfg(feature = "server")]
+async fn list(State(state): State<Arc<ServerState>>) -> Response {
+    let runs = state.runs.lock().await;
+    Json(runs.values().rev().collect::<Vec<_>>()).into_response()
+}
+
+#[cfg(feature = "server")]
+async fn show(Path(id): Path<u64>, State(state): State<Arc<ServerState>>) -> Response {
+    let (mut body, live, entry) = {
+        let runs = state.runs.lock().await;
+        let Some(run) = runs.get(&id) else {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(json!({ "error": format!("no run #{id}") })),
+            )
+                .into_response();
+        };
+
+        (json!(run), run.live.clone(), run.history_entry.clone())
+    };
+
+    if let Some(live) = live {
+        body["status"] = json!(&*live.read().await);
+    }
+
+    if let Some(entry) = entry {
+        let report = read_to_string(&entry)
+            .await
+            .ok()
+            .and_then(|raw| serde_json::from_str::<PipelineReport>(&raw).ok());
+        body["report"] = json!(report);
+    }
+
+    Json(body).into_response()
+}
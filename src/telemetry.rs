@@ -0,0 +1,90 @@
+#[cfg(feature = "otel")]
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use tracing::Subscriber;
+use tracing_subscriber::{
+    Layer, fmt, layer::SubscriberExt, registry::LookupSpan, util::SubscriberInitExt,
+};
+
+#[cfg(feature = "otel")]
+use crate::output::Icon;
+use crate::output::Verbosity;
+
+/// Prints tracing events on the console according to the verbosity, and keeps the trace
+/// exporter alive for the duration of the process. Without the `otel` feature, or when no
+/// OTLP endpoint is configured, nothing is exported.
+pub struct Telemetry {
+    #[cfg(feature = "otel")]
+    provider: Option<SdkTracerProvider>,
+}
+
+impl Telemetry {
+    #[cfg(feature = "otel")]
+    pub fn init(verbosity: Verbosity) -> anyhow::Result<Self> {
+        use opentelemetry::trace::TracerProvider;
+        use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+        use opentelemetry_sdk::Resource;
+
+        let configured = [
+            "OTEL_EXPORTER_OTLP_ENDPOINT",
+            "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT",
+        ]
+        .iter()
+        .any(|var| std::env::var_os(var).is_some());
+
+        if !configured {
+            tracing_subscriber::registry()
+                .with(Self::console(verbosity))
+                .try_init()?;
+            return Ok(Self { provider: None });
+        }
+
+        // Endpoint and headers are picked up from the standard OTEL_* variables.
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_protocol(opentelemetry_otlp::Protocol::HttpBinary)
+            .build()?;
+
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(Resource::builder().with_service_name("ciroach").build())
+            .build();
+
+        let tracer = provider.tracer("ciroach");
+        tracing_subscriber::registry()
+            .with(Self::console(verbosity))
+            .with(tracing_opentelemetry::layer().with_tracer(tracer))
+            .try_init()?;
+
+        Ok(Self {
+            provider: Some(provider),
+        })
+    }
+
+    #[cfg(not(feature = "otel"))]
+    pub fn init(verbosity: Verbosity) -> anyhow::Result<Self> {
+        tracing_subscriber::registry()
+            .with(Self::console(verbosity))
+            .try_init()?;
+        Ok(Self {})
+    }
+
+    fn console<S>(verbosity: Verbosity) -> impl Layer<S>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        fmt::layer()
+            .with_writer(std::io::stderr)
+            .with_target(false)
+            .with_filter(verbosity.console_filter())
+    }
+
+    /// Flushes pending spans. Must be called before the process exits.
+    pub fn shutdown(self) {
+        #[cfg(feature = "otel")]
+        if let Some(provider) = self.provider
+            && let Err(err) = provider.shutdown()
+        {
+            eprintln!("{} Failed to flush traces: {}", Icon::Warning, err);
+        }
+    }
+}
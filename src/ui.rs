@@ -1,39 +1,184 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+    time::Duration,
+};
 
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use colored::Colorize;
+use crossterm::terminal;
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use tokio::{sync::broadcast, task::JoinHandle, time::interval};
+
+use crate::{
+    engine::PullProgress,
+    events::PipelineEvent,
+    models::{Stage, StepStatus, format_wall_clock},
+    output::Icon,
+};
+
+/// Lines kept free below the bars for the stage header and surrounding output.
+const RESERVED_ROWS: usize = 4;
+
+const SPINNER_TICK: Duration = Duration::from_millis(100);
+
+/// Bars of the stage currently drawn, so other output can get out of their way.
+static ACTIVE: Mutex<Option<MultiProgress>> = Mutex::new(None);
+
+/// Bars of the pulls while they are drawn. Stages starting before the pulls finish draw
+/// their bars below them.
+static PULLS: Mutex<Option<MultiProgress>> = Mutex::new(None);
+
+/// The bars currently drawn, of the stage or else of the pulls.
+fn drawn() -> Option<MultiProgress> {
+    let active = ACTIVE.lock().ok().and_then(|active| active.clone());
+    active.or_else(|| PULLS.lock().ok().and_then(|pulls| pulls.clone()))
+}
+
+/// Runs `f` with the stage bars cleared, so whatever it prints is not drawn over.
+pub fn suspend<R>(f: impl FnOnce() -> R) -> R {
+    match drawn() {
+        Some(multi) => multi.suspend(f),
+        None => f(),
+    }
+}
+
+/// Hides the stage bars until the guard is dropped, e.g. while a shell owns the terminal.
+pub fn hide_bars() -> HiddenBars {
+    let active = drawn();
+    if let Some(multi) = &active {
+        multi.clear().ok();
+        multi.set_draw_target(ProgressDrawTarget::hidden());
+    }
+    HiddenBars(active)
+}
+
+pub struct HiddenBars(Option<MultiProgress>);
+
+impl Drop for HiddenBars {
+    fn drop(&mut self) {
+        if let Some(multi) = &self.0 {
+            multi.set_draw_target(ProgressDrawTarget::stderr());
+        }
+    }
+}
+
+/// How pulls and steps report progress on the console.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Progress {
+    /// Animated bars and spinners.
+    Live,
+    /// One plain line per transition.
+    Plain,
+    Off,
+}
 
 pub struct PreFlightUI {
     _multi: MultiProgress,
     bars: HashMap<String, ProgressBar>,
+    /// Present locally already; their bars stay done while they are refreshed.
+    cached: HashSet<String>,
+    /// Single bar used instead of one bar per image when those would not fit on screen.
+    total: Option<ProgressBar>,
+    plain: bool,
 }
 
 impl PreFlightUI {
-    pub fn new(images: &HashSet<String>) -> Self {
-        let multi = MultiProgress::new();
+    /// Without live progress the bars are hidden, and with [`Progress::Plain`] every
+    /// transition is printed as a line instead, which keeps non-interactive logs readable.
+    /// Images in `cached` are already present locally and start out as done while they are
+    /// refreshed.
+    pub fn new(images: &HashSet<String>, cached: &HashSet<String>, progress: Progress) -> Self {
+        let live = progress == Progress::Live;
+        let plain = progress == Progress::Plain;
+        let multi = if live {
+            MultiProgress::new()
+        } else {
+            MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+        };
+        if live && let Ok(mut pulls) = PULLS.lock() {
+            *pulls = Some(multi.clone());
+        }
         let mut bars = HashMap::new();
 
+        let rows = terminal::size()
+            .map(|(_, rows)| rows as usize)
+            .unwrap_or(24);
+        let aggregate = live && images.len() + RESERVED_ROWS > rows;
+
+        let total = aggregate.then(|| {
+            let pb = multi.add(ProgressBar::new(images.len() as u64));
+            pb.set_style(
+                ProgressStyle::with_template(
+                    "  {elapsed_precise} {bar:30.cyan/blue} {pos}/{len} images pulled {msg}",
+                )
+                .unwrap()
+                .progress_chars("#> "),
+            );
+            pb
+        });
+
         let style =
             ProgressStyle::with_template("  {elapsed_precise} {bar:30.white/black} WAIT {msg}")
                 .unwrap()
                 .progress_chars("·  ");
 
         for img in images {
-            let pb = multi.add(ProgressBar::new(0));
+            // Per-image bars still track the elapsed time when they are not drawn.
+            let pb = if total.is_some() {
+                ProgressBar::hidden()
+            } else {
+                multi.add(ProgressBar::new(0))
+            };
             pb.set_style(style.clone());
-            pb.set_message(format!("PULL {}", img));
+            pb.set_message(img.clone());
+
+            if cached.contains(img) {
+                pb.set_length(1);
+                pb.set_position(1);
+                pb.set_style(
+                    ProgressStyle::with_template(
+                        "  {elapsed_precise} {bar:30.green/green} CACHED {msg}",
+                    )
+                    .unwrap()
+                    .progress_chars("##"),
+                );
+                pb.set_message(format!("{} (checking for updates)", img));
+            }
+
             bars.insert(img.clone(), pb);
         }
 
         Self {
             _multi: multi,
             bars,
+            cached: cached.clone(),
+            total,
+            plain,
         }
     }
 
-    pub fn update_progress(&self, img: &str, current: u64, total: u64) {
+    /// `img` got one of the pull slots; until then it shows as waiting.
+    pub fn start_image(&self, img: &str) {
+        if let Some(pb) = self.bars.get(img)
+            && !self.cached.contains(img)
+        {
+            pb.set_style(
+                ProgressStyle::with_template("  {elapsed_precise} {bar:30.cyan/blue} PULL {msg}")
+                    .unwrap()
+                    .progress_chars("#> "),
+            );
+            pb.reset_elapsed();
+        }
+
+        if self.plain {
+            println!("  pulling {}...", img);
+        }
+    }
+
+    pub fn update_progress(&self, img: &str, progress: PullProgress) {
         if let Some(pb) = self.bars.get(img) {
-            pb.set_length(total);
-            pb.set_position(current);
+            pb.set_length(progress.total);
+            pb.set_position(progress.current);
             pb.set_style(
                 ProgressStyle::with_template(
                     "  {elapsed_precise} {bar:30.cyan/blue} {bytes}/{total_bytes} {msg}",
@@ -41,30 +186,327 @@ impl PreFlightUI {
                 .unwrap()
                 .progress_chars("#> "),
             );
-            pb.set_message(format!("PULLING {}", img));
+            pb.set_message(format!(
+                "PULLING {} ({}/{} layers)",
+                img, progress.layers_done, progress.layers_total
+            ));
+        }
+
+        if let Some(total) = &self.total {
+            total.set_message(format!("(pulling {})", img));
         }
     }
 
-    pub fn succeed_image(&self, img: &str) {
+    pub fn retry_image(&self, img: &str, attempt: u32, attempts: u32) {
+        if let Some(pb) = self.bars.get(img) {
+            pb.set_message(format!("RETRY {}/{} {}", attempt, attempts, img));
+        }
+
+        if let Some(total) = &self.total {
+            total.set_message(format!("(retrying {} {}/{})", img, attempt, attempts));
+        }
+
+        if self.plain {
+            println!("  retrying {} ({}/{})...", img, attempt, attempts);
+        }
+    }
+
+    /// `bytes` were downloaded in `elapsed`; none means the local copy was up to date.
+    pub fn succeed_image(&self, img: &str, bytes: u64, elapsed: Duration) {
+        let took = format_wall_clock(elapsed.as_millis() as u64);
+        let (label, detail) = match bytes {
+            0 => ("CACHED", format!("up to date, {took}")),
+            _ => ("DONE", format!("{}, {took}", HumanBytes(bytes))),
+        };
         if let Some(pb) = self.bars.get(img) {
             pb.set_length(100);
             pb.set_position(100);
             pb.set_style(
-                ProgressStyle::with_template("  {elapsed_precise} {bar:30.green/green} DONE {msg}")
-                    .unwrap()
-                    .progress_chars("##"),
+                ProgressStyle::with_template(&format!(
+                    "  {{elapsed_precise}} {{bar:30.green/green}} {label} {{msg}}"
+                ))
+                .unwrap()
+                .progress_chars("##"),
+            );
+            pb.finish_with_message(format!("{img} ({detail})"));
+
+            if self.plain {
+                println!("  pulling {}... done ({})", img, detail);
+            }
+        }
+
+        self.advance_total();
+    }
+
+    pub fn cached_image(&self, img: &str) {
+        if let Some(pb) = self.bars.get(img) {
+            pb.set_length(100);
+            pb.set_position(100);
+            pb.set_style(
+                ProgressStyle::with_template(
+                    "  {elapsed_precise} {bar:30.yellow/yellow} CACHED {msg}",
+                )
+                .unwrap()
+                .progress_chars("##"),
             );
-            pb.finish_with_message(img.to_string());
+            pb.finish_with_message(format!("{} (pull failed, using local copy)", img));
+
+            if self.plain {
+                println!("  pulling {}... failed, using the local copy", img);
+            }
+        }
+
+        if let Some(total) = &self.total {
+            total.println(format!(
+                "  {} {} (pull failed, using local copy)",
+                Icon::Warning,
+                img
+            ));
         }
+
+        self.advance_total();
     }
 
-    pub fn failed_image(&self, img: &str) {
+    /// Marks the pull of `img` as failed, with the first line of `reason`.
+    pub fn failed_image(&self, img: &str, reason: &str) {
+        let reason = reason.lines().next().unwrap_or_default();
         if let Some(pb) = self.bars.get(img) {
             pb.set_style(
                 ProgressStyle::with_template("  {elapsed_precise} {bar:30.red/red} ERROR {msg}")
                     .unwrap(),
             );
-            pb.abandon_with_message(format!("❌ Failed {}", img));
+            pb.abandon_with_message(format!("{} {}: {}", Icon::Error, img, reason));
+
+            if self.plain {
+                println!("  pulling {}... failed: {}", img, reason);
+            }
+        }
+
+        if let Some(total) = &self.total {
+            total.println(format!("  {} {}: {}", Icon::Error, img, reason));
+        }
+
+        self.advance_total();
+    }
+
+    fn advance_total(&self) {
+        if let Some(total) = &self.total {
+            total.inc(1);
+            if total.position() >= total.length().unwrap_or_default() {
+                total.finish_with_message("");
+            }
+        }
+    }
+}
+
+impl Drop for PreFlightUI {
+    fn drop(&mut self) {
+        if let Ok(mut pulls) = PULLS.lock() {
+            *pulls = None;
+        }
+    }
+}
+
+struct StepLine {
+    bar: ProgressBar,
+    status: String,
+    /// How long the step took before; its bar fills up over that time.
+    expected: Option<Duration>,
+}
+
+/// One line per step of a running stage: elapsed time, status and the latest log line.
+/// Finished steps collapse into a single summary line.
+pub struct StageUI {
+    stage: String,
+    _multi: MultiProgress,
+    steps: HashMap<String, StepLine>,
+}
+
+impl StageUI {
+    pub fn new(stage: &Stage) -> Self {
+        let multi = PULLS
+            .lock()
+            .ok()
+            .and_then(|pulls| pulls.clone())
+            .unwrap_or_default();
+        let style =
+            ProgressStyle::with_template("  {spinner:.cyan} {prefix:.bold} [{elapsed}] {wide_msg}")
+                .unwrap()
+                .tick_chars("⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏ ");
+
+        let steps = stage
+            .steps
+            .iter()
+            .filter(|step| step.skip.is_none())
+            .map(|step| {
+                let bar = multi.add(ProgressBar::new_spinner());
+                bar.set_style(style.clone());
+                bar.set_prefix(step.exploded_name.clone());
+                bar.set_message("QUEUED".dimmed().to_string());
+
+                let line = StepLine {
+                    bar,
+                    status: "QUEUED".to_string(),
+                    expected: None,
+                };
+                (step.exploded_name.clone(), line)
+            })
+            .collect();
+
+        if let Ok(mut active) = ACTIVE.lock() {
+            *active = Some(multi.clone());
         }
+
+        Self {
+            stage: stage.name.clone(),
+            _multi: multi,
+            steps,
+        }
+    }
+
+    /// Updates the lines from `events` until the stage finishes.
+    pub fn follow(mut self, mut events: broadcast::Receiver<PipelineEvent>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticks = interval(SPINNER_TICK);
+            loop {
+                let event = tokio::select! {
+                    event = events.recv() => event,
+                    _ = ticks.tick() => {
+                        self.advance_bars();
+                        continue;
+                    }
+                };
+                match event {
+                    Ok(PipelineEvent::StageFinished { stage, .. }) if stage == self.stage => break,
+                    Ok(event) => self.apply(event),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+            self.finish();
+        })
+    }
+
+    /// Moves the bars of running steps with a known duration along with their time.
+    fn advance_bars(&self) {
+        for line in self.steps.values() {
+            if line.expected.is_some() && !line.bar.is_finished() {
+                let elapsed = line.bar.elapsed().as_millis() as u64;
+                line.bar
+                    .set_position(elapsed.min(line.bar.length().unwrap_or(0)));
+            }
+        }
+    }
+
+    fn apply(&mut self, event: PipelineEvent) {
+        match event {
+            PipelineEvent::StepStarted { step, expected, .. } => {
+                if let Some(line) = self.steps.get_mut(&step) {
+                    line.status = "RUNNING".to_string();
+                    line.bar.reset_elapsed();
+                    line.bar.enable_steady_tick(SPINNER_TICK);
+                    line.bar.set_message(line.status.cyan().to_string());
+
+                    // Without an earlier run there is nothing to measure against.
+                    if let Some(expected) = expected {
+                        line.expected = Some(Duration::from_millis(expected));
+                        line.bar.set_length(expected);
+                        line.bar.set_style(
+                            ProgressStyle::with_template(&format!(
+                                "  {{bar:10.cyan/blue}} {{prefix:.bold}} [{{elapsed}}/{}] {{wide_msg}}",
+                                format_wall_clock(expected)
+                            ))
+                            .unwrap()
+                            .progress_chars("#> "),
+                        );
+                    }
+                }
+            }
+            PipelineEvent::StepRetrying {
+                step,
+                attempt,
+                max_retries,
+            } => {
+                if let Some(line) = self.steps.get_mut(&step) {
+                    line.status = format!("RETRYING {}/{}", attempt, max_retries);
+                    line.bar.set_message(line.status.yellow().to_string());
+                }
+            }
+            PipelineEvent::StepSilent {
+                step, silent_for, ..
+            } => {
+                if let Some(line) = self.steps.get(&step)
+                    && !line.bar.is_finished()
+                {
+                    let silence = format!("no output for {}", format_wall_clock(silent_for));
+                    line.bar
+                        .set_message(format!("{} {}", line.status.cyan(), silence.yellow()));
+                }
+            }
+            PipelineEvent::StepLog {
+                step, line: log, ..
+            } => {
+                if let Some(line) = self.steps.get(&step)
+                    && !line.bar.is_finished()
+                {
+                    let status = if line.status.starts_with("RETRYING") {
+                        line.status.yellow()
+                    } else {
+                        line.status.cyan()
+                    };
+                    line.bar.set_message(format!("{} {}", status, log.dimmed()));
+                }
+            }
+            PipelineEvent::StepFinished {
+                step,
+                status,
+                retries,
+                elapsed,
+                ..
+            } => {
+                if let Some(line) = self.steps.get(&step) {
+                    let label = match status {
+                        StepStatus::Success => "DONE".green().bold(),
+                        StepStatus::Failed => "FAILED".red().bold(),
+                        StepStatus::Cancelled => "STOPPED".yellow().bold(),
+                        StepStatus::Skipped => "SKIPPED".dimmed(),
+                    };
+                    let retries = if retries > 0 {
+                        format!(", {} retries", retries)
+                    } else {
+                        String::new()
+                    };
+                    Self::collapse(
+                        &line.bar,
+                        format!(
+                            "{} {} ({:.1}s{})",
+                            label,
+                            step.bold(),
+                            elapsed as f64 / 1000.0,
+                            retries
+                        ),
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Steps that never got to run, e.g. after an earlier failure.
+    fn finish(&self) {
+        for (name, line) in self.steps.iter() {
+            if !line.bar.is_finished() {
+                Self::collapse(&line.bar, format!("{} {}", "SKIPPED".dimmed(), name.bold()));
+            }
+        }
+
+        if let Ok(mut active) = ACTIVE.lock() {
+            *active = None;
+        }
+    }
+
+    fn collapse(bar: &ProgressBar, message: String) {
+        bar.set_style(ProgressStyle::with_template("  {msg}").unwrap());
+        bar.finish_with_message(message);
     }
 }
@@ -0,0 +1,229 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Ok;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+/// Patterns in gitignore syntax, one per line, for files steps never need to see.
+pub const IGNORE_FILE: &str = ".ciroachignore";
+
+/// Applied before `ignore = [...]` and `.ciroachignore`, which can re-include them with
+/// `!pattern`. `.git` is a file in a linked worktree.
+const DEFAULT_IGNORES: [&str; 4] = [".git", "logs/", ".ciroach/", "target/"];
+
+/// Which workspace files are left out when the workspace is copied or hashed, in
+/// gitignore syntax: the last matching pattern wins and `!` re-includes.
+pub struct IgnoreRules {
+    gitignore: Gitignore,
+}
+
+impl IgnoreRules {
+    /// The defaults, then `patterns` from the pipeline, then `.ciroachignore` in `root`.
+    pub fn load(root: &Path, patterns: &[String]) -> anyhow::Result<Self> {
+        let mut builder = GitignoreBuilder::new(root);
+        for line in DEFAULT_IGNORES
+            .iter()
+            .copied()
+            .chain(patterns.iter().map(String::as_str))
+        {
+            builder
+                .add_line(None, line)
+                .map_err(|err| anyhow::anyhow!("Invalid ignore pattern '{line}': {err}"))?;
+        }
+
+        let path = root.join(IGNORE_FILE);
+        let file = fs::read_to_string(&path).unwrap_or_default();
+        for line in file.lines() {
+            builder.add_line(Some(path.clone()), line).map_err(|err| {
+                anyhow::anyhow!("Invalid pattern '{line}' in {IGNORE_FILE}: {err}")
+            })?;
+        }
+
+        Ok(Self {
+            gitignore: builder.build()?,
+        })
+    }
+
+    /// `path` is relative to the workspace root and uses `/` as separator.
+    pub fn is_ignored(&self, path: &str, is_dir: bool) -> bool {
+        self.gitignore.matched(path, is_dir).is_ignore()
+    }
+}
+
+/// Every file and directory under `root` that is not ignored, relative to it and sorted.
+/// Ignored directories are not descended into, and symlinks are listed, not followed.
+pub fn walk(root: &Path, rules: &IgnoreRules) -> anyhow::Result<Vec<PathBuf>> {
+    let mut entries = Vec::new();
+    let mut pending = vec![PathBuf::new()];
+
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(root.join(&dir))? {
+            let entry = entry?;
+            let relative = dir.join(entry.file_name());
+            let is_dir = entry.file_type()?.is_dir();
+
+            let key = relative.to_string_lossy().replace('\\', "/");
+            if rules.is_ignored(&key, is_dir) {
+                continue;
+            }
+
+            if is_dir {
+                pending.push(relative.clone());
+            }
+            entries.push(relative);
+        }
+    }
+
+    entries.sort();
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Each path with whether `patterns` leave it out; a trailing `/` marks a directory.
+    fn ignored(patterns: &[&str], paths: &[&str]) -> Vec<(String, bool)> {
+        let patterns: Vec<String> = patterns.iter().map(|p| p.to_string()).collect();
+        let rules = IgnoreRules::load(Path::new("/nonexistent"), &patterns).unwrap();
+        paths
+            .iter()
+            .map(|path| {
+                let (path, is_dir) = match path.strip_suffix('/') {
+                    Some(dir) => (dir, true),
+                    None => (*path, false),
+                };
+                (path.to_string(), rules.is_ignored(path, is_dir))
+            })
+            .collect()
+    }
+
+    fn expected(cases: &[(&str, bool)]) -> Vec<(String, bool)> {
+        cases
+            .iter()
+            .map(|(path, ignored)| (path.trim_end_matches('/').to_string(), *ignored))
+            .collect()
+    }
+
+    #[test]
+    fn defaults_leave_out_git_logs_and_build_output() {
+        let cases = [
+            (".git/", true),
+            // A linked worktree has a `.git` file.
+            (".git", true),
+            ("logs/", true),
+            (".ciroach/", true),
+            ("target/", true),
+            ("crates/cli/target/", true),
+            // Only directories are meant.
+            ("target", false),
+            ("src/", false),
+            ("src/main.rs", false),
+        ];
+        let paths: Vec<&str> = cases.iter().map(|(path, _)| *path).collect();
+        assert_eq!(ignored(&[], &paths), expected(&cases));
+    }
+
+    #[test]
+    fn a_default_can_be_included_again() {
+        let cases = [("target/", false), ("logs/", true)];
+        let paths: Vec<&str> = cases.iter().map(|(path, _)| *path).collect();
+        assert_eq!(ignored(&["!target/"], &paths), expected(&cases));
+    }
+
+    #[test]
+    fn the_last_matching_pattern_wins() {
+        let cases = [("build.log", true), ("keep.log", false), ("main.rs", false)];
+        let paths: Vec<&str> = cases.iter().map(|(path, _)| *path).collect();
+        assert_eq!(ignored(&["*.log", "!keep.log"], &paths), expected(&cases));
+
+        let cases = [("build.log", true), ("keep.log", true)];
+        let paths: Vec<&str> = cases.iter().map(|(path, _)| *path).collect();
+        assert_eq!(ignored(&["!keep.log", "*.log"], &paths), expected(&cases));
+    }
+
+    #[test]
+    fn a_slash_anchors_a_pattern_to_the_root() {
+        let cases = [
+            ("build/", true),
+            ("src/build/", false),
+            ("docs/out/", true),
+            ("site/docs/out/", false),
+            ("node_modules/", true),
+            ("web/node_modules/", true),
+        ];
+        let paths: Vec<&str> = cases.iter().map(|(path, _)| *path).collect();
+        assert_eq!(
+            ignored(&["/build", "docs/out", "node_modules"], &paths),
+            expected(&cases)
+        );
+    }
+
+    #[test]
+    fn a_trailing_slash_only_matches_directories() {
+        let cases = [("cache/", true), ("cache", false), ("src/cache/", true)];
+        let paths: Vec<&str> = cases.iter().map(|(path, _)| *path).collect();
+        assert_eq!(ignored(&["cache/"], &paths), expected(&cases));
+    }
+
+    #[test]
+    fn double_stars_match_any_number_of_directories() {
+        let cases = [
+            ("tmp/", true),
+            ("a/b/tmp/", true),
+            ("fixtures/data.json", true),
+            ("fixtures/deep/data.json", true),
+            ("a/z", true),
+            ("a/x/y/z", true),
+            ("b/x/z", false),
+            ("vendor/lib.rs", true),
+            ("vendor/", false),
+        ];
+        let paths: Vec<&str> = cases.iter().map(|(path, _)| *path).collect();
+        assert_eq!(
+            ignored(
+                &["**/tmp", "fixtures/**/*.json", "a/**/z", "vendor/**"],
+                &paths
+            ),
+            expected(&cases)
+        );
+    }
+
+    #[test]
+    fn classes_and_escapes_follow_gitignore() {
+        let cases = [
+            ("file1.txt", true),
+            ("filex.txt", false),
+            ("]x", true),
+            ("ax", true),
+            ("bx", false),
+            ("#notes", true),
+            ("!important", true),
+            ("q.md", true),
+        ];
+        let paths: Vec<&str> = cases.iter().map(|(path, _)| *path).collect();
+        assert_eq!(
+            ignored(
+                &["file[0-9].txt", "[]a]x", r"\#notes", r"\!important", "?.md"],
+                &paths
+            ),
+            expected(&cases)
+        );
+    }
+
+    #[test]
+    fn the_ignore_file_comes_after_the_pipeline_patterns() {
+        let root = std::env::temp_dir().join(format!("ciroach-ignore-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join(IGNORE_FILE), "# generated\n\n!debug.log\n").unwrap();
+
+        let rules = IgnoreRules::load(&root, &["*.log".to_string()]).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert!(rules.is_ignored("build.log", false));
+        assert!(!rules.is_ignored("debug.log", false));
+        assert!(!rules.is_ignored("# generated", false));
+    }
+}